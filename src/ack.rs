@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::error::WsError;
+use crate::timers::clear_timeout;
+use crate::WsMessage;
+
+pub type AckMatcher = Box<dyn Fn(&str) -> Option<String>>;
+pub type AckHandler = Box<dyn FnOnce(Result<(), WsError>)>;
+
+pub struct AckConfig {
+    id_field: String,
+    matcher: AckMatcher,
+    resend_on_reconnect: bool,
+}
+
+impl AckConfig {
+    // By default the correlation id goes out as `ack_id` and the server
+    // confirms it with a `{"ack": <id>}` frame.
+    pub fn new() -> Self {
+        Self {
+            id_field: String::from("ack_id"),
+            matcher: Box::new(|payload| {
+                let value: Value = serde_json::from_str(payload).ok()?;
+                match value.get("ack")? {
+                    Value::String(id) => Some(id.clone()),
+                    Value::Number(id) => Some(id.to_string()),
+                    _ => None,
+                }
+            }),
+            resend_on_reconnect: true,
+        }
+    }
+
+    pub fn id_field(mut self, id_field: &str) -> Self {
+        self.id_field = String::from(id_field);
+        self
+    }
+
+    pub fn matcher(mut self, matcher: impl Fn(&str) -> Option<String> + 'static) -> Self {
+        self.matcher = Box::new(matcher);
+        self
+    }
+
+    pub fn no_resend_on_reconnect(mut self) -> Self {
+        self.resend_on_reconnect = false;
+        self
+    }
+}
+
+impl Default for AckConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct PendingAck {
+    message: WsMessage,
+    handler: AckHandler,
+    needs_resend: bool,
+    timer: Option<i32>,
+}
+
+impl PendingAck {
+    fn into_handler(self) -> AckHandler {
+        if let Some(id) = self.timer {
+            clear_timeout(id);
+        }
+        self.handler
+    }
+}
+
+pub struct AckTracker {
+    config: AckConfig,
+    next_id: u64,
+    pending: HashMap<String, PendingAck>,
+}
+
+impl AckTracker {
+    pub fn new(config: AckConfig) -> Self {
+        Self {
+            config,
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Injects a fresh correlation id into the json object carried by the
+    // message, the frame type is kept as it is.
+    pub fn prepare(&mut self, message: WsMessage) -> Result<(String, WsMessage), WsError> {
+        let mut value: Value = match &message {
+            WsMessage::Text(payload) => serde_json::from_str(payload)?,
+            WsMessage::Binary(payload) => serde_json::from_slice(payload)?,
+        };
+        let object = match value.as_object_mut() {
+            Some(object) => object,
+            None => {
+                return Err(WsError::Serde(String::from(
+                    "ack requires a json object message",
+                )))
+            }
+        };
+        self.next_id += 1;
+        let ack_id = self.next_id.to_string();
+        object.insert(self.config.id_field.clone(), Value::String(ack_id.clone()));
+        let message = match message {
+            WsMessage::Text(_) => WsMessage::Text(serde_json::to_string(&value)?),
+            WsMessage::Binary(_) => WsMessage::Binary(serde_json::to_vec(&value)?),
+        };
+        Ok((ack_id, message))
+    }
+
+    pub fn register(&mut self, ack_id: String, message: WsMessage, handler: AckHandler) {
+        self.pending.insert(
+            ack_id,
+            PendingAck {
+                message,
+                handler,
+                needs_resend: false,
+                timer: None,
+            },
+        );
+    }
+
+    // The ack timeout of the message, cleared once the ack arrived or the
+    // message failed otherwise.
+    pub fn set_timer(&mut self, ack_id: &str, id: i32) {
+        match self.pending.get_mut(ack_id) {
+            Some(pending) => pending.timer = Some(id),
+            None => clear_timeout(id),
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // Returns the handler of the acknowledged message, frames that are not an
+    // ack for a pending message give `None`.
+    pub fn match_ack(&mut self, payload: &str) -> Option<AckHandler> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let ack_id = (self.config.matcher)(payload)?;
+        self.take(&ack_id)
+    }
+
+    // Binary acks are matched like text ones when they are valid utf-8.
+    pub fn match_binary_ack(&mut self, payload: &[u8]) -> Option<AckHandler> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.match_ack(std::str::from_utf8(payload).ok()?)
+    }
+
+    pub fn take(&mut self, ack_id: &str) -> Option<AckHandler> {
+        self.pending.remove(ack_id).map(PendingAck::into_handler)
+    }
+
+    // Called by the timeout itself, its timer is already gone.
+    pub(crate) fn take_expired(&mut self, ack_id: &str) -> Option<AckHandler> {
+        self.pending.remove(ack_id).map(|pending| pending.handler)
+    }
+
+    pub fn mark_resend(&mut self) {
+        if !self.config.resend_on_reconnect {
+            return;
+        }
+        for pending in self.pending.values_mut() {
+            pending.needs_resend = true;
+        }
+    }
+
+    // Messages still waiting for an ack when the connection dropped, they go
+    // out again once it is back, so delivery is at least once.
+    pub fn take_resend(&mut self) -> Vec<WsMessage> {
+        self.pending
+            .values_mut()
+            .filter(|pending| pending.needs_resend)
+            .map(|pending| {
+                pending.needs_resend = false;
+                pending.message.clone()
+            })
+            .collect()
+    }
+
+    pub fn fail_pending(&mut self) -> Vec<AckHandler> {
+        self.pending
+            .drain()
+            .map(|(_, pending)| pending.into_handler())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn injects_ids_and_matches_text_and_binary_acks() {
+        let mut tracker = AckTracker::new(AckConfig::new());
+        let (ack_id, message) = tracker
+            .prepare(WsMessage::Text(String::from("{\"a\": 1}")))
+            .unwrap();
+        match &message {
+            WsMessage::Text(payload) => assert!(payload.contains("\"ack_id\":\"1\"")),
+            _ => panic!("expected a text frame"),
+        }
+        let acked = Rc::new(Cell::new(false));
+        let acked_ref = acked.clone();
+        tracker.register(
+            ack_id,
+            message,
+            Box::new(move |result| acked_ref.set(result.is_ok())),
+        );
+        assert!(tracker.match_ack("{\"ack\": 2}").is_none());
+        assert!(tracker.match_binary_ack(&[0xff, 0xfe]).is_none());
+        let handler = tracker.match_binary_ack(b"{\"ack\": 1}").unwrap();
+        handler(Ok(()));
+        assert!(acked.get());
+        assert!(!tracker.has_pending());
+    }
+
+    #[test]
+    fn refuses_messages_that_are_no_json_objects() {
+        let mut tracker = AckTracker::new(AckConfig::new());
+        assert!(tracker
+            .prepare(WsMessage::Text(String::from("[1]")))
+            .is_err());
+        assert!(tracker.prepare(WsMessage::Binary(vec![1, 2])).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+use crate::close::CloseCode;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+const ACTION_CABLE_SUBPROTOCOL: &str = "actioncable-v1-json";
+
+// The server pings every 3 seconds, two missed pings mean the socket is dead.
+const STALE_THRESHOLD: u32 = 6_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CableEvent {
+    // confirmed by the server, again after every reconnect
+    Connected,
+    Rejected,
+    Received(Value),
+    // the socket closed, the subscription is made again on the next welcome
+    Disconnected,
+}
+
+#[derive(Deserialize)]
+struct Frame {
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+    #[serde(default)]
+    identifier: Option<String>,
+    #[serde(default)]
+    message: Option<Value>,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    reconnect: Option<bool>,
+}
+
+type CableHandler = Rc<dyn Fn(CableEvent)>;
+
+struct SubscriptionInner {
+    identifier: String,
+    handler: CableHandler,
+    confirmed: Cell<bool>,
+}
+
+struct CableInner {
+    websocket: Websocket,
+    // keyed by the identifier json, the server echoes it unchanged
+    subscriptions: RefCell<HashMap<String, Rc<SubscriptionInner>>>,
+    welcomed: Cell<bool>,
+    stale_timeout: Cell<Option<i32>>,
+}
+
+impl CableInner {
+    fn is_open(&self) -> bool {
+        self.welcomed.get() && matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+
+    fn command(
+        &self,
+        command: &str,
+        identifier: &str,
+        data: Option<String>,
+    ) -> Result<(), WsError> {
+        let mut frame = json!({ "command": command, "identifier": identifier });
+        if let Some(data) = data {
+            frame["data"] = json!(data);
+        }
+        self.websocket.send(WsMessage::Text(frame.to_string()))
+    }
+
+    fn subscribe(&self, subscription: &SubscriptionInner) {
+        if !self.is_open() {
+            return;
+        }
+        if let Err(err) = self.command("subscribe", &subscription.identifier, None) {
+            console_log!("error on subscribe {}: {:?}", subscription.identifier, err);
+        }
+    }
+
+    fn closed(&self) {
+        self.welcomed.set(false);
+        if let Some(id) = self.stale_timeout.take() {
+            clear_timeout(id);
+        }
+        let subscriptions: Vec<Rc<SubscriptionInner>> =
+            self.subscriptions.borrow().values().cloned().collect();
+        for subscription in subscriptions {
+            if subscription.confirmed.replace(false) {
+                (subscription.handler)(CableEvent::Disconnected);
+            }
+        }
+    }
+
+    fn reset_stale_timeout(&self) {
+        if let Some(id) = self.stale_timeout.take() {
+            clear_timeout(id);
+        }
+        if !self.welcomed.get() {
+            return;
+        }
+        let core = Rc::downgrade(&self.websocket.core);
+        let id = set_timeout_once(
+            move || {
+                if let Some(core) = core.upgrade() {
+                    console_log!("no action cable ping in time, reconnecting");
+                    force_reconnect(&core);
+                }
+            },
+            STALE_THRESHOLD,
+        );
+        self.stale_timeout.set(Some(id));
+    }
+
+    fn dispatch(&self, data: &str) {
+        let frame = match serde_json::from_str::<Frame>(data) {
+            Ok(frame) => frame,
+            Err(err) => {
+                console_log!("error on parse action cable frame {}: {:?}", data, err);
+                return;
+            }
+        };
+        let subscription = frame
+            .identifier
+            .as_ref()
+            .and_then(|identifier| self.subscriptions.borrow().get(identifier).cloned());
+        match frame.kind.as_deref() {
+            Some("welcome") => {
+                self.welcomed.set(true);
+                let subscriptions: Vec<Rc<SubscriptionInner>> =
+                    self.subscriptions.borrow().values().cloned().collect();
+                for subscription in subscriptions {
+                    self.subscribe(&subscription);
+                }
+            }
+            Some("ping") => (),
+            Some("disconnect") => {
+                let reason = frame.reason.unwrap_or_default();
+                if frame.reconnect.unwrap_or(true) {
+                    console_log!("action cable disconnect: {}, reconnecting", reason);
+                    force_reconnect(&self.websocket.core);
+                } else if let Err(err) = self
+                    .websocket
+                    .clone()
+                    .close(Some(CloseCode::Normal), Some(reason))
+                {
+                    console_log!("error on close: {:?}", err);
+                }
+            }
+            Some("confirm_subscription") => {
+                if let Some(subscription) = subscription {
+                    subscription.confirmed.set(true);
+                    (subscription.handler)(CableEvent::Connected);
+                }
+            }
+            Some("reject_subscription") => {
+                if let Some(subscription) = subscription {
+                    self.subscriptions
+                        .borrow_mut()
+                        .remove(&subscription.identifier);
+                    (subscription.handler)(CableEvent::Rejected);
+                }
+            }
+            Some(kind) => console_log!("unknown action cable frame type {}", kind),
+            None => {
+                if let (Some(subscription), Some(message)) = (subscription, frame.message) {
+                    (subscription.handler)(CableEvent::Received(message));
+                }
+            }
+        }
+        self.reset_stale_timeout();
+    }
+}
+
+// An Action Cable client for Rails backends. The factory url is the cable
+// endpoint, e.g. `wss://host/cable`. Subscriptions are made again after every
+// reconnect, once the server sent its welcome. Two missed server pings get
+// the socket reconnected, and so does a `disconnect` from the server unless
+// it says not to reconnect.
+#[derive(Clone)]
+pub struct ActionCable {
+    inner: Rc<CableInner>,
+}
+
+impl ActionCable {
+    pub fn build(mut factory: WsFactory) -> Result<Self, WsError> {
+        if factory.socket_factory.is_none() {
+            factory = factory
+                .socket_factory(|url| WebSocket::new_with_str(url, ACTION_CABLE_SUBPROTOCOL));
+        }
+        let inner = ProtocolClient::new(factory, |inner: &Rc<CableInner>, message| {
+            if let WsMessage::Text(data) = message {
+                inner.dispatch(&data);
+            }
+        })
+        .on_close(|cable: &Rc<CableInner>| cable.closed())
+        .build(|websocket| CableInner {
+            websocket,
+            subscriptions: RefCell::new(HashMap::new()),
+            welcomed: Cell::new(false),
+            stale_timeout: Cell::new(None),
+        })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.is_open()
+    }
+
+    // `params` are merged into the identifier next to the channel name, e.g.
+    // `subscribe("ChatChannel", json!({ "room": "1" }), ...)`.
+    pub fn subscribe(
+        &self,
+        channel: &str,
+        params: Value,
+        handler: impl Fn(CableEvent) + 'static,
+    ) -> Result<CableSubscription, WsError> {
+        let mut identifier = match params {
+            Value::Object(params) => params,
+            Value::Null => Map::new(),
+            _ => return Err(WsError::Params(String::from("params must be an object"))),
+        };
+        identifier.insert(String::from("channel"), json!(channel));
+        let identifier = Value::Object(identifier).to_string();
+        if self.inner.subscriptions.borrow().contains_key(&identifier) {
+            return Err(WsError::Params(format!(
+                "already subscribed to {}",
+                identifier
+            )));
+        }
+        let subscription = Rc::new(SubscriptionInner {
+            identifier: identifier.clone(),
+            handler: Rc::new(handler),
+            confirmed: Cell::new(false),
+        });
+        self.inner
+            .subscriptions
+            .borrow_mut()
+            .insert(identifier, subscription.clone());
+        self.inner.subscribe(&subscription);
+        Ok(CableSubscription {
+            subscription,
+            cable: self.inner.clone(),
+        })
+    }
+
+    pub fn identifiers(&self) -> Vec<String> {
+        self.inner.subscriptions.borrow().keys().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct CableSubscription {
+    subscription: Rc<SubscriptionInner>,
+    cable: Rc<CableInner>,
+}
+
+impl CableSubscription {
+    pub fn identifier(&self) -> &str {
+        &self.subscription.identifier
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.subscription.confirmed.get()
+    }
+
+    // Calls the public method `action` of the channel with `data`.
+    pub fn perform(&self, action: &str, data: Value) -> Result<(), WsError> {
+        let mut data = match data {
+            Value::Object(data) => data,
+            Value::Null => Map::new(),
+            _ => return Err(WsError::Params(String::from("data must be an object"))),
+        };
+        data.insert(String::from("action"), json!(action));
+        self.send(Value::Object(data))
+    }
+
+    // Handed to the `receive` method of the channel.
+    pub fn send(&self, data: Value) -> Result<(), WsError> {
+        if !self.subscription.confirmed.get() || !self.cable.is_open() {
+            return Err(WsError::NotConnected {
+                state: self.cable.websocket.ready_state(),
+            });
+        }
+        self.cable.command(
+            "message",
+            &self.subscription.identifier,
+            Some(data.to_string()),
+        )
+    }
+
+    pub fn unsubscribe(self) -> Result<(), WsError> {
+        self.cable
+            .subscriptions
+            .borrow_mut()
+            .remove(&self.subscription.identifier);
+        let confirmed = self.subscription.confirmed.replace(false);
+        if !confirmed || !self.cable.is_open() {
+            return Ok(());
+        }
+        self.cable
+            .command("unsubscribe", &self.subscription.identifier, None)
+    }
+}
@@ -0,0 +1,66 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::WsMessage;
+
+pub type TokenFuture = Pin<Box<dyn Future<Output = Result<String, String>>>>;
+pub type TokenProvider = Rc<dyn Fn() -> TokenFuture>;
+
+// Gets the latest token of the token provider, if there is one.
+pub type LoginBuilder = Box<dyn Fn(Option<&str>) -> WsMessage>;
+
+// `Some(Ok(()))` accepts the login, `Some(Err(reason))` rejects it and `None`
+// means the frame is not a reply to the login.
+pub type AuthReply = Box<dyn Fn(&WsMessage) -> Option<Result<(), String>>>;
+
+// After every open the login frame is sent first, the socket only counts as
+// open once the server accepted it. Sends made in between wait in the queue.
+pub struct AuthHandshake {
+    login: LoginBuilder,
+    reply: AuthReply,
+    // the rest of the open sequence, run once the login is accepted
+    pending: RefCell<Option<Box<dyn FnOnce()>>>,
+    rejected: Cell<bool>,
+}
+
+impl AuthHandshake {
+    pub fn new(
+        login: impl Fn(Option<&str>) -> WsMessage + 'static,
+        reply: impl Fn(&WsMessage) -> Option<Result<(), String>> + 'static,
+    ) -> Self {
+        Self {
+            login: Box::new(login),
+            reply: Box::new(reply),
+            pending: RefCell::new(None),
+            rejected: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn begin(&self, ready: Box<dyn FnOnce()>, token: Option<&str>) -> WsMessage {
+        *self.pending.borrow_mut() = Some(ready);
+        (self.login)(token)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.borrow().is_some()
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.rejected.get()
+    }
+
+    pub(crate) fn reply(&self, message: &WsMessage) -> Option<Result<(), String>> {
+        (self.reply)(message)
+    }
+
+    pub(crate) fn take_pending(&self) -> Option<Box<dyn FnOnce()>> {
+        self.pending.borrow_mut().take()
+    }
+
+    pub(crate) fn reject(&self) {
+        self.pending.borrow_mut().take();
+        self.rejected.set(true);
+    }
+}
@@ -0,0 +1,662 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::close::CloseCode;
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+pub const CENTRIFUGE_ERROR_EVENT: &str = "centrifuge_error";
+
+// The server pings every `ping` seconds, a ping this late means the socket
+// is dead.
+const MAX_PING_DELAY: u32 = 10_000;
+const TOKEN_EXPIRED: u32 = 109;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamPosition {
+    pub offset: u64,
+    pub epoch: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelEvent {
+    // `recovered` is true when the publications missed while disconnected
+    // were delivered before this event.
+    Subscribed {
+        recovered: bool,
+    },
+    Publication {
+        data: Value,
+        offset: Option<u64>,
+        info: Option<Value>,
+    },
+    Join(Value),
+    Leave(Value),
+    // the server unsubscribed the client, it is not subscribed again
+    Unsubscribed {
+        code: u32,
+        reason: String,
+    },
+    Error {
+        code: u32,
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ReplyError {
+    code: u32,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    temporary: bool,
+}
+
+#[derive(Deserialize)]
+struct Publication {
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    offset: Option<u64>,
+    #[serde(default)]
+    info: Option<Value>,
+}
+
+#[derive(Default, Deserialize)]
+struct ConnectResult {
+    #[serde(default)]
+    expires: bool,
+    #[serde(default)]
+    ttl: u32,
+    #[serde(default)]
+    ping: u32,
+    #[serde(default)]
+    pong: bool,
+}
+
+#[derive(Default, Deserialize)]
+struct SubscribeResult {
+    #[serde(default)]
+    recoverable: bool,
+    #[serde(default)]
+    epoch: String,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default)]
+    publications: Vec<Publication>,
+    #[serde(default)]
+    recovered: bool,
+}
+
+#[derive(Deserialize)]
+struct Disconnect {
+    code: u32,
+    #[serde(default)]
+    reason: String,
+}
+
+// Replies carry the id of their command, pushes have none.
+#[derive(Deserialize)]
+struct Reply {
+    #[serde(default)]
+    id: u32,
+    #[serde(default)]
+    error: Option<ReplyError>,
+    #[serde(default)]
+    connect: Option<Value>,
+    #[serde(default)]
+    subscribe: Option<Value>,
+    #[serde(default)]
+    refresh: Option<Value>,
+    #[serde(default)]
+    push: Option<Map<String, Value>>,
+}
+
+type ChannelHandler = Rc<dyn Fn(ChannelEvent)>;
+type ReplyCallback = Box<dyn FnOnce(&Rc<CentrifugeInner>, Result<Value, ReplyError>)>;
+
+struct ChannelInner {
+    name: String,
+    handler: ChannelHandler,
+    subscribed: Cell<bool>,
+    // set when the channel history allows recovery
+    position: RefCell<Option<StreamPosition>>,
+}
+
+struct CentrifugeInner {
+    websocket: Websocket,
+    name: String,
+    channels: RefCell<HashMap<String, Rc<ChannelInner>>>,
+    replies: RefCell<HashMap<u32, ReplyCallback>>,
+    next_id: Cell<u32>,
+    connected: Cell<bool>,
+    pong: Cell<bool>,
+    ping_interval: Cell<u32>,
+    ping_timeout: Cell<Option<i32>>,
+    refresh_timeout: Cell<Option<i32>>,
+    // bumped on every close, drops the token refresh of the previous socket
+    session: Cell<u64>,
+}
+
+impl CentrifugeInner {
+    fn is_open(&self) -> bool {
+        matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+
+    fn command(inner: &Rc<Self>, method: &str, params: Value, callback: ReplyCallback) {
+        let id = inner.next_id.get();
+        inner.next_id.set(id + 1);
+        let mut command = Map::new();
+        command.insert(String::from("id"), json!(id));
+        command.insert(String::from(method), params);
+        inner.replies.borrow_mut().insert(id, callback);
+        let sent = inner
+            .websocket
+            .send(WsMessage::Text(Value::Object(command).to_string()));
+        if let Err(err) = sent {
+            inner.replies.borrow_mut().remove(&id);
+            console_log!("error on centrifuge {}: {:?}", method, err);
+        }
+    }
+
+    fn emit_error(&self, code: u32, message: &str) {
+        if let Some(emitter) = self.websocket.core.factory.emitter.clone() {
+            let error = json!({ "code": code, "message": message }).to_string();
+            emitter
+                .borrow()
+                .emit(String::from(CENTRIFUGE_ERROR_EVENT), &Payload::Data(error));
+        }
+    }
+
+    fn reconnect(&self) {
+        force_reconnect(&self.websocket.core);
+    }
+
+    fn opened(inner: &Rc<Self>) {
+        let factory = &inner.websocket.core.factory;
+        let token_provider = factory.token_provider.clone();
+        if factory.token.borrow().is_some() || token_provider.is_none() {
+            return Self::connect(inner);
+        }
+        // the first socket opens before the core asked the provider
+        let centrifuge = Rc::downgrade(inner);
+        let session = inner.session.get();
+        spawn_local(async move {
+            let token = match token_provider {
+                Some(token_provider) => token_provider().await,
+                None => return,
+            };
+            let centrifuge = match centrifuge.upgrade() {
+                Some(centrifuge) if centrifuge.session.get() == session => centrifuge,
+                _ => return,
+            };
+            match token {
+                Ok(token) => {
+                    *centrifuge.websocket.core.factory.token.borrow_mut() = Some(token);
+                    Self::connect(&centrifuge);
+                }
+                Err(err) => {
+                    centrifuge.emit_error(TOKEN_EXPIRED, &err);
+                    centrifuge.reconnect();
+                }
+            }
+        });
+    }
+
+    fn connect(inner: &Rc<Self>) {
+        let mut params = json!({ "name": inner.name });
+        if let Some(token) = inner.websocket.core.factory.token.borrow().clone() {
+            params["token"] = json!(token);
+        }
+        Self::command(
+            inner,
+            "connect",
+            params,
+            Box::new(|centrifuge, result| match result {
+                Ok(result) => Self::connected(centrifuge, result),
+                Err(err) => {
+                    centrifuge.emit_error(err.code, &err.message);
+                    if err.code == TOKEN_EXPIRED || err.temporary {
+                        centrifuge.reconnect();
+                    } else if let Err(err) = centrifuge
+                        .websocket
+                        .clone()
+                        .close(Some(CloseCode::Normal), Some(err.message))
+                    {
+                        console_log!("error on close: {:?}", err);
+                    }
+                }
+            }),
+        );
+    }
+
+    fn connected(inner: &Rc<Self>, result: Value) {
+        let result: ConnectResult = serde_json::from_value(result).unwrap_or_default();
+        inner.connected.set(true);
+        inner.pong.set(result.pong);
+        inner.ping_interval.set(result.ping * 1000);
+        inner.reset_ping_timeout();
+        if result.expires && result.ttl > 0 {
+            Self::schedule_refresh(inner, result.ttl);
+        }
+        let channels: Vec<Rc<ChannelInner>> = inner.channels.borrow().values().cloned().collect();
+        for channel in channels {
+            Self::subscribe(inner, &channel);
+        }
+    }
+
+    fn closed(&self) {
+        self.connected.set(false);
+        self.session.set(self.session.get() + 1);
+        for id in [self.ping_timeout.take(), self.refresh_timeout.take()]
+            .iter()
+            .flatten()
+        {
+            clear_timeout(*id);
+        }
+        // the server forgets commands with the connection
+        self.replies.borrow_mut().clear();
+        for channel in self.channels.borrow().values() {
+            channel.subscribed.set(false);
+        }
+    }
+
+    fn reset_ping_timeout(&self) {
+        if let Some(id) = self.ping_timeout.take() {
+            clear_timeout(id);
+        }
+        if self.ping_interval.get() == 0 {
+            return;
+        }
+        let core = Rc::downgrade(&self.websocket.core);
+        let id = set_timeout_once(
+            move || {
+                if let Some(core) = core.upgrade() {
+                    console_log!("no centrifuge ping in time, reconnecting");
+                    force_reconnect(&core);
+                }
+            },
+            self.ping_interval.get() + MAX_PING_DELAY,
+        );
+        self.ping_timeout.set(Some(id));
+    }
+
+    // Asks the token provider for a new token before the current one
+    // expires. Without a provider the server disconnects on expiry and the
+    // client connects again with the token it has.
+    fn schedule_refresh(inner: &Rc<Self>, ttl: u32) {
+        let token_provider = match inner.websocket.core.factory.token_provider.clone() {
+            Some(token_provider) => token_provider,
+            None => return,
+        };
+        let centrifuge = Rc::downgrade(inner);
+        let session = inner.session.get();
+        let id = set_timeout_once(
+            move || {
+                spawn_local(async move {
+                    let token = token_provider().await;
+                    let centrifuge = match centrifuge.upgrade() {
+                        Some(centrifuge) if centrifuge.session.get() == session => centrifuge,
+                        _ => return,
+                    };
+                    centrifuge.refresh_timeout.take();
+                    match token {
+                        Ok(token) => Self::refresh(&centrifuge, token),
+                        Err(err) => centrifuge.emit_error(TOKEN_EXPIRED, &err),
+                    }
+                });
+            },
+            ttl * 1000,
+        );
+        inner.refresh_timeout.set(Some(id));
+    }
+
+    fn refresh(inner: &Rc<Self>, token: String) {
+        *inner.websocket.core.factory.token.borrow_mut() = Some(token.clone());
+        Self::command(
+            inner,
+            "refresh",
+            json!({ "token": token }),
+            Box::new(|centrifuge, result| match result {
+                Ok(result) => {
+                    let result: ConnectResult = serde_json::from_value(result).unwrap_or_default();
+                    if result.expires && result.ttl > 0 {
+                        Self::schedule_refresh(centrifuge, result.ttl);
+                    }
+                }
+                Err(err) => centrifuge.emit_error(err.code, &err.message),
+            }),
+        );
+    }
+
+    fn subscribe(inner: &Rc<Self>, channel: &Rc<ChannelInner>) {
+        if !inner.connected.get() || !inner.is_open() {
+            return;
+        }
+        let mut params = json!({ "channel": channel.name });
+        if let Some(position) = channel.position.borrow().as_ref() {
+            params["recover"] = json!(true);
+            params["offset"] = json!(position.offset);
+            params["epoch"] = json!(position.epoch);
+        }
+        let subscribed = Rc::downgrade(channel);
+        Self::command(
+            inner,
+            "subscribe",
+            params,
+            Box::new(move |centrifuge, result| {
+                let channel = match subscribed.upgrade() {
+                    Some(channel) => channel,
+                    None => return,
+                };
+                // unsubscribed while waiting for the reply
+                if !centrifuge.channels.borrow().contains_key(&channel.name) {
+                    return;
+                }
+                match result {
+                    Ok(result) => channel.subscribed_with(result),
+                    Err(err) => (channel.handler)(ChannelEvent::Error {
+                        code: err.code,
+                        message: err.message,
+                    }),
+                }
+            }),
+        );
+    }
+
+    fn receive(inner: &Rc<Self>, data: &str) {
+        // several replies may share a frame, one json object per line
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<Reply>(line) {
+                Ok(reply) => Self::dispatch(inner, reply),
+                Err(err) => console_log!("error on parse centrifuge reply {}: {:?}", line, err),
+            }
+        }
+    }
+
+    fn dispatch(inner: &Rc<Self>, reply: Reply) {
+        if reply.id > 0 {
+            let callback = inner.replies.borrow_mut().remove(&reply.id);
+            if let Some(callback) = callback {
+                let result = match reply.error {
+                    Some(err) => Err(err),
+                    None => Ok(reply
+                        .connect
+                        .or(reply.subscribe)
+                        .or(reply.refresh)
+                        .unwrap_or(Value::Null)),
+                };
+                callback(inner, result);
+            }
+            return;
+        }
+        let push = match reply.push {
+            Some(push) => push,
+            // an empty object is a ping
+            None => {
+                inner.reset_ping_timeout();
+                if inner.pong.get() {
+                    if let Err(err) = inner.websocket.send(WsMessage::Text(String::from("{}"))) {
+                        console_log!("error on centrifuge pong: {:?}", err);
+                    }
+                }
+                return;
+            }
+        };
+        if let Some(disconnect) = push.get("disconnect").cloned() {
+            return inner.disconnected(disconnect);
+        }
+        let channel = push
+            .get("channel")
+            .and_then(Value::as_str)
+            .and_then(|name| inner.channels.borrow().get(name).cloned());
+        let channel = match channel {
+            Some(channel) => channel,
+            None => return,
+        };
+        if let Some(publication) = push.get("pub").cloned() {
+            if let Ok(publication) = serde_json::from_value::<Publication>(publication) {
+                channel.publish(publication);
+            }
+        } else if let Some(join) = push.get("join") {
+            (channel.handler)(ChannelEvent::Join(
+                join.get("info").cloned().unwrap_or(Value::Null),
+            ));
+        } else if let Some(leave) = push.get("leave") {
+            (channel.handler)(ChannelEvent::Leave(
+                leave.get("info").cloned().unwrap_or(Value::Null),
+            ));
+        } else if let Some(unsubscribe) = push.get("unsubscribe").cloned() {
+            inner.channels.borrow_mut().remove(&channel.name);
+            channel.subscribed.set(false);
+            let unsubscribe: Disconnect =
+                serde_json::from_value(unsubscribe).unwrap_or(Disconnect {
+                    code: 0,
+                    reason: String::new(),
+                });
+            (channel.handler)(ChannelEvent::Unsubscribed {
+                code: unsubscribe.code,
+                reason: unsubscribe.reason,
+            });
+        }
+    }
+
+    // Codes from 3500 to 3999 ask the client not to connect again.
+    fn disconnected(&self, disconnect: Value) {
+        let disconnect = match serde_json::from_value::<Disconnect>(disconnect) {
+            Ok(disconnect) => disconnect,
+            Err(_) => return self.reconnect(),
+        };
+        self.emit_error(disconnect.code, &disconnect.reason);
+        if !(3500..4000).contains(&disconnect.code) {
+            return self.reconnect();
+        }
+        if let Err(err) = self
+            .websocket
+            .clone()
+            .close(Some(CloseCode::Normal), Some(disconnect.reason))
+        {
+            console_log!("error on close: {:?}", err);
+        }
+    }
+}
+
+impl ChannelInner {
+    fn subscribed_with(&self, result: Value) {
+        let result: SubscribeResult = serde_json::from_value(result).unwrap_or_default();
+        self.subscribed.set(true);
+        if result.recoverable {
+            *self.position.borrow_mut() = Some(StreamPosition {
+                offset: result.offset,
+                epoch: result.epoch,
+            });
+        }
+        for publication in result.publications {
+            self.publish(publication);
+        }
+        (self.handler)(ChannelEvent::Subscribed {
+            recovered: result.recovered,
+        });
+    }
+
+    fn publish(&self, publication: Publication) {
+        if let (Some(position), Some(offset)) =
+            (self.position.borrow_mut().as_mut(), publication.offset)
+        {
+            position.offset = offset;
+        }
+        (self.handler)(ChannelEvent::Publication {
+            data: publication.data,
+            offset: publication.offset,
+            info: publication.info,
+        });
+    }
+}
+
+// A Centrifuge client (the JSON protocol of Centrifugo v4 and later) over the
+// reconnecting socket. The factory url is the connection endpoint, e.g.
+// `wss://host/connection/websocket`. The connection token comes from the
+// factory `token` and `token_provider`, the provider is asked again before
+// the token expires and before every reconnect. Channels are subscribed
+// again after a reconnect, with their last offset when the channel history
+// allows recovery. The server pings at the interval it sent in the connect
+// reply, a ping that is overdue gets the socket reconnected. Private channel
+// tokens are not supported.
+#[derive(Clone)]
+pub struct CentrifugeClient {
+    inner: Rc<CentrifugeInner>,
+}
+
+impl CentrifugeClient {
+    // `name` is the client name shown in the server logs.
+    pub fn build(factory: WsFactory, name: &str) -> Result<Self, WsError> {
+        let inner = ProtocolClient::new(factory, |inner: &Rc<CentrifugeInner>, message| {
+            if let WsMessage::Text(data) = message {
+                CentrifugeInner::receive(inner, &data);
+            }
+        })
+        .on_open(CentrifugeInner::opened)
+        .on_close(|centrifuge: &Rc<CentrifugeInner>| centrifuge.closed())
+        .build(|websocket| CentrifugeInner {
+            websocket,
+            name: String::from(name),
+            channels: RefCell::new(HashMap::new()),
+            replies: RefCell::new(HashMap::new()),
+            next_id: Cell::new(1),
+            connected: Cell::new(false),
+            pong: Cell::new(false),
+            ping_interval: Cell::new(0),
+            ping_timeout: Cell::new(None),
+            refresh_timeout: Cell::new(None),
+            session: Cell::new(0),
+        })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected.get() && self.inner.is_open()
+    }
+
+    // The handler gets every event of the channel, the subscribe is sent
+    // once the client is connected. `since` starts from a known position,
+    // the publications after it are recovered.
+    pub fn subscribe(
+        &self,
+        channel: &str,
+        since: Option<StreamPosition>,
+        handler: impl Fn(ChannelEvent) + 'static,
+    ) -> Result<CentrifugeSubscription, WsError> {
+        if self.inner.channels.borrow().contains_key(channel) {
+            return Err(WsError::Params(format!(
+                "already subscribed to {}",
+                channel
+            )));
+        }
+        let subscription = Rc::new(ChannelInner {
+            name: String::from(channel),
+            handler: Rc::new(handler),
+            subscribed: Cell::new(false),
+            position: RefCell::new(since),
+        });
+        self.inner
+            .channels
+            .borrow_mut()
+            .insert(String::from(channel), subscription.clone());
+        CentrifugeInner::subscribe(&self.inner, &subscription);
+        Ok(CentrifugeSubscription {
+            channel: subscription,
+            centrifuge: self.inner.clone(),
+        })
+    }
+
+    pub fn channels(&self) -> Vec<String> {
+        self.inner.channels.borrow().keys().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct CentrifugeSubscription {
+    channel: Rc<ChannelInner>,
+    centrifuge: Rc<CentrifugeInner>,
+}
+
+impl CentrifugeSubscription {
+    pub fn channel(&self) -> &str {
+        &self.channel.name
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.channel.subscribed.get()
+    }
+
+    // The position of the last publication, kept when the channel is
+    // recoverable.
+    pub fn position(&self) -> Option<StreamPosition> {
+        self.channel.position.borrow().clone()
+    }
+
+    pub fn publish(&self, data: Value) -> Result<(), WsError> {
+        if !self.channel.subscribed.get() || !self.centrifuge.is_open() {
+            return Err(WsError::NotConnected {
+                state: self.centrifuge.websocket.ready_state(),
+            });
+        }
+        let handler = self.channel.handler.clone();
+        CentrifugeInner::command(
+            &self.centrifuge,
+            "publish",
+            json!({ "channel": self.channel.name, "data": data }),
+            Box::new(move |_, result| {
+                if let Err(err) = result {
+                    handler(ChannelEvent::Error {
+                        code: err.code,
+                        message: err.message,
+                    });
+                }
+            }),
+        );
+        Ok(())
+    }
+
+    pub fn unsubscribe(self) -> Result<(), WsError> {
+        self.centrifuge
+            .channels
+            .borrow_mut()
+            .remove(&self.channel.name);
+        let subscribed = self.channel.subscribed.replace(false);
+        if !subscribed || !self.centrifuge.is_open() {
+            return Ok(());
+        }
+        CentrifugeInner::command(
+            &self.centrifuge,
+            "unsubscribe",
+            json!({ "channel": self.channel.name }),
+            Box::new(|_, _| ()),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+// Every chunk starts with a 16 bytes header: the magic marker followed by the
+// message id, the chunk index and the total number of chunks, all big endian.
+pub const CHUNK_MAGIC: [u8; 4] = *b"WSCK";
+pub const CHUNK_HEADER_SIZE: usize = 16;
+
+// Reassembly is bounded, so a peer can't make it grow without end: messages
+// of more than `max_chunks` chunks are dropped, at most `max_pending`
+// messages are assembled at once, the oldest one makes room, and a message
+// whose chunks stopped coming for `timeout` ms is given up.
+pub const DEFAULT_MAX_CHUNKS: usize = 1024;
+pub const DEFAULT_MAX_PENDING: usize = 16;
+pub const DEFAULT_CHUNK_TIMEOUT: u32 = 30_000;
+
+struct PartialMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    updated_at: f64,
+}
+
+pub struct Chunking {
+    max_chunk_size: usize,
+    max_chunks: usize,
+    max_pending: usize,
+    timeout: u32,
+    next_message_id: u32,
+    partial: HashMap<u32, PartialMessage>,
+}
+
+impl Chunking {
+    pub fn new(max_chunk_size: usize) -> Self {
+        Self {
+            max_chunk_size: max_chunk_size.max(1),
+            max_chunks: DEFAULT_MAX_CHUNKS,
+            max_pending: DEFAULT_MAX_PENDING,
+            timeout: DEFAULT_CHUNK_TIMEOUT,
+            next_message_id: 0,
+            partial: HashMap::new(),
+        }
+    }
+
+    pub fn max_chunks(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = max_chunks.max(1);
+        self
+    }
+
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u32) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn max_chunk_size(&self) -> usize {
+        self.max_chunk_size
+    }
+
+    pub fn split(&mut self, payload: &[u8]) -> Vec<Vec<u8>> {
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        let total = payload.len().div_ceil(self.max_chunk_size) as u32;
+        payload
+            .chunks(self.max_chunk_size)
+            .enumerate()
+            .map(|(index, data)| {
+                let mut chunk = Vec::with_capacity(CHUNK_HEADER_SIZE + data.len());
+                chunk.extend_from_slice(&CHUNK_MAGIC);
+                chunk.extend_from_slice(&message_id.to_be_bytes());
+                chunk.extend_from_slice(&(index as u32).to_be_bytes());
+                chunk.extend_from_slice(&total.to_be_bytes());
+                chunk.extend_from_slice(data);
+                chunk
+            })
+            .collect()
+    }
+
+    pub fn is_chunk(payload: &[u8]) -> bool {
+        payload.len() >= CHUNK_HEADER_SIZE && payload[..4] == CHUNK_MAGIC
+    }
+
+    // Stores the chunk and returns the whole message once its last missing
+    // chunk arrived. Malformed chunks are dropped. Chunks are kept with their
+    // header so the payload is only copied once, into the assembled message.
+    // `now` is the receive time in ms, it drives the expiry.
+    pub fn assemble(&mut self, mut chunk: Vec<u8>, now: f64) -> Option<Vec<u8>> {
+        if !Self::is_chunk(&chunk) {
+            return None;
+        }
+        let message_id = u32::from_be_bytes(chunk[4..8].try_into().ok()?);
+        let index = u32::from_be_bytes(chunk[8..12].try_into().ok()?) as usize;
+        let total = u32::from_be_bytes(chunk[12..16].try_into().ok()?) as usize;
+        if total == 0 || index >= total || total > self.max_chunks {
+            return None;
+        }
+        if total == 1 {
+            chunk.drain(..CHUNK_HEADER_SIZE);
+            return Some(chunk);
+        }
+        self.expire(now);
+        if !self.partial.contains_key(&message_id) && self.partial.len() >= self.max_pending {
+            self.evict_oldest();
+        }
+        let partial = self
+            .partial
+            .entry(message_id)
+            .or_insert_with(|| PartialMessage {
+                chunks: vec![None; total],
+                received: 0,
+                updated_at: now,
+            });
+        if partial.chunks.len() != total {
+            return None;
+        }
+        partial.updated_at = now;
+        if partial.chunks[index].is_none() {
+            partial.chunks[index] = Some(chunk);
+            partial.received += 1;
+        }
+        if partial.received < total {
+            return None;
+        }
+        let partial = self.partial.remove(&message_id)?;
+        let size = partial
+            .chunks
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.len() - CHUNK_HEADER_SIZE)
+            .sum();
+        let mut message = Vec::with_capacity(size);
+        for chunk in partial.chunks.iter().flatten() {
+            message.extend_from_slice(&chunk[CHUNK_HEADER_SIZE..]);
+        }
+        Some(message)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    fn expire(&mut self, now: f64) {
+        let timeout = f64::from(self.timeout);
+        self.partial
+            .retain(|_, partial| now - partial.updated_at < timeout);
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .partial
+            .iter()
+            .min_by(|(_, a), (_, b)| a.updated_at.total_cmp(&b.updated_at))
+            .map(|(message_id, _)| *message_id);
+        if let Some(message_id) = oldest {
+            self.partial.remove(&message_id);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.partial.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(message_id: u32, index: u32, total: u32, data: &[u8]) -> Vec<u8> {
+        let mut chunk = CHUNK_MAGIC.to_vec();
+        chunk.extend_from_slice(&message_id.to_be_bytes());
+        chunk.extend_from_slice(&index.to_be_bytes());
+        chunk.extend_from_slice(&total.to_be_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn assembles_split_messages_in_any_order() {
+        let mut sender = Chunking::new(3);
+        let mut chunks = sender.split(b"hello world");
+        assert_eq!(chunks.len(), 4);
+        chunks.reverse();
+        let mut receiver = Chunking::new(3);
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert_eq!(receiver.assemble(chunk, 0.0), None);
+        }
+        assert_eq!(receiver.assemble(last, 0.0), Some(b"hello world".to_vec()));
+        assert_eq!(receiver.pending(), 0);
+    }
+
+    #[test]
+    fn drops_malformed_chunks() {
+        let mut chunking = Chunking::new(4);
+        assert_eq!(chunking.assemble(b"WSCK".to_vec(), 0.0), None);
+        assert_eq!(chunking.assemble(chunk(1, 0, 0, b"a"), 0.0), None);
+        assert_eq!(chunking.assemble(chunk(1, 2, 2, b"a"), 0.0), None);
+        assert_eq!(
+            chunking.assemble(chunk(1, 0, 1, b"a"), 0.0),
+            Some(b"a".to_vec())
+        );
+        // the total of a message can't change half way
+        assert_eq!(chunking.assemble(chunk(2, 0, 2, b"a"), 0.0), None);
+        assert_eq!(chunking.assemble(chunk(2, 1, 3, b"b"), 0.0), None);
+        assert_eq!(
+            chunking.assemble(chunk(2, 1, 2, b"b"), 0.0),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn rejects_messages_over_max_chunks() {
+        let mut chunking = Chunking::new(4).max_chunks(2);
+        assert_eq!(chunking.assemble(chunk(1, 0, 3, b"a"), 0.0), None);
+        assert_eq!(chunking.pending(), 0);
+        assert_eq!(chunking.assemble(chunk(1, 0, u32::MAX, b"a"), 0.0), None);
+        assert_eq!(chunking.pending(), 0);
+    }
+
+    #[test]
+    fn evicts_the_oldest_message_when_full() {
+        let mut chunking = Chunking::new(4).max_pending(2);
+        chunking.assemble(chunk(1, 0, 2, b"a"), 0.0);
+        chunking.assemble(chunk(2, 0, 2, b"b"), 1.0);
+        chunking.assemble(chunk(3, 0, 2, b"c"), 2.0);
+        assert_eq!(chunking.pending(), 2);
+        assert_eq!(
+            chunking.assemble(chunk(2, 1, 2, b"b"), 3.0),
+            Some(b"bb".to_vec())
+        );
+        assert_eq!(
+            chunking.assemble(chunk(3, 1, 2, b"c"), 3.0),
+            Some(b"cc".to_vec())
+        );
+        assert_eq!(chunking.assemble(chunk(1, 1, 2, b"a"), 3.0), None);
+    }
+
+    #[test]
+    fn expires_stale_messages() {
+        let mut chunking = Chunking::new(4).timeout(100);
+        chunking.assemble(chunk(1, 0, 2, b"a"), 0.0);
+        chunking.assemble(chunk(2, 0, 2, b"b"), 50.0);
+        assert_eq!(chunking.assemble(chunk(3, 0, 2, b"c"), 120.0), None);
+        assert_eq!(chunking.pending(), 2);
+        assert_eq!(chunking.assemble(chunk(1, 1, 2, b"a"), 120.0), None);
+        assert_eq!(
+            chunking.assemble(chunk(2, 1, 2, b"b"), 130.0),
+            Some(b"bb".to_vec())
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+
+use web_sys::CloseEvent;
+
+// Close codes from RFC 6455, 3000-3999 are registered by libraries and
+// frameworks, 4000-4999 are free for applications.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+    NoStatus,
+    AbnormalClosure,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    MandatoryExtension,
+    InternalError,
+    TlsHandshake,
+    Library(u16),
+    Custom(u16),
+    Other(u16),
+}
+
+impl CloseCode {
+    // Codes that mean the connection dropped rather than being closed on
+    // purpose by either side.
+    pub fn is_abnormal(&self) -> bool {
+        matches!(
+            self,
+            CloseCode::AbnormalClosure
+                | CloseCode::NoStatus
+                | CloseCode::InternalError
+                | CloseCode::TlsHandshake
+        )
+    }
+
+    pub fn is_normal(&self) -> bool {
+        matches!(self, CloseCode::Normal | CloseCode::GoingAway)
+    }
+
+    // Browsers only accept 1000 and 3000-4999 in `WebSocket.close()`.
+    pub fn is_sendable(&self) -> bool {
+        matches!(
+            self,
+            CloseCode::Normal | CloseCode::Library(_) | CloseCode::Custom(_)
+        )
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::AbnormalClosure,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1015 => CloseCode::TlsHandshake,
+            3000..=3999 => CloseCode::Library(code),
+            4000..=4999 => CloseCode::Custom(code),
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::AbnormalClosure => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Library(code) | CloseCode::Custom(code) | CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<&CloseEvent> for CloseCode {
+    fn from(event: &CloseEvent) -> Self {
+        CloseCode::from(event.code())
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({})", self, u16::from(*self))
+    }
+}
+
+// What dropping the last `Websocket` handle does to the connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DropBehavior {
+    Close {
+        code: CloseCode,
+        reason: Option<String>,
+    },
+    // flushes the outgoing queue first, like `close_graceful`
+    Graceful {
+        code: CloseCode,
+        reason: Option<String>,
+        timeout: u32,
+    },
+    // the connection stays open, and keeps reconnecting, without a handle
+    Leak,
+}
+
+impl Default for DropBehavior {
+    fn default() -> Self {
+        DropBehavior::Close {
+            code: CloseCode::Normal,
+            reason: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_codes() {
+        for code in [
+            1000, 1001, 1006, 1011, 1015, 1016, 2999, 3000, 3999, 4000, 4999, 5000,
+        ] {
+            assert_eq!(u16::from(CloseCode::from(code)), code);
+        }
+        assert_eq!(CloseCode::from(3001), CloseCode::Library(3001));
+        assert_eq!(CloseCode::from(4001), CloseCode::Custom(4001));
+        assert_eq!(CloseCode::from(1004), CloseCode::Other(1004));
+    }
+
+    #[test]
+    fn classifies_codes() {
+        assert!(CloseCode::AbnormalClosure.is_abnormal());
+        assert!(!CloseCode::Normal.is_abnormal());
+        assert!(CloseCode::GoingAway.is_normal());
+        assert!(CloseCode::Normal.is_sendable());
+        assert!(CloseCode::Custom(4000).is_sendable());
+        assert!(!CloseCode::GoingAway.is_sendable());
+        assert!(!CloseCode::Other(1004).is_sendable());
+    }
+
+    #[test]
+    fn displays_name_and_code() {
+        assert_eq!(CloseCode::Custom(4001).to_string(), "Custom(4001) (4001)");
+        assert_eq!(CloseCode::Normal.to_string(), "Normal (1000)");
+    }
+}
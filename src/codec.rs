@@ -0,0 +1,363 @@
+use std::fmt;
+
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+use crate::emitter::Router;
+use crate::WsMessage;
+
+// Frames decoded to this event name are handed to the json-rpc subscriber
+// instead of the emitter, with the whole frame as data.
+pub const RPC_EVENT: &str = "jsonrpc";
+pub const MESSAGE_EVENT: &str = "message";
+pub const BINARY_EVENT: &str = "binary";
+// Carries a `FrameEvent` for every incoming frame with `frame_events`.
+pub const FRAME_EVENT: &str = "frame";
+
+// What to do with binary frames that are not valid utf8 json.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Utf8Policy {
+    // the frame is reported as a parse error
+    #[default]
+    Strict,
+    // invalid sequences are replaced with U+FFFD and the frame is decoded
+    Lossy,
+    // the raw bytes go to the `binary` handler as `Payload::Bytes`
+    Binary,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutedMessage {
+    pub event: String,
+    pub data: String,
+}
+
+impl RoutedMessage {
+    pub fn new(event: &str, data: String) -> Self {
+        Self {
+            event: String::from(event),
+            data,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CodecError {
+    pub message: String,
+    // the frame that failed, lossy utf8 for binary frames
+    pub payload: String,
+}
+
+impl CodecError {
+    pub fn new(message: impl ToString, payload: String) -> Self {
+        Self {
+            message: message.to_string(),
+            payload,
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "codec error: {}", self.message)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+// The keys of a json object in frame order, with their values left as raw
+// json. Anything but an object fails to deserialize.
+struct TopLevel<'a>(Vec<(String, &'a RawValue)>);
+
+impl<'de: 'a, 'a> Deserialize<'de> for TopLevel<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TopLevelVisitor<'a>(std::marker::PhantomData<&'a ()>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for TopLevelVisitor<'a> {
+            type Value = TopLevel<'a>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a json object")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Self::Value, M::Error> {
+                let mut fields = Vec::new();
+                while let Some(field) = map.next_entry::<String, &'a RawValue>()? {
+                    fields.push(field);
+                }
+                Ok(TopLevel(fields))
+            }
+        }
+
+        deserializer.deserialize_map(TopLevelVisitor(std::marker::PhantomData))
+    }
+}
+
+// Turns incoming frames into emitter events and events back into frames, so
+// any wire format can reuse the reconnect and emitter machinery.
+pub trait Codec {
+    fn decode(&self, message: WsMessage) -> Result<RoutedMessage, CodecError>;
+    fn encode(&self, message: RoutedMessage) -> Result<WsMessage, CodecError>;
+}
+
+// Objects are routed by the router, or by their first key, anything left
+// unrouted goes to the `message` event as it is.
+#[derive(Default)]
+pub struct JsonCodec {
+    router: Option<Router>,
+    plain_text: bool,
+    utf8: Utf8Policy,
+    // binary frames carry MessagePack maps, routed the same way as json
+    #[cfg(feature = "rmp-serde")]
+    msgpack: bool,
+}
+
+impl JsonCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_router(&mut self, router: Router) {
+        self.router = Some(router);
+    }
+
+    pub fn set_plain_text(&mut self, plain_text: bool) {
+        self.plain_text = plain_text;
+    }
+
+    pub fn set_utf8_policy(&mut self, utf8: Utf8Policy) {
+        self.utf8 = utf8;
+    }
+
+    // Binary frames the `Binary` policy hands to the `binary` handler instead
+    // of decoding them.
+    pub fn is_raw_binary(&self, message: &WsMessage) -> bool {
+        #[cfg(feature = "rmp-serde")]
+        {
+            if self.msgpack {
+                return false;
+            }
+        }
+        match message {
+            WsMessage::Binary(payload) => {
+                self.utf8 == Utf8Policy::Binary && std::str::from_utf8(payload).is_err()
+            }
+            WsMessage::Text(_) => false,
+        }
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    pub fn set_msgpack(&mut self, msgpack: bool) {
+        self.msgpack = msgpack;
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    fn decode_msgpack(&self, payload: Vec<u8>) -> Result<RoutedMessage, CodecError> {
+        let value: Value = rmp_serde::from_slice(&payload)
+            .map_err(|err| CodecError::new(err, String::from_utf8_lossy(&payload).into_owned()))?;
+        if value.get(RPC_EVENT).is_some() {
+            return Ok(RoutedMessage::new(RPC_EVENT, value.to_string()));
+        }
+        let (event, data) = self
+            .route(&value)
+            .unwrap_or_else(|| (String::from(MESSAGE_EVENT), value));
+        Ok(RoutedMessage {
+            event,
+            data: data.to_string(),
+        })
+    }
+
+    fn route(&self, value: &Value) -> Option<(String, Value)> {
+        match self.router.as_ref() {
+            Some(router) => router(value),
+            None => value
+                .as_object()
+                .and_then(|object| object.iter().next())
+                .map(|(event, data)| (event.clone(), data.clone())),
+        }
+    }
+}
+
+impl Codec for JsonCodec {
+    fn decode(&self, message: WsMessage) -> Result<RoutedMessage, CodecError> {
+        let payload = match message {
+            #[cfg(feature = "rmp-serde")]
+            WsMessage::Binary(payload) if self.msgpack => return self.decode_msgpack(payload),
+            WsMessage::Text(payload) => payload,
+            WsMessage::Binary(payload) => match String::from_utf8(payload) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    let payload = String::from_utf8_lossy(err.as_bytes()).into_owned();
+                    if self.utf8 != Utf8Policy::Lossy {
+                        return Err(CodecError::new(err, payload));
+                    }
+                    payload
+                }
+            },
+        };
+        // only the top level is split up, so rpc frames and default routed
+        // data are passed on without building a `Value` tree
+        let fields = match serde_json::from_str::<TopLevel>(payload.as_str()) {
+            Ok(TopLevel(fields)) => fields,
+            Err(_) if self.plain_text => return Ok(RoutedMessage::new(MESSAGE_EVENT, payload)),
+            Err(err) => {
+                return match serde_json::from_str::<IgnoredAny>(payload.as_str()) {
+                    Ok(_) => Ok(RoutedMessage::new(MESSAGE_EVENT, payload)),
+                    Err(_) => Err(CodecError::new(err, payload)),
+                }
+            }
+        };
+        if fields.iter().any(|(key, _)| key == RPC_EVENT) {
+            return Ok(RoutedMessage::new(RPC_EVENT, payload));
+        }
+        if self.router.is_some() {
+            let value = serde_json::from_str::<Value>(payload.as_str())
+                .map_err(|err| CodecError::new(err, payload.clone()))?;
+            return Ok(match self.route(&value) {
+                Some((event, data)) => RoutedMessage {
+                    event,
+                    data: data.to_string(),
+                },
+                None => RoutedMessage::new(MESSAGE_EVENT, payload),
+            });
+        }
+        Ok(match fields.first() {
+            Some((event, data)) => RoutedMessage {
+                event: event.clone(),
+                data: data.get().to_string(),
+            },
+            None => RoutedMessage::new(MESSAGE_EVENT, payload),
+        })
+    }
+
+    // Data that is valid json is embedded as it is, anything else as a string.
+    fn encode(&self, message: RoutedMessage) -> Result<WsMessage, CodecError> {
+        let data = serde_json::from_str::<Value>(message.data.as_str())
+            .unwrap_or(Value::String(message.data));
+        let mut frame = serde_json::Map::new();
+        frame.insert(message.event, data);
+        let frame = Value::Object(frame);
+        #[cfg(feature = "rmp-serde")]
+        {
+            if self.msgpack {
+                return rmp_serde::to_vec_named(&frame)
+                    .map(WsMessage::Binary)
+                    .map_err(|err| CodecError::new(err, frame.to_string()));
+            }
+        }
+        Ok(WsMessage::Text(frame.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(codec: &JsonCodec, payload: &str) -> Result<RoutedMessage, CodecError> {
+        codec.decode(WsMessage::Text(String::from(payload)))
+    }
+
+    fn routed(event: &str, data: &str) -> RoutedMessage {
+        RoutedMessage::new(event, String::from(data))
+    }
+
+    #[test]
+    fn hands_frames_with_a_top_level_jsonrpc_key_to_rpc() {
+        let codec = JsonCodec::new();
+        let response = r#"{"id": 1, "result": {"jsonrpc": "nested"}, "jsonrpc": "2.0"}"#;
+        assert_eq!(
+            decode(&codec, response).unwrap(),
+            routed(RPC_EVENT, response)
+        );
+        let nested = r#"{"event": {"jsonrpc": "2.0"}}"#;
+        assert_eq!(
+            decode(&codec, nested).unwrap(),
+            routed("event", r#"{"jsonrpc": "2.0"}"#)
+        );
+    }
+
+    #[test]
+    fn routes_objects_by_their_first_key_in_frame_order() {
+        let codec = JsonCodec::new();
+        assert_eq!(
+            decode(&codec, r#"{"zeta": [1, 2], "alpha": 3}"#).unwrap(),
+            routed("zeta", "[1, 2]")
+        );
+        assert_eq!(
+            decode(&codec, r#"{"greeting": "hi"}"#).unwrap(),
+            routed("greeting", r#""hi""#)
+        );
+        assert_eq!(decode(&codec, "{}").unwrap(), routed(MESSAGE_EVENT, "{}"));
+    }
+
+    #[test]
+    fn routes_with_the_router_when_there_is_one() {
+        let mut codec = JsonCodec::new();
+        codec.set_router(Box::new(|value: &Value| {
+            let event = value.get("type")?.as_str()?;
+            Some((String::from(event), value.get("payload")?.clone()))
+        }));
+        assert_eq!(
+            decode(&codec, r#"{"type": "tick", "payload": {"n": 1}}"#).unwrap(),
+            routed("tick", r#"{"n":1}"#)
+        );
+        assert_eq!(
+            decode(&codec, r#"{"other": 1}"#).unwrap(),
+            routed(MESSAGE_EVENT, r#"{"other": 1}"#)
+        );
+    }
+
+    #[test]
+    fn non_object_json_goes_to_message() {
+        let codec = JsonCodec::new();
+        for payload in ["[1, 2]", "42", r#""text""#, "null"].iter() {
+            assert_eq!(
+                decode(&codec, payload).unwrap(),
+                routed(MESSAGE_EVENT, payload)
+            );
+        }
+    }
+
+    #[test]
+    fn plain_text_is_an_error_unless_allowed() {
+        let mut codec = JsonCodec::new();
+        let err = decode(&codec, "pong").unwrap_err();
+        assert_eq!(err.payload, "pong");
+        codec.set_plain_text(true);
+        assert_eq!(
+            decode(&codec, "pong").unwrap(),
+            routed(MESSAGE_EVENT, "pong")
+        );
+        assert_eq!(
+            decode(&codec, r#"{"event": 1}"#).unwrap(),
+            routed("event", "1")
+        );
+    }
+
+    #[test]
+    fn applies_the_utf8_policy_to_binary_frames() {
+        let invalid = || WsMessage::Binary(b"{\"event\": \"\xff\"}".to_vec());
+        let mut codec = JsonCodec::new();
+        assert!(!codec.is_raw_binary(&invalid()));
+        let err = codec.decode(invalid()).unwrap_err();
+        assert_eq!(err.payload, "{\"event\": \"\u{fffd}\"}");
+        assert_eq!(
+            codec
+                .decode(WsMessage::Binary(b"{\"event\": 1}".to_vec()))
+                .unwrap(),
+            routed("event", "1")
+        );
+
+        codec.set_utf8_policy(Utf8Policy::Lossy);
+        assert_eq!(
+            codec.decode(invalid()).unwrap(),
+            routed("event", "\"\u{fffd}\"")
+        );
+
+        codec.set_utf8_policy(Utf8Policy::Binary);
+        assert!(codec.is_raw_binary(&invalid()));
+        assert!(!codec.is_raw_binary(&WsMessage::Binary(b"{}".to_vec())));
+        assert!(!codec.is_raw_binary(&WsMessage::Text(String::from("{}"))));
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+
+use crate::WsMessage;
+
+// Compressed frames are always sent as binary, the marker keeps the original
+// frame type so text comes back out as text on the other side.
+pub const COMPRESSED_TEXT_MAGIC: [u8; 4] = *b"WSZT";
+pub const COMPRESSED_BINARY_MAGIC: [u8; 4] = *b"WSZB";
+pub const COMPRESSED_HEADER_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CompressionFormat {
+    #[default]
+    Deflate,
+    Gzip,
+    Zlib,
+}
+
+impl CompressionFormat {
+    // Fails once the output grows past `max_decoded` bytes, a small frame can
+    // inflate to gigabytes.
+    pub fn decode(&self, payload: &[u8], max_decoded: usize) -> io::Result<Vec<u8>> {
+        let limit = max_decoded as u64 + 1;
+        let mut decoded = Vec::new();
+        match self {
+            CompressionFormat::Deflate => DeflateDecoder::new(payload)
+                .take(limit)
+                .read_to_end(&mut decoded),
+            CompressionFormat::Gzip => GzDecoder::new(payload)
+                .take(limit)
+                .read_to_end(&mut decoded),
+            CompressionFormat::Zlib => ZlibDecoder::new(payload)
+                .take(limit)
+                .read_to_end(&mut decoded),
+        }?;
+        if decoded.len() > max_decoded {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decoded frame is larger than {} bytes", max_decoded),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+pub const DEFAULT_MAX_DECODED: usize = 16 * 1024 * 1024;
+
+// Which incoming binary frames the server compressed. Frames are never
+// guessed from their first bytes, plain binary data can start like a zlib or
+// gzip header.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompressedFrames {
+    // all of them, as agreed with the server out of band
+    All,
+    // the ones starting with the marker, it is cut off before inflating
+    Marked(Vec<u8>),
+    // all of them once the server accepted this subprotocol
+    Subprotocol(String),
+}
+
+// Inflates binary frames compressed by the server.
+pub struct Decompression {
+    format: CompressionFormat,
+    frames: CompressedFrames,
+    max_decoded: usize,
+}
+
+impl Decompression {
+    pub fn new(format: CompressionFormat, frames: CompressedFrames) -> Self {
+        Self {
+            format,
+            frames,
+            max_decoded: DEFAULT_MAX_DECODED,
+        }
+    }
+
+    // Frames that inflate to more than `max_decoded` bytes are dropped.
+    pub fn max_decoded(mut self, max_decoded: usize) -> Self {
+        self.max_decoded = max_decoded;
+        self
+    }
+
+    // `None` when the frame is not compressed, `protocol` is the subprotocol
+    // the server accepted.
+    pub fn decompress(&self, payload: &[u8], protocol: &str) -> Option<io::Result<Vec<u8>>> {
+        let compressed = match &self.frames {
+            CompressedFrames::All => payload,
+            CompressedFrames::Marked(marker) => payload.strip_prefix(marker.as_slice())?,
+            CompressedFrames::Subprotocol(subprotocol) if subprotocol == protocol => payload,
+            CompressedFrames::Subprotocol(_) => return None,
+        };
+        Some(self.format.decode(compressed, self.max_decoded))
+    }
+}
+
+pub struct Compression {
+    format: CompressionFormat,
+    threshold: usize,
+    level: u32,
+    max_decoded: usize,
+}
+
+impl Compression {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            format: CompressionFormat::Deflate,
+            threshold,
+            level: 6,
+            max_decoded: DEFAULT_MAX_DECODED,
+        }
+    }
+
+    pub fn format(mut self, format: CompressionFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    // Incoming frames that inflate to more than `max_decoded` bytes are
+    // dropped.
+    pub fn max_decoded(mut self, max_decoded: usize) -> Self {
+        self.max_decoded = max_decoded;
+        self
+    }
+
+    pub fn should_compress(&self, size: usize) -> bool {
+        size > self.threshold
+    }
+
+    // Frames at or below the threshold, or that would not get any smaller,
+    // are sent as they are.
+    pub fn compress(&self, message: WsMessage) -> WsMessage {
+        if !self.should_compress(message.len()) {
+            return message;
+        }
+        let (magic, payload) = match &message {
+            WsMessage::Text(payload) => (COMPRESSED_TEXT_MAGIC, payload.as_bytes()),
+            WsMessage::Binary(payload) => (COMPRESSED_BINARY_MAGIC, payload.as_slice()),
+        };
+        match self.encode(&magic, payload) {
+            Ok(compressed) if compressed.len() < payload.len() => WsMessage::Binary(compressed),
+            _ => message,
+        }
+    }
+
+    pub fn is_compressed(payload: &[u8]) -> bool {
+        payload.len() >= COMPRESSED_HEADER_SIZE
+            && (payload[..4] == COMPRESSED_TEXT_MAGIC || payload[..4] == COMPRESSED_BINARY_MAGIC)
+    }
+
+    // Returns `None` when the payload is not a compressed frame, can't be
+    // decoded or inflates to more than `max_decoded` bytes.
+    pub fn decompress(&self, payload: &[u8]) -> Option<WsMessage> {
+        if !Self::is_compressed(payload) {
+            return None;
+        }
+        let decoded = self
+            .format
+            .decode(&payload[COMPRESSED_HEADER_SIZE..], self.max_decoded)
+            .ok()?;
+        if payload[..4] == COMPRESSED_TEXT_MAGIC {
+            String::from_utf8(decoded).ok().map(WsMessage::Text)
+        } else {
+            Some(WsMessage::Binary(decoded))
+        }
+    }
+
+    fn encode(&self, magic: &[u8], payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        let level = flate2::Compression::new(self.level);
+        let mut compressed = Vec::with_capacity(payload.len() / 2);
+        compressed.extend_from_slice(magic);
+        match self.format {
+            CompressionFormat::Deflate => {
+                let mut encoder = DeflateEncoder::new(compressed, level);
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            CompressionFormat::Gzip => {
+                let mut encoder = GzEncoder::new(compressed, level);
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            CompressionFormat::Zlib => {
+                let mut encoder = ZlibEncoder::new(compressed, level);
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(message: &WsMessage) -> Vec<u8> {
+        match message {
+            WsMessage::Text(payload) => payload.clone().into_bytes(),
+            WsMessage::Binary(payload) => payload.clone(),
+        }
+    }
+
+    #[test]
+    fn round_trips_text_and_binary() {
+        for format in [
+            CompressionFormat::Deflate,
+            CompressionFormat::Gzip,
+            CompressionFormat::Zlib,
+        ] {
+            let compression = Compression::new(8).format(format);
+            let compressed = payload(&compression.compress(WsMessage::Text("a".repeat(100))));
+            assert!(Compression::is_compressed(&compressed));
+            match compression.decompress(&compressed) {
+                Some(WsMessage::Text(text)) => assert_eq!(text, "a".repeat(100)),
+                _ => panic!("expected a text frame"),
+            }
+            let compressed = payload(&compression.compress(WsMessage::Binary(vec![7; 100])));
+            match compression.decompress(&compressed) {
+                Some(WsMessage::Binary(binary)) => assert_eq!(binary, vec![7; 100]),
+                _ => panic!("expected a binary frame"),
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_small_and_incompressible_frames_alone() {
+        let compression = Compression::new(8);
+        let small = compression.compress(WsMessage::Text(String::from("short")));
+        assert!(matches!(small, WsMessage::Text(_)));
+        let noise: Vec<u8> = (0..64u32).map(|i| (i * 97 % 251) as u8).collect();
+        let compressed = compression.compress(WsMessage::Binary(noise.clone()));
+        assert_eq!(payload(&compressed), noise);
+    }
+
+    #[test]
+    fn only_inflates_frames_the_server_compressed() {
+        let deflated = Compression::new(0)
+            .format(CompressionFormat::Zlib)
+            .encode(b"", &[1; 64])
+            .unwrap();
+        let all = Decompression::new(CompressionFormat::Zlib, CompressedFrames::All);
+        assert_eq!(all.decompress(&deflated, "").unwrap().unwrap(), vec![1; 64]);
+        let mut marked_frame = b"Z".to_vec();
+        marked_frame.extend_from_slice(&deflated);
+        let marked = Decompression::new(
+            CompressionFormat::Zlib,
+            CompressedFrames::Marked(b"Z".to_vec()),
+        );
+        assert_eq!(
+            marked.decompress(&marked_frame, "").unwrap().unwrap(),
+            vec![1; 64]
+        );
+        assert!(marked.decompress(&deflated, "").is_none());
+        let negotiated = Decompression::new(
+            CompressionFormat::Zlib,
+            CompressedFrames::Subprotocol(String::from("v1.zlib")),
+        );
+        assert!(negotiated.decompress(&deflated, "v1").is_none());
+        assert!(negotiated.decompress(&deflated, "v1.zlib").is_some());
+    }
+
+    #[test]
+    fn fails_frames_that_inflate_past_the_limit() {
+        let deflated = Compression::new(0).encode(b"", &[0; 2000]).unwrap();
+        let decompression =
+            Decompression::new(CompressionFormat::Deflate, CompressedFrames::All).max_decoded(1999);
+        assert!(decompression.decompress(&deflated, "").unwrap().is_err());
+        assert!(CompressionFormat::Deflate.decode(&deflated, 2000).is_ok());
+    }
+
+    #[test]
+    fn rejects_frames_that_inflate_past_the_limit() {
+        let compression = Compression::new(0).max_decoded(1000);
+        let compressed = payload(&compression.compress(WsMessage::Binary(vec![0; 1001])));
+        assert!(compression.decompress(&compressed).is_none());
+        let compressed = payload(&compression.compress(WsMessage::Binary(vec![0; 1000])));
+        assert!(compression.decompress(&compressed).is_some());
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent, WebSocket};
+
+use crate::close::CloseCode;
+use crate::error::WsError;
+use crate::timers::set_timeout_once;
+use crate::Websocket;
+
+// Settled by the first open, error or close event of the socket, or by the
+// connect timeout, whichever comes first. Later events are ignored.
+#[derive(Default)]
+pub struct ConnectState {
+    settled: bool,
+    result: Option<Result<(), WsError>>,
+    waker: Option<Waker>,
+}
+
+impl ConnectState {
+    pub(crate) fn settle(&mut self, result: Result<(), WsError>) {
+        if self.settled {
+            return;
+        }
+        self.settled = true;
+        self.result = Some(result);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type DeferredConnect = Pin<Box<dyn Future<Output = Result<Websocket, WsError>>>>;
+
+pub struct ConnectFuture {
+    websocket: Option<Websocket>,
+    state: Rc<RefCell<ConnectState>>,
+    // the socket is only created once this resolves, e.g. after a probe
+    deferred: Option<DeferredConnect>,
+}
+
+impl ConnectFuture {
+    pub(crate) fn new(
+        websocket: Websocket,
+        state: Rc<RefCell<ConnectState>>,
+        timeout: Option<u32>,
+    ) -> Self {
+        if let Some(timeout) = timeout {
+            let state = state.clone();
+            set_timeout_once(
+                move || {
+                    state
+                        .borrow_mut()
+                        .settle(Err(WsError::ConnectionFailed(format!(
+                            "not open after {} ms",
+                            timeout
+                        ))));
+                },
+                timeout,
+            );
+        }
+        Self {
+            websocket: Some(websocket),
+            state,
+            deferred: None,
+        }
+    }
+
+    // Resolves right away, with a handle to a connection that exists already.
+    pub(crate) fn ready(websocket: Websocket) -> Self {
+        let mut state = ConnectState::default();
+        state.settle(Ok(()));
+        Self {
+            websocket: Some(websocket),
+            state: Rc::new(RefCell::new(state)),
+            deferred: None,
+        }
+    }
+
+    pub(crate) fn deferred(connect: DeferredConnect) -> Self {
+        Self {
+            websocket: None,
+            state: Rc::new(RefCell::new(ConnectState::default())),
+            deferred: Some(connect),
+        }
+    }
+
+    pub(crate) fn failed(err: WsError) -> Self {
+        let mut state = ConnectState::default();
+        state.settle(Err(err));
+        Self {
+            websocket: None,
+            state: Rc::new(RefCell::new(state)),
+            deferred: None,
+        }
+    }
+}
+
+impl Future for ConnectFuture {
+    type Output = Result<Websocket, WsError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(deferred) = self.deferred.as_mut() {
+            return deferred.as_mut().poll(cx);
+        }
+        let result = {
+            let mut state = self.state.borrow_mut();
+            match state.result.take() {
+                Some(result) => result,
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        // the socket is dropped, and closed, when the connect failed
+        let websocket = self.websocket.take();
+        Poll::Ready(result.and_then(|_| {
+            websocket.ok_or_else(|| WsError::ConnectionFailed(String::from("already resolved")))
+        }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloseInfo {
+    pub code: CloseCode,
+    pub reason: String,
+    pub was_clean: bool,
+}
+
+// Removes the `close` listener of a `CloseFuture` from its socket when dropped.
+struct CloseListener {
+    websocket: WebSocket,
+    closure: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl Drop for CloseListener {
+    fn drop(&mut self) {
+        let _ = self
+            .websocket
+            .remove_event_listener_with_callback("close", self.closure.as_ref().unchecked_ref());
+    }
+}
+
+#[derive(Default)]
+struct CloseState {
+    result: Option<Result<CloseInfo, WsError>>,
+    waker: Option<Waker>,
+    // dropped once the future resolved or was dropped itself
+    listener: Option<CloseListener>,
+}
+
+// Resolves on the close event of the socket it was created for, a reconnect
+// that replaced the socket in the meantime doesn't matter.
+pub struct CloseFuture {
+    state: Rc<RefCell<CloseState>>,
+}
+
+impl CloseFuture {
+    pub(crate) fn listen(websocket: &WebSocket) -> Self {
+        let state = Rc::new(RefCell::new(CloseState::default()));
+        if websocket.ready_state() == WebSocket::CLOSED {
+            state.borrow_mut().result = Some(Ok(CloseInfo {
+                code: CloseCode::NoStatus,
+                reason: String::new(),
+                was_clean: false,
+            }));
+            return Self { state };
+        }
+        let state_ref = state.clone();
+        let closure = Closure::wrap(Box::new(move |event: CloseEvent| {
+            let mut state = state_ref.borrow_mut();
+            state.result = Some(Ok(CloseInfo {
+                code: CloseCode::from(&event),
+                reason: event.reason(),
+                was_clean: event.was_clean(),
+            }));
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }) as Box<dyn FnMut(CloseEvent)>);
+        match websocket.add_event_listener_with_callback("close", closure.as_ref().unchecked_ref())
+        {
+            Ok(()) => {
+                state.borrow_mut().listener = Some(CloseListener {
+                    websocket: websocket.clone(),
+                    closure,
+                })
+            }
+            Err(err) => state.borrow_mut().result = Some(Err(WsError::Js(err))),
+        }
+        Self { state }
+    }
+
+    pub(crate) fn failed(err: WsError) -> Self {
+        let state = CloseState {
+            result: Some(Err(err)),
+            ..CloseState::default()
+        };
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+}
+
+impl Future for CloseFuture {
+    type Output = Result<CloseInfo, WsError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        match state.result.take() {
+            Some(result) => {
+                state.listener.take();
+                Poll::Ready(result)
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for CloseFuture {
+    fn drop(&mut self) {
+        self.state.borrow_mut().listener.take();
+    }
+}
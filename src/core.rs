@@ -1,23 +1,42 @@
-use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::str;
 
-use js_sys::{JsString, Uint8Array};
+use js_sys::{ArrayBuffer, Function, JsString, Uint8Array};
+use jsonrpc_core::{Id, Notification};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::value::RawValue;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
 use web_sys::{CloseEvent, ErrorEvent, Event, MessageEvent, WebSocket};
 
-use crate::emitter::Payload;
+use crate::ack::AckHandler;
+use crate::chunking::Chunking;
+use crate::close::CloseCode;
+use crate::codec::{BINARY_EVENT, FRAME_EVENT, RPC_EVENT};
+#[cfg(feature = "flate2")]
+use crate::compression::Compression;
+use crate::connect::CloseInfo;
+use crate::emitter::{FrameEvent, Payload, SubscriptionAction, ValidationError};
+use crate::error::WsError;
 use crate::factory::WsFactory;
-use crate::simple_rpc::RPCSubscriber;
+use crate::fragments::Fragment;
+use crate::incremental::Step;
+use crate::limits::{is_high_surrogate, truncate_text, MessageTooLarge, OversizePolicy};
+use crate::outgoing::{BackpressureConfig, Priority, RateLimitExceeded};
+use crate::pause::{PausePolicy, PausedInbox};
+use crate::reorder::ParsedFrame;
+#[cfg(feature = "rmp-serde")]
+use crate::simple_rpc::RpcCodec;
+use crate::simple_rpc::{RPCResponse, RpcError, RpcMessage, CONNECTION_CLOSED};
+use crate::sockjs::{SockJs, SockJsFrame};
+use crate::stream::ConnectionState;
+use crate::timers::{clear_interval, clear_timeout, set_interval, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
 
 #[wasm_bindgen]
 extern "C" {
-    fn setInterval(closure: &Closure<dyn FnMut()>, time: u32) -> i32;
-    fn setTimeout(closure: &Closure<dyn FnMut()>, time: u32);
     // Use `js_namespace` here to bind `console.log(..)` instead of just
     // `log(..)`
     #[wasm_bindgen(js_namespace = console)]
@@ -30,32 +49,435 @@ macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 }
 
+type LoadEndSlot = Rc<RefCell<Option<Closure<dyn FnMut(web_sys::ProgressEvent)>>>>;
+
 pub struct WsCore {
     pub factory: Rc<WsFactory>,
     pub websocket: Rc<RefCell<WebSocket>>,
 }
 
 impl WsCore {
-    pub fn build_new_websocket(url: &Cow<'static, str>) -> Result<WebSocket, JsValue> {
-        let websocket = WebSocket::new(url.as_ref())?;
-        Ok(websocket)
+    pub fn build_new_websocket(url: &str) -> Result<WebSocket, WsError> {
+        WebSocket::new(url).map_err(WsError::connection_failed)
     }
 
     pub fn new(factory: WsFactory, websocket: Rc<RefCell<WebSocket>>) -> Self {
         let factory = Rc::new(factory);
         let pinger = Some(Rc::new(RefCell::new(Pinger::new(None))));
         Self::init_new_websocket(factory.clone(), websocket.clone(), pinger.clone());
+        // an attached socket may already be open, its open event is gone
+        if websocket.borrow().ready_state() == WebSocket::OPEN {
+            Self::replay_open(&factory);
+        }
+        if let Some(interval) = factory.rpc_stats_interval {
+            let id = Self::start_rpc_stats(factory.clone(), interval);
+            factory.intervals.borrow_mut().push(id);
+        }
+        if let Some(interval) = factory.tx_stats_interval {
+            let id = Self::start_tx_stats(factory.clone(), interval);
+            factory.intervals.borrow_mut().push(id);
+        }
+        if let Some(backpressure) = factory.backpressure.clone() {
+            let id =
+                Self::start_backpressure_monitor(factory.clone(), websocket.clone(), backpressure);
+            factory.intervals.borrow_mut().push(id);
+        }
         Self { factory, websocket }
     }
 
-    pub fn close(&self, code: u16, reason: Option<String>) -> Result<(), JsValue> {
+    fn start_backpressure_monitor(
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+        backpressure: Rc<BackpressureConfig>,
+    ) -> i32 {
+        let poll_interval = backpressure.poll_interval;
+        set_interval(
+            move || {
+                let buffered_amount = websocket.borrow().buffered_amount();
+                let event = match backpressure.update(buffered_amount) {
+                    Some(true) => "backpressure",
+                    Some(false) => "drained",
+                    None => return,
+                };
+                if let Some(emitter) = factory.emitter.clone() {
+                    emitter.borrow().emit(
+                        String::from(event),
+                        &Payload::Data(buffered_amount.to_string()),
+                    );
+                }
+            },
+            poll_interval,
+        )
+    }
+
+    fn start_rpc_stats(factory: Rc<WsFactory>, interval: u32) -> i32 {
+        set_interval(
+            move || {
+                if let (Some(emitter), Some(rpc_subscriber)) =
+                    (factory.emitter.clone(), factory.rpc_subscriber.clone())
+                {
+                    let stats = rpc_subscriber.borrow().stats();
+                    match serde_json::to_string(&stats) {
+                        Ok(stats) => emitter
+                            .borrow()
+                            .emit(String::from("rpc_stats"), &Payload::Data(stats)),
+                        Err(err) => console_log!("error serialize rpc stats: {:?}", err),
+                    }
+                }
+            },
+            interval,
+        )
+    }
+
+    fn start_tx_stats(factory: Rc<WsFactory>, interval: u32) -> i32 {
+        set_interval(
+            move || {
+                if let Some(emitter) = factory.emitter.clone() {
+                    match serde_json::to_string(&factory.tx_stats()) {
+                        Ok(stats) => emitter
+                            .borrow()
+                            .emit(String::from("tx_stats"), &Payload::Data(stats)),
+                        Err(err) => console_log!("error serialize tx stats: {:?}", err),
+                    }
+                }
+            },
+            interval,
+        )
+    }
+
+    pub fn send(&self, message: WsMessage, priority: Priority) -> Result<(), WsError> {
+        if *self.factory.is_closing.borrow() {
+            return Err(WsError::NotConnected {
+                state: ReadyState::Closing,
+            });
+        }
+        // interceptors see the frame as the caller built it, before it is
+        // compressed or split into chunks
+        let message = match Self::intercept(&self.factory, message) {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        #[cfg(feature = "flate2")]
+        let message = match self.factory.compression.as_ref() {
+            Some(compression) => compression.compress(message),
+            None => message,
+        };
+        if let (Some(chunking), WsMessage::Binary(payload)) =
+            (self.factory.chunking.as_ref(), &message)
+        {
+            if payload.len() > chunking.borrow().max_chunk_size() {
+                let chunks = chunking.borrow_mut().split(payload);
+                for chunk in chunks {
+                    self.send_frame(WsMessage::Binary(chunk), priority)?;
+                }
+                return Ok(());
+            }
+        }
+        self.send_frame(message, priority)
+    }
+
+    pub fn send_with_ack(
+        &self,
+        message: WsMessage,
+        handler: AckHandler,
+        timeout: u32,
+    ) -> Result<(), WsError> {
+        let (ack_id, message) = self.factory.ack.borrow_mut().prepare(message)?;
+        self.factory
+            .ack
+            .borrow_mut()
+            .register(ack_id.clone(), message.clone(), handler);
+        if let Err(err) = self.send(message, Priority::Normal) {
+            self.factory.ack.borrow_mut().take(&ack_id);
+            return Err(err);
+        }
+        let ack = self.factory.ack.clone();
+        let expired_id = ack_id.clone();
+        let id = set_timeout_once(
+            move || {
+                let handler = ack.borrow_mut().take_expired(&expired_id);
+                if let Some(handler) = handler {
+                    handler(Err(WsError::AckTimeout));
+                }
+            },
+            timeout,
+        );
+        self.factory.ack.borrow_mut().set_timer(&ack_id, id);
+        Ok(())
+    }
+
+    // Only an open socket gets the frame right away, otherwise the change is
+    // picked up by the resubscribe on the next open.
+    pub fn update_subscription(
+        &self,
+        action: SubscriptionAction,
+        name: &str,
+    ) -> Result<(), WsError> {
+        match action {
+            SubscriptionAction::Subscribe => {
+                self.factory.subscriptions.borrow_mut().subscribe(name)
+            }
+            SubscriptionAction::Unsubscribe => {
+                self.factory.subscriptions.borrow_mut().unsubscribe(name)
+            }
+        }
+        // resubscribed on the next open, or once the login is accepted
+        if self.websocket.borrow().ready_state() != WebSocket::OPEN
+            || self.factory.is_authenticating()
+        {
+            return Ok(());
+        }
+        let message = self
+            .factory
+            .frame((self.factory.subscription_format)(action, name));
+        self.send(message, Priority::High)
+    }
+
+    fn send_frame(&self, message: WsMessage, priority: Priority) -> Result<(), WsError> {
+        let state = self.websocket.borrow().ready_state();
+        let authenticating = self.factory.is_authenticating();
+        if state == WebSocket::OPEN && !authenticating {
+            Self::flush_outgoing(&self.factory, &self.websocket);
+            if !self.factory.outgoing.borrow().is_empty() {
+                return self
+                    .factory
+                    .outgoing
+                    .borrow_mut()
+                    .push_with_priority(message, priority);
+            }
+            if let Err(wait) = Self::acquire_rate(&self.factory, message.len()) {
+                return self.handle_rate_exceeded(message, priority, wait);
+            }
+            return Self::send_raw(&self.factory, &self.websocket.borrow(), &message);
+        }
+        // a closed socket only comes back when it is going to be reconnected
+        let will_open = state == WebSocket::CONNECTING
+            || authenticating
+            || (self.factory.reconnect.is_some() && !*self.factory.is_closing.borrow());
+        if self.factory.queue_offline && will_open {
+            return self
+                .factory
+                .outgoing
+                .borrow_mut()
+                .push_with_priority(message, priority);
+        }
+        Err(WsError::NotConnected {
+            state: ReadyState::from(state),
+        })
+    }
+
+    fn handle_rate_exceeded(
+        &self,
+        message: WsMessage,
+        priority: Priority,
+        wait: u32,
+    ) -> Result<(), WsError> {
+        let on_exceeded = match self.factory.rate_limiter.as_ref() {
+            Some(rate_limiter) => rate_limiter.borrow().on_exceeded(),
+            None => RateLimitExceeded::Queue,
+        };
+        match on_exceeded {
+            RateLimitExceeded::Reject => Err(WsError::RateLimited),
+            RateLimitExceeded::Queue => {
+                self.factory
+                    .outgoing
+                    .borrow_mut()
+                    .push_with_priority(message, priority)?;
+                Self::schedule_flush(self.factory.clone(), self.websocket.clone(), wait);
+                Ok(())
+            }
+        }
+    }
+
+    fn acquire_rate(factory: &Rc<WsFactory>, bytes: usize) -> Result<(), u32> {
+        match factory.rate_limiter.as_ref() {
+            Some(rate_limiter) => rate_limiter.borrow_mut().acquire(bytes),
+            None => Ok(()),
+        }
+    }
+
+    fn schedule_flush(factory: Rc<WsFactory>, websocket: Rc<RefCell<WebSocket>>, timeout: u32) {
+        if let Some(rate_limiter) = factory.rate_limiter.as_ref() {
+            if rate_limiter.borrow_mut().set_flush_scheduled(true) {
+                return;
+            }
+        }
+        set_timeout_once(
+            move || {
+                if let Some(rate_limiter) = factory.rate_limiter.as_ref() {
+                    rate_limiter.borrow_mut().set_flush_scheduled(false);
+                }
+                if websocket.borrow().ready_state() == WebSocket::OPEN {
+                    Self::flush_outgoing(&factory, &websocket);
+                }
+            },
+            timeout,
+        );
+    }
+
+    // Buffers owned by js are handed to the socket as they are. They take the
+    // path of `send` with a copy when the frame has to wait in the offline
+    // queue or is intercepted, compressed or chunked.
+    pub fn send_array_buffer(&self, buffer: &ArrayBuffer) -> Result<(), WsError> {
+        let bytes = buffer.byte_length() as usize;
+        if self.is_ready_for_direct_send(bytes) {
+            let result = self.websocket.borrow().send_with_array_buffer(buffer);
+            self.factory
+                .tx_stats
+                .borrow_mut()
+                .record(true, bytes, result.is_ok());
+            return result.map_err(WsError::SendFailed);
+        }
+        self.send(
+            WsMessage::Binary(Uint8Array::new(buffer).to_vec()),
+            Priority::Normal,
+        )
+    }
+
+    pub fn send_u8_array(&self, array: &Uint8Array) -> Result<(), WsError> {
+        let bytes = array.byte_length() as usize;
+        if self.is_ready_for_direct_send(bytes) {
+            let result = self.websocket.borrow().send_with_array_buffer_view(array);
+            self.factory
+                .tx_stats
+                .borrow_mut()
+                .record(true, bytes, result.is_ok());
+            return result.map_err(WsError::SendFailed);
+        }
+        self.send(WsMessage::Binary(array.to_vec()), Priority::Normal)
+    }
+
+    fn is_ready_for_direct_send(&self, bytes: usize) -> bool {
+        self.websocket.borrow().ready_state() == WebSocket::OPEN
+            && !*self.factory.is_closing.borrow()
+            && !self.factory.is_authenticating()
+            && self.factory.outgoing.borrow().is_empty()
+            && !self.transforms_binary(bytes)
+            && Self::acquire_rate(&self.factory, bytes).is_ok()
+    }
+
+    // Whether `send` would change a binary frame of `bytes` on its way out,
+    // only frames it leaves alone skip the copy.
+    fn transforms_binary(&self, bytes: usize) -> bool {
+        let chunked = match self.factory.chunking.as_ref() {
+            Some(chunking) => bytes > chunking.borrow().max_chunk_size(),
+            None => false,
+        };
+        chunked
+            || self.factory.sockjs.is_some()
+            || !self.factory.outbound_interceptors.is_empty()
+            || self.should_compress(bytes)
+    }
+
+    fn should_compress(&self, _bytes: usize) -> bool {
+        #[cfg(feature = "flate2")]
+        {
+            if let Some(compression) = self.factory.compression.as_ref() {
+                return compression.should_compress(_bytes);
+            }
+        }
+        false
+    }
+
+    fn flush_outgoing(factory: &Rc<WsFactory>, websocket: &Rc<RefCell<WebSocket>>) {
+        if factory.is_authenticating() {
+            return;
+        }
+        loop {
+            let (priority, message) = match factory.outgoing.borrow_mut().pop() {
+                Some(message) => message,
+                None => return,
+            };
+            if let Err(wait) = Self::acquire_rate(factory, message.len()) {
+                factory.outgoing.borrow_mut().push_front(priority, message);
+                Self::schedule_flush(factory.clone(), websocket.clone(), wait);
+                return;
+            }
+            if let Err(err) = Self::send_raw(factory, &websocket.borrow(), &message) {
+                console_log!("error on flush queued message: {:?}", err);
+                factory.outgoing.borrow_mut().push_front(priority, message);
+                return;
+            }
+        }
+    }
+
+    pub fn close(&self, code: u16, reason: Option<String>) -> Result<(), WsError> {
+        Self::close_socket(&self.factory, &self.websocket, code, reason)
+    }
+
+    // New sends are refused right away, the socket itself is closed once the
+    // outgoing queue and the socket buffer are drained or `timeout` ms passed.
+    // A socket that is still connecting is waited for. `handle` is dropped
+    // once the socket is closed.
+    pub fn close_graceful(
+        &self,
+        code: u16,
+        reason: Option<String>,
+        timeout: u32,
+        handle: Option<Websocket>,
+    ) {
         *self.factory.is_closing.borrow_mut() = true;
-        match reason {
-            None => self.websocket.borrow().close_with_code(code),
-            Some(reason) => self
-                .websocket
+        let factory = self.factory.clone();
+        let websocket = self.websocket.clone();
+        let deadline = js_sys::Date::now() + f64::from(timeout);
+        let interval_id = Rc::new(Cell::new(None));
+        let interval_id_ref = interval_id.clone();
+        let mut reason = Some(reason);
+        let mut handle = handle;
+        let id = set_interval(
+            move || {
+                let ready_state = websocket.borrow().ready_state();
+                if ready_state == WebSocket::OPEN {
+                    Self::flush_outgoing(&factory, &websocket);
+                }
+                let drained = factory.outgoing.borrow().is_empty()
+                    && websocket.borrow().buffered_amount() == 0;
+                let pending = ready_state == WebSocket::CONNECTING
+                    || (ready_state == WebSocket::OPEN && !drained);
+                if pending && js_sys::Date::now() < deadline {
+                    return;
+                }
+                if let Some(id) = interval_id_ref.take() {
+                    clear_interval(id);
+                }
+                if let Some(reason) = reason.take() {
+                    if let Err(err) = Self::close_socket(&factory, &websocket, code, reason) {
+                        console_log!("error on graceful close: {:?}", err);
+                    }
+                }
+                drop(handle.take());
+            },
+            50,
+        );
+        interval_id.set(Some(id));
+    }
+
+    fn close_socket(
+        factory: &Rc<WsFactory>,
+        websocket: &Rc<RefCell<WebSocket>>,
+        code: u16,
+        reason: Option<String>,
+    ) -> Result<(), WsError> {
+        *factory.is_closing.borrow_mut() = true;
+        Self::stop_intervals(factory);
+        Self::cancel_retry(factory);
+        Self::fail_pending_rpc(factory.clone());
+        Self::fail_pending_acks(factory);
+        factory.scheduler.cancel_all();
+        let result = match reason {
+            None => websocket.borrow().close_with_code(code),
+            Some(reason) => websocket
                 .borrow()
                 .close_with_code_and_reason(code, reason.as_str()),
+        };
+        Ok(result?)
+    }
+
+    // The monitors started with the core hold the factory, they end with the
+    // connection.
+    fn stop_intervals(factory: &WsFactory) {
+        for id in factory.intervals.borrow_mut().drain(..) {
+            clear_interval(id);
         }
     }
 
@@ -67,12 +489,13 @@ impl WsCore {
         if let Some(pinger) = pinger.clone() {
             *pinger.borrow_mut() = Pinger::new(Some(websocket.clone()));
         }
-        let onmessage = Self::build_onmessage(factory.clone());
+        let onmessage = Self::build_onmessage(factory.clone(), websocket.clone());
         let onopen = Self::build_onopen(factory.clone(), websocket.clone(), pinger.clone());
         let onerror = Self::build_onerror(factory.clone());
         let onclose = Self::build_onclose(factory.clone(), websocket.clone(), pinger.clone());
         {
             let inner_ws = websocket.as_ref().borrow();
+            inner_ws.set_binary_type(factory.binary_type);
             inner_ws.set_onmessage(
                 onmessage
                     .as_ref()
@@ -94,355 +517,1528 @@ impl WsCore {
                     .map(|closure| closure.as_ref().unchecked_ref()),
             );
         }
-        match onmessage {
-            Some(closure) => closure.forget(),
-            None => (),
+        // the handlers of the previous socket are dropped here
+        *factory.handlers.borrow_mut() = SocketHandlers {
+            _onmessage: onmessage,
+            _onopen: onopen,
+            _onerror: onerror,
+            _onclose: onclose,
+        };
+    }
+
+    fn replay_open(factory: &WsFactory) {
+        let onopen: Option<Function> = factory
+            .handlers
+            .borrow()
+            ._onopen
+            .as_ref()
+            .map(|closure| closure.as_ref().unchecked_ref::<Function>().clone());
+        if let (Some(onopen), Ok(event)) = (onopen, Event::new("open")) {
+            if let Err(err) = onopen.call1(&JsValue::NULL, &event) {
+                console_log!("error on replay open: {:?}", err);
+            }
         }
-        match onopen {
-            Some(closure) => closure.forget(),
-            None => (),
+    }
+
+    // Detaches the handlers before dropping them, js would otherwise call
+    // into freed closures.
+    fn release_handlers(factory: &WsFactory, websocket: &WebSocket) {
+        if let Some(id) = factory.release_timer.take() {
+            clear_timeout(id);
         }
-        match onerror {
-            Some(closure) => closure.forget(),
-            None => (),
+        Self::detach_handlers(websocket);
+        factory.handlers.replace(SocketHandlers::default());
+    }
+
+    // Called from the close handler, which is one of the handlers. On the
+    // main thread they are dropped once it returned, the timer only holds the
+    // factory weakly. A worker can be torn down before the timer fires, there
+    // they are dropped right away and wasm-bindgen frees the running closure
+    // when it returns.
+    fn release_after_close(factory: &Rc<WsFactory>, websocket: &WebSocket) {
+        if web_sys::window().is_none() {
+            return Self::release_handlers(factory, websocket);
         }
-        match onclose {
-            Some(closure) => closure.forget(),
-            None => (),
+        Self::detach_handlers(websocket);
+        let weak_factory = Rc::downgrade(factory);
+        let id = set_timeout_once(
+            move || {
+                if let Some(factory) = weak_factory.upgrade() {
+                    factory.release_timer.set(None);
+                    factory.handlers.replace(SocketHandlers::default());
+                }
+            },
+            0,
+        );
+        if let Some(id) = factory.release_timer.replace(Some(id)) {
+            clear_timeout(id);
         }
     }
 
-    fn schedule_reconnect(closure: &Closure<dyn FnMut()>, timeout: u32) {
-        setTimeout(closure, timeout);
+    fn detach_handlers(websocket: &WebSocket) {
+        websocket.set_onmessage(None);
+        websocket.set_onopen(None);
+        websocket.set_onerror(None);
+        websocket.set_onclose(None);
+    }
+
+    fn schedule_reconnect(factory: Rc<WsFactory>, websocket: Rc<RefCell<WebSocket>>, timeout: u32) {
+        let reconnect_config = match factory.reconnect.clone() {
+            Some(reconnect_config) => reconnect_config,
+            None => return,
+        };
+        // the pending retry is cancelled on close and when the core is
+        // dropped, it doesn't keep either alive
+        let weak_factory = Rc::downgrade(&factory);
+        let weak_websocket = Rc::downgrade(&websocket);
+        let id = set_timeout_once(
+            move || {
+                let (factory, websocket) = match (weak_factory.upgrade(), weak_websocket.upgrade())
+                {
+                    (Some(factory), Some(websocket)) => (factory, websocket),
+                    _ => return,
+                };
+                if let Some(reconnect_config) = factory.reconnect.as_ref() {
+                    reconnect_config.borrow_mut().retry_fired();
+                }
+                Self::retry(factory, websocket);
+            },
+            timeout,
+        );
+        reconnect_config.borrow_mut().set_retry(id);
+    }
+
+    fn cancel_retry(factory: &WsFactory) {
+        if let Some(reconnect_config) = factory.reconnect.as_ref() {
+            reconnect_config.borrow_mut().cancel_retry();
+        }
     }
 
     fn build_onmessage(
         factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
     ) -> Option<Closure<dyn FnMut(MessageEvent) + 'static>> {
-        // @TODO need thick how to use building on_message
-        // Unpack the user supplied value. If none, we have nothing to do.
-        // if factory.on_message.is_none() {
-        //     return None;
-        // }
-        // let cb = match factory.on_message.clone() {
-        //     None => return None,
-        //     Some(cb) => cb.clone(),
-        // };
         Some(Closure::wrap(Box::new(move |event: MessageEvent| {
             let event: MessageEvent = event.unchecked_into();
+            let received_at = event.time_stamp();
             if let Ok(js_string) = event.data().dyn_into::<JsString>() {
-                Self::process_text_message(String::from(js_string), factory.clone());
+                factory.received_binary.set(false);
+                if let Some(incremental) = factory.incremental.as_ref() {
+                    let units = js_string.length() as usize;
+                    let within_limit =
+                        !matches!(factory.size_limit, Some(limit) if units > limit.max_size);
+                    if within_limit && incremental.borrow().is_large(units) {
+                        incremental.borrow_mut().push_text(js_string, received_at);
+                        Self::schedule_incremental(factory.clone(), websocket.clone());
+                        return;
+                    }
+                }
+                let message = match Self::limit_text(&factory, js_string) {
+                    Some(text) => WsMessage::Text(text),
+                    None => return,
+                };
+                match factory.incremental.as_ref() {
+                    Some(incremental) if !incremental.borrow().is_idle() => {
+                        incremental.borrow_mut().push_frame(message, received_at);
+                        Self::schedule_incremental(factory.clone(), websocket.clone());
+                    }
+                    _ => Self::dispatch_incoming(
+                        message,
+                        received_at,
+                        factory.clone(),
+                        websocket.clone(),
+                    ),
+                }
             } else if let Ok(js_array_buffer) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
-                let array = Uint8Array::new(&js_array_buffer).to_vec();
-                Self::process_array_message(array, factory.clone());
+                factory.received_binary.set(true);
+                let array = match Self::limit_array(&factory, Uint8Array::new(&js_array_buffer)) {
+                    Some(array) => array,
+                    None => return,
+                };
+                match factory.incremental.as_ref() {
+                    Some(incremental)
+                        if !incremental.borrow().is_idle()
+                            || incremental.borrow().is_large(array.length() as usize) =>
+                    {
+                        incremental.borrow_mut().push_buffer(array, received_at);
+                        Self::schedule_incremental(factory.clone(), websocket.clone());
+                    }
+                    _ => Self::dispatch_incoming(
+                        WsMessage::Binary(array.to_vec()),
+                        received_at,
+                        factory.clone(),
+                        websocket.clone(),
+                    ),
+                }
             } else if let Ok(js_blob_array) = event.data().dyn_into::<web_sys::Blob>() {
-                Self::process_blob_message(js_blob_array, factory.clone());
+                factory.received_binary.set(true);
+                if let Some(js_blob_array) = Self::limit_blob(&factory, js_blob_array) {
+                    Self::process_blob_message(
+                        js_blob_array,
+                        received_at,
+                        factory.clone(),
+                        websocket.clone(),
+                    );
+                }
             } else {
                 console_log!("type not supported!!!")
             }
-            // @TODO will use if we need callback on message
-            // if let Some(val) = JsString::try_from(&data) {
-            //     let inner_cb = &mut *cb.borrow_mut();
-            //     return inner_cb(WsMessage::Text(String::from(val)));
-            // }
         })))
     }
 
-    fn build_onopen(
-        factory: Rc<WsFactory>,
-        websocket: Rc<RefCell<WebSocket>>,
-        pinger: Option<Rc<RefCell<Pinger>>>,
-    ) -> Option<Closure<dyn FnMut(Event) + 'static>> {
-        if factory.on_open.is_none() && factory.reconnect.is_none() {
-            return None;
+    // A text frame is at least as many utf8 bytes as it has utf16 units, so
+    // most oversized frames are refused before being copied out of js.
+    fn limit_text(factory: &WsFactory, js_string: JsString) -> Option<String> {
+        let limit = match factory.size_limit {
+            Some(limit) => limit,
+            None => return Some(String::from(js_string)),
+        };
+        let units = js_string.length() as usize;
+        if units > limit.max_size {
+            Self::report_too_large(factory, MessageTooLarge::new("text", units, &limit));
+            return match limit.policy {
+                OversizePolicy::Drop => None,
+                OversizePolicy::Truncate => {
+                    // a surrogate pair cut in half would decode to U+FFFD
+                    let mut end = limit.max_size as u32;
+                    if end > 0 && is_high_surrogate(js_string.char_code_at(end - 1)) {
+                        end -= 1;
+                    }
+                    Some(truncate_text(
+                        String::from(js_string.slice(0, end)),
+                        limit.max_size,
+                    ))
+                }
+            };
         }
-        Some(Closure::wrap(Box::new(move |event: Event| {
-            if let Some(reconnect_config) = factory.reconnect.clone() {
-                reconnect_config.borrow_mut().reset();
-            }
-            if let Some(on_open_callback) = factory.on_open.clone() {
-                let mut inner_callback = on_open_callback.as_ref().borrow_mut();
-                inner_callback(event);
-            }
-            if let Some(pinger) = pinger.clone() {
-                let mut pinger_ref = pinger.as_ref().borrow_mut();
-                let ping = Ping { ping: "ping" };
-                let ping_data = serde_json::to_string(&ping).unwrap();
-                match websocket.borrow().send_with_str(ping_data.as_str()) {
-                    Ok(_) => (),
-                    Err(err) => console_log!("error on send {:?}", err),
-                };
-                pinger_ref.ping();
+        Self::limit_decoded(factory, String::from(js_string))
+    }
+
+    fn limit_decoded(factory: &WsFactory, text: String) -> Option<String> {
+        let limit = match factory.size_limit {
+            Some(limit) => limit,
+            None => return Some(text),
+        };
+        if text.len() <= limit.max_size {
+            return Some(text);
+        }
+        Self::report_too_large(factory, MessageTooLarge::new("text", text.len(), &limit));
+        match limit.policy {
+            OversizePolicy::Drop => None,
+            OversizePolicy::Truncate => Some(truncate_text(text, limit.max_size)),
+        }
+    }
+
+    // Reassembled and inflated payloads are held to the limit of a frame.
+    fn limit_bytes(factory: &WsFactory, mut payload: Vec<u8>) -> Option<Vec<u8>> {
+        let limit = match factory.size_limit {
+            Some(limit) if payload.len() > limit.max_size => limit,
+            _ => return Some(payload),
+        };
+        Self::report_too_large(
+            factory,
+            MessageTooLarge::new("binary", payload.len(), &limit),
+        );
+        match limit.policy {
+            OversizePolicy::Drop => None,
+            OversizePolicy::Truncate => {
+                payload.truncate(limit.max_size);
+                Some(payload)
             }
-            if let Some(emitter) = factory.emitter.clone() {
-                let mut emitter_ref = emitter.as_ref().borrow_mut();
-                let handlers = emitter_ref.get_handlers_names();
-                for handler in handlers.iter() {
-                    let subscribe_data = serde_json::to_string(&Subscribe {
-                        subscribe: handler.as_str(),
-                    })
-                    .unwrap();
-                    websocket
-                        .borrow()
-                        .send_with_str(subscribe_data.as_str())
-                        .unwrap();
-                }
-                emitter_ref.emit(String::from("open"), &Payload::Data(String::from("open")));
+        }
+    }
+
+    fn limit_array(factory: &WsFactory, array: Uint8Array) -> Option<Uint8Array> {
+        let limit = match factory.size_limit {
+            Some(limit) if array.length() as usize > limit.max_size => limit,
+            _ => return Some(array),
+        };
+        let byte_length = array.length() as usize;
+        Self::report_too_large(factory, MessageTooLarge::new("binary", byte_length, &limit));
+        match limit.policy {
+            OversizePolicy::Drop => None,
+            OversizePolicy::Truncate => Some(array.subarray(0, limit.max_size as u32)),
+        }
+    }
+
+    fn limit_blob(factory: &WsFactory, blob: web_sys::Blob) -> Option<web_sys::Blob> {
+        let limit = match factory.size_limit {
+            Some(limit) if blob.size() > limit.max_size as f64 => limit,
+            _ => return Some(blob),
+        };
+        let byte_length = blob.size() as usize;
+        Self::report_too_large(factory, MessageTooLarge::new("binary", byte_length, &limit));
+        match limit.policy {
+            OversizePolicy::Drop => None,
+            OversizePolicy::Truncate => {
+                blob.slice_with_f64_and_f64(0.0, limit.max_size as f64).ok()
             }
-        })))
+        }
     }
 
-    fn build_onerror(factory: Rc<WsFactory>) -> Option<Closure<dyn FnMut(ErrorEvent) + 'static>> {
-        // Unpack the user supplied value. If none, we have nothing to do.
-        let on_error_callback = match factory.on_error.clone() {
-            None => return None,
-            Some(callback) => callback,
+    fn report_too_large(factory: &WsFactory, too_large: MessageTooLarge) {
+        if let (Some(emitter), Ok(too_large)) =
+            (factory.emitter.clone(), serde_json::to_string(&too_large))
+        {
+            emitter
+                .borrow()
+                .emit(String::from("message_too_large"), &Payload::Data(too_large));
+        }
+    }
+
+    // One step of work per macrotask, until the reader has nothing left.
+    fn schedule_incremental(factory: Rc<WsFactory>, websocket: Rc<RefCell<WebSocket>>) {
+        let incremental = match factory.incremental.clone() {
+            Some(incremental) => incremental,
+            None => return,
         };
-        Some(Closure::wrap(Box::new(move |event: ErrorEvent| {
-            let event: ErrorEvent = event.unchecked_into();
-            let websocket_error_message = event.error();
-            if let Some(emitter) = factory.emitter.clone() {
-                match websocket_error_message.dyn_into::<JsString>() {
-                    Ok(error_message) => {
-                        emitter.borrow_mut().emit(
-                            String::from("error"),
-                            &Payload::Data(String::from(error_message)),
-                        );
+        if incremental.borrow_mut().set_scheduled(true) {
+            return;
+        }
+        set_timeout_once(
+            move || {
+                let step = {
+                    let mut incremental_ref = incremental.borrow_mut();
+                    incremental_ref.set_scheduled(false);
+                    incremental_ref.step()
+                };
+                match step {
+                    Step::Idle => return,
+                    Step::Copied => (),
+                    Step::Frame(message, received_at) => Self::dispatch_incoming(
+                        message,
+                        received_at,
+                        factory.clone(),
+                        websocket.clone(),
+                    ),
+                    Step::Text(text, received_at) => {
+                        if let Some(text) = Self::limit_decoded(&factory, text) {
+                            Self::dispatch_incoming(
+                                WsMessage::Text(text),
+                                received_at,
+                                factory.clone(),
+                                websocket.clone(),
+                            )
+                        }
                     }
-                    Err(e) => console_log!("err cast js value: {:?}", e),
                 }
-            }
-            let mut inner_error_callback = on_error_callback.as_ref().borrow_mut();
-            inner_error_callback(event);
-        })))
+                if !incremental.borrow().is_idle() {
+                    Self::schedule_incremental(factory, websocket);
+                }
+            },
+            0,
+        );
     }
 
-    fn build_onclose(
+    // The `on_message` callback sees every frame as it came off the wire, with
+    // `raw_passthrough` the routing pipeline is skipped altogether.
+    fn dispatch_incoming(
+        message: WsMessage,
+        received_at: f64,
         factory: Rc<WsFactory>,
         websocket: Rc<RefCell<WebSocket>>,
-        pinger: Option<Rc<RefCell<Pinger>>>,
-    ) -> Option<Closure<dyn FnMut(CloseEvent) + 'static>> {
-        if factory.on_close.is_none() && factory.reconnect.is_none() {
-            return None;
+    ) {
+        match factory.sockjs.clone() {
+            Some(sockjs) => {
+                Self::process_sockjs_frame(&sockjs, message, received_at, factory, websocket)
+            }
+            None => Self::dispatch_message(message, received_at, factory, websocket),
         }
-        Some(Closure::wrap(Box::new(move |event: CloseEvent| {
-            // @TODO maybe not needed
-            //if *factory.is_closing.borrow() {
-            if let Some(reconnect_config) = factory.reconnect.clone() {
-                let retry_callback = Self::build_retry_closure(factory.clone(), websocket.clone());
-                Self::schedule_reconnect(&retry_callback, 1000u32);
-                reconnect_config.borrow_mut().set_retry_cb(retry_callback);
+    }
+
+    fn process_sockjs_frame(
+        sockjs: &SockJs,
+        message: WsMessage,
+        received_at: f64,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        let frame = match message {
+            WsMessage::Text(frame) => frame,
+            WsMessage::Binary(_) => {
+                console_log!("binary frames are not sockjs frames");
+                return;
             }
-            //}
-            if let Some(emitter) = factory.emitter.clone() {
-                emitter
-                    .borrow_mut()
-                    .emit(String::from("close"), &Payload::Data(String::from("close")));
+        };
+        match SockJs::decode(&frame) {
+            Ok(SockJsFrame::Open) => {
+                if let Some(ready) = sockjs.take_pending() {
+                    ready();
+                }
             }
-            if let Some(pinger) = pinger.clone() {
-                let pinger_ref = pinger.as_ref().borrow_mut();
-                let raw_id = pinger_ref.get_interval_id();
-                if let Some(id) = raw_id {
-                    let id = id.as_ref().borrow();
-                    pinger_ref.close_ping(*id);
+            Ok(SockJsFrame::Heartbeat) => (),
+            Ok(SockJsFrame::Messages(messages)) => {
+                for message in messages {
+                    Self::dispatch_message(
+                        WsMessage::Text(message),
+                        received_at,
+                        factory.clone(),
+                        websocket.clone(),
+                    );
                 }
-            };
-            if let Some(on_close_callback) = factory.on_close.clone() {
-                let mut inner_callback = on_close_callback.as_ref().borrow_mut();
-                inner_callback(event);
             }
-        })))
+            Ok(SockJsFrame::Close { code, reason }) => sockjs.set_close(code, reason),
+            Err(err) => Self::report_parse_error(&factory, err, frame),
+        }
     }
 
-    fn build_retry_closure(
+    fn dispatch_message(
+        message: WsMessage,
+        received_at: f64,
         factory: Rc<WsFactory>,
         websocket: Rc<RefCell<WebSocket>>,
-    ) -> Closure<dyn FnMut() + 'static> {
-        Closure::wrap(Box::new(move || {
-            // @TODO will think need this or not
-            // if !*factory.is_closing.borrow() {
-            //     return;
-            // }
-            let new_websocket_instance = match Self::build_new_websocket(&factory.url) {
-                Ok(websocket) => websocket,
-                Err(_) => {
-                    let reconnect_config = factory.reconnect.clone().unwrap();
-                    let retry_callback =
-                        Self::build_retry_closure(factory.clone(), websocket.clone());
-                    Self::schedule_reconnect(&retry_callback, 1000u32);
-                    reconnect_config.borrow_mut().set_retry_cb(retry_callback);
-                    return;
-                }
-            };
-            {
-                *websocket.borrow_mut() = new_websocket_instance;
+    ) {
+        if Self::process_auth_reply(&message, &factory, &websocket) {
+            return;
+        }
+        if let Some(paused) = factory.paused.borrow_mut().as_mut() {
+            paused.push(message, received_at);
+            return;
+        }
+        if factory.frame_events {
+            Self::emit_frame_event(&factory, &message);
+        }
+        if factory.raw_passthrough {
+            // nothing is reassembled, the receivers get the frames as they are
+            factory.deliver_to_receivers(&message);
+        }
+        if let Some(on_message_callback) = factory.on_message.clone() {
+            let mut inner_callback = on_message_callback.as_ref().borrow_mut();
+            if factory.raw_passthrough {
+                inner_callback(message);
+                return;
             }
-            let pinger = Some(Rc::new(RefCell::new(Pinger::new(None))));
-            Self::init_new_websocket(factory.clone(), websocket.clone(), pinger.clone());
-        }))
-    }
-
-    fn process_text_message(payload: String, factory: Rc<WsFactory>) {
-        if let Some(emitter) = factory.emitter.clone() {
-            let response: Value =
-                serde_json::from_str(payload.as_str()).expect("can't deserialize");
-            let end_bytes = payload.find(":").unwrap();
-            let handler_name = &payload[..end_bytes].replace("{", "").replace("\"", "");
-            let data = response[handler_name].clone();
-            if handler_name == "jsonrpc" {
-                Self::process_rpc_message(payload, factory.clone());
-            } else {
-                emitter
-                    .borrow_mut()
-                    .emit(String::from(handler_name), &Payload::Data(data.to_string()));
+            inner_callback(message.clone());
+        }
+        match message {
+            WsMessage::Text(payload) => {
+                Self::process_text_message(payload, received_at, factory, websocket)
+            }
+            WsMessage::Binary(payload) => {
+                Self::process_array_message(payload, received_at, factory, websocket)
             }
         }
     }
 
-    fn process_array_message(payload: Vec<u8>, factory: Rc<WsFactory>) {
-        if let Some(emitter) = factory.emitter.clone() {
-            let response: Value =
-                serde_json::from_slice(&*payload.clone()).expect("can't deserialize");
-            match str::from_utf8(&*payload.clone()) {
-                Ok(string_payload) => {
-                    let end_bytes = string_payload.find(":").unwrap();
-                    let handler_name = &string_payload[..end_bytes]
-                        .replace("{", "")
-                        .replace("\"", "");
-                    let data = response[handler_name].clone();
-                    if handler_name == "jsonrpc" {
-                        Self::process_rpc_message(string_payload.to_string(), factory.clone());
-                    } else {
-                        emitter
-                            .borrow_mut()
-                            .emit(String::from(handler_name), &Payload::Data(data.to_string()));
-                    }
-                }
-                Err(err) => {
-                    emitter
-                        .borrow_mut()
-                        .emit(String::from("error"), &Payload::Data(err.to_string()));
-                }
+    fn build_onopen(
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+        pinger: Option<Rc<RefCell<Pinger>>>,
+    ) -> Option<Closure<dyn FnMut(Event) + 'static>> {
+        Some(Closure::wrap(Box::new(move |event: Event| {
+            if let Some(reconnect_config) = factory.reconnect.clone() {
+                reconnect_config.borrow_mut().reset();
             }
-        }
+            factory
+                .connection_stats
+                .borrow_mut()
+                .record_open(js_sys::Date::now());
+            let sockjs = match factory.sockjs.as_ref() {
+                Some(sockjs) => sockjs,
+                None => return Self::begin_session(&factory, &websocket, pinger.clone(), event),
+            };
+            // the sockjs session only counts as open with the `o` frame
+            let factory = factory.clone();
+            let websocket = websocket.clone();
+            let pinger = pinger.clone();
+            sockjs.begin(Box::new(move || {
+                Self::begin_session(&factory, &websocket, pinger, event)
+            }));
+        })))
     }
 
-    fn process_blob_message(js_blob_array: web_sys::Blob, factory: Rc<WsFactory>) {
-        let fr = web_sys::FileReader::new().unwrap();
-        let fr_c = fr.clone();
-        let factory_ref = factory.clone();
-        let onloadend_cb = Closure::wrap(Box::new(move |_e: web_sys::ProgressEvent| {
-            let array = js_sys::Uint8Array::new(&fr_c.result().unwrap());
-            let array = Uint8Array::new(&array).to_vec();
-            Self::process_array_message(array, factory_ref.clone());
-        }) as Box<dyn FnMut(web_sys::ProgressEvent)>);
-        fr.set_onloadend(Some(onloadend_cb.as_ref().unchecked_ref()));
-        fr.read_as_array_buffer(&js_blob_array)
-            .expect("blob not readable");
-        onloadend_cb.forget();
+    fn begin_session(
+        factory: &Rc<WsFactory>,
+        websocket: &Rc<RefCell<WebSocket>>,
+        pinger: Option<Rc<RefCell<Pinger>>>,
+        event: Event,
+    ) {
+        let auth = match factory.auth.as_ref() {
+            Some(auth) => auth,
+            None => return Self::on_ready(factory, websocket, pinger, event),
+        };
+        let ready = {
+            let factory = factory.clone();
+            let websocket = websocket.clone();
+            Box::new(move || Self::on_ready(&factory, &websocket, pinger, event))
+        };
+        let login = factory.frame(auth.begin(ready, factory.token.borrow().as_deref()));
+        if let Err(err) = Self::send_message(factory, &websocket.borrow(), &login) {
+            console_log!("error on send login: {:?}", err);
+        }
     }
 
-    fn process_rpc_message(payload: String, factory: Rc<WsFactory>) {
+    // The open sequence, run right after the open event or once the auth
+    // handshake succeeded.
+    fn on_ready(
+        factory: &Rc<WsFactory>,
+        websocket: &Rc<RefCell<WebSocket>>,
+        pinger: Option<Rc<RefCell<Pinger>>>,
+        event: Event,
+    ) {
+        if let Some(connect) = factory.connect.as_ref() {
+            connect.borrow_mut().settle(Ok(()));
+        }
+        factory.notify_state(ConnectionState::Open);
+        if factory.connection_stats.borrow().reconnects > 0 {
+            Self::send_resume(factory, websocket);
+        }
+        if let Some(on_open_callback) = factory.on_open.borrow().clone() {
+            let mut inner_callback = on_open_callback.as_ref().borrow_mut();
+            inner_callback(event);
+        }
+        if let Some(pinger) = pinger.filter(|_| factory.heartbeat) {
+            let mut pinger_ref = pinger.as_ref().borrow_mut();
+            if !factory.is_paused() {
+                let ping = Ping { ping: "ping" };
+                let ping_data = serde_json::to_string(&ping).unwrap();
+                let ping_message = factory.frame(WsMessage::Text(ping_data));
+                match Self::send_message(factory, &websocket.borrow(), &ping_message) {
+                    Ok(_) => (),
+                    Err(err) => console_log!("error on send {:?}", err),
+                };
+            }
+            pinger_ref.ping(factory.clone());
+        }
+        let skipped = match factory.paused.borrow_mut().as_mut() {
+            Some(paused) => {
+                paused.skip_resubscribe();
+                true
+            }
+            None => false,
+        };
+        if !skipped {
+            Self::resubscribe(factory, websocket);
+        }
+        let resend = factory.ack.borrow_mut().take_resend();
+        for message in resend {
+            if let Err(err) = factory
+                .outgoing
+                .borrow_mut()
+                .push_with_priority(message, Priority::High)
+            {
+                console_log!("error on resend unacked message: {:?}", err);
+            }
+        }
+        Self::flush_outgoing(factory, websocket);
         if let Some(emitter) = factory.emitter.clone() {
-            if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
-                let mut rpc_subscriber_ref = rpc_subscriber.as_ref().borrow_mut();
-                let raw_rpc_response = RPCSubscriber::get_response(payload);
-                match raw_rpc_response {
-                    Ok(rpc_response) => {
-                        let request_id = rpc_response.id;
-                        match request_id {
-                            Some(id) => {
-                                let handler = rpc_subscriber_ref.get_handler(id);
-                                if let Some(handle) = handler {
-                                    handle(rpc_response.result.to_string());
-                                }
-                            }
-                            None => console_log!("this is notification"),
-                        }
+            emitter
+                .borrow()
+                .emit(String::from("open"), &Payload::Data(String::from("open")));
+        }
+    }
+
+    fn send_resume(factory: &WsFactory, websocket: &Rc<RefCell<WebSocket>>) {
+        let frame = match factory.resume.as_ref() {
+            Some(resume) => resume.borrow().resume_frame(),
+            None => None,
+        };
+        if let Some(frame) = frame {
+            let frame = factory.frame(frame);
+            if let Err(err) = Self::send_message(factory, &websocket.borrow(), &frame) {
+                console_log!("error on send resume: {:?}", err);
+            }
+        }
+    }
+
+    fn resubscribe(factory: &WsFactory, websocket: &Rc<RefCell<WebSocket>>) {
+        if let Some(emitter) = factory.emitter.clone() {
+            let handlers = if factory.auto_subscribe {
+                emitter.as_ref().borrow_mut().get_handlers_names()
+            } else {
+                Vec::new()
+            };
+            let names = factory.subscriptions.borrow().names(handlers);
+            for name in names.iter() {
+                let subscribe_data = factory.frame((factory.subscription_format)(
+                    SubscriptionAction::Subscribe,
+                    name,
+                ));
+                if let Err(err) = Self::send_message(factory, &websocket.borrow(), &subscribe_data)
+                {
+                    console_log!("error on send {:?}", err);
+                }
+            }
+        }
+    }
+
+    pub fn pause(&self, policy: PausePolicy) {
+        let mut paused = self.factory.paused.borrow_mut();
+        if paused.is_none() {
+            *paused = Some(PausedInbox::new(policy));
+        }
+    }
+
+    // Held frames are processed in arrival order before anything new.
+    pub fn resume(&self) {
+        let paused = match self.factory.paused.borrow_mut().take() {
+            Some(paused) => paused,
+            None => return,
+        };
+        let (frames, resubscribe) = paused.into_parts();
+        let is_open = self.websocket.borrow().ready_state() == WebSocket::OPEN;
+        if resubscribe && is_open && !self.factory.is_authenticating() {
+            Self::resubscribe(&self.factory, &self.websocket);
+        }
+        for (message, received_at) in frames {
+            Self::dispatch_message(
+                message,
+                received_at,
+                self.factory.clone(),
+                self.websocket.clone(),
+            );
+        }
+    }
+
+    // Returns `true` when the frame was the reply to the login.
+    fn process_auth_reply(
+        message: &WsMessage,
+        factory: &Rc<WsFactory>,
+        websocket: &Rc<RefCell<WebSocket>>,
+    ) -> bool {
+        let auth = match factory.auth.as_ref() {
+            Some(auth) if auth.is_pending() => auth,
+            _ => return false,
+        };
+        match auth.reply(message) {
+            None => false,
+            Some(Ok(())) => {
+                if let Some(ready) = auth.take_pending() {
+                    ready();
+                }
+                true
+            }
+            Some(Err(reason)) => {
+                auth.reject();
+                if let Some(connect) = factory.connect.as_ref() {
+                    connect
+                        .borrow_mut()
+                        .settle(Err(WsError::ConnectionFailed(format!(
+                            "authentication failed: {}",
+                            reason
+                        ))));
+                }
+                if let Some(emitter) = factory.emitter.clone() {
+                    emitter
+                        .borrow()
+                        .emit(String::from("auth_failed"), &Payload::Data(reason));
+                }
+                let reason = Some(String::from("authentication failed"));
+                if let Err(err) = Self::close_socket(factory, websocket, 1000, reason) {
+                    console_log!("error on close after auth failure: {:?}", err);
+                }
+                true
+            }
+        }
+    }
+
+    fn build_onerror(factory: Rc<WsFactory>) -> Option<Closure<dyn FnMut(ErrorEvent) + 'static>> {
+        Some(Closure::wrap(Box::new(move |event: ErrorEvent| {
+            let event: ErrorEvent = event.unchecked_into();
+            // socket errors are usually plain events without a message
+            let message = js_sys::Reflect::get(&event, &JsValue::from_str("message"))
+                .ok()
+                .and_then(|message| message.as_string())
+                .unwrap_or_else(|| String::from("websocket error"));
+            factory
+                .connection_stats
+                .borrow_mut()
+                .record_error(message.clone(), js_sys::Date::now());
+            factory.notify_state(ConnectionState::Error(message.clone()));
+            if let Some(incremental) = factory.incremental.as_ref() {
+                incremental.borrow_mut().reset();
+            }
+            if let Some(connect) = factory.connect.as_ref() {
+                connect
+                    .borrow_mut()
+                    .settle(Err(WsError::ConnectionFailed(message)));
+            }
+            let websocket_error_message = event.error();
+            if let Some(emitter) = factory.emitter.clone() {
+                match websocket_error_message.dyn_into::<JsString>() {
+                    Ok(error_message) => {
+                        emitter.borrow_mut().emit(
+                            String::from("error"),
+                            &Payload::Data(String::from(error_message)),
+                        );
                     }
+                    Err(e) => console_log!("err cast js value: {:?}", e),
+                }
+            }
+            if let Some(on_error_callback) = factory.on_error.borrow().clone() {
+                let mut inner_error_callback = on_error_callback.as_ref().borrow_mut();
+                inner_error_callback(event);
+            }
+        })))
+    }
+
+    fn build_onclose(
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+        pinger: Option<Rc<RefCell<Pinger>>>,
+    ) -> Option<Closure<dyn FnMut(CloseEvent) + 'static>> {
+        Some(Closure::wrap(Box::new(move |event: CloseEvent| {
+            // a sockjs server closes its session with a `c` frame first
+            let (code, reason) = match factory
+                .sockjs
+                .as_ref()
+                .and_then(|sockjs| sockjs.take_close())
+            {
+                Some((code, reason)) => (CloseCode::from(code), reason),
+                None => (CloseCode::from(&event), event.reason()),
+            };
+            {
+                let now = js_sys::Date::now();
+                let mut connection_stats = factory.connection_stats.borrow_mut();
+                connection_stats.record_close(now);
+                if !event.was_clean() {
+                    connection_stats.record_error(
+                        format!("closed with code {}: {}", u16::from(code), reason),
+                        now,
+                    );
+                }
+            }
+            if let Some(connect) = factory.connect.as_ref() {
+                connect.borrow_mut().settle(Err(WsError::Closed {
+                    code,
+                    reason: reason.clone(),
+                }));
+            }
+            // @TODO maybe not needed
+            //if *factory.is_closing.borrow() {
+            let released = factory.released.get();
+            if let Some(sockjs) = factory.sockjs.as_ref() {
+                sockjs.take_pending();
+            }
+            let auth_rejected = match factory.auth.as_ref() {
+                Some(auth) => {
+                    auth.take_pending();
+                    auth.is_rejected()
+                }
+                None => false,
+            };
+            if !released && !auth_rejected {
+                Self::schedule_reconnect(factory.clone(), websocket.clone(), 1000u32);
+            }
+            //}
+            factory.notify_state(ConnectionState::Closed(CloseInfo {
+                code,
+                reason,
+                was_clean: event.was_clean(),
+            }));
+            if factory.reconnect.is_none()
+                || released
+                || auth_rejected
+                || *factory.is_closing.borrow()
+            {
+                factory.close_streams();
+            }
+            if let Some(emitter) = factory.emitter.clone() {
+                emitter
+                    .borrow_mut()
+                    .emit(String::from("close"), &Payload::Data(String::from("close")));
+            }
+            if let Some(pinger) = pinger.clone() {
+                pinger.borrow_mut().stop();
+            }
+            Self::end_rpc_subscriptions(&factory);
+            if factory.reconnect.is_none() {
+                Self::stop_intervals(&factory);
+                Self::fail_pending_rpc(factory.clone());
+                Self::fail_pending_acks(&factory);
+                factory.scheduler.cancel_all();
+            } else {
+                factory.ack.borrow_mut().mark_resend();
+            }
+            if let Some(chunking) = factory.chunking.as_ref() {
+                chunking.borrow_mut().reset();
+            }
+            if let Some(incremental) = factory.incremental.as_ref() {
+                incremental.borrow_mut().reset();
+            }
+            if let Some(fragments) = factory.fragments.as_ref() {
+                fragments.borrow_mut().reset();
+            }
+            if let Some(reorder) = factory.reorder.clone() {
+                let held = reorder.borrow_mut().reset();
+                for frame in held {
+                    Self::route_message(frame, factory.clone(), websocket.clone());
+                }
+            }
+            if let Some(on_close_callback) = factory.on_close.borrow().clone() {
+                let mut inner_callback = on_close_callback.as_ref().borrow_mut();
+                inner_callback(event);
+            }
+            if released {
+                Self::release_after_close(&factory, &websocket.borrow());
+            }
+        })))
+    }
+
+    fn retry(factory: Rc<WsFactory>, websocket: Rc<RefCell<WebSocket>>) {
+        // @TODO will think need this or not
+        // if !*factory.is_closing.borrow() {
+        //     return;
+        // }
+        if factory.token_provider.is_none() && factory.probe.is_none() {
+            return Self::reconnect(factory, websocket);
+        }
+        spawn_local(async move {
+            if let Some(token_provider) = factory.token_provider.clone() {
+                match token_provider().await {
+                    Ok(token) => *factory.token.borrow_mut() = Some(token),
                     Err(err) => {
-                        let request_id = err.id;
-                        match request_id {
-                            Some(id) => {
-                                let handler = rpc_subscriber_ref.get_error_handler(id);
-                                if let Some(handle) = handler {
-                                    handle(err.msg.to_string());
-                                }
-                            }
-                            None => console_log!("this is notification"),
+                        if let Some(emitter) = factory.emitter.clone() {
+                            emitter
+                                .borrow()
+                                .emit(String::from("token_error"), &Payload::Data(err));
                         }
+                        if *factory.is_closing.borrow() {
+                            return;
+                        }
+                        return Self::retry_later(factory, websocket);
                     }
                 }
             }
+            factory.select_endpoint().await;
+            Self::reconnect(factory, websocket);
+        });
+    }
+
+    // The token and the probe are awaited before this runs, the socket may
+    // have been closed or dropped in the meantime.
+    fn reconnect(factory: Rc<WsFactory>, websocket: Rc<RefCell<WebSocket>>) {
+        if factory.released.get() || *factory.is_closing.borrow() {
+            return;
         }
+        let new_websocket_instance = match factory.open_socket() {
+            Ok(websocket) => websocket,
+            Err(_) => return Self::retry_later(factory, websocket),
+        };
+        {
+            // a late event from the old socket must not trigger another
+            // reconnect
+            let old_websocket = websocket.borrow();
+            Self::detach_handlers(&old_websocket);
+            let state = old_websocket.ready_state();
+            if state == WebSocket::CONNECTING || state == WebSocket::OPEN {
+                let _ = old_websocket.close();
+            }
+        }
+        {
+            *websocket.borrow_mut() = new_websocket_instance;
+        }
+        let pinger = Some(Rc::new(RefCell::new(Pinger::new(None))));
+        Self::init_new_websocket(factory.clone(), websocket, pinger);
+        factory.notify_state(ConnectionState::Connecting);
+    }
+
+    fn retry_later(factory: Rc<WsFactory>, websocket: Rc<RefCell<WebSocket>>) {
+        Self::schedule_reconnect(factory, websocket, 1000u32);
+    }
+
+    fn process_text_message(
+        payload: String,
+        received_at: f64,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        let ack_handler = factory.ack.borrow_mut().match_ack(&payload);
+        if let Some(ack_handler) = ack_handler {
+            ack_handler(Ok(()));
+            return;
+        }
+        Self::reassemble_message(WsMessage::Text(payload), received_at, factory, websocket);
+    }
+
+    // A reassembled message counts as received with its last part.
+    fn reassemble_message(
+        message: WsMessage,
+        received_at: f64,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        let fragment = match factory.fragments.as_ref() {
+            Some(fragments) => fragments.borrow_mut().push(message, js_sys::Date::now()),
+            None => return Self::reorder_message(message, received_at, factory, websocket),
+        };
+        match fragment {
+            Fragment::Whole(message) => {
+                Self::reorder_message(message, received_at, factory, websocket)
+            }
+            Fragment::Complete(payload) => {
+                if let Some(payload) = Self::limit_decoded(&factory, payload) {
+                    Self::reorder_message(WsMessage::Text(payload), received_at, factory, websocket)
+                }
+            }
+            Fragment::Pending => Self::schedule_fragment_expiry(&factory),
+        }
+    }
+
+    // Incomplete messages are dropped once they timed out, also when no
+    // other frame comes in to evict them.
+    fn schedule_fragment_expiry(factory: &Rc<WsFactory>) {
+        let fragments = match factory.fragments.as_ref() {
+            Some(fragments) => fragments,
+            None => return,
+        };
+        let delay = {
+            let fragments = fragments.borrow();
+            if fragments.expiry_timer.is_some() {
+                return;
+            }
+            match fragments.next_expiry(js_sys::Date::now()) {
+                Some(delay) => delay,
+                None => return,
+            }
+        };
+        let weak_factory = Rc::downgrade(factory);
+        let id = set_timeout_once(
+            move || {
+                let factory = match weak_factory.upgrade() {
+                    Some(factory) => factory,
+                    None => return,
+                };
+                if let Some(fragments) = factory.fragments.as_ref() {
+                    let mut fragments = fragments.borrow_mut();
+                    fragments.expiry_timer = None;
+                    fragments.expire(js_sys::Date::now());
+                }
+                Self::schedule_fragment_expiry(&factory);
+            },
+            delay,
+        );
+        fragments.borrow_mut().expiry_timer = Some(id);
+    }
+
+    fn reorder_message(
+        message: WsMessage,
+        received_at: f64,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        factory.deliver_to_receivers(&message);
+        let frame = ParsedFrame::new(message, received_at);
+        let reorder = match factory.reorder.clone() {
+            Some(reorder) => reorder,
+            None => return Self::route_message(frame, factory, websocket),
+        };
+        let ready = reorder.borrow_mut().push_parsed(frame);
+        for frame in ready {
+            Self::route_message(frame, factory.clone(), websocket.clone());
+        }
+        let (has_gap, timeout) = {
+            let reorder_ref = reorder.borrow();
+            (reorder_ref.has_gap(), reorder_ref.timeout())
+        };
+        if has_gap && !reorder.borrow_mut().set_flush_scheduled(true) {
+            set_timeout_once(
+                move || {
+                    let held = {
+                        let mut reorder_ref = reorder.borrow_mut();
+                        reorder_ref.set_flush_scheduled(false);
+                        reorder_ref.flush_parsed()
+                    };
+                    for frame in held {
+                        Self::route_message(frame, factory.clone(), websocket.clone());
+                    }
+                },
+                timeout,
+            );
+        }
+    }
+
+    // The json parsed by the reorder window is reused for the resume id
+    // instead of parsing the frame again.
+    fn route_message(
+        frame: ParsedFrame,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        let ParsedFrame {
+            message,
+            json,
+            received_at,
+        } = frame;
+        let bytes = message.len();
+        if factory.codec.is_none() && factory.json_codec.is_raw_binary(&message) {
+            factory
+                .rx_stats
+                .borrow_mut()
+                .record(BINARY_EVENT, bytes, received_at);
+            if let (Some(emitter), WsMessage::Binary(payload)) = (factory.emitter.clone(), message)
+            {
+                emitter
+                    .borrow()
+                    .emit(String::from(BINARY_EVENT), &Payload::Bytes(payload));
+            }
+            return;
+        }
+        let resume_id = factory
+            .resume
+            .as_ref()
+            .and_then(|resume| resume.borrow().extract(&message, json.as_ref()));
+        let routed = match factory.active_codec().decode(message) {
+            Ok(routed) => routed,
+            Err(err) => {
+                Self::report_parse_error(&factory, err.message, err.payload);
+                return;
+            }
+        };
+        if let (Some(resume), Some(id)) = (factory.resume.as_ref(), resume_id) {
+            resume.borrow_mut().record(id);
+        }
+        factory
+            .rx_stats
+            .borrow_mut()
+            .record(&routed.event, bytes, received_at);
+        if routed.event == RPC_EVENT {
+            Self::process_rpc_message(routed.data, factory.clone(), websocket);
+            return;
+        }
+        if let Err(reason) = Self::validate_event(&factory, &routed.event, &routed.data) {
+            let validation_error = ValidationError {
+                event: routed.event,
+                reason,
+            };
+            if let (Some(emitter), Ok(validation_error)) = (
+                factory.emitter.clone(),
+                serde_json::to_string(&validation_error),
+            ) {
+                emitter.borrow().emit(
+                    String::from("validation_error"),
+                    &Payload::Data(validation_error),
+                );
+            }
+            return;
+        }
+        if let Some(emitter) = factory.emitter.clone() {
+            emitter
+                .borrow()
+                .emit(routed.event, &Payload::Data(routed.data));
+        }
+    }
+
+    fn validate_event(factory: &WsFactory, event: &str, data: &str) -> Result<(), String> {
+        let validator = match factory.event_validators.get(event) {
+            Some(validator) => validator,
+            None => return Ok(()),
+        };
+        let value = serde_json::from_str(data).map_err(|err| err.to_string())?;
+        validator(&value)
+    }
+
+    fn emit_frame_event(factory: &WsFactory, message: &WsMessage) {
+        let emitter = match factory.emitter.clone() {
+            Some(emitter) => emitter,
+            None => return,
+        };
+        let frame_event = FrameEvent::new(message, js_sys::Date::now());
+        match serde_json::to_string(&frame_event) {
+            Ok(frame_event) => emitter
+                .borrow()
+                .emit(String::from(FRAME_EVENT), &Payload::Data(frame_event)),
+            Err(err) => console_log!("error serialize frame event: {:?}", err),
+        }
+    }
+
+    fn process_array_message(
+        payload: Vec<u8>,
+        received_at: f64,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        let payload = match factory.chunking.as_ref() {
+            Some(chunking) if Chunking::is_chunk(&payload) => {
+                let assembled = chunking.borrow_mut().assemble(payload, js_sys::Date::now());
+                match assembled.and_then(|payload| Self::limit_bytes(&factory, payload)) {
+                    Some(payload) => payload,
+                    None => return,
+                }
+            }
+            _ => payload,
+        };
+        #[cfg(feature = "flate2")]
+        let payload = match factory.compression.as_ref() {
+            Some(compression) if Compression::is_compressed(&payload) => {
+                match compression.decompress(&payload) {
+                    Some(WsMessage::Text(payload)) => {
+                        if let Some(payload) = Self::limit_decoded(&factory, payload) {
+                            Self::process_text_message(
+                                payload,
+                                received_at,
+                                factory.clone(),
+                                websocket,
+                            );
+                        }
+                        return;
+                    }
+                    Some(WsMessage::Binary(payload)) => {
+                        match Self::limit_bytes(&factory, payload) {
+                            Some(payload) => payload,
+                            None => return,
+                        }
+                    }
+                    None => {
+                        console_log!("error decompress message");
+                        return;
+                    }
+                }
+            }
+            _ => payload,
+        };
+        #[cfg(feature = "flate2")]
+        let payload = match factory.decompress_incoming.as_ref() {
+            Some(decompression) => {
+                let protocol = websocket.borrow().protocol();
+                match decompression.decompress(&payload, &protocol) {
+                    Some(Ok(payload)) => match Self::limit_bytes(&factory, payload) {
+                        Some(payload) => payload,
+                        None => return,
+                    },
+                    Some(Err(err)) => {
+                        Self::report_parse_error(
+                            &factory,
+                            err.to_string(),
+                            String::from_utf8_lossy(&payload).into_owned(),
+                        );
+                        return;
+                    }
+                    None => payload,
+                }
+            }
+            None => payload,
+        };
+        let ack_handler = factory.ack.borrow_mut().match_binary_ack(&payload);
+        if let Some(ack_handler) = ack_handler {
+            ack_handler(Ok(()));
+            return;
+        }
+        #[cfg(feature = "prost")]
+        {
+            if factory.protobuf_router.is_some() {
+                factory.deliver_to_receivers(&WsMessage::Binary(payload.clone()));
+                Self::process_protobuf_message(&payload, &factory);
+                return;
+            }
+        }
+        #[cfg(feature = "rmp-serde")]
+        {
+            if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+                if rpc_subscriber.borrow().codec() == RpcCodec::MessagePack
+                    && Self::process_msgpack_rpc_message(
+                        &payload,
+                        factory.clone(),
+                        websocket.clone(),
+                    )
+                {
+                    return;
+                }
+            }
+        }
+        Self::reassemble_message(WsMessage::Binary(payload), received_at, factory, websocket);
+    }
+
+    // Frames that can't be decoded never bring the module down, they end up
+    // in the `error` event and the `on_parse_error` callback.
+    fn report_parse_error(factory: &WsFactory, error: String, payload: String) {
+        console_log!("error parse incoming message: {}", error);
+        if let Some(emitter) = factory.emitter.clone() {
+            emitter
+                .borrow()
+                .emit(String::from("error"), &Payload::Data(error.clone()));
+        }
+        if let Some(on_parse_error_callback) = factory.on_parse_error.clone() {
+            let mut inner_callback = on_parse_error_callback.as_ref().borrow_mut();
+            inner_callback(error, payload);
+        }
+    }
+
+    #[cfg(feature = "prost")]
+    fn process_protobuf_message(payload: &[u8], factory: &WsFactory) {
+        let protobuf_router = match factory.protobuf_router.as_ref() {
+            Some(protobuf_router) => protobuf_router,
+            None => return,
+        };
+        match protobuf_router(payload) {
+            Ok(Some((handler_name, message))) => {
+                if let Some(emitter) = factory.emitter.clone() {
+                    emitter
+                        .borrow()
+                        .emit(handler_name, &Payload::Bytes(message));
+                }
+            }
+            Ok(None) => (),
+            Err(err) => Self::report_parse_error(
+                factory,
+                err,
+                String::from_utf8_lossy(payload).into_owned(),
+            ),
+        }
+    }
+
+    fn process_blob_message(
+        js_blob_array: web_sys::Blob,
+        received_at: f64,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        let fr = match web_sys::FileReader::new() {
+            Ok(fr) => fr,
+            Err(err) => {
+                Self::report_parse_error(&factory, format!("{:?}", err), String::new());
+                return;
+            }
+        };
+        // the callback takes its own closure out of the slot, js frees it once
+        // it returned
+        let slot: LoadEndSlot = Rc::new(RefCell::new(None));
+        let fr_c = fr.clone();
+        let slot_ref = slot.clone();
+        let factory_ref = factory.clone();
+        let onloadend_cb = Closure::once(move |_e: web_sys::ProgressEvent| {
+            fr_c.set_onloadend(None);
+            let closure = slot_ref.borrow_mut().take();
+            match fr_c.result() {
+                Ok(result) => Self::dispatch_incoming(
+                    WsMessage::Binary(Uint8Array::new(&result).to_vec()),
+                    received_at,
+                    factory_ref,
+                    websocket,
+                ),
+                Err(err) => {
+                    Self::report_parse_error(&factory_ref, format!("{:?}", err), String::new())
+                }
+            }
+            drop(closure);
+        });
+        fr.set_onloadend(Some(onloadend_cb.as_ref().unchecked_ref()));
+        *slot.borrow_mut() = Some(onloadend_cb);
+        if let Err(err) = fr.read_as_array_buffer(&js_blob_array) {
+            fr.set_onloadend(None);
+            slot.borrow_mut().take();
+            Self::report_parse_error(&factory, format!("{:?}", err), String::new());
+        }
+    }
+
+    fn process_rpc_message(
+        payload: String,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+            let message = rpc_subscriber.as_ref().borrow().get_message(&payload);
+            Self::dispatch_rpc_message(message, factory, websocket);
+        }
+    }
+
+    // Only responses and notifications are taken, other binary frames go on
+    // to the listeners.
+    #[cfg(feature = "rmp-serde")]
+    fn process_msgpack_rpc_message(
+        payload: &[u8],
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) -> bool {
+        let rpc_subscriber = match factory.rpc_subscriber.clone() {
+            Some(rpc_subscriber) => rpc_subscriber,
+            None => return false,
+        };
+        let message = rpc_subscriber
+            .as_ref()
+            .borrow()
+            .get_msgpack_message(payload);
+        match message {
+            Some(message) => Self::dispatch_rpc_message(message, factory, websocket),
+            None => return false,
+        }
+        true
+    }
+
+    fn dispatch_rpc_message(
+        message: RpcMessage,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        match message {
+            RpcMessage::Notification(notification) => {
+                Self::dispatch_rpc_progress(notification, factory)
+            }
+            RpcMessage::Result(id, result) => Self::dispatch_rpc_result(id, result, factory),
+            RpcMessage::Response(response) => {
+                Self::dispatch_rpc_response(response, factory, websocket)
+            }
+        }
+    }
+
+    fn dispatch_rpc_progress(notification: Notification, factory: Rc<WsFactory>) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+            let subscription = rpc_subscriber
+                .as_ref()
+                .borrow()
+                .get_subscription_handler(&notification);
+            if let Some((handle, result)) = subscription {
+                handle(Some(result));
+                return;
+            }
+            let progress = rpc_subscriber
+                .as_ref()
+                .borrow()
+                .get_progress_handler(&notification);
+            match progress {
+                Some((request, handle)) => {
+                    let params = serde_json::to_string(&notification.params)
+                        .unwrap_or_else(|_| String::from("null"));
+                    handle(&request, params);
+                }
+                None => console_log!("this is notification: {}", notification.method),
+            }
+        }
+    }
+
+    fn dispatch_rpc_response(
+        raw_rpc_response: Result<RPCResponse, RpcError>,
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+    ) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+            match raw_rpc_response {
+                Ok(rpc_response) => match rpc_response.id {
+                    Some(id) => match serde_json::value::to_raw_value(&rpc_response.result) {
+                        Ok(result) => Self::dispatch_rpc_result(id, &result, factory.clone()),
+                        Err(err) => console_log!("error serialize rpc result: {:?}", err),
+                    },
+                    None => console_log!("this is notification"),
+                },
+                Err(err) => {
+                    let retry = rpc_subscriber.as_ref().borrow_mut().next_retry(&err);
+                    if let Some((request, delay)) = retry {
+                        Self::schedule_rpc_retry(
+                            factory.clone(),
+                            websocket.clone(),
+                            err,
+                            request,
+                            delay,
+                        );
+                        return;
+                    }
+                    Self::fail_rpc_request(factory.clone(), err);
+                }
+            }
+        }
+    }
+
+    fn dispatch_rpc_result(id: Id, result: &RawValue, factory: Rc<WsFactory>) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+            let (request, raw_handler, handler) = {
+                let mut rpc_subscriber_ref = rpc_subscriber.as_ref().borrow_mut();
+                let raw_handler = rpc_subscriber_ref.take_raw_handler(&id);
+                let (request, handler) = rpc_subscriber_ref.complete_request(&id, false);
+                (request, raw_handler, handler)
+            };
+            match (raw_handler, handler) {
+                (Some(raw_handle), _) => raw_handle(&request, result),
+                (None, Some(handle)) => handle(&request, result.get().to_string()),
+                (None, None) => console_log!(
+                    "no handler for rpc response id: {:?}, method: {}",
+                    request.id(),
+                    request.method()
+                ),
+            }
+        }
+    }
+
+    fn fail_pending_rpc(factory: Rc<WsFactory>) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+            let pending = rpc_subscriber.as_ref().borrow_mut().fail_pending();
+            for (request, handle) in pending {
+                handle(&request, String::from(CONNECTION_CLOSED));
+            }
+        }
+    }
+
+    fn end_rpc_subscriptions(factory: &WsFactory) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.as_ref() {
+            let subscriptions = rpc_subscriber.borrow_mut().take_subscriptions();
+            for handle in subscriptions {
+                handle(None);
+            }
+        }
+    }
+
+    fn fail_pending_acks(factory: &WsFactory) {
+        let pending = factory.ack.borrow_mut().fail_pending();
+        for handler in pending {
+            handler(Err(WsError::NotConnected {
+                state: ReadyState::Closed,
+            }));
+        }
+    }
+
+    fn fail_rpc_request(factory: Rc<WsFactory>, err: RpcError) {
+        if let Some(rpc_subscriber) = factory.rpc_subscriber.clone() {
+            match err.id {
+                Some(id) => {
+                    let (request, handler) = rpc_subscriber
+                        .as_ref()
+                        .borrow_mut()
+                        .complete_request(&id, true);
+                    match handler {
+                        Some(handle) => handle(&request, err.msg.to_string()),
+                        None => console_log!(
+                            "no error handler for rpc response id: {:?}, method: {}, error: {}",
+                            request.id(),
+                            request.method(),
+                            err.msg
+                        ),
+                    }
+                }
+                None => console_log!("this is notification"),
+            }
+        }
+    }
+
+    fn schedule_rpc_retry(
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+        err: RpcError,
+        request: WsMessage,
+        timeout: u32,
+    ) {
+        set_timeout_once(
+            move || {
+                if let Err(send_err) = Self::send_message(&factory, &websocket.borrow(), &request) {
+                    console_log!("error on rpc retry: {:?}", send_err);
+                    Self::fail_rpc_request(factory.clone(), err);
+                }
+            },
+            timeout,
+        );
+    }
+
+    // Frames the crate builds itself, heartbeats and resubscribes, go out
+    // through here. Frames of `send` were intercepted before they were
+    // queued, compressed and chunked.
+    fn send_message(
+        factory: &WsFactory,
+        websocket: &WebSocket,
+        message: &WsMessage,
+    ) -> Result<(), WsError> {
+        if factory.outbound_interceptors.is_empty() {
+            return Self::send_raw(factory, websocket, message);
+        }
+        match Self::intercept(factory, message.clone()) {
+            Some(message) => Self::send_raw(factory, websocket, &message),
+            None => Ok(()),
+        }
+    }
+
+    fn intercept(factory: &WsFactory, mut message: WsMessage) -> Option<WsMessage> {
+        for interceptor in factory.outbound_interceptors.iter() {
+            message = match interceptor(message) {
+                Some(message) => message,
+                None => {
+                    factory.tx_stats.borrow_mut().intercepted += 1;
+                    return None;
+                }
+            };
+        }
+        Some(message)
+    }
+
+    fn send_raw(
+        factory: &WsFactory,
+        websocket: &WebSocket,
+        message: &WsMessage,
+    ) -> Result<(), WsError> {
+        let result = match (message, factory.sockjs.as_ref()) {
+            (message, Some(_)) => websocket.send_with_str(&SockJs::encode(message)?),
+            (WsMessage::Text(payload), None) => websocket.send_with_str(payload.as_str()),
+            (WsMessage::Binary(payload), None) => websocket.send_with_u8_array(payload.as_slice()),
+        };
+        let binary = matches!(message, WsMessage::Binary(_));
+        factory
+            .tx_stats
+            .borrow_mut()
+            .record(binary, message.len(), result.is_ok());
+        result.map_err(WsError::SendFailed)
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Ping<'a> {
-    ping: &'a str,
+// The event handlers of the current socket, only held to keep them alive as
+// long as the socket is in use. Replaced on every reconnect.
+#[derive(Default)]
+pub(crate) struct SocketHandlers {
+    _onmessage: Option<Closure<dyn FnMut(MessageEvent)>>,
+    _onopen: Option<Closure<dyn FnMut(Event)>>,
+    _onerror: Option<Closure<dyn FnMut(ErrorEvent)>>,
+    _onclose: Option<Closure<dyn FnMut(CloseEvent)>>,
+}
+
+// The handlers keep the factory alive, so they are released once the last
+// handle is gone, after the close event when the socket is still closing.
+impl Drop for WsCore {
+    fn drop(&mut self) {
+        self.factory.released.set(true);
+        Self::stop_intervals(&self.factory);
+        Self::cancel_retry(&self.factory);
+        let websocket = self.websocket.borrow();
+        if websocket.ready_state() == WebSocket::CLOSED {
+            Self::release_handlers(&self.factory, &websocket);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-struct Subscribe<'a> {
-    subscribe: &'a str,
+struct Ping<'a> {
+    ping: &'a str,
 }
 
 struct Pinger {
     websocket: Option<Rc<RefCell<WebSocket>>>,
-    interval_id: Option<Rc<RefCell<i32>>>,
+    interval_id: Option<i32>,
 }
 
 impl Pinger {
     fn new(websocket: Option<Rc<RefCell<WebSocket>>>) -> Self {
         Self {
             websocket,
-            interval_id: Some(Rc::new(RefCell::new(0))),
+            interval_id: None,
         }
     }
 
-    fn ping(&mut self) {
+    fn ping(&mut self, factory: Rc<WsFactory>) {
         let raw_websocket = self.websocket.clone();
-        let closure = Closure::wrap(Box::new(move || {
-            let ping = Ping { ping: "ping" };
-            let ping_data = serde_json::to_string(&ping).unwrap();
-            if let Some(websocket) = raw_websocket.clone() {
-                match websocket.borrow_mut().send_with_str(ping_data.as_str()) {
-                    Ok(_) => (),
-                    Err(err) => console_log!("error send ping: {:?}", err),
-                };
-            }
-        }) as Box<dyn FnMut()>);
-        let interval_id = setInterval(&closure, 10_000);
-        self.interval_id = Some(Rc::new(RefCell::new(interval_id)));
-        closure.forget();
-    }
-
-    fn close_ping(&self, interval_id: i32) {
-        IntervalHandle {
-            interval_id: Some(interval_id),
-        };
+        let interval_id = set_interval(
+            move || {
+                if factory.is_paused() {
+                    return;
+                }
+                let ping = Ping { ping: "ping" };
+                let ping_data = serde_json::to_string(&ping).unwrap();
+                if let Some(websocket) = raw_websocket.clone() {
+                    let ping_message = factory.frame(WsMessage::Text(ping_data));
+                    match WsCore::send_message(&factory, &websocket.borrow(), &ping_message) {
+                        Ok(_) => (),
+                        Err(err) => console_log!("error send ping: {:?}", err),
+                    };
+                }
+            },
+            10_000,
+        );
+        self.stop();
+        self.interval_id = Some(interval_id);
     }
 
-    fn get_interval_id(&self) -> Option<Rc<RefCell<i32>>> {
-        self.interval_id.clone()
+    fn stop(&mut self) {
+        if let Some(id) = self.interval_id.take() {
+            clear_interval(id);
+        }
     }
 }
 
-struct IntervalHandle {
-    interval_id: Option<i32>,
-}
-
-impl Drop for IntervalHandle {
+impl Drop for Pinger {
     fn drop(&mut self) {
-        match self.interval_id {
-            Some(id) => {
-                let window = web_sys::window().unwrap();
-                window.clear_interval_with_handle(id);
-            }
-            None => {
-                console_log!("no drop id!!!");
-            }
-        }
+        self.stop();
     }
 }
@@ -1,8 +1,12 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use wasm_bindgen::prelude::*;
 use web_sys::{CloseEvent, ErrorEvent, MessageEvent};
 
+use crate::WsMessage;
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
@@ -15,8 +19,10 @@ macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 }
 
+#[derive(Clone)]
 pub enum Payload {
     Data(String),
+    Bytes(Vec<u8>),
     MessageEvent(MessageEvent),
     CloseEvent(CloseEvent),
     ErrorEvent(ErrorEvent),
@@ -31,6 +37,7 @@ impl fmt::Display for Payload {
         // is very similar to `println!`.
         match self {
             Payload::Data(val) => write!(f, "{}", val),
+            Payload::Bytes(bytes) => write!(f, "{:?}", bytes),
             Payload::MessageEvent(msg_evt) => write!(f, "{:?}", msg_evt),
             Payload::CloseEvent(close_evt) => write!(f, "{:?}", close_evt),
             Payload::ErrorEvent(err_evt) => write!(f, "{:?}", err_evt),
@@ -38,28 +45,146 @@ impl fmt::Display for Payload {
     }
 }
 
+// Payload of the catch-all `frame` event, text frames carry their data as a
+// string and binary frames as an array of bytes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FrameEvent {
+    pub frame_type: String,
+    pub byte_length: usize,
+    pub received_at: f64,
+    pub data: serde_json::Value,
+}
+
+impl FrameEvent {
+    pub fn new(message: &WsMessage, received_at: f64) -> Self {
+        let (frame_type, data) = match message {
+            WsMessage::Text(payload) => ("text", serde_json::Value::from(payload.as_str())),
+            WsMessage::Binary(payload) => ("binary", serde_json::Value::from(payload.as_slice())),
+        };
+        Self {
+            frame_type: String::from(frame_type),
+            byte_length: message.len(),
+            received_at,
+            data,
+        }
+    }
+}
+
+// Payload of the `validation_error` event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ValidationError {
+    pub event: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EventStats {
+    pub messages: u64,
+    pub bytes: u64,
+    // DOMHighResTimeStamp of the message events, ms since the page loaded
+    pub first_received_at: f64,
+    pub last_received_at: f64,
+}
+
+// Counted per routed event name, `bytes` is the size of the frame on the wire
+// before decoding.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RxStats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub events: HashMap<String, EventStats>,
+}
+
+impl RxStats {
+    pub(crate) fn record(&mut self, event: &str, bytes: usize, received_at: f64) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+        let stats = self
+            .events
+            .entry(String::from(event))
+            .or_insert_with(|| EventStats {
+                first_received_at: received_at,
+                ..EventStats::default()
+            });
+        stats.messages += 1;
+        stats.bytes += bytes as u64;
+        stats.last_received_at = received_at;
+    }
+}
+
 pub type Callback = Box<dyn Fn(&Payload) + 'static>;
+pub type Filter = Box<dyn Fn(&Payload) -> bool + 'static>;
+pub type EventValidator = Box<dyn Fn(&serde_json::Value) -> Result<(), String>>;
+pub type Router = Box<dyn Fn(&serde_json::Value) -> Option<(String, serde_json::Value)>>;
 
 pub struct Emitter {
     handlers: HashMap<String, Callback>,
+    filters: HashMap<String, Filter>,
+    filtered: RefCell<HashMap<String, u64>>,
+    // handlers that are removed again by whoever registered them, unless
+    // they were replaced in the meantime
+    owners: HashMap<String, u64>,
+    next_owner: u64,
 }
 
 impl Emitter {
     pub fn new() -> Self {
         Self {
             handlers: HashMap::new(),
+            filters: HashMap::new(),
+            filtered: RefCell::new(HashMap::new()),
+            owners: HashMap::new(),
+            next_owner: 0,
         }
     }
 
+    // Payloads the filter returns `false` for never reach the handler, they
+    // are only counted.
+    pub fn set_filter(&mut self, handler_name: String, filter: Filter) {
+        self.filters.insert(handler_name, filter);
+    }
+
+    pub fn remove_filter(&mut self, handler_name: &str) {
+        self.filters.remove(handler_name);
+    }
+
+    pub fn filtered_counts(&self) -> HashMap<String, u64> {
+        self.filtered.borrow().clone()
+    }
+
     pub fn on(&mut self, handler_name: String, handler: Callback) {
+        self.owners.remove(&handler_name);
         self.handlers.insert(handler_name, handler);
     }
 
     pub fn off(&mut self, handler_name: String) {
+        self.owners.remove(&handler_name);
         self.handlers.remove(&handler_name);
     }
 
+    // Returns the owner id to pass to `off_owned`.
+    pub fn on_owned(&mut self, handler_name: String, handler: Callback) -> u64 {
+        let owner = self.next_owner;
+        self.next_owner += 1;
+        self.handlers.insert(handler_name.clone(), handler);
+        self.owners.insert(handler_name, owner);
+        owner
+    }
+
+    pub fn off_owned(&mut self, handler_name: &str, owner: u64) {
+        if self.owners.get(handler_name) == Some(&owner) {
+            self.owners.remove(handler_name);
+            self.handlers.remove(handler_name);
+        }
+    }
+
     pub fn emit(&self, handler_name: String, payload: &Payload) {
+        if let Some(filter) = self.filters.get(&handler_name) {
+            if !filter(payload) {
+                *self.filtered.borrow_mut().entry(handler_name).or_insert(0) += 1;
+                return;
+            }
+        }
         match self.handlers.get(&handler_name) {
             Some(handler) => {
                 handler(payload);
@@ -72,3 +197,52 @@ impl Emitter {
         self.handlers.keys().cloned().collect()
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SubscriptionAction {
+    Subscribe,
+    Unsubscribe,
+}
+
+pub type SubscriptionFormat = Box<dyn Fn(SubscriptionAction, &str) -> WsMessage>;
+
+// Frames look like `{"subscribe": name}` and `{"unsubscribe": name}` unless the
+// factory was given another format.
+pub fn default_subscription_format(action: SubscriptionAction, name: &str) -> WsMessage {
+    let key = match action {
+        SubscriptionAction::Subscribe => "subscribe",
+        SubscriptionAction::Unsubscribe => "unsubscribe",
+    };
+    let mut frame = serde_json::Map::new();
+    frame.insert(String::from(key), serde_json::Value::from(name));
+    WsMessage::Text(serde_json::Value::Object(frame).to_string())
+}
+
+// Every emitter handler is subscribed on open, on top of that topics can be
+// subscribed without a handler or unsubscribed while keeping the handler.
+#[derive(Default)]
+pub struct Subscriptions {
+    explicit: HashSet<String>,
+    excluded: HashSet<String>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, name: &str) {
+        self.excluded.remove(name);
+        self.explicit.insert(String::from(name));
+    }
+
+    pub fn unsubscribe(&mut self, name: &str) {
+        self.explicit.remove(name);
+        self.excluded.insert(String::from(name));
+    }
+
+    pub fn names(&self, handler_names: Vec<String>) -> Vec<String> {
+        let mut names: Vec<String> = handler_names
+            .into_iter()
+            .filter(|name| !self.explicit.contains(name) && !self.excluded.contains(name))
+            .collect();
+        names.extend(self.explicit.iter().cloned());
+        names
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+
+use wasm_bindgen::{JsCast, JsValue};
+
+use crate::close::CloseCode;
+use crate::codec::CodecError;
+use crate::simple_rpc::RpcError;
+use crate::ReadyState;
+
+#[derive(Debug)]
+pub enum WsError {
+    Js(JsValue),
+    ConnectionFailed(String),
+    SendFailed(JsValue),
+    Closed { code: CloseCode, reason: String },
+    NotConnected { state: ReadyState },
+    RpcDisabled,
+    Rpc(RpcError),
+    Params(String),
+    QueueFull,
+    RateLimited,
+    AckTimeout,
+    Serde(String),
+    Codec(CodecError),
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::Js(value) => write!(f, "websocket error: {:?}", value),
+            WsError::ConnectionFailed(msg) => write!(f, "can't connect: {}", msg),
+            WsError::SendFailed(value) => write!(f, "can't send message: {:?}", value),
+            WsError::Closed { code, reason } => {
+                write!(f, "websocket closed with {}: {}", code, reason)
+            }
+            WsError::NotConnected { state } => {
+                write!(f, "websocket is not connected, ready state: {:?}", state)
+            }
+            WsError::RpcDisabled => write!(f, "rpc subscriber is not configured"),
+            WsError::Rpc(err) => write!(f, "rpc error: {}", err),
+            WsError::Params(msg) => write!(f, "invalid rpc params: {}", msg),
+            WsError::QueueFull => write!(f, "outgoing queue is full"),
+            WsError::RateLimited => write!(f, "outgoing rate limit exceeded"),
+            WsError::AckTimeout => write!(f, "message was not acknowledged in time"),
+            WsError::Serde(msg) => write!(f, "serde error: {}", msg),
+            WsError::Codec(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl WsError {
+    // Js exceptions are usually `Error` objects, their message is far more
+    // readable than the debug output of the value.
+    pub(crate) fn connection_failed(value: JsValue) -> Self {
+        let message = match value.dyn_ref::<js_sys::Error>() {
+            Some(err) => String::from(err.message()),
+            None => value.as_string().unwrap_or_else(|| format!("{:?}", value)),
+        };
+        WsError::ConnectionFailed(message)
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<JsValue> for WsError {
+    fn from(value: JsValue) -> Self {
+        WsError::Js(value)
+    }
+}
+
+impl From<RpcError> for WsError {
+    fn from(err: RpcError) -> Self {
+        WsError::Rpc(err)
+    }
+}
+
+impl From<serde_json::Error> for WsError {
+    fn from(err: serde_json::Error) -> Self {
+        WsError::Serde(err.to_string())
+    }
+}
+
+impl From<CodecError> for WsError {
+    fn from(err: CodecError) -> Self {
+        WsError::Codec(err)
+    }
+}
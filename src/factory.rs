@@ -1,45 +1,524 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
-use std::rc::Rc;
-use wasm_bindgen::{closure::Closure, JsValue};
-use web_sys::{CloseEvent, ErrorEvent, Event};
-
-use crate::core::WsCore;
-use crate::emitter::Emitter;
-use crate::simple_rpc::RPCSubscriber;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::rc::{Rc, Weak};
+
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::join_all;
+use jsonrpc_core::{MethodCall, Output};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{BinaryType, CloseEvent, ErrorEvent, Event, WebSocket};
+
+use crate::ack::{AckConfig, AckTracker};
+use crate::auth::{AuthHandshake, TokenFuture, TokenProvider};
+use crate::chunking::Chunking;
+use crate::close::DropBehavior;
+use crate::codec::{Codec, JsonCodec, Utf8Policy};
+#[cfg(feature = "flate2")]
+use crate::compression::{Compression, Decompression};
+use crate::connect::{ConnectFuture, ConnectState};
+use crate::core::{SocketHandlers, WsCore};
+use crate::emitter::{
+    default_subscription_format, Emitter, EventValidator, Payload, RxStats, SubscriptionAction,
+    SubscriptionFormat, Subscriptions,
+};
+use crate::error::WsError;
+use crate::fragments::FragmentBuffer;
+use crate::incremental::IncrementalReader;
+use crate::limits::{OversizePolicy, SizeLimit};
+use crate::outgoing::{
+    BackpressureConfig, BatchCombiner, OutboundInterceptor, OutgoingQueue, OverflowPolicy,
+    RateLimiter, TxStats, WireMode,
+};
+use crate::pause::PausedInbox;
+use crate::probe::EndpointProbe;
+#[cfg(feature = "prost")]
+use crate::protobuf::{envelope_router, ProtobufRouter};
+use crate::registry;
+use crate::reorder::ReorderBuffer;
+use crate::resume::ResumeTracker;
+use crate::schedule::Scheduler;
+use crate::simple_rpc::{IdGenerator, RPCSubscriber, RpcCodec, RpcRequestHandle};
+use crate::sockjs::SockJs;
+use crate::stats::ConnectionStats;
+use crate::stream::{ConnectionState, Inbox};
+use crate::timers::clear_timeout;
+use crate::url::{is_secure_page, upgrade_to_tls, UrlBuilder};
 use crate::{Websocket, WsMessage};
 
+pub type SocketFactoryFn = Box<dyn Fn(&str) -> Result<WebSocket, JsValue>>;
+// Called with the decode error and the payload that failed.
+pub type ParseErrorCallback = Rc<RefCell<dyn FnMut(String, String)>>;
+
 pub struct WsFactory {
     pub url: Rc<Cow<'static, str>>,
+    pub url_builder: UrlBuilder,
+    pub existing: Option<WebSocket>,
+    pub drop_behavior: DropBehavior,
+    pub shared: bool,
+    pub probe: Option<Rc<EndpointProbe>>,
+    pub endpoint: RefCell<Option<String>>,
+    pub socket_factory: Option<SocketFactoryFn>,
+    pub require_tls: bool,
+    pub upgrade_insecure: bool,
     pub on_message: Option<Rc<RefCell<dyn FnMut(WsMessage)>>>,
-    pub on_open: Option<Rc<RefCell<dyn FnMut(Event)>>>,
-    pub on_error: Option<Rc<RefCell<dyn FnMut(ErrorEvent)>>>,
-    pub on_close: Option<Rc<RefCell<dyn FnMut(CloseEvent)>>>,
+    pub raw_passthrough: bool,
+    pub heartbeat: bool,
+    pub auto_subscribe: bool,
+    pub frame_events: bool,
+    pub on_open: RefCell<Option<Rc<RefCell<dyn FnMut(Event)>>>>,
+    pub on_error: RefCell<Option<Rc<RefCell<dyn FnMut(ErrorEvent)>>>>,
+    pub connect: Option<Rc<RefCell<ConnectState>>>,
+    pub auth: Option<AuthHandshake>,
+    pub sockjs: Option<Rc<SockJs>>,
+    pub token_provider: Option<TokenProvider>,
+    pub token: Rc<RefCell<Option<String>>>,
+    pub connect_timeout: Option<u32>,
+    pub on_close: RefCell<Option<Rc<RefCell<dyn FnMut(CloseEvent)>>>>,
+    pub on_parse_error: Option<ParseErrorCallback>,
     pub reconnect: Option<Rc<RefCell<ReconnectConfig>>>,
     pub is_closing: Rc<RefCell<bool>>,
+    pub outgoing: Rc<RefCell<OutgoingQueue>>,
+    pub queue_offline: bool,
+    pub backpressure: Option<Rc<BackpressureConfig>>,
+    pub chunking: Option<Rc<RefCell<Chunking>>>,
+    pub reorder: Option<Rc<RefCell<ReorderBuffer>>>,
+    pub resume: Option<RefCell<ResumeTracker>>,
+    pub fragments: Option<RefCell<FragmentBuffer>>,
+    pub incremental: Option<Rc<RefCell<IncrementalReader>>>,
+    pub size_limit: Option<SizeLimit>,
+    pub paused: RefCell<Option<PausedInbox>>,
+    #[cfg(feature = "flate2")]
+    pub compression: Option<Rc<Compression>>,
+    #[cfg(feature = "flate2")]
+    pub decompress_incoming: Option<Decompression>,
+    pub rate_limiter: Option<Rc<RefCell<RateLimiter>>>,
+    pub outbound_interceptors: Vec<OutboundInterceptor>,
+    pub batch_combiner: Option<BatchCombiner>,
+    pub scheduler: Scheduler,
+    pub wire_mode: WireMode,
+    pub binary_type: BinaryType,
+    pub received_binary: Cell<bool>,
+    pub rx_stats: RefCell<RxStats>,
+    pub connection_stats: RefCell<ConnectionStats>,
+    pub(crate) handlers: RefCell<SocketHandlers>,
+    pub(crate) released: Cell<bool>,
+    // the timeout that drops the handlers after the close event
+    pub(crate) release_timer: Cell<Option<i32>>,
+    pub(crate) intervals: RefCell<Vec<i32>>,
+    pub(crate) receivers: RefCell<Vec<Weak<RefCell<Inbox>>>>,
+    pub(crate) state_senders: RefCell<Vec<UnboundedSender<ConnectionState>>>,
+    pub ack: Rc<RefCell<AckTracker>>,
     pub emitter: Option<Rc<RefCell<Emitter>>>,
+    pub json_codec: JsonCodec,
+    pub event_validators: HashMap<String, EventValidator>,
+    pub codec: Option<Box<dyn Codec>>,
+    #[cfg(feature = "prost")]
+    pub protobuf_router: Option<ProtobufRouter>,
+    pub subscriptions: RefCell<Subscriptions>,
+    pub subscription_format: SubscriptionFormat,
     pub rpc_subscriber: Option<Rc<RefCell<RPCSubscriber>>>,
+    pub rpc_stats_interval: Option<u32>,
+    pub tx_stats: RefCell<TxStats>,
+    pub tx_stats_interval: Option<u32>,
 }
 
 impl WsFactory {
     pub(crate) fn new(url: Cow<'static, str>) -> Self {
         Self {
             url: Rc::new(url),
+            url_builder: UrlBuilder::default(),
+            existing: None,
+            drop_behavior: DropBehavior::default(),
+            shared: false,
+            probe: None,
+            endpoint: RefCell::new(None),
+            socket_factory: None,
+            require_tls: false,
+            upgrade_insecure: false,
             on_message: None,
-            on_open: None,
-            on_error: None,
-            on_close: None,
+            raw_passthrough: false,
+            heartbeat: true,
+            auto_subscribe: true,
+            frame_events: false,
+            on_open: RefCell::new(None),
+            on_error: RefCell::new(None),
+            connect: None,
+            auth: None,
+            sockjs: None,
+            token_provider: None,
+            token: Rc::new(RefCell::new(None)),
+            connect_timeout: None,
+            on_close: RefCell::new(None),
+            on_parse_error: None,
             reconnect: Some(Rc::new(RefCell::new(ReconnectConfig::default()))),
             is_closing: Rc::new(RefCell::new(false)),
+            outgoing: Rc::new(RefCell::new(OutgoingQueue::new())),
+            queue_offline: true,
+            backpressure: None,
+            chunking: None,
+            reorder: None,
+            resume: None,
+            fragments: None,
+            incremental: None,
+            size_limit: None,
+            paused: RefCell::new(None),
+            #[cfg(feature = "flate2")]
+            compression: None,
+            #[cfg(feature = "flate2")]
+            decompress_incoming: None,
+            rate_limiter: None,
+            outbound_interceptors: Vec::new(),
+            batch_combiner: None,
+            scheduler: Scheduler::new(),
+            wire_mode: WireMode::default(),
+            binary_type: BinaryType::Arraybuffer,
+            received_binary: Cell::new(false),
+            rx_stats: RefCell::new(RxStats::default()),
+            connection_stats: RefCell::new(ConnectionStats::default()),
+            handlers: RefCell::new(SocketHandlers::default()),
+            released: Cell::new(false),
+            release_timer: Cell::new(None),
+            intervals: RefCell::new(Vec::new()),
+            receivers: RefCell::new(Vec::new()),
+            state_senders: RefCell::new(Vec::new()),
+            ack: Rc::new(RefCell::new(AckTracker::new(AckConfig::default()))),
             emitter: Some(Rc::new(RefCell::new(Emitter::new()))),
+            json_codec: JsonCodec::new(),
+            event_validators: HashMap::new(),
+            codec: None,
+            #[cfg(feature = "prost")]
+            protobuf_router: None,
+            subscriptions: RefCell::new(Subscriptions::default()),
+            subscription_format: Box::new(default_subscription_format),
             rpc_subscriber: Some(Rc::new(RefCell::new(RPCSubscriber::new()))),
+            rpc_stats_interval: None,
+            tx_stats: RefCell::new(TxStats::default()),
+            tx_stats_interval: None,
         }
     }
 
-    pub fn build(self) -> Result<Websocket, JsValue> {
-        let websocket_ref = Rc::new(RefCell::new(WsCore::build_new_websocket(&self.url)?));
+    // Wraps a socket created elsewhere, reconnects open new sockets to the
+    // url it was created with.
+    pub fn from_existing(websocket: WebSocket) -> Self {
+        let mut factory = Self::new(Cow::Owned(websocket.url()));
+        factory.existing = Some(websocket);
+        factory
+    }
+
+    pub fn build(mut self) -> Result<Websocket, WsError> {
+        if let Some(websocket) = self.lookup_shared() {
+            return Ok(websocket);
+        }
+        let websocket_ref = Rc::new(RefCell::new(self.first_socket()?));
+        let shared = self.shared_key();
         let core = WsCore::new(self, websocket_ref);
-        Ok(Websocket::new(core))
+        let websocket = Websocket::new(core);
+        if let Some(url) = shared {
+            registry::register(&url, &websocket);
+        }
+        Ok(websocket)
+    }
+
+    // Resolves with the socket on its first open event, and fails on an error
+    // or close event before that, or once `connect_timeout` passed.
+    // With `probe_endpoints` the candidates are probed first.
+    pub fn build_async(self) -> ConnectFuture {
+        if let Some(websocket) = self.lookup_shared() {
+            return ConnectFuture::ready(websocket);
+        }
+        if self.probe.is_none() || self.existing.is_some() {
+            return self.connect_async();
+        }
+        ConnectFuture::deferred(Box::pin(async move {
+            self.select_endpoint().await;
+            self.connect_async().await
+        }))
+    }
+
+    fn connect_async(mut self) -> ConnectFuture {
+        let websocket = match self.first_socket() {
+            Ok(websocket) => websocket,
+            Err(err) => return ConnectFuture::failed(err),
+        };
+        let state = Rc::new(RefCell::new(ConnectState::default()));
+        self.connect = Some(state.clone());
+        let timeout = self.connect_timeout;
+        let shared = self.shared_key();
+        let core = WsCore::new(self, Rc::new(RefCell::new(websocket)));
+        let websocket = Websocket::new(core);
+        if let Some(url) = shared {
+            registry::register(&url, &websocket);
+        }
+        ConnectFuture::new(websocket, state, timeout)
+    }
+
+    fn shared_key(&self) -> Option<String> {
+        if self.shared && self.existing.is_none() {
+            Some(self.url.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn lookup_shared(&self) -> Option<Websocket> {
+        registry::lookup(&self.shared_key()?)
+    }
+
+    // Appended to the url path, percent-encoded.
+    pub fn path_segment(mut self, segment: &str) -> Self {
+        self.url_builder.path_segment(segment);
+        self
+    }
+
+    pub fn query_param(mut self, key: &str, value: &str) -> Self {
+        self.url_builder.query_param(key, value);
+        self
+    }
+
+    // The value is read again for every reconnect attempt.
+    pub fn query_param_fn(mut self, key: &str, f: impl Fn() -> String + 'static) -> Self {
+        self.url_builder.query_param_fn(key, f);
+        self
+    }
+
+    // `build` hands out another handle to the live connection built for the
+    // same url with `shared`, the rest of this factory is ignored then. The
+    // connection closes once its last handle is dropped.
+    pub fn shared(mut self) -> Self {
+        self.shared = true;
+        self
+    }
+
+    // Probes the factory url and the candidates before the first connect of
+    // `build_async` and before every reconnect, and connects to the first
+    // healthy one with an `endpoint_selected` event. `build` connects to the
+    // factory url right away.
+    pub fn probe_endpoints(mut self, probe: EndpointProbe) -> Self {
+        self.probe = Some(Rc::new(probe));
+        self
+    }
+
+    // Closes with 1000 and no reason by default.
+    pub fn on_drop(mut self, drop_behavior: DropBehavior) -> Self {
+        self.drop_behavior = drop_behavior;
+        self
+    }
+
+    // Creates every socket instead of `new WebSocket(url)`, reconnects and
+    // endpoint probes included, e.g. to hand out instrumented sockets in tests.
+    pub fn socket_factory(
+        mut self,
+        f: impl Fn(&str) -> Result<WebSocket, JsValue> + 'static,
+    ) -> Self {
+        self.socket_factory = Some(Box::new(f));
+        self
+    }
+
+    // Non `wss://` urls fail to build.
+    pub fn require_tls(mut self) -> Self {
+        self.require_tls = true;
+        self
+    }
+
+    // `ws://` urls are switched to `wss://` when the page itself was served
+    // over https, where browsers block plain sockets as mixed content.
+    pub fn upgrade_insecure(mut self) -> Self {
+        self.upgrade_insecure = true;
+        self
+    }
+
+    // Connects to the first healthy candidate from then on, the current
+    // endpoint is kept when none is healthy.
+    pub(crate) async fn select_endpoint(&self) {
+        let probe = match self.probe.clone() {
+            Some(probe) => probe,
+            None => return,
+        };
+        let mut candidates = vec![self.url.to_string()];
+        candidates.extend(probe.endpoints().iter().cloned());
+        let connect_urls: Vec<String> = candidates
+            .iter()
+            .map(|endpoint| self.connect_url_for(endpoint))
+            .collect();
+        // probed in parallel, a dead endpoint costs one timeout in total
+        let healthy = join_all(
+            candidates
+                .iter()
+                .zip(connect_urls.iter())
+                .map(|(endpoint, connect_url)| probe.is_healthy(self, endpoint, connect_url)),
+        )
+        .await;
+        let selected = candidates
+            .into_iter()
+            .zip(healthy)
+            .find(|(_, healthy)| *healthy);
+        if let Some((endpoint, _)) = selected {
+            if let Some(emitter) = self.emitter.clone() {
+                emitter.borrow().emit(
+                    String::from("endpoint_selected"),
+                    &Payload::Data(endpoint.clone()),
+                );
+            }
+            *self.endpoint.borrow_mut() = Some(endpoint);
+        }
+    }
+
+    pub(crate) fn connect_url(&self) -> String {
+        match self.endpoint.borrow().as_deref() {
+            Some(endpoint) => self.connect_url_for(endpoint),
+            None => self.connect_url_for(&self.url),
+        }
+    }
+
+    fn connect_url_for(&self, base: &str) -> String {
+        let url = self.url_builder.build(base);
+        if self.upgrade_insecure && is_secure_page() {
+            return upgrade_to_tls(url);
+        }
+        url
+    }
+
+    // Receivers that were dropped are pruned on the way.
+    pub(crate) fn deliver_to_receivers(&self, message: &WsMessage) {
+        let mut receivers = self.receivers.borrow_mut();
+        if receivers.is_empty() {
+            return;
+        }
+        receivers.retain(|inbox| match inbox.upgrade() {
+            Some(inbox) => {
+                inbox.borrow_mut().push(message.clone());
+                true
+            }
+            None => false,
+        });
+    }
+
+    pub(crate) fn notify_state(&self, state: ConnectionState) {
+        self.state_senders
+            .borrow_mut()
+            .retain(|sender| sender.unbounded_send(state.clone()).is_ok());
+    }
+
+    // Ends the receivers and state streams once no reconnect follows.
+    pub(crate) fn close_streams(&self) {
+        for inbox in self.receivers.borrow_mut().drain(..) {
+            if let Some(inbox) = inbox.upgrade() {
+                inbox.borrow_mut().close();
+            }
+        }
+        self.state_senders.borrow_mut().clear();
+    }
+
+    fn first_socket(&mut self) -> Result<WebSocket, WsError> {
+        match self.existing.take() {
+            Some(websocket) => Ok(websocket),
+            None => self.open_socket(),
+        }
+    }
+
+    pub(crate) fn open_socket(&self) -> Result<WebSocket, WsError> {
+        let url = self.connect_url();
+        if self.require_tls && !url.starts_with("wss://") {
+            return Err(WsError::ConnectionFailed(format!(
+                "{} is not a wss:// url",
+                url
+            )));
+        }
+        self.new_socket(&url)
+    }
+
+    pub(crate) fn new_socket(&self, url: &str) -> Result<WebSocket, WsError> {
+        match self.socket_factory.as_ref() {
+            Some(socket_factory) => socket_factory(url).map_err(WsError::connection_failed),
+            None => WsCore::build_new_websocket(url),
+        }
+    }
+
+    // `login` builds the frame sent after every open, `reply` recognises the
+    // server answer. `open` is only emitted, and queued frames and
+    // subscriptions only sent, once the login was accepted. A rejection emits
+    // `auth_failed` and closes the socket without reconnecting.
+    pub fn auth_handshake(
+        mut self,
+        login: impl Fn(Option<&str>) -> WsMessage + 'static,
+        reply: impl Fn(&WsMessage) -> Option<Result<(), String>> + 'static,
+    ) -> Self {
+        self.auth = Some(AuthHandshake::new(login, reply));
+        self
+    }
+
+    // Connects to a SockJS endpoint, the factory url is its prefix with a ws
+    // or wss scheme. Every connect attempt appends a fresh
+    // `/<server-id>/<session-id>/websocket` behind all path segments, the
+    // ones added after this call too. Frames are unwrapped before the rest of
+    // the pipeline sees them and the socket counts as open with the `o` frame,
+    // the code and reason of a `c` frame are reported as those of the close.
+    // The crate heartbeat is turned off, the server sends its own.
+    pub fn sockjs(mut self) -> Self {
+        self.url_builder.suffix_segment_fn(SockJs::server_id);
+        self.url_builder.suffix_segment_fn(SockJs::session_id);
+        self.url_builder.suffix_segment("websocket");
+        self.sockjs = Some(Rc::new(SockJs::new()));
+        self.heartbeat = false;
+        self
+    }
+
+    // Awaited before every reconnect attempt, the token is handed to the
+    // login of the auth handshake and, with `token_query_param`, added to the
+    // url. A failure emits `token_error` and the attempt is retried later.
+    pub fn token_provider<F, Fut, E>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = Result<String, E>> + 'static,
+        E: fmt::Display + 'static,
+    {
+        self.token_provider = Some(Rc::new(move || -> TokenFuture {
+            let token = f();
+            Box::pin(async move { token.await.map_err(|err| err.to_string()) })
+        }));
+        self
+    }
+
+    // Sets the token used until the provider delivers a fresh one.
+    pub fn token(self, token: &str) -> Self {
+        *self.token.borrow_mut() = Some(String::from(token));
+        self
+    }
+
+    pub fn token_query_param(mut self, key: &str) -> Self {
+        let token = self.token.clone();
+        self.url_builder
+            .query_param_opt(key, move || token.borrow().clone());
+        self
+    }
+
+    // Released, or closed without a reconnect to come.
+    pub(crate) fn is_terminated(&self, websocket: &WebSocket) -> bool {
+        self.released.get()
+            || (websocket.ready_state() == WebSocket::CLOSED
+                && (self.reconnect.is_none() || *self.is_closing.borrow()))
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.borrow().is_some()
+    }
+
+    // Sends wait in the queue until the handshakes of the socket are done.
+    pub(crate) fn is_authenticating(&self) -> bool {
+        self.auth.as_ref().is_some_and(|auth| auth.is_pending())
+            || self
+                .sockjs
+                .as_ref()
+                .is_some_and(|sockjs| sockjs.is_pending())
+    }
+
+    pub fn connect_timeout(mut self, timeout: u32) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
     }
 
     pub fn on_message(mut self, f: impl FnMut(WsMessage) + 'static) -> Self {
@@ -47,18 +526,73 @@ impl WsFactory {
         self
     }
 
+    // Frames only go to the `on_message` callback, json routing, rpc and the
+    // emitter are bypassed for apps with their own framing.
+    pub fn raw_passthrough(mut self) -> Self {
+        self.raw_passthrough = true;
+        self
+    }
+
+    // Every incoming frame is also emitted as a `frame` event carrying a
+    // `FrameEvent`, next to the event it is routed to.
+    pub fn frame_events(mut self) -> Self {
+        self.frame_events = true;
+        self
+    }
+
     pub fn on_open(mut self, f: impl FnMut(Event) + 'static) -> Self {
-        self.on_open = Some(Rc::new(RefCell::new(f)));
+        *self.on_open.get_mut() = Some(Rc::new(RefCell::new(f)));
         self
     }
 
     pub fn on_error(mut self, f: impl FnMut(ErrorEvent) + 'static) -> Self {
-        self.on_error = Some(Rc::new(RefCell::new(f)));
+        *self.on_error.get_mut() = Some(Rc::new(RefCell::new(f)));
         self
     }
 
     pub fn on_close(mut self, f: impl FnMut(CloseEvent) + 'static) -> Self {
-        self.on_close = Some(Rc::new(RefCell::new(f)));
+        *self.on_close.get_mut() = Some(Rc::new(RefCell::new(f)));
+        self
+    }
+
+    // The async variants spawn the returned future with `spawn_local` for
+    // every event, the open sequence doesn't wait for it to finish.
+    pub fn on_message_async<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(WsMessage) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.on_message(move |message| spawn_local(f(message)))
+    }
+
+    pub fn on_open_async<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(Event) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.on_open(move |event| spawn_local(f(event)))
+    }
+
+    pub fn on_error_async<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(ErrorEvent) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.on_error(move |event| spawn_local(f(event)))
+    }
+
+    pub fn on_close_async<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(CloseEvent) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.on_close(move |event| spawn_local(f(event)))
+    }
+
+    // Gets the parse error and the raw payload of every incoming frame that
+    // could not be decoded.
+    pub fn on_parse_error(mut self, f: impl FnMut(String, String) + 'static) -> Self {
+        self.on_parse_error = Some(Rc::new(RefCell::new(f)));
         self
     }
 
@@ -71,12 +605,394 @@ impl WsFactory {
         self.reconnect = None;
         self
     }
+
+    pub fn backpressure(mut self, cfg: BackpressureConfig) -> Self {
+        self.backpressure = Some(Rc::new(cfg));
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(Rc::new(RefCell::new(rate_limiter)));
+        self
+    }
+
+    // Interceptors run in the order they were added, returning `None` drops
+    // the frame without sending it.
+    pub fn outbound_interceptor(
+        mut self,
+        f: impl Fn(WsMessage) -> Option<WsMessage> + 'static,
+    ) -> Self {
+        self.outbound_interceptors.push(Box::new(f));
+        self
+    }
+
+    pub fn wire_mode(mut self, wire_mode: WireMode) -> Self {
+        self.wire_mode = wire_mode;
+        self
+    }
+
+    // Applied to every socket, reconnects included. `Blob` frames go through a
+    // FileReader before they can be processed.
+    pub fn binary_type(mut self, binary_type: BinaryType) -> Self {
+        self.binary_type = binary_type;
+        self
+    }
+
+    pub(crate) fn frame(&self, message: WsMessage) -> WsMessage {
+        self.wire_mode.frame(message, self.received_binary.get())
+    }
+
+    // Lets `send_all` wrap a burst of messages into the single batch frame
+    // the server expects.
+    pub fn batch_combiner(mut self, f: impl Fn(Vec<WsMessage>) -> WsMessage + 'static) -> Self {
+        self.batch_combiner = Some(Box::new(f));
+        self
+    }
+
+    // Messages of `event` that fail the validator go to the `validation_error`
+    // event instead of the handler.
+    pub fn validate_event(
+        mut self,
+        event: &str,
+        f: impl Fn(&serde_json::Value) -> Result<(), String> + 'static,
+    ) -> Self {
+        self.event_validators
+            .insert(String::from(event), Box::new(f));
+        self
+    }
+
+    // Replaces the json routing for incoming frames, the router and plain text
+    // options only apply to the default json codec.
+    pub fn codec(mut self, codec: impl Codec + 'static) -> Self {
+        self.codec = Some(Box::new(codec));
+        self
+    }
+
+    // Binary frames are decoded as the envelope `E`, `route` maps it to the
+    // event name and the encoded inner message for `add_protobuf_listener`.
+    #[cfg(feature = "prost")]
+    pub fn protobuf_envelope<E>(
+        mut self,
+        route: impl Fn(E) -> Option<(String, Vec<u8>)> + 'static,
+    ) -> Self
+    where
+        E: prost::Message + Default,
+    {
+        self.protobuf_router = Some(envelope_router(route));
+        self
+    }
+
+    pub(crate) fn active_codec(&self) -> &dyn Codec {
+        match self.codec.as_ref() {
+            Some(codec) => codec.as_ref(),
+            None => &self.json_codec,
+        }
+    }
+
+    // Text that is not a json object, like csv lines or a bare "pong", goes to
+    // the `message` handler verbatim instead of being reported as an error.
+    pub fn plain_text_messages(mut self) -> Self {
+        self.json_codec.set_plain_text(true);
+        self
+    }
+
+    // Binary frames with invalid utf8 are reported as parse errors by
+    // default, `Lossy` decodes them anyway and `Binary` hands them to the
+    // `binary` handler as raw bytes.
+    pub fn utf8_policy(mut self, utf8: Utf8Policy) -> Self {
+        self.json_codec.set_utf8_policy(utf8);
+        self
+    }
+
+    // Incoming binary frames are decoded as MessagePack maps, and events sent
+    // with `send_event` go out as MessagePack.
+    #[cfg(feature = "rmp-serde")]
+    pub fn msgpack_messages(mut self) -> Self {
+        self.json_codec.set_msgpack(true);
+        self
+    }
+
+    // The router picks the handler name and the payload handed to it for
+    // every incoming json frame, `None` sends the frame to `message`.
+    pub fn route_by(
+        mut self,
+        f: impl Fn(&serde_json::Value) -> Option<(String, serde_json::Value)> + 'static,
+    ) -> Self {
+        self.json_codec.set_router(Box::new(f));
+        self
+    }
+
+    // Routes incoming objects by the string value of this field, e.g. `type`,
+    // and hands the whole object to the handler.
+    pub fn routing_field(self, routing_field: &str) -> Self {
+        let routing_field = String::from(routing_field);
+        self.route_by(move |value| {
+            let handler_name = value.get(routing_field.as_str())?.as_str()?;
+            Some((String::from(handler_name), value.clone()))
+        })
+    }
+
+    pub fn subscription_format(
+        mut self,
+        f: impl Fn(SubscriptionAction, &str) -> WsMessage + 'static,
+    ) -> Self {
+        self.subscription_format = Box::new(f);
+        self
+    }
+
+    pub fn ack(mut self, config: AckConfig) -> Self {
+        self.ack = Rc::new(RefCell::new(AckTracker::new(config)));
+        self
+    }
+
+    // Partial messages are dropped when they are not complete after `timeout`
+    // ms.
+    pub fn reassemble_fragments(self, timeout: u32) -> Self {
+        self.reassemble_fragments_with(FragmentBuffer::new(timeout))
+    }
+
+    // Reassembly with other limits than the defaults.
+    pub fn reassemble_fragments_with(mut self, fragments: FragmentBuffer) -> Self {
+        self.fragments = Some(RefCell::new(fragments));
+        self
+    }
+
+    // Frames above `max_size` bytes are dropped, or truncated, before they are
+    // decoded and reported with a `message_too_large` event. Messages put
+    // together from chunks or fragments and inflated frames are held to the
+    // same limit.
+    pub fn max_message_size(mut self, max_size: usize, policy: OversizePolicy) -> Self {
+        self.size_limit = Some(SizeLimit { max_size, policy });
+        self
+    }
+
+    // Binary frames above `threshold` bytes are read `slice_size` bytes per
+    // macrotask, text frames above `threshold` utf16 units are decoded
+    // `slice_size` units per macrotask. Without it every frame is processed
+    // synchronously, which is what latency sensitive feeds want.
+    pub fn incremental(mut self, threshold: usize, slice_size: usize) -> Self {
+        self.incremental = Some(Rc::new(RefCell::new(IncrementalReader::new(
+            threshold, slice_size,
+        ))));
+        self
+    }
+
+    pub fn reorder(mut self, reorder: ReorderBuffer) -> Self {
+        self.reorder = Some(Rc::new(RefCell::new(reorder)));
+        self
+    }
+
+    // After every reconnect the resume frame goes out first, before the
+    // resubscribes and the queued messages.
+    pub fn resume(mut self, resume: ResumeTracker) -> Self {
+        self.resume = Some(RefCell::new(resume));
+        self
+    }
+
+    pub fn chunking(self, max_chunk_size: usize) -> Self {
+        self.chunking_with(Chunking::new(max_chunk_size))
+    }
+
+    // Chunking with other reassembly limits than the defaults.
+    pub fn chunking_with(mut self, chunking: Chunking) -> Self {
+        self.chunking = Some(Rc::new(RefCell::new(chunking)));
+        self
+    }
+
+    #[cfg(feature = "flate2")]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(Rc::new(compression));
+        self
+    }
+
+    // Binary frames compressed by the server are inflated before routing,
+    // which frames those are is part of the config.
+    #[cfg(feature = "flate2")]
+    pub fn decompress_incoming(mut self, decompression: Decompression) -> Self {
+        self.decompress_incoming = Some(decompression);
+        self
+    }
+
+    // Stops the `{"ping": "ping"}` frame sent on open and every 10s, for
+    // servers that close on frames they don't know.
+    pub fn no_heartbeat(mut self) -> Self {
+        self.heartbeat = false;
+        self
+    }
+
+    // Listeners are no longer subscribed on the server on open, only the
+    // names passed to `subscribe` are. For protocols with subscriptions of
+    // their own, where the frame would be garbage to the server.
+    pub fn no_auto_subscribe(mut self) -> Self {
+        self.auto_subscribe = false;
+        self
+    }
+
+    pub fn no_offline_queue(mut self) -> Self {
+        self.queue_offline = false;
+        self
+    }
+
+    pub fn outgoing_queue(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.outgoing = Rc::new(RefCell::new(OutgoingQueue::bounded(capacity, policy)));
+        self
+    }
+
+    pub fn rpc_codec(self, codec: RpcCodec) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber.borrow_mut().set_codec(codec);
+        }
+        self
+    }
+
+    pub fn rpc_stats_interval(mut self, interval: u32) -> Self {
+        self.rpc_stats_interval = Some(interval);
+        self
+    }
+
+    pub fn tx_stats_interval(mut self, interval: u32) -> Self {
+        self.tx_stats_interval = Some(interval);
+        self
+    }
+
+    pub(crate) fn tx_stats(&self) -> TxStats {
+        let mut stats = self.tx_stats.borrow().clone();
+        let outgoing = self.outgoing.borrow();
+        stats.queued = outgoing.len();
+        stats.dropped = outgoing.dropped();
+        stats
+    }
+
+    pub(crate) fn connection_stats(&self) -> ConnectionStats {
+        let mut stats = self.connection_stats.borrow().snapshot(js_sys::Date::now());
+        let tx_stats = self.tx_stats.borrow();
+        stats.messages_sent = tx_stats.text_messages + tx_stats.binary_messages;
+        stats.bytes_sent = tx_stats.text_bytes + tx_stats.binary_bytes;
+        let rx_stats = self.rx_stats.borrow();
+        stats.messages_received = rx_stats.messages;
+        stats.bytes_received = rx_stats.bytes;
+        stats
+    }
+
+    pub fn rpc_progress_key(self, progress_key: &str) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_progress_key(String::from(progress_key));
+        }
+        self
+    }
+
+    // The params field of subscription notifications that holds the
+    // subscription id, `subscription` by default.
+    pub fn rpc_subscription_key(self, subscription_key: &str) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_subscription_key(String::from(subscription_key));
+        }
+        self
+    }
+
+    pub fn rpc_method_prefix(self, prefix: &str) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_method_prefix(String::from(prefix));
+        }
+        self
+    }
+
+    pub fn rpc_id_generator(self, id_generator: impl IdGenerator + 'static) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_id_generator(Box::new(id_generator));
+        }
+        self
+    }
+
+    pub fn rpc_method_handler(
+        self,
+        method: &str,
+        f: impl Fn(&RpcRequestHandle, String) + 'static,
+    ) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_method_handler(String::from(method), Box::new(f));
+        }
+        self
+    }
+
+    pub fn rpc_method_error_handler(
+        self,
+        method: &str,
+        f: impl Fn(&RpcRequestHandle, String) + 'static,
+    ) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_method_error_handler(String::from(method), Box::new(f));
+        }
+        self
+    }
+
+    pub fn rpc_fallback_handler(self, f: impl Fn(&RpcRequestHandle, String) + 'static) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_fallback_handler(Box::new(f));
+        }
+        self
+    }
+
+    pub fn rpc_fallback_error_handler(
+        self,
+        f: impl Fn(&RpcRequestHandle, String) + 'static,
+    ) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_fallback_error_handler(Box::new(f));
+        }
+        self
+    }
+
+    pub fn rpc_validator(
+        self,
+        f: impl Fn(&RpcRequestHandle, &Output) -> Result<(), String> + 'static,
+    ) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber.borrow_mut().set_validator(Box::new(f));
+        }
+        self
+    }
+
+    pub fn rpc_outgoing_interceptor(self, f: impl Fn(&mut MethodCall) + 'static) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .add_outgoing_interceptor(Box::new(f));
+        }
+        self
+    }
+
+    pub fn rpc_incoming_interceptor(self, f: impl Fn(&mut Output) + 'static) -> Self {
+        if let Some(rpc_subscriber) = self.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .add_incoming_interceptor(Box::new(f));
+        }
+        self
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ReconnectConfig {
     is_reconnecting: bool,
-    retry_closure: Rc<RefCell<Option<Closure<dyn FnMut() + 'static>>>>,
+    // the pending retry timeout, cleared when another one replaces it
+    retry_timeout: Option<i32>,
 }
 
 impl ReconnectConfig {
@@ -92,17 +1008,18 @@ impl ReconnectConfig {
         self.is_reconnecting = false;
     }
 
-    pub fn set_retry_cb(&self, cb: Closure<dyn FnMut() + 'static>) {
-        self.retry_closure.borrow_mut().replace(cb);
+    pub(crate) fn set_retry(&mut self, id: i32) {
+        self.cancel_retry();
+        self.retry_timeout = Some(id);
+    }
+
+    pub(crate) fn retry_fired(&mut self) {
+        self.retry_timeout = None;
     }
-}
 
-impl Default for ReconnectConfig {
-    fn default() -> Self {
-        let retry_closure = Rc::new(RefCell::new(None));
-        ReconnectConfig {
-            is_reconnecting: false,
-            retry_closure,
+    pub(crate) fn cancel_retry(&mut self) {
+        if let Some(id) = self.retry_timeout.take() {
+            clear_timeout(id);
         }
     }
 }
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::timers::clear_timeout;
+use crate::WsMessage;
+
+pub enum Fragment {
+    // not a fragment envelope, the frame is routed as it is
+    Whole(WsMessage),
+    Pending,
+    Complete(String),
+}
+
+struct PartialPayload {
+    parts: Vec<Option<String>>,
+    received: usize,
+    started_at: f64,
+}
+
+// Reassembles logical messages split by the server into
+// `{"id": ..., "part": i, "total": n, "data": "..."}` frames, the `data`
+// strings are concatenated in part order. Messages still incomplete after
+// `timeout` ms are dropped, as are messages of more than `max_parts` parts.
+// At most `max_pending` messages are buffered, the oldest one makes room.
+pub const DEFAULT_MAX_PARTS: usize = 1024;
+pub const DEFAULT_MAX_PENDING: usize = 16;
+
+pub struct FragmentBuffer {
+    timeout: f64,
+    max_parts: usize,
+    max_pending: usize,
+    partial: HashMap<String, PartialPayload>,
+    pub(crate) expiry_timer: Option<i32>,
+}
+
+impl FragmentBuffer {
+    pub fn new(timeout: u32) -> Self {
+        Self {
+            timeout: f64::from(timeout),
+            max_parts: DEFAULT_MAX_PARTS,
+            max_pending: DEFAULT_MAX_PENDING,
+            partial: HashMap::new(),
+            expiry_timer: None,
+        }
+    }
+
+    pub fn max_parts(mut self, max_parts: usize) -> Self {
+        self.max_parts = max_parts.max(1);
+        self
+    }
+
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    // `now` is the receive time in ms, it drives the expiry.
+    pub fn push(&mut self, message: WsMessage, now: f64) -> Fragment {
+        self.expire(now);
+        let value: Value = match &message {
+            WsMessage::Text(payload) if payload.contains("\"part\"") => {
+                match serde_json::from_str(payload) {
+                    Ok(value) => value,
+                    Err(_) => return Fragment::Whole(message),
+                }
+            }
+            _ => return Fragment::Whole(message),
+        };
+        let (id, part, total, data) = match Self::envelope(&value) {
+            Some(envelope) => envelope,
+            None => return Fragment::Whole(message),
+        };
+        if total > self.max_parts {
+            return Fragment::Pending;
+        }
+        if total == 1 {
+            return Fragment::Complete(data);
+        }
+        if !self.partial.contains_key(&id) && self.partial.len() >= self.max_pending {
+            self.evict_oldest();
+        }
+        let partial = self
+            .partial
+            .entry(id.clone())
+            .or_insert_with(|| PartialPayload {
+                parts: vec![None; total],
+                received: 0,
+                started_at: now,
+            });
+        if partial.parts.len() != total {
+            return Fragment::Pending;
+        }
+        if partial.parts[part].is_none() {
+            partial.parts[part] = Some(data);
+            partial.received += 1;
+        }
+        if partial.received < total {
+            return Fragment::Pending;
+        }
+        match self.partial.remove(&id) {
+            Some(partial) => Fragment::Complete(partial.parts.into_iter().flatten().collect()),
+            None => Fragment::Pending,
+        }
+    }
+
+    // Ms until the oldest buffered message times out.
+    pub(crate) fn next_expiry(&self, now: f64) -> Option<u32> {
+        self.partial
+            .values()
+            .map(|partial| partial.started_at + self.timeout - now)
+            .min_by(f64::total_cmp)
+            .map(|remaining| remaining.max(0.0).ceil() as u32)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.partial.clear();
+        if let Some(id) = self.expiry_timer.take() {
+            clear_timeout(id);
+        }
+    }
+
+    fn envelope(value: &Value) -> Option<(String, usize, usize, String)> {
+        let id = match value.get("id")? {
+            Value::String(id) => id.clone(),
+            id => id.to_string(),
+        };
+        let part = value.get("part")?.as_u64()? as usize;
+        let total = value.get("total")?.as_u64()? as usize;
+        if total == 0 || part >= total {
+            return None;
+        }
+        let data = match value.get("data")? {
+            Value::String(data) => data.clone(),
+            data => data.to_string(),
+        };
+        Some((id, part, total, data))
+    }
+
+    pub(crate) fn expire(&mut self, now: f64) {
+        let timeout = self.timeout;
+        self.partial
+            .retain(|_, partial| now - partial.started_at < timeout);
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .partial
+            .iter()
+            .min_by(|(_, a), (_, b)| a.started_at.total_cmp(&b.started_at))
+            .map(|(id, _)| id.clone());
+        if let Some(id) = oldest {
+            self.partial.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(id: &str, part: usize, total: usize, data: &str) -> WsMessage {
+        let value = serde_json::json!({"id": id, "part": part, "total": total, "data": data});
+        WsMessage::Text(value.to_string())
+    }
+
+    fn complete(fragment: Fragment) -> Option<String> {
+        match fragment {
+            Fragment::Complete(payload) => Some(payload),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn joins_parts_in_order() {
+        let mut fragments = FragmentBuffer::new(1000);
+        assert!(complete(fragments.push(part("a", 1, 2, "lo"), 0.0)).is_none());
+        let joined = complete(fragments.push(part("a", 0, 2, "hel"), 0.0));
+        assert_eq!(joined.as_deref(), Some("hello"));
+        assert_eq!(fragments.pending(), 0);
+    }
+
+    #[test]
+    fn passes_other_frames_through() {
+        let mut fragments = FragmentBuffer::new(1000);
+        let frames = [
+            WsMessage::Text(String::from("{\"part\": ")),
+            WsMessage::Text(String::from("{\"part\": 1}")),
+            part("a", 2, 2, "x"),
+            WsMessage::Binary(vec![1, 2]),
+        ];
+        for frame in frames {
+            assert!(matches!(fragments.push(frame, 0.0), Fragment::Whole(_)));
+        }
+    }
+
+    #[test]
+    fn drops_messages_over_max_parts() {
+        let mut fragments = FragmentBuffer::new(1000).max_parts(2);
+        assert!(matches!(
+            fragments.push(part("a", 0, 3, "x"), 0.0),
+            Fragment::Pending
+        ));
+        assert_eq!(fragments.pending(), 0);
+    }
+
+    #[test]
+    fn evicts_the_oldest_message_when_full() {
+        let mut fragments = FragmentBuffer::new(1000).max_pending(1);
+        fragments.push(part("a", 0, 2, "a"), 0.0);
+        fragments.push(part("b", 0, 2, "b"), 1.0);
+        assert_eq!(fragments.pending(), 1);
+        let joined = complete(fragments.push(part("b", 1, 2, "b"), 2.0));
+        assert_eq!(joined.as_deref(), Some("bb"));
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let mut fragments = FragmentBuffer::new(100);
+        fragments.push(part("a", 0, 2, "a"), 0.0);
+        fragments.push(part("b", 0, 2, "b"), 40.0);
+        assert_eq!(fragments.next_expiry(50.0), Some(50));
+        fragments.expire(100.0);
+        assert_eq!(fragments.pending(), 1);
+        assert_eq!(fragments.next_expiry(100.0), Some(40));
+        fragments.expire(140.0);
+        assert_eq!(fragments.next_expiry(140.0), None);
+    }
+}
@@ -0,0 +1,112 @@
+// Conversions to and from the `gloo-net` websocket types. There is no
+// `from_gloo`: a gloo `WebSocket` keeps its `web_sys::WebSocket` private and
+// listens on it itself, so it can't be taken over while open. Code moving off
+// gloo connects with `Websocket::connect`, or wraps a raw socket with
+// `WsFactory::from_existing`, and keeps the gloo types at its edges with
+// `into_gloo_stream` and the `Sink<Message>` of `WsSender`.
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::sink::Sink;
+use futures::stream::Stream;
+use gloo_net::websocket::events::CloseEvent;
+use gloo_net::websocket::{Message, WebSocketError};
+use wasm_bindgen::JsValue;
+
+use crate::close::CloseCode;
+use crate::error::WsError;
+use crate::stream::{WsReceiver, WsSender};
+use crate::WsMessage;
+
+impl From<Message> for WsMessage {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Text(text) => WsMessage::Text(text),
+            Message::Bytes(bytes) => WsMessage::Binary(bytes),
+        }
+    }
+}
+
+impl From<WsMessage> for Message {
+    fn from(message: WsMessage) -> Self {
+        match message {
+            WsMessage::Text(text) => Message::Text(text),
+            WsMessage::Binary(bytes) => Message::Bytes(bytes),
+        }
+    }
+}
+
+impl From<WebSocketError> for WsError {
+    fn from(err: WebSocketError) -> Self {
+        match err {
+            WebSocketError::ConnectionClose(event) => WsError::Closed {
+                code: CloseCode::from(event.code),
+                reason: event.reason,
+            },
+            WebSocketError::MessageSendError(err) => {
+                WsError::SendFailed(JsValue::from_str(&err.to_string()))
+            }
+            err => WsError::ConnectionFailed(err.to_string()),
+        }
+    }
+}
+
+// So `?` keeps working in code written against the gloo error type.
+impl From<WsError> for WebSocketError {
+    fn from(err: WsError) -> Self {
+        match err {
+            WsError::Closed { code, reason } => {
+                let code = u16::from(code);
+                WebSocketError::ConnectionClose(CloseEvent {
+                    code,
+                    reason,
+                    was_clean: code == 1000,
+                })
+            }
+            _ => WebSocketError::ConnectionError,
+        }
+    }
+}
+
+// Incoming frames with the item type of a gloo `WebSocket`, for code that
+// consumes the read half of one. Ends once the connection closed for good.
+pub struct GlooStream {
+    receiver: WsReceiver,
+}
+
+impl GlooStream {
+    pub(crate) fn new(receiver: WsReceiver) -> Self {
+        Self { receiver }
+    }
+}
+
+impl Stream for GlooStream {
+    type Item = Result<Message, WebSocketError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver)
+            .poll_next(cx)
+            .map(|message| message.map(|message| Ok(Message::from(message))))
+    }
+}
+
+// The write half of a gloo `WebSocket` can be swapped for a `WsSender`.
+impl Sink<Message> for WsSender {
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<WsMessage>::poll_ready(self, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Sink::<WsMessage>::start_send(self, WsMessage::from(item))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<WsMessage>::poll_flush(self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<WsMessage>::poll_close(self, cx)
+    }
+}
@@ -0,0 +1,333 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+pub const GRAPHQL_TRANSPORT_WS: &str = "graphql-transport-ws";
+
+// Every frame of the protocol is routed to this event by its `type` field.
+const GRAPHQL_EVENT: &str = "graphql";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphqlRequest {
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+    #[serde(
+        default,
+        rename = "operationName",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub operation_name: Option<String>,
+}
+
+impl GraphqlRequest {
+    pub fn new(query: &str) -> Self {
+        Self {
+            query: String::from(query),
+            variables: None,
+            operation_name: None,
+        }
+    }
+
+    pub fn variables(mut self, variables: Value) -> Self {
+        self.variables = Some(variables);
+        self
+    }
+
+    pub fn operation_name(mut self, operation_name: &str) -> Self {
+        self.operation_name = Some(String::from(operation_name));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    ConnectionInit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    ConnectionAck {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    Subscribe {
+        id: String,
+        payload: GraphqlRequest,
+    },
+    Next {
+        id: String,
+        payload: Value,
+    },
+    Error {
+        id: String,
+        payload: Value,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum OperationEvent {
+    // an execution result, `data` and `errors` as the server sent them
+    Next(Value),
+    // the list of graphql errors the operation failed with, it is over then
+    Error(Value),
+    Complete,
+}
+
+type OperationHandler = Rc<dyn Fn(OperationEvent)>;
+
+struct Operation {
+    request: GraphqlRequest,
+    handler: OperationHandler,
+}
+
+struct GraphqlInner {
+    websocket: Websocket,
+    connection_params: Option<Value>,
+    // subscribe frames only go out once the server acknowledged the
+    // connection_init of the current socket
+    acked: Cell<bool>,
+    next_id: Cell<u64>,
+    operations: RefCell<HashMap<String, Operation>>,
+}
+
+impl GraphqlInner {
+    fn send(&self, frame: &Frame) -> Result<(), WsError> {
+        let frame = serde_json::to_string(frame)?;
+        self.websocket.send(WsMessage::Text(frame))
+    }
+
+    fn init(&self) {
+        self.acked.set(false);
+        let frame = Frame::ConnectionInit {
+            payload: self.connection_params.clone(),
+        };
+        if let Err(err) = self.send(&frame) {
+            console_log!("error on graphql connection_init: {:?}", err);
+        }
+    }
+
+    // Active operations are subscribed again on every acknowledged socket.
+    fn subscribe_operations(&self) {
+        let frames: Vec<Frame> = self
+            .operations
+            .borrow()
+            .iter()
+            .map(|(id, operation)| Frame::Subscribe {
+                id: id.clone(),
+                payload: operation.request.clone(),
+            })
+            .collect();
+        for frame in frames {
+            if let Err(err) = self.send(&frame) {
+                console_log!("error on graphql subscribe: {:?}", err);
+            }
+        }
+    }
+
+    fn finish(&self, id: &str, event: OperationEvent) {
+        let operation = self.operations.borrow_mut().remove(id);
+        if let Some(operation) = operation {
+            (operation.handler)(event);
+        }
+    }
+
+    fn dispatch(&self, data: &str) {
+        let frame: Frame = match serde_json::from_str(data) {
+            Ok(frame) => frame,
+            Err(err) => {
+                console_log!("error on parse graphql frame {}: {:?}", data, err);
+                return;
+            }
+        };
+        match frame {
+            Frame::ConnectionAck { .. } => {
+                self.acked.set(true);
+                self.subscribe_operations();
+            }
+            Frame::Ping { .. } => {
+                if let Err(err) = self.send(&Frame::Pong { payload: None }) {
+                    console_log!("error on graphql pong: {:?}", err);
+                }
+            }
+            Frame::Next { id, payload } => {
+                let handler = self
+                    .operations
+                    .borrow()
+                    .get(&id)
+                    .map(|operation| operation.handler.clone());
+                if let Some(handler) = handler {
+                    handler(OperationEvent::Next(payload));
+                }
+            }
+            Frame::Error { id, payload } => self.finish(&id, OperationEvent::Error(payload)),
+            Frame::Complete { id } => self.finish(&id, OperationEvent::Complete),
+            Frame::Pong { .. } | Frame::ConnectionInit { .. } | Frame::Subscribe { .. } => {}
+        }
+    }
+}
+
+// A graphql-transport-ws client over the reconnecting socket. It owns the
+// `open`, `close` and `graphql` listeners of the socket, and opens sockets
+// with the `graphql-transport-ws` subprotocol unless the factory has its own
+// socket factory. The crate heartbeat and subscribe frames are off, the
+// server pings and operations are subscribed with `subscribe` frames.
+#[derive(Clone)]
+pub struct GraphqlClient {
+    inner: Rc<GraphqlInner>,
+}
+
+impl GraphqlClient {
+    // `connection_params` is the payload of every connection_init.
+    pub fn build(
+        mut factory: WsFactory,
+        connection_params: Option<Value>,
+    ) -> Result<Self, WsError> {
+        if factory.socket_factory.is_none() {
+            factory =
+                factory.socket_factory(|url| WebSocket::new_with_str(url, GRAPHQL_TRANSPORT_WS));
+        }
+        let factory = factory
+            .no_heartbeat()
+            .no_auto_subscribe()
+            .route_by(|value| {
+                value.get("type")?.as_str()?;
+                Some((String::from(GRAPHQL_EVENT), value.clone()))
+            });
+        let inner = Rc::new(GraphqlInner {
+            websocket: factory.build()?,
+            connection_params,
+            acked: Cell::new(false),
+            next_id: Cell::new(1),
+            operations: RefCell::new(HashMap::new()),
+        });
+        let graphql: Weak<GraphqlInner> = Rc::downgrade(&inner);
+        inner
+            .websocket
+            .add_listener(String::from(GRAPHQL_EVENT), move |payload| {
+                if let (Some(graphql), Payload::Data(data)) = (graphql.upgrade(), payload) {
+                    graphql.dispatch(data);
+                }
+            });
+        let graphql = Rc::downgrade(&inner);
+        inner
+            .websocket
+            .add_listener(String::from("open"), move |_| {
+                if let Some(graphql) = graphql.upgrade() {
+                    graphql.init();
+                }
+            });
+        let graphql = Rc::downgrade(&inner);
+        inner
+            .websocket
+            .add_listener(String::from("close"), move |_| {
+                if let Some(graphql) = graphql.upgrade() {
+                    graphql.acked.set(false);
+                }
+            });
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_acknowledged(&self) -> bool {
+        self.inner.acked.get()
+    }
+
+    // Queries and mutations are operations too, they complete after their
+    // single result. The operation is subscribed again after a reconnect
+    // until it completes, fails or is unsubscribed.
+    pub fn subscribe(
+        &self,
+        request: GraphqlRequest,
+        handler: impl Fn(OperationEvent) + 'static,
+    ) -> Result<GraphqlSubscription, WsError> {
+        let id = self.inner.next_id.get();
+        self.inner.next_id.set(id + 1);
+        let id = id.to_string();
+        let frame = Frame::Subscribe {
+            id: id.clone(),
+            payload: request.clone(),
+        };
+        self.inner.operations.borrow_mut().insert(
+            id.clone(),
+            Operation {
+                request,
+                handler: Rc::new(handler),
+            },
+        );
+        if self.inner.acked.get() && matches!(self.inner.websocket.ready_state(), ReadyState::Open)
+        {
+            if let Err(err) = self.inner.send(&frame) {
+                self.inner.operations.borrow_mut().remove(&id);
+                return Err(err);
+            }
+        }
+        Ok(GraphqlSubscription {
+            id,
+            graphql: Rc::downgrade(&self.inner),
+        })
+    }
+
+    pub fn operation_ids(&self) -> Vec<String> {
+        self.inner.operations.borrow().keys().cloned().collect()
+    }
+}
+
+pub struct GraphqlSubscription {
+    id: String,
+    graphql: Weak<GraphqlInner>,
+}
+
+impl GraphqlSubscription {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // Tells the server to stop the operation, the handler is not called
+    // anymore.
+    pub fn unsubscribe(self) -> Result<(), WsError> {
+        let graphql = match self.graphql.upgrade() {
+            Some(graphql) => graphql,
+            None => return Ok(()),
+        };
+        let operation = graphql.operations.borrow_mut().remove(&self.id);
+        if operation.is_some() && graphql.acked.get() {
+            return graphql.send(&Frame::Complete { id: self.id });
+        }
+        Ok(())
+    }
+}
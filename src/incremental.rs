@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use js_sys::{JsString, Uint8Array};
+
+use crate::limits::is_high_surrogate;
+use crate::WsMessage;
+
+enum Pending {
+    Ready(WsMessage),
+    Buffer {
+        source: Uint8Array,
+        copied: Vec<u8>,
+    },
+    Text {
+        source: JsString,
+        decoded: String,
+        offset: u32,
+    },
+}
+
+struct PendingFrame {
+    pending: Pending,
+    received_at: f64,
+}
+
+// Finished frames carry the time they were received.
+pub(crate) enum Step {
+    Idle,
+    Copied,
+    Frame(WsMessage, f64),
+    // a text frame decoded in slices, the size limit is checked again on the
+    // utf8 length
+    Text(String, f64),
+}
+
+// Frames above the threshold are copied out of js in slices, one slice per
+// macrotask, so a multi-megabyte snapshot doesn't freeze the ui thread. Text
+// frames are decoded to utf8 the same way. Later frames wait behind it to
+// keep the order.
+pub struct IncrementalReader {
+    threshold: usize,
+    slice_size: usize,
+    frames: VecDeque<PendingFrame>,
+    scheduled: bool,
+}
+
+impl IncrementalReader {
+    pub fn new(threshold: usize, slice_size: usize) -> Self {
+        Self {
+            threshold,
+            slice_size: slice_size.max(1),
+            frames: VecDeque::new(),
+            scheduled: false,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn is_large(&self, size: usize) -> bool {
+        size > self.threshold
+    }
+
+    pub(crate) fn push_buffer(&mut self, source: Uint8Array, received_at: f64) {
+        let copied = Vec::with_capacity(source.length() as usize);
+        self.push(Pending::Buffer { source, copied }, received_at);
+    }
+
+    pub(crate) fn push_text(&mut self, source: JsString, received_at: f64) {
+        let decoded = String::with_capacity(source.length() as usize);
+        let text = Pending::Text {
+            source,
+            decoded,
+            offset: 0,
+        };
+        self.push(text, received_at);
+    }
+
+    pub(crate) fn push_frame(&mut self, message: WsMessage, received_at: f64) {
+        self.push(Pending::Ready(message), received_at);
+    }
+
+    fn push(&mut self, pending: Pending, received_at: f64) {
+        self.frames.push_back(PendingFrame {
+            pending,
+            received_at,
+        });
+    }
+
+    // Copies or decodes the next slice of the front frame, or hands it out
+    // once it is complete.
+    pub(crate) fn step(&mut self) -> Step {
+        let slice_size = self.slice_size;
+        match self.frames.front_mut().map(|frame| &mut frame.pending) {
+            None => return Step::Idle,
+            Some(Pending::Buffer { source, copied }) => {
+                let length = source.length() as usize;
+                if copied.len() < length {
+                    let start = copied.len();
+                    let end = (start + slice_size).min(length);
+                    // copied straight into the buffer, no intermediate vec
+                    copied.resize(end, 0);
+                    source
+                        .subarray(start as u32, end as u32)
+                        .copy_to(&mut copied[start..end]);
+                    return Step::Copied;
+                }
+            }
+            Some(Pending::Text {
+                source,
+                decoded,
+                offset,
+            }) => {
+                let length = source.length();
+                if *offset < length {
+                    let mut end = offset.saturating_add(slice_size as u32).min(length);
+                    // never split a surrogate pair between two slices
+                    if end < length && is_high_surrogate(source.char_code_at(end - 1)) {
+                        end += 1;
+                    }
+                    decoded.push_str(&String::from(source.slice(*offset, end)));
+                    *offset = end;
+                    return Step::Copied;
+                }
+            }
+            Some(Pending::Ready(_)) => (),
+        }
+        let frame = match self.frames.pop_front() {
+            Some(frame) => frame,
+            None => return Step::Idle,
+        };
+        match frame.pending {
+            Pending::Ready(message) => Step::Frame(message, frame.received_at),
+            Pending::Buffer { copied, .. } => {
+                Step::Frame(WsMessage::Binary(copied), frame.received_at)
+            }
+            Pending::Text { decoded, .. } => Step::Text(decoded, frame.received_at),
+        }
+    }
+
+    pub(crate) fn set_scheduled(&mut self, scheduled: bool) -> bool {
+        let was_scheduled = self.scheduled;
+        self.scheduled = scheduled;
+        was_scheduled
+    }
+
+    // Drops the frames of a connection that failed or went away, a step that
+    // is still scheduled finds the reader idle.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+    }
+}
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Poll;
+
+use futures::future::poll_fn;
+use futures::stream::Stream;
+use js_sys::{Array, ArrayBuffer, Function, JsString, Object, Promise, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::future_to_promise;
+use web_sys::ReadableStream;
+
+use crate::close::CloseCode;
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::stream::WsReceiver;
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+#[wasm_bindgen(js_name = ReadyState)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JsReadyState {
+    Connecting = 0,
+    Open = 1,
+    Closing = 2,
+    Closed = 3,
+}
+
+impl From<ReadyState> for JsReadyState {
+    fn from(state: ReadyState) -> Self {
+        match state {
+            ReadyState::Connecting => JsReadyState::Connecting,
+            ReadyState::Open => JsReadyState::Open,
+            ReadyState::Closing => JsReadyState::Closing,
+            ReadyState::Closed | ReadyState::Other(_) => JsReadyState::Closed,
+        }
+    }
+}
+
+fn message_value(message: WsMessage) -> JsValue {
+    match message {
+        WsMessage::Text(text) => JsValue::from_str(&text),
+        WsMessage::Binary(bytes) => Uint8Array::from(bytes.as_slice()).into(),
+    }
+}
+
+fn call_method(target: &JsValue, name: &str, args: &Array) -> Result<JsValue, JsValue> {
+    let method: Function = Reflect::get(target, &JsValue::from_str(name))?.dyn_into()?;
+    Reflect::apply(&method, target, args)
+}
+
+impl From<WsError> for JsValue {
+    fn from(err: WsError) -> Self {
+        match err {
+            WsError::Js(value) | WsError::SendFailed(value) => value,
+            err => js_sys::Error::new(&err.to_string()).into(),
+        }
+    }
+}
+
+// The reconnecting client as a class for plain js and ts code. Listeners get
+// strings for json data and `Uint8Array`s for raw binary frames.
+#[wasm_bindgen(js_name = ReconnectingWebSocket)]
+pub struct JsWebsocket {
+    inner: Websocket,
+}
+
+#[wasm_bindgen(js_class = ReconnectingWebSocket)]
+impl JsWebsocket {
+    pub fn connect(url: String, reconnect: Option<bool>) -> Result<JsWebsocket, JsValue> {
+        let mut factory = Websocket::connect(url);
+        if !reconnect.unwrap_or(true) {
+            factory = factory.no_reconnect();
+        }
+        Ok(JsWebsocket {
+            inner: factory.build()?,
+        })
+    }
+
+    // Strings go out as text frames, `ArrayBuffer`s and `Uint8Array`s as
+    // binary frames.
+    pub fn send(&self, data: JsValue) -> Result<(), JsValue> {
+        if let Some(text) = data.dyn_ref::<JsString>() {
+            return Ok(self.inner.send(WsMessage::Text(String::from(text)))?);
+        }
+        if let Some(buffer) = data.dyn_ref::<ArrayBuffer>() {
+            return Ok(self.inner.send_array_buffer(buffer)?);
+        }
+        if let Some(array) = data.dyn_ref::<Uint8Array>() {
+            return Ok(self.inner.send_u8_array(array)?);
+        }
+        Err(js_sys::TypeError::new("expected a string, ArrayBuffer or Uint8Array").into())
+    }
+
+    pub fn close(self, code: Option<u16>, reason: Option<String>) -> Result<(), JsValue> {
+        Ok(self.inner.close(code.map(CloseCode::from), reason)?)
+    }
+
+    #[wasm_bindgen(getter, js_name = readyState)]
+    pub fn ready_state(&self) -> JsReadyState {
+        JsReadyState::from(self.inner.ready_state())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn url(&self) -> String {
+        self.inner.url()
+    }
+
+    // Every incoming frame as a `ReadableStream`, so js code can
+    // `for await (const message of socket.readable())`. Text frames are
+    // strings, binary frames `Uint8Array`s. The stream ends once the
+    // connection closed for good, cancelling it stops reading.
+    pub fn readable(&self) -> Result<ReadableStream, JsValue> {
+        let receiver: Rc<RefCell<Option<WsReceiver>>> =
+            Rc::new(RefCell::new(Some(self.inner.receiver())));
+        let pull = {
+            let receiver = receiver.clone();
+            Closure::wrap(Box::new(move |controller: JsValue| -> Promise {
+                let receiver = receiver.clone();
+                future_to_promise(async move {
+                    let message = poll_fn(|cx| match receiver.borrow_mut().as_mut() {
+                        Some(receiver) => Pin::new(receiver).poll_next(cx),
+                        None => Poll::Ready(None),
+                    })
+                    .await;
+                    match message {
+                        Some(message) => call_method(
+                            &controller,
+                            "enqueue",
+                            &Array::of1(&message_value(message)),
+                        )?,
+                        None => call_method(&controller, "close", &Array::new())?,
+                    };
+                    Ok(JsValue::UNDEFINED)
+                })
+            }) as Box<dyn FnMut(JsValue) -> Promise>)
+        };
+        let cancel = Closure::wrap(Box::new(move || {
+            receiver.borrow_mut().take();
+        }) as Box<dyn FnMut()>);
+        let source = Object::new();
+        Reflect::set(&source, &JsValue::from_str("pull"), &pull.into_js_value())?;
+        Reflect::set(
+            &source,
+            &JsValue::from_str("cancel"),
+            &cancel.into_js_value(),
+        )?;
+        let constructor: Function =
+            Reflect::get(&js_sys::global(), &JsValue::from_str("ReadableStream"))?.dyn_into()?;
+        let stream = Reflect::construct(&constructor, &Array::of1(&source))?;
+        Ok(stream.unchecked_into())
+    }
+
+    #[wasm_bindgen(js_name = addListener)]
+    pub fn add_listener(&self, event: String, callback: Function) {
+        self.inner.add_listener(event, move |payload| {
+            let value = match payload {
+                Payload::Data(data) => JsValue::from_str(data),
+                Payload::Bytes(bytes) => Uint8Array::from(bytes.as_slice()).into(),
+                Payload::MessageEvent(event) => event.clone().into(),
+                Payload::CloseEvent(event) => event.clone().into(),
+                Payload::ErrorEvent(event) => event.clone().into(),
+            };
+            if let Err(err) = callback.call1(&JsValue::NULL, &value) {
+                console_log!("error in js listener: {:?}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,346 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{BroadcastChannel, MessageEvent};
+
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::timers::{clear_interval, set_interval};
+use crate::{Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+const HEARTBEAT_INTERVAL: u32 = 1_000;
+// missed heartbeats before the followers elect a new leader
+const HEARTBEAT_MISSES: f64 = 3.0;
+
+// Exchanged as json strings over the channel. Tab ids are random, the lowest
+// id wins when two tabs claim the leadership at the same time.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChannelMessage {
+    Heartbeat { id: f64 },
+    Claim { id: f64 },
+    Resign { id: f64 },
+    Subscribe { event: String },
+    Send { data: String },
+    Event { event: String, data: String },
+}
+
+type TabListener = Rc<dyn Fn(&str)>;
+
+struct Election {
+    id: f64,
+    channel: BroadcastChannel,
+    factory: Box<dyn Fn() -> WsFactory>,
+    websocket: RefCell<Option<Websocket>>,
+    leader: Cell<Option<f64>>,
+    last_heartbeat: Cell<f64>,
+    claimed_at: Cell<Option<f64>>,
+    listeners: RefCell<HashMap<String, TabListener>>,
+    // events the followers listen to, only known to the leader
+    remote_events: RefCell<HashSet<String>>,
+    // events the leader listens to on its socket
+    listening: RefCell<HashSet<String>>,
+}
+
+impl Election {
+    fn post(&self, message: &ChannelMessage) {
+        let message = match serde_json::to_string(message) {
+            Ok(message) => message,
+            Err(err) => {
+                console_log!("error on serialize channel message: {:?}", err);
+                return;
+            }
+        };
+        if let Err(err) = self.channel.post_message(&JsValue::from_str(&message)) {
+            console_log!("error on post channel message: {:?}", err);
+        }
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader.get() == Some(self.id)
+    }
+
+    fn tick(self: &Rc<Self>) {
+        let now = js_sys::Date::now();
+        if self.is_leader() {
+            self.post(&ChannelMessage::Heartbeat { id: self.id });
+            return;
+        }
+        match self.claimed_at.get() {
+            // nobody with a lower id objected within one interval
+            Some(claimed_at) if now - claimed_at >= f64::from(HEARTBEAT_INTERVAL) => {
+                self.lead();
+            }
+            Some(_) => (),
+            None => {
+                let timeout = f64::from(HEARTBEAT_INTERVAL) * HEARTBEAT_MISSES;
+                if now - self.last_heartbeat.get() > timeout {
+                    self.claim();
+                }
+            }
+        }
+    }
+
+    fn claim(&self) {
+        self.leader.set(None);
+        self.claimed_at.set(Some(js_sys::Date::now()));
+        self.post(&ChannelMessage::Claim { id: self.id });
+    }
+
+    fn lead(self: &Rc<Self>) {
+        self.claimed_at.set(None);
+        self.leader.set(Some(self.id));
+        match (self.factory)().build() {
+            Ok(websocket) => *self.websocket.borrow_mut() = Some(websocket),
+            Err(err) => {
+                console_log!("error on connect as leader: {:?}", err);
+                self.leader.set(None);
+                self.last_heartbeat.set(js_sys::Date::now());
+                return;
+            }
+        }
+        let events: Vec<String> = self.listeners.borrow().keys().cloned().collect();
+        for event in events {
+            self.listen(event);
+        }
+        self.post(&ChannelMessage::Heartbeat { id: self.id });
+    }
+
+    fn step_down(&self) {
+        let websocket = self.websocket.borrow_mut().take();
+        drop(websocket);
+        self.listening.borrow_mut().clear();
+        self.remote_events.borrow_mut().clear();
+    }
+
+    fn follow(&self, leader: f64) {
+        if self.is_leader() {
+            self.step_down();
+        }
+        self.claimed_at.set(None);
+        self.last_heartbeat.set(js_sys::Date::now());
+        if self.leader.replace(Some(leader)) != Some(leader) {
+            // a new leader doesn't know what this tab listens to
+            let events: Vec<String> = self.listeners.borrow().keys().cloned().collect();
+            for event in events {
+                self.post(&ChannelMessage::Subscribe { event });
+            }
+        }
+    }
+
+    fn listen(self: &Rc<Self>, event: String) {
+        if !self.listening.borrow_mut().insert(event.clone()) {
+            return;
+        }
+        if let Some(websocket) = self.websocket.borrow().as_ref() {
+            let election = Rc::downgrade(self);
+            let name = event.clone();
+            websocket.add_listener(event, move |payload| {
+                if let (Some(election), Payload::Data(data)) = (election.upgrade(), payload) {
+                    election.deliver(&name, data);
+                }
+            });
+        }
+    }
+
+    fn deliver(&self, event: &str, data: &str) {
+        let listener = self.listeners.borrow().get(event).cloned();
+        if let Some(listener) = listener {
+            listener(data);
+        }
+        if self.is_leader() && self.remote_events.borrow().contains(event) {
+            self.post(&ChannelMessage::Event {
+                event: String::from(event),
+                data: String::from(data),
+            });
+        }
+    }
+
+    fn on_message(self: &Rc<Self>, message: ChannelMessage) {
+        match message {
+            ChannelMessage::Heartbeat { id } => {
+                // the lower id keeps the leadership
+                if !self.is_leader() || id < self.id {
+                    self.follow(id);
+                }
+            }
+            ChannelMessage::Claim { id } => {
+                if self.is_leader() {
+                    self.post(&ChannelMessage::Heartbeat { id: self.id });
+                } else if self.claimed_at.get().is_some() && id < self.id {
+                    self.claimed_at.set(None);
+                    self.last_heartbeat.set(js_sys::Date::now());
+                }
+            }
+            ChannelMessage::Resign { id } => {
+                if self.leader.get() == Some(id) {
+                    self.claim();
+                }
+            }
+            ChannelMessage::Subscribe { event } => {
+                if self.is_leader() {
+                    self.remote_events.borrow_mut().insert(event.clone());
+                    self.listen(event);
+                }
+            }
+            ChannelMessage::Send { data } => {
+                if self.is_leader() {
+                    self.send(data);
+                }
+            }
+            ChannelMessage::Event { event, data } => {
+                if !self.is_leader() {
+                    let listener = self.listeners.borrow().get(&event).cloned();
+                    if let Some(listener) = listener {
+                        listener(&data);
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&self, data: String) {
+        if let Some(websocket) = self.websocket.borrow().as_ref() {
+            if let Err(err) = websocket.send(WsMessage::Text(data)) {
+                console_log!("error on send as leader: {:?}", err);
+            }
+        }
+    }
+}
+
+// One tab per origin owns the socket and rebroadcasts routed events over a
+// `BroadcastChannel`, the others follow. When the leader tab goes away, or
+// stops sending heartbeats, the followers elect a new one which connects with
+// a fresh factory. Only `Payload::Data` payloads reach the followers.
+pub struct LeaderElection {
+    election: Rc<Election>,
+    interval_id: i32,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl LeaderElection {
+    pub fn start(
+        channel: &str,
+        factory: impl Fn() -> WsFactory + 'static,
+    ) -> Result<Self, WsError> {
+        let channel = BroadcastChannel::new(channel).map_err(WsError::Js)?;
+        let election = Rc::new(Election {
+            id: js_sys::Math::random(),
+            channel,
+            factory: Box::new(factory),
+            websocket: RefCell::new(None),
+            leader: Cell::new(None),
+            last_heartbeat: Cell::new(js_sys::Date::now()),
+            claimed_at: Cell::new(None),
+            listeners: RefCell::new(HashMap::new()),
+            remote_events: RefCell::new(HashSet::new()),
+            listening: RefCell::new(HashSet::new()),
+        });
+        let onmessage = {
+            let election = Rc::downgrade(&election);
+            Closure::wrap(Box::new(move |event: MessageEvent| {
+                let election = match election.upgrade() {
+                    Some(election) => election,
+                    None => return,
+                };
+                let data = match event.data().as_string() {
+                    Some(data) => data,
+                    None => return,
+                };
+                match serde_json::from_str(&data) {
+                    Ok(message) => election.on_message(message),
+                    Err(err) => console_log!("unknown channel message {}: {:?}", data, err),
+                }
+            }) as Box<dyn FnMut(MessageEvent)>)
+        };
+        election
+            .channel
+            .set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        let interval_id = {
+            let election: Weak<Election> = Rc::downgrade(&election);
+            set_interval(
+                move || {
+                    if let Some(election) = election.upgrade() {
+                        election.tick();
+                    }
+                },
+                HEARTBEAT_INTERVAL,
+            )
+        };
+        // an existing leader answers the claim with a heartbeat
+        election.claim();
+        Ok(Self {
+            election,
+            interval_id,
+            _onmessage: onmessage,
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.election.is_leader()
+    }
+
+    // The socket, while this tab is the leader.
+    pub fn websocket(&self) -> Option<Websocket> {
+        self.election.websocket.borrow().clone()
+    }
+
+    pub fn add_listener(&self, event: &str, f: impl Fn(&str) + 'static) {
+        self.election
+            .listeners
+            .borrow_mut()
+            .insert(String::from(event), Rc::new(f));
+        if self.election.is_leader() {
+            self.election.listen(String::from(event));
+        } else {
+            self.election.post(&ChannelMessage::Subscribe {
+                event: String::from(event),
+            });
+        }
+    }
+
+    pub fn remove_listener(&self, event: &str) {
+        self.election.listeners.borrow_mut().remove(event);
+    }
+
+    // Followers hand the message to the leader, it is lost while there is
+    // none.
+    pub fn send(&self, data: &str) {
+        if self.election.is_leader() {
+            self.election.send(String::from(data));
+        } else {
+            self.election.post(&ChannelMessage::Send {
+                data: String::from(data),
+            });
+        }
+    }
+}
+
+impl Drop for LeaderElection {
+    fn drop(&mut self) {
+        clear_interval(self.interval_id);
+        if self.election.is_leader() {
+            self.election.post(&ChannelMessage::Resign {
+                id: self.election.id,
+            });
+            self.election.step_down();
+        }
+        self.election.channel.set_onmessage(None);
+        self.election.channel.close();
+    }
+}
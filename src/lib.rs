@@ -1,26 +1,109 @@
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use jsonrpc_core::Params;
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use js_sys::{ArrayBuffer, Uint8Array};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen::JsValue;
-use web_sys::{BinaryType, Event};
+use web_sys::{BinaryType, CloseEvent, ErrorEvent, Event};
 
+use crate::close::{CloseCode, DropBehavior};
+use crate::codec::RoutedMessage;
+use crate::connect::CloseFuture;
 use crate::core::WsCore;
-use crate::emitter::Payload;
+use crate::emitter::{Payload, RxStats, SubscriptionAction};
+use crate::error::WsError;
 use crate::factory::WsFactory;
-use crate::simple_rpc::RPCHandler;
+use crate::outgoing::{Priority, SendFuture, TxStats, WsSink};
+use crate::pause::PausePolicy;
+use crate::schedule::ScheduleHandle;
+use crate::simple_rpc::{
+    IntoParams, PendingRpc, RPCHandler, RawRPCHandler, RetryPolicy, RpcMethodStats, RpcNamespace,
+    RpcRequestHandle,
+};
+use crate::stats::ConnectionStats;
+use crate::stream::{ConnectionState, ListenStream, RpcSubscriptionStream, WsReceiver, WsSender};
 
+pub mod ack;
+#[cfg(feature = "action-cable")]
+pub mod actioncable;
+pub mod auth;
+#[cfg(feature = "centrifuge")]
+pub mod centrifuge;
+pub mod chunking;
+pub mod close;
+pub mod codec;
+#[cfg(feature = "flate2")]
+pub mod compression;
+pub mod connect;
 pub mod core;
 pub mod emitter;
+pub mod error;
 pub mod factory;
+pub mod fragments;
+#[cfg(feature = "gloo")]
+pub mod gloo;
+#[cfg(feature = "graphql-ws")]
+pub mod graphql;
+pub mod incremental;
+pub mod js;
+#[cfg(feature = "leader-election")]
+pub mod leader;
+pub mod limits;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod mux;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod outgoing;
+pub mod pause;
+#[cfg(feature = "phoenix")]
+pub mod phoenix;
+pub mod probe;
+#[cfg(feature = "prost")]
+pub mod protobuf;
+#[cfg(any(
+    feature = "socket-io",
+    feature = "stomp",
+    feature = "mqtt",
+    feature = "phoenix",
+    feature = "signalr",
+    feature = "centrifuge",
+    feature = "nats",
+    feature = "action-cable",
+    feature = "wamp"
+))]
+pub(crate) mod protocol;
+pub mod registry;
+pub mod reorder;
+pub mod resume;
+pub mod schedule;
+#[cfg(feature = "shared-worker")]
+pub mod shared;
+#[cfg(feature = "signalr")]
+pub mod signalr;
 pub mod simple_rpc;
+#[cfg(feature = "socket-io")]
+pub mod socketio;
+pub mod sockjs;
+pub mod stats;
+#[cfg(feature = "stomp")]
+pub mod stomp;
+pub mod stream;
+pub mod timers;
+pub mod url;
 pub mod utils;
+#[cfg(feature = "wamp")]
+pub mod wamp;
+#[cfg(feature = "yew")]
+pub mod yew;
 
 #[wasm_bindgen]
 extern "C" {
-    fn setInterval(closure: &Closure<dyn FnMut()>, time: u32) -> i32;
-    fn setTimeout(closure: &Closure<dyn FnMut()>, time: u32);
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
 }
@@ -31,100 +114,453 @@ macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 }
 
-#[wasm_bindgen]
+// Clones share the same connection. Dropping the last handle closes the
+// socket, an explicit `close` closes it for every handle.
+#[derive(Clone)]
 pub struct Websocket {
     core: Rc<WsCore>,
+    // only counts handles, timers and futures hold their own `core`
+    handles: Rc<()>,
 }
 
 impl Websocket {
     pub fn new(core: WsCore) -> Self {
         Self {
             core: Rc::new(core),
+            handles: Rc::new(()),
         }
     }
 
+    pub fn handle_count(&self) -> usize {
+        Rc::strong_count(&self.handles)
+    }
+
     pub fn connect<U: Into<Cow<'static, str>>>(url: U) -> WsFactory {
         WsFactory::new(url.into())
     }
 
-    pub fn close(self, code: Option<u16>, reason: Option<String>) -> Result<(), JsValue> {
-        self.core.close(code.unwrap_or(1000u16), reason)
+    pub fn close(self, code: Option<CloseCode>, reason: Option<String>) -> Result<(), WsError> {
+        let code = code.unwrap_or(CloseCode::Normal);
+        self.core.close(u16::from(code), reason)
+    }
+
+    // Resolves once the close event for the current socket fired, with the
+    // code and reason the server answered with.
+    pub fn close_async(self, code: Option<CloseCode>, reason: Option<String>) -> CloseFuture {
+        let closed = CloseFuture::listen(&self.core.websocket.borrow());
+        match self.close(code, reason) {
+            Ok(()) => closed,
+            Err(err) => CloseFuture::failed(err),
+        }
     }
 
-    pub fn close_from_drop(&mut self) -> Result<(), JsValue> {
-        self.core.close(1000u16, None)
+    pub fn close_graceful(self, code: Option<CloseCode>, reason: Option<String>, timeout: u32) {
+        let core = self.core.clone();
+        let code = code.unwrap_or(CloseCode::Normal);
+        // the handle is held until the socket is closed, dropping it now
+        // would close it before it is drained
+        core.close_graceful(u16::from(code), reason, timeout, Some(self));
     }
 
-    pub fn send(&self, websocket_message: WsMessage) -> Result<(), JsValue> {
-        match websocket_message {
-            WsMessage::Text(payload) => {
-                self.core.websocket.borrow().send_with_str(payload.as_str())
+    pub fn close_from_drop(&mut self) -> Result<(), WsError> {
+        match self.core.factory.drop_behavior.clone() {
+            DropBehavior::Close { code, reason } => self.core.close(u16::from(code), reason),
+            DropBehavior::Graceful {
+                code,
+                reason,
+                timeout,
+            } => {
+                self.core
+                    .close_graceful(u16::from(code), reason, timeout, None);
+                Ok(())
+            }
+            DropBehavior::Leak => {
+                // keeps the handlers and timers of the connection alive
+                std::mem::forget(self.core.clone());
+                Ok(())
             }
-            WsMessage::Binary(mut payload) => self
-                .core
-                .websocket
-                .borrow()
-                .send_with_u8_array(payload.as_mut_slice()),
         }
     }
+
+    pub fn send(&self, websocket_message: WsMessage) -> Result<(), WsError> {
+        self.core.send(websocket_message, Priority::Normal)
+    }
+
+    // Without a batch combiner the messages go out one frame each, stopping at
+    // the first one that fails.
+    pub fn send_all(&self, websocket_messages: Vec<WsMessage>) -> Result<(), WsError> {
+        match self.core.factory.batch_combiner.as_ref() {
+            Some(combiner) if websocket_messages.len() > 1 => {
+                self.send(combiner(websocket_messages))
+            }
+            _ => websocket_messages
+                .into_iter()
+                .try_for_each(|websocket_message| self.send(websocket_message)),
+        }
+    }
+
+    pub fn send_after(&self, websocket_message: WsMessage, delay: u32) -> ScheduleHandle {
+        let core = self.core.clone();
+        let mut websocket_message = Some(websocket_message);
+        self.core.factory.scheduler.schedule(
+            move || {
+                if let Some(websocket_message) = websocket_message.take() {
+                    if let Err(err) = core.send(websocket_message, Priority::Normal) {
+                        console_log!("error on scheduled send: {}", err);
+                    }
+                }
+            },
+            delay,
+            false,
+        )
+    }
+
+    // The builder runs on every tick so each frame carries fresh data.
+    pub fn send_every(
+        &self,
+        message_builder: impl Fn() -> WsMessage + 'static,
+        interval: u32,
+    ) -> ScheduleHandle {
+        let core = self.core.clone();
+        self.core.factory.scheduler.schedule(
+            move || {
+                if let Err(err) = core.send(message_builder(), Priority::Normal) {
+                    console_log!("error on scheduled send: {}", err);
+                }
+            },
+            interval,
+            true,
+        )
+    }
+
+    pub fn send_with_priority(
+        &self,
+        websocket_message: WsMessage,
+        priority: Priority,
+    ) -> Result<(), WsError> {
+        self.core.send(websocket_message, priority)
+    }
+
+    // `on_ack` gets `Ok` once the server acknowledged the message, or
+    // `Err(WsError::AckTimeout)` when no ack arrived within `timeout` ms.
+    pub fn send_with_ack(
+        &self,
+        websocket_message: WsMessage,
+        on_ack: impl FnOnce(Result<(), WsError>) + 'static,
+        timeout: u32,
+    ) -> Result<(), WsError> {
+        self.core
+            .send_with_ack(websocket_message, Box::new(on_ack), timeout)
+    }
+
+    pub fn send_array_buffer(&self, buffer: &ArrayBuffer) -> Result<(), WsError> {
+        self.core.send_array_buffer(buffer)
+    }
+
+    pub fn send_u8_array(&self, array: &Uint8Array) -> Result<(), WsError> {
+        self.core.send_u8_array(array)
+    }
+
+    // Encodes the event with the factory codec, `{event: data}` by default.
+    pub fn send_event(&self, event: &str, data: String) -> Result<(), WsError> {
+        let websocket_message = self
+            .core
+            .factory
+            .active_codec()
+            .encode(RoutedMessage::new(event, data))?;
+        self.send(self.core.factory.frame(websocket_message))
+    }
+
+    pub fn send_json<T: Serialize>(&self, payload: &T) -> Result<(), WsError> {
+        let payload = serde_json::to_string(payload)?;
+        self.send(self.core.factory.frame(WsMessage::Text(payload)))
+    }
+
+    pub fn send_json_binary<T: Serialize>(&self, payload: &T) -> Result<(), WsError> {
+        let payload = serde_json::to_vec(payload)?;
+        self.send(WsMessage::Binary(payload))
+    }
+
+    pub fn send_async(&self, websocket_message: WsMessage) -> SendFuture {
+        let (threshold, poll_interval) = match self.core.factory.backpressure.as_ref() {
+            Some(backpressure) => (backpressure.low_water_mark, backpressure.poll_interval),
+            None => (0, 50),
+        };
+        SendFuture::new(
+            self.core.factory.clone(),
+            self.core.websocket.clone(),
+            threshold,
+            poll_interval,
+            self.send(websocket_message).err(),
+        )
+    }
+
+    // A stream of every incoming message, next to the listeners and callbacks.
+    pub fn receiver(&self) -> WsReceiver {
+        WsReceiver::new(self.clone())
+    }
+
+    // Transitions from now on, ends once the connection closed for good.
+    pub fn state_stream(&self) -> impl Stream<Item = ConnectionState> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.core.factory.state_senders.borrow_mut().push(sender);
+        receiver
+    }
+
+    // Both halves are handles of their own, the connection stays open until
+    // the last one is dropped.
+    pub fn split(self) -> (WsSender, WsReceiver) {
+        let receiver = self.receiver();
+        (WsSender::new(self), receiver)
+    }
+
+    // Incoming frames as gloo messages. Together with the `Sink<Message>` of
+    // a `WsSender` this stands in for a split gloo `WebSocket`.
+    #[cfg(feature = "gloo")]
+    pub fn into_gloo_stream(self) -> gloo::GlooStream {
+        gloo::GlooStream::new(self.receiver())
+    }
+
+    // For `SinkExt::send_all` and other sink combinators.
+    pub fn sink(&self) -> WsSink {
+        WsSink::new(self.clone())
+    }
+
+    pub fn buffered_amount(&self) -> u32 {
+        self.core.websocket.borrow().buffered_amount()
+    }
+
+    pub fn is_backpressured(&self) -> bool {
+        match self.core.factory.backpressure.as_ref() {
+            Some(backpressure) => backpressure.is_backpressured(),
+            None => false,
+        }
+    }
+
+    pub fn tx_stats(&self) -> TxStats {
+        self.core.factory.tx_stats()
+    }
+
+    pub fn rx_stats(&self) -> RxStats {
+        self.core.factory.rx_stats.borrow().clone()
+    }
+
+    // The endpoint selected by the last probe, `None` before the first one.
+    pub fn endpoint(&self) -> Option<String> {
+        self.core.factory.endpoint.borrow().clone()
+    }
+
+    // The id the next resume frame will carry.
+    pub fn resume_id(&self) -> Option<serde_json::Value> {
+        let resume = self.core.factory.resume.as_ref()?;
+        let id = resume.borrow().last_id().cloned();
+        id
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.core.factory.connection_stats()
+    }
+
+    pub fn queued_messages(&self) -> usize {
+        self.core.factory.outgoing.borrow().len()
+    }
+
     pub fn prepare_rpc_request(
         &self,
         method: String,
-        rpc_params: Params,
+        rpc_params: impl IntoParams,
         callback: RPCHandler,
         error_callback: RPCHandler,
-    ) -> Option<String> {
+    ) -> Result<(RpcRequestHandle, WsMessage), WsError> {
+        self.prepare_rpc_call(method, rpc_params, Some(callback), Some(error_callback))
+    }
+
+    fn prepare_rpc_call(
+        &self,
+        method: String,
+        rpc_params: impl IntoParams,
+        callback: Option<RPCHandler>,
+        error_callback: Option<RPCHandler>,
+    ) -> Result<(RpcRequestHandle, WsMessage), WsError> {
+        let rpc_params = rpc_params.into_params()?;
         let websocket_core = self.core.clone();
         let factory = websocket_core.factory.clone();
-        if !factory.rpc_subscriber.is_none() {
-            let raw_rpc_subscriber = factory.rpc_subscriber.as_ref();
-            if let Some(rpc_subscriber) = raw_rpc_subscriber {
-                let mut rpc_subscriber_ref = rpc_subscriber.borrow_mut();
-                let (request_id, raw_request) =
-                    rpc_subscriber_ref.prepare_request(method.as_str(), rpc_params);
-                rpc_subscriber_ref.set_handler(request_id, callback);
-                rpc_subscriber_ref.set_error_handler(request_id, error_callback);
-                let rpc_request = serde_json::to_string(&raw_request).unwrap();
-                return Some(rpc_request);
+        let rpc_subscriber = match factory.rpc_subscriber.as_ref() {
+            Some(rpc_subscriber) => rpc_subscriber,
+            None => return Err(WsError::RpcDisabled),
+        };
+        let mut rpc_subscriber_ref = rpc_subscriber.borrow_mut();
+        let (handle, raw_request) = rpc_subscriber_ref.prepare_request(method.as_str(), rpc_params);
+        let rpc_request = match rpc_subscriber_ref.encode_request(&raw_request) {
+            Ok(rpc_request) => rpc_request,
+            Err(err) => {
+                rpc_subscriber_ref.cancel_request(handle.id());
+                return Err(WsError::from(err));
             }
+        };
+        if let Some(callback) = callback {
+            rpc_subscriber_ref.set_handler(handle.id().clone(), callback);
         }
-        None
+        if let Some(error_callback) = error_callback {
+            rpc_subscriber_ref.set_error_handler(handle.id().clone(), error_callback);
+        }
+        Ok((handle, rpc_request))
+    }
+
+    // Sends an rpc request without per-request handlers, the response is routed
+    // to the method default handlers or the fallback handlers of the subscriber.
+    pub fn send_rpc(
+        &self,
+        method: String,
+        rpc_params: impl IntoParams,
+    ) -> Result<RpcRequestHandle, WsError> {
+        let (handle, rpc_request) = self.prepare_rpc_call(method, rpc_params, None, None)?;
+        self.send_rpc_request(handle, rpc_request)
     }
 
     pub fn send_text_rpc(
         &self,
         method: String,
-        rpc_params: Params,
+        rpc_params: impl IntoParams,
         callback: RPCHandler,
         error_callback: RPCHandler,
-    ) {
-        if let Some(rpc_request) =
-            self.prepare_rpc_request(method, rpc_params, callback, error_callback)
-        {
-            match self.send(WsMessage::Text(rpc_request)) {
-                Ok(_) => {}
-                Err(_) => {}
-            }
-        }
+    ) -> Result<RpcRequestHandle, WsError> {
+        let (handle, rpc_request) =
+            self.prepare_rpc_request(method, rpc_params, callback, error_callback)?;
+        self.send_rpc_request(handle, rpc_request)
+    }
+
+    pub fn send_text_rpc_with_retry(
+        &self,
+        method: String,
+        rpc_params: impl IntoParams,
+        callback: RPCHandler,
+        error_callback: RPCHandler,
+        retry_policy: RetryPolicy,
+    ) -> Result<RpcRequestHandle, WsError> {
+        let (handle, rpc_request) =
+            self.prepare_rpc_request(method, rpc_params, callback, error_callback)?;
+        self.set_rpc_retry(&handle, retry_policy, &rpc_request);
+        self.send_rpc_request(handle, rpc_request)
     }
 
     pub fn send_binary_rpc(
         &self,
         method: String,
-        rpc_params: Params,
+        rpc_params: impl IntoParams,
         callback: RPCHandler,
         error_callback: RPCHandler,
+    ) -> Result<RpcRequestHandle, WsError> {
+        let (handle, rpc_request) =
+            self.prepare_rpc_request(method, rpc_params, callback, error_callback)?;
+        self.send_rpc_request(handle, Self::into_binary(rpc_request))
+    }
+
+    pub fn send_binary_rpc_with_retry(
+        &self,
+        method: String,
+        rpc_params: impl IntoParams,
+        callback: RPCHandler,
+        error_callback: RPCHandler,
+        retry_policy: RetryPolicy,
+    ) -> Result<RpcRequestHandle, WsError> {
+        let (handle, rpc_request) =
+            self.prepare_rpc_request(method, rpc_params, callback, error_callback)?;
+        let rpc_request = Self::into_binary(rpc_request);
+        self.set_rpc_retry(&handle, retry_policy, &rpc_request);
+        self.send_rpc_request(handle, rpc_request)
+    }
+
+    fn into_binary(rpc_request: WsMessage) -> WsMessage {
+        match rpc_request {
+            WsMessage::Text(payload) => WsMessage::Binary(Vec::from(payload)),
+            binary => binary,
+        }
+    }
+
+    fn set_rpc_retry(
+        &self,
+        handle: &RpcRequestHandle,
+        retry_policy: RetryPolicy,
+        rpc_request: &WsMessage,
     ) {
-        if let Some(rpc_request) =
-            self.prepare_rpc_request(method, rpc_params, callback, error_callback)
-        {
-            match self.send(WsMessage::Binary(Vec::from(rpc_request))) {
-                Ok(_) => {}
-                Err(_) => {}
+        if let Some(rpc_subscriber) = self.core.factory.rpc_subscriber.as_ref() {
+            rpc_subscriber.borrow_mut().set_retry(
+                handle.id().clone(),
+                retry_policy,
+                rpc_request.clone(),
+            );
+        }
+    }
+
+    fn send_rpc_request(
+        &self,
+        handle: RpcRequestHandle,
+        rpc_request: WsMessage,
+    ) -> Result<RpcRequestHandle, WsError> {
+        match self.send(rpc_request) {
+            Ok(_) => Ok(handle),
+            Err(err) => {
+                // the request never left the client, nobody will answer it
+                if let Some(rpc_subscriber) = self.core.factory.rpc_subscriber.as_ref() {
+                    rpc_subscriber.borrow_mut().cancel_request(handle.id());
+                }
+                Err(err)
             }
         }
     }
 
+    pub fn on_rpc_raw_result(&self, handle: &RpcRequestHandle, raw_callback: RawRPCHandler) {
+        if let Some(rpc_subscriber) = self.core.factory.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_raw_handler(handle.id().clone(), raw_callback);
+        }
+    }
+
+    pub fn on_rpc_progress(&self, handle: &RpcRequestHandle, progress_callback: RPCHandler) {
+        if let Some(rpc_subscriber) = self.core.factory.rpc_subscriber.as_ref() {
+            rpc_subscriber
+                .borrow_mut()
+                .set_progress_handler(handle.id().clone(), progress_callback);
+        }
+    }
+
+    // Calls `method`, whose result is the subscription id, and streams the
+    // notifications for it. Dropping the stream calls `unsubscribe_method`
+    // with the id.
+    pub fn subscribe_rpc_stream<T: DeserializeOwned + 'static>(
+        &self,
+        method: String,
+        rpc_params: impl IntoParams,
+        unsubscribe_method: &str,
+    ) -> Result<RpcSubscriptionStream<T>, WsError> {
+        RpcSubscriptionStream::subscribe(
+            self.clone(),
+            method,
+            rpc_params,
+            String::from(unsubscribe_method),
+        )
+    }
+
+    pub fn rpc_namespace(&self, namespace: &str) -> RpcNamespace<'_> {
+        RpcNamespace::new(self, String::from(namespace))
+    }
+
+    pub fn pending_rpc(&self) -> Vec<PendingRpc> {
+        match self.core.factory.rpc_subscriber.as_ref() {
+            Some(rpc_subscriber) => rpc_subscriber.borrow().pending_requests(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn rpc_stats(&self) -> HashMap<String, RpcMethodStats> {
+        match self.core.factory.rpc_subscriber.as_ref() {
+            Some(rpc_subscriber) => rpc_subscriber.borrow().stats(),
+            None => HashMap::new(),
+        }
+    }
+
     pub fn url(&self) -> String {
         self.core.websocket.borrow().url()
     }
@@ -145,21 +581,114 @@ impl Websocket {
         }
     }
 
+    // An async alternative to `add_listener`.
+    pub fn listen(&self, handler_name: &str) -> ListenStream {
+        ListenStream::new(self.clone(), String::from(handler_name))
+    }
+
+    pub fn add_filter<F>(&self, handler_name: String, filter: F)
+    where
+        F: Fn(&Payload) -> bool + 'static,
+    {
+        if let Some(emitter) = self.core.factory.emitter.as_ref() {
+            emitter
+                .borrow_mut()
+                .set_filter(handler_name, Box::new(filter));
+        }
+    }
+
+    pub fn remove_filter(&self, handler_name: &str) {
+        if let Some(emitter) = self.core.factory.emitter.as_ref() {
+            emitter.borrow_mut().remove_filter(handler_name);
+        }
+    }
+
+    // Number of payloads dropped by the filters, per handler name.
+    pub fn filtered_counts(&self) -> HashMap<String, u64> {
+        match self.core.factory.emitter.as_ref() {
+            Some(emitter) => emitter.borrow().filtered_counts(),
+            None => HashMap::new(),
+        }
+    }
+
+    pub fn subscribe(&self, name: &str) -> Result<(), WsError> {
+        self.core
+            .update_subscription(SubscriptionAction::Subscribe, name)
+    }
+
+    pub fn unsubscribe(&self, name: &str) -> Result<(), WsError> {
+        self.core
+            .update_subscription(SubscriptionAction::Unsubscribe, name)
+    }
+
+    // The handler gets the inner message of every envelope routed to
+    // `handler_name` by the factory protobuf router.
+    #[cfg(feature = "prost")]
+    pub fn add_protobuf_listener<M, H>(&self, handler_name: String, handler: H)
+    where
+        M: prost::Message + Default,
+        H: Fn(M) + 'static,
+    {
+        self.add_listener(
+            handler_name,
+            move |payload: &Payload| match protobuf::decode_payload::<M>(payload) {
+                Ok(message) => handler(message),
+                Err(err) => console_log!("error decode protobuf message: {}", err),
+            },
+        );
+    }
+
+    // Lifecycle callbacks can be swapped on a live connection, the new one is
+    // used from the next event on.
+    pub fn set_on_open(&self, f: impl FnMut(Event) + 'static) {
+        *self.core.factory.on_open.borrow_mut() = Some(Rc::new(RefCell::new(f)));
+    }
+
+    pub fn set_on_error(&self, f: impl FnMut(ErrorEvent) + 'static) {
+        *self.core.factory.on_error.borrow_mut() = Some(Rc::new(RefCell::new(f)));
+    }
+
+    pub fn set_on_close(&self, f: impl FnMut(CloseEvent) + 'static) {
+        *self.core.factory.on_close.borrow_mut() = Some(Rc::new(RefCell::new(f)));
+    }
+
+    pub fn clear_lifecycle_callbacks(&self) {
+        self.core.factory.on_open.borrow_mut().take();
+        self.core.factory.on_error.borrow_mut().take();
+        self.core.factory.on_close.borrow_mut().take();
+    }
+
+    // Inbound frames are held back, or dropped, until `resume`. The socket
+    // stays open, heartbeats and resubscribes are skipped meanwhile.
+    pub fn pause(&self, policy: PausePolicy) {
+        self.core.pause(policy)
+    }
+
+    pub fn resume(&self) {
+        self.core.resume()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.core.factory.is_paused()
+    }
+
     pub fn ready_state(&self) -> ReadyState {
         ReadyState::from(self.core.websocket.borrow().ready_state())
     }
 
-    pub fn set_binary_type(&self) {
-        self.core
-            .websocket
-            .borrow()
-            .set_binary_type(BinaryType::Arraybuffer)
+    // Only lasts until the next reconnect, use `WsFactory::binary_type` to
+    // keep it.
+    pub fn set_binary_type(&self, binary_type: BinaryType) {
+        self.core.websocket.borrow().set_binary_type(binary_type)
     }
 }
 
 impl Drop for Websocket {
     fn drop(&mut self) {
-        let _ = self.close_from_drop();
+        // a socket closed explicitly is not closed again
+        if self.handle_count() == 1 && !*self.core.factory.is_closing.borrow() {
+            let _ = self.close_from_drop();
+        }
     }
 }
 
@@ -198,3 +727,16 @@ pub enum WsMessage {
     Text(String),
     Binary(Vec<u8>),
 }
+
+impl WsMessage {
+    pub fn len(&self) -> usize {
+        match self {
+            WsMessage::Text(payload) => payload.len(),
+            WsMessage::Binary(payload) => payload.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
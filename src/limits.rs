@@ -0,0 +1,74 @@
+use serde::Serialize;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum OversizePolicy {
+    #[default]
+    Drop,
+    // the first `max_size` bytes are processed, text is cut at a char boundary
+    Truncate,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SizeLimit {
+    pub max_size: usize,
+    pub policy: OversizePolicy,
+}
+
+// Payload of the `message_too_large` event. `byte_length` is a lower bound
+// for text frames that were refused before being copied out of js.
+#[derive(Clone, Debug, Serialize)]
+pub struct MessageTooLarge {
+    pub frame_type: String,
+    pub byte_length: usize,
+    pub max_size: usize,
+    pub truncated: bool,
+}
+
+impl MessageTooLarge {
+    pub fn new(frame_type: &str, byte_length: usize, limit: &SizeLimit) -> Self {
+        Self {
+            frame_type: String::from(frame_type),
+            byte_length,
+            max_size: limit.max_size,
+            truncated: limit.policy == OversizePolicy::Truncate,
+        }
+    }
+}
+
+pub fn truncate_text(mut text: String, max_size: usize) -> String {
+    if text.len() > max_size {
+        let mut end = max_size;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+// `unit` is a utf16 code unit as js hands it out, a slice ending right after
+// a high surrogate splits a pair.
+pub(crate) fn is_high_surrogate(unit: f64) -> bool {
+    (f64::from(0xD800u16)..=f64::from(0xDBFFu16)).contains(&unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_a_char_boundary() {
+        assert_eq!(truncate_text(String::from("abc"), 5), "abc");
+        assert_eq!(truncate_text(String::from("aé"), 2), "a");
+        assert_eq!(truncate_text(String::from("a😀b"), 4), "a");
+        assert_eq!(truncate_text(String::from("a😀b"), 5), "a😀");
+    }
+
+    #[test]
+    fn finds_high_surrogates() {
+        let units: Vec<u16> = "a😀".encode_utf16().collect();
+        assert!(!is_high_surrogate(f64::from(units[0])));
+        assert!(is_high_surrogate(f64::from(units[1])));
+        assert!(!is_high_surrogate(f64::from(units[2])));
+    }
+}
@@ -0,0 +1,723 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+// Rejected CONNECTs are emitted under this event with the return code.
+pub const MQTT_CONNACK_EVENT: &str = "mqtt_connack";
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MqttVersion {
+    V311,
+    V5,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+pub struct MqttConfig {
+    client_id: String,
+    version: MqttVersion,
+    // seconds, 0 turns PINGREQ off
+    keep_alive: u16,
+    credentials: Option<(String, Option<String>)>,
+    clean_session: bool,
+}
+
+impl MqttConfig {
+    pub fn new(client_id: &str) -> Self {
+        Self {
+            client_id: String::from(client_id),
+            version: MqttVersion::V311,
+            keep_alive: 30,
+            credentials: None,
+            clean_session: true,
+        }
+    }
+
+    pub fn version(mut self, version: MqttVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn keep_alive(mut self, seconds: u16) -> Self {
+        self.keep_alive = seconds;
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, password: Option<&str>) -> Self {
+        self.credentials = Some((String::from(username), password.map(String::from)));
+        self
+    }
+
+    // With a persistent session the broker keeps the subscriptions and QoS 1
+    // messages across reconnects.
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn binary(&mut self, value: &[u8]) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.bytes.extend_from_slice(value);
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.binary(value.as_bytes())
+    }
+
+    // The fixed header in front of the variable header and payload.
+    fn packet(self, kind: u8, flags: u8) -> Vec<u8> {
+        let mut packet = vec![kind << 4 | flags];
+        let mut len = self.bytes.len();
+        loop {
+            let mut byte = (len % 128) as u8;
+            len /= 128;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend(self.bytes);
+        packet
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let (value, rest) = self
+            .bytes
+            .split_first()
+            .ok_or_else(|| String::from("packet too short"))?;
+        self.bytes = rest;
+        Ok(*value)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from(self.u8()?) << 8 | u16::from(self.u8()?))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(String::from("packet too short"));
+        }
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = usize::from(self.u16()?);
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|err| err.to_string())
+    }
+
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value = 0;
+        for shift in 0..4 {
+            let byte = self.u8()?;
+            value |= usize::from(byte & 0x7f) << (7 * shift);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("malformed variable byte integer"))
+    }
+
+    // MQTT 5 properties are not used, they are only skipped.
+    fn skip_properties(&mut self, version: MqttVersion) -> Result<(), String> {
+        if version == MqttVersion::V5 {
+            let len = self.varint()?;
+            self.take(len)?;
+        }
+        Ok(())
+    }
+}
+
+// The first byte of the fixed header, the rest of the packet after the fixed
+// header and the length of the whole packet.
+type RawPacket<'a> = (u8, &'a [u8], usize);
+
+// Splits off the first complete packet, `None` while it is still partial.
+fn next_packet(buffer: &[u8]) -> Result<Option<RawPacket<'_>>, String> {
+    let mut reader = Reader {
+        bytes: buffer.get(1..).unwrap_or(&[]),
+    };
+    let len = match reader.varint() {
+        Ok(len) => len,
+        Err(_) if buffer.len() < 5 => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let header = buffer.len() - reader.bytes.len();
+    match buffer.get(header..header + len) {
+        Some(body) => Ok(Some((buffer[0], body, header + len))),
+        None => Ok(None),
+    }
+}
+
+// `+` matches one topic level, a trailing `#` the rest.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (level, Some(topic_level)) if level == topic_level => (),
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+struct Publish {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+}
+
+struct MqttInner {
+    websocket: Websocket,
+    config: MqttConfig,
+    subscriptions: RefCell<BTreeMap<String, QoS>>,
+    // QoS 1 publishes without a PUBACK yet, sent again with DUP after a
+    // reconnect
+    inflight: RefCell<BTreeMap<u16, Publish>>,
+    // publishes made before the session of the current socket started
+    queue: RefCell<VecDeque<Publish>>,
+    // a websocket message can end in the middle of a packet
+    incoming: RefCell<Vec<u8>>,
+    next_packet_id: Cell<u16>,
+    connected: Cell<bool>,
+    // bumped on every close, stops the PINGREQs of the previous session
+    session: Cell<u64>,
+    watchdog: Cell<Option<i32>>,
+}
+
+impl MqttInner {
+    fn send(&self, packet: Vec<u8>) -> Result<(), WsError> {
+        self.websocket.send(WsMessage::Binary(packet))
+    }
+
+    fn properties(&self, writer: &mut Writer) {
+        if self.config.version == MqttVersion::V5 {
+            writer.u8(0);
+        }
+    }
+
+    fn packet_id(&self) -> u16 {
+        // 0 is not a valid packet id
+        let id = self.next_packet_id.get().max(1);
+        self.next_packet_id.set(id.wrapping_add(1));
+        id
+    }
+
+    fn connect(&self) {
+        let config = &self.config;
+        let mut flags = 0;
+        if config.clean_session {
+            flags |= 0x02;
+        }
+        if let Some((_, password)) = config.credentials.as_ref() {
+            flags |= 0x80;
+            if password.is_some() {
+                flags |= 0x40;
+            }
+        }
+        let mut writer = Writer::new();
+        writer
+            .string("MQTT")
+            .u8(match config.version {
+                MqttVersion::V311 => 4,
+                MqttVersion::V5 => 5,
+            })
+            .u8(flags)
+            .u16(config.keep_alive);
+        self.properties(&mut writer);
+        writer.string(&config.client_id);
+        if let Some((username, password)) = config.credentials.as_ref() {
+            writer.string(username);
+            if let Some(password) = password {
+                writer.string(password);
+            }
+        }
+        if let Err(err) = self.send(writer.packet(CONNECT, 0)) {
+            console_log!("error on mqtt connect: {:?}", err);
+        }
+    }
+
+    fn disconnected(&self) {
+        self.connected.set(false);
+        self.session.set(self.session.get() + 1);
+        self.incoming.borrow_mut().clear();
+        if let Some(id) = self.watchdog.take() {
+            clear_timeout(id);
+        }
+    }
+
+    fn subscribe_packet(&self, filters: &[(String, QoS)]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.u16(self.packet_id());
+        self.properties(&mut writer);
+        for (filter, qos) in filters {
+            writer.string(filter).u8(*qos as u8);
+        }
+        writer.packet(SUBSCRIBE, 0x02)
+    }
+
+    fn publish_packet(&self, publish: &Publish, packet_id: Option<u16>, dup: bool) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.string(&publish.topic);
+        if let Some(packet_id) = packet_id {
+            writer.u16(packet_id);
+        }
+        self.properties(&mut writer);
+        writer.bytes.extend_from_slice(&publish.payload);
+        let mut flags = (publish.qos as u8) << 1;
+        if dup {
+            flags |= 0x08;
+        }
+        if publish.retain {
+            flags |= 0x01;
+        }
+        writer.packet(PUBLISH, flags)
+    }
+
+    fn publish(&self, publish: Publish) -> Result<(), WsError> {
+        if !self.is_open() {
+            self.queue.borrow_mut().push_back(publish);
+            return Ok(());
+        }
+        let packet_id = match publish.qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(self.packet_id()),
+        };
+        let packet = self.publish_packet(&publish, packet_id, false);
+        if let Some(packet_id) = packet_id {
+            self.inflight.borrow_mut().insert(packet_id, publish);
+        }
+        self.send(packet)
+    }
+
+    fn receive(inner: &Rc<Self>, message: WsMessage) {
+        let bytes = match message {
+            WsMessage::Binary(bytes) => bytes,
+            WsMessage::Text(_) => {
+                console_log!("text frames are not mqtt packets");
+                return;
+            }
+        };
+        inner.reset_watchdog();
+        let mut buffer = std::mem::take(&mut *inner.incoming.borrow_mut());
+        buffer.extend(bytes);
+        let mut offset = 0;
+        loop {
+            match next_packet(&buffer[offset..]) {
+                Ok(Some((header, body, len))) => {
+                    if let Err(err) = Self::dispatch(inner, header, body) {
+                        console_log!("error on parse mqtt packet: {}", err);
+                    }
+                    offset += len;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    console_log!("error on parse mqtt packet: {}", err);
+                    return;
+                }
+            }
+        }
+        buffer.drain(..offset);
+        *inner.incoming.borrow_mut() = buffer;
+    }
+
+    fn dispatch(inner: &Rc<Self>, header: u8, body: &[u8]) -> Result<(), String> {
+        let version = inner.config.version;
+        let mut reader = Reader { bytes: body };
+        match header >> 4 {
+            CONNACK => {
+                reader.u8()?;
+                let code = reader.u8()?;
+                if code != 0 {
+                    inner.emit(MQTT_CONNACK_EVENT, Payload::Data(code.to_string()));
+                    return Ok(());
+                }
+                Self::session_started(inner);
+            }
+            PUBLISH => {
+                let qos = (header >> 1) & 0x03;
+                let topic = reader.string()?;
+                let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+                reader.skip_properties(version)?;
+                let payload = reader.bytes.to_vec();
+                if let Some(packet_id) = packet_id {
+                    let mut writer = Writer::new();
+                    writer.u16(packet_id);
+                    if let Err(err) = inner.send(writer.packet(PUBACK, 0)) {
+                        console_log!("error on mqtt puback: {:?}", err);
+                    }
+                }
+                let mut events = vec![topic.clone()];
+                events.extend(
+                    inner
+                        .subscriptions
+                        .borrow()
+                        .keys()
+                        .filter(|filter| *filter != &topic && topic_matches(filter, &topic))
+                        .cloned(),
+                );
+                for event in events {
+                    inner.emit(&event, Payload::Bytes(payload.clone()));
+                }
+            }
+            PUBACK => {
+                let packet_id = reader.u16()?;
+                inner.inflight.borrow_mut().remove(&packet_id);
+            }
+            // refused filters come back with 0x80 and up, the broker logs them
+            SUBACK | UNSUBACK | PINGRESP => (),
+            DISCONNECT => console_log!("mqtt broker disconnected"),
+            kind => return Err(format!("unexpected packet type {}", kind)),
+        }
+        Ok(())
+    }
+
+    fn emit(&self, event: &str, payload: Payload) {
+        if let Some(emitter) = self.websocket.core.factory.emitter.clone() {
+            emitter.borrow().emit(String::from(event), &payload);
+        }
+    }
+
+    // Subscriptions are restored on every new session, then unacknowledged
+    // and queued publishes go out.
+    fn session_started(inner: &Rc<Self>) {
+        inner.connected.set(true);
+        if inner.config.keep_alive > 0 {
+            Self::ping(
+                Rc::downgrade(inner),
+                u32::from(inner.config.keep_alive) * 1000,
+            );
+        }
+        inner.reset_watchdog();
+        let filters: Vec<(String, QoS)> = inner
+            .subscriptions
+            .borrow()
+            .iter()
+            .map(|(filter, qos)| (filter.clone(), *qos))
+            .collect();
+        if !filters.is_empty() {
+            if let Err(err) = inner.send(inner.subscribe_packet(&filters)) {
+                console_log!("error on mqtt subscribe: {:?}", err);
+            }
+        }
+        let inflight: Vec<Vec<u8>> = inner
+            .inflight
+            .borrow()
+            .iter()
+            .map(|(packet_id, publish)| inner.publish_packet(publish, Some(*packet_id), true))
+            .collect();
+        for packet in inflight {
+            if let Err(err) = inner.send(packet) {
+                console_log!("error on mqtt publish: {:?}", err);
+            }
+        }
+        let queued: Vec<Publish> = inner.queue.borrow_mut().drain(..).collect();
+        for publish in queued {
+            if let Err(err) = inner.publish(publish) {
+                console_log!("error on mqtt publish: {:?}", err);
+            }
+        }
+    }
+
+    fn ping(inner: Weak<Self>, interval: u32) {
+        let session = match inner.upgrade() {
+            Some(inner) => inner.session.get(),
+            None => return,
+        };
+        set_timeout_once(
+            move || {
+                let mqtt = match inner.upgrade() {
+                    Some(mqtt) if mqtt.session.get() == session => mqtt,
+                    _ => return,
+                };
+                if let Err(err) = mqtt.send(Writer::new().packet(PINGREQ, 0)) {
+                    console_log!("error on mqtt pingreq: {:?}", err);
+                }
+                Self::ping(inner, interval);
+            },
+            interval,
+        );
+    }
+
+    // The broker answers every PINGREQ, so without any packet for one and a
+    // half keep alive intervals the socket counts as dead.
+    fn reset_watchdog(&self) {
+        if let Some(id) = self.watchdog.take() {
+            clear_timeout(id);
+        }
+        if !self.connected.get() || self.config.keep_alive == 0 {
+            return;
+        }
+        let core = Rc::downgrade(&self.websocket.core);
+        let id = set_timeout_once(
+            move || {
+                if let Some(core) = core.upgrade() {
+                    console_log!("no mqtt packet in time, reconnecting");
+                    force_reconnect(&core);
+                }
+            },
+            u32::from(self.config.keep_alive) * 1500,
+        );
+        self.watchdog.set(Some(id));
+    }
+
+    fn is_open(&self) -> bool {
+        self.connected.get() && matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+}
+
+// An MQTT 3.1.1 or 5 client over binary frames of the reconnecting socket.
+// PUBLISH payloads are emitted as bytes under their topic, and under every
+// wildcard filter subscribed to that matches it. Every open sends a CONNECT,
+// subscriptions and unacknowledged QoS 1 publishes go out again after the
+// CONNACK, and PINGREQ at the keep alive interval checks the link. QoS 2 is
+// not supported.
+#[derive(Clone)]
+pub struct MqttClient {
+    inner: Rc<MqttInner>,
+}
+
+impl MqttClient {
+    pub fn build(mut factory: WsFactory, config: MqttConfig) -> Result<Self, WsError> {
+        if factory.socket_factory.is_none() {
+            factory = factory.socket_factory(|url| WebSocket::new_with_str(url, MQTT_SUBPROTOCOL));
+        }
+        let inner = ProtocolClient::new(factory, MqttInner::receive)
+            .on_open(|mqtt: &Rc<MqttInner>| mqtt.connect())
+            .on_close(|mqtt: &Rc<MqttInner>| mqtt.disconnected())
+            .build(|websocket| MqttInner {
+                websocket,
+                config,
+                subscriptions: RefCell::new(BTreeMap::new()),
+                inflight: RefCell::new(BTreeMap::new()),
+                queue: RefCell::new(VecDeque::new()),
+                incoming: RefCell::new(Vec::new()),
+                next_packet_id: Cell::new(1),
+                connected: Cell::new(false),
+                session: Cell::new(0),
+                watchdog: Cell::new(None),
+            })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected.get()
+    }
+
+    // Restored on every new session until it is unsubscribed.
+    pub fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), WsError> {
+        self.inner
+            .subscriptions
+            .borrow_mut()
+            .insert(String::from(filter), qos);
+        if !self.inner.is_open() {
+            return Ok(());
+        }
+        let packet = self.inner.subscribe_packet(&[(String::from(filter), qos)]);
+        self.inner.send(packet)
+    }
+
+    pub fn unsubscribe(&self, filter: &str) -> Result<(), WsError> {
+        let subscription = self.inner.subscriptions.borrow_mut().remove(filter);
+        if subscription.is_none() || !self.inner.is_open() {
+            return Ok(());
+        }
+        let mut writer = Writer::new();
+        writer.u16(self.inner.packet_id());
+        self.inner.properties(&mut writer);
+        writer.string(filter);
+        self.inner.send(writer.packet(UNSUBSCRIBE, 0x02))
+    }
+
+    // Queued until the session of the current socket started. QoS 1
+    // publishes are sent again until the broker acknowledged them.
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), WsError> {
+        self.inner.publish(Publish {
+            topic: String::from(topic),
+            payload,
+            qos,
+            retain,
+        })
+    }
+
+    pub fn filters(&self) -> Vec<String> {
+        self.inner.subscriptions.borrow().keys().cloned().collect()
+    }
+
+    pub fn inflight_count(&self) -> usize {
+        self.inner.inflight.borrow().len()
+    }
+
+    // Ends the session cleanly, the socket stays open.
+    pub fn disconnect(&self) -> Result<(), WsError> {
+        if !self.inner.is_open() {
+            return Ok(());
+        }
+        self.inner.connected.set(false);
+        self.inner.send(Writer::new().packet(DISCONNECT, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_fields_in_order() {
+        let mut reader = Reader {
+            bytes: &[0x01, 0x02, 0x03, 0x00, 0x02, b'h', b'i', 0x80, 0x01],
+        };
+        assert_eq!(reader.u8(), Ok(0x01));
+        assert_eq!(reader.u16(), Ok(0x0203));
+        assert_eq!(reader.string(), Ok(String::from("hi")));
+        assert_eq!(reader.varint(), Ok(128));
+        assert!(reader.u8().is_err());
+    }
+
+    #[test]
+    fn rejects_short_and_malformed_fields() {
+        let mut reader = Reader {
+            bytes: &[0x00, 0x05, b'h'],
+        };
+        assert!(reader.string().is_err());
+        let mut reader = Reader {
+            bytes: &[0xff, 0xff, 0xff, 0xff, 0x01],
+        };
+        assert!(reader.varint().is_err());
+    }
+
+    #[test]
+    fn skips_properties_of_mqtt_5_only() {
+        let mut reader = Reader {
+            bytes: &[0x02, 0xaa, 0xbb, 0x07],
+        };
+        assert_eq!(reader.skip_properties(MqttVersion::V5), Ok(()));
+        assert_eq!(reader.u8(), Ok(0x07));
+        let mut reader = Reader { bytes: &[0x02] };
+        assert_eq!(reader.skip_properties(MqttVersion::V311), Ok(()));
+        assert_eq!(reader.u8(), Ok(0x02));
+    }
+
+    #[test]
+    fn splits_off_complete_packets() {
+        let mut writer = Writer::new();
+        writer.u16(7);
+        let mut buffer = writer.packet(4, 0);
+        buffer.extend_from_slice(&[0xd0, 0x00]);
+        let (header, body, len) = next_packet(&buffer).unwrap().unwrap();
+        assert_eq!((header, body, len), (0x40, &[0x00, 0x07][..], 4));
+        let (header, body, len) = next_packet(&buffer[len..]).unwrap().unwrap();
+        assert_eq!((header, body, len), (0xd0, &[][..], 2));
+    }
+
+    #[test]
+    fn waits_for_partial_packets() {
+        assert_eq!(next_packet(&[]), Ok(None));
+        assert_eq!(next_packet(&[0x30]), Ok(None));
+        assert_eq!(next_packet(&[0x30, 0x80]), Ok(None));
+        assert_eq!(next_packet(&[0x30, 0x03, 0x00]), Ok(None));
+        let long = Writer {
+            bytes: vec![0; 200],
+        }
+        .packet(3, 0);
+        assert_eq!(next_packet(&long[..100]), Ok(None));
+        assert_eq!(next_packet(&long).unwrap().unwrap().2, 203);
+        assert!(next_packet(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[test]
+    fn matches_topic_filters() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(topic_matches("+/+", "a/"));
+    }
+}
@@ -0,0 +1,366 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::close::CloseCode;
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::{ReadyState, Websocket};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+// Channel frames travel as `{"mux": {"channel": <id>, ...}}`.
+pub const MUX_EVENT: &str = "mux";
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChannelAction {
+    Open,
+    Close,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MuxFrame {
+    channel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    action: Option<ChannelAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl MuxFrame {
+    fn action(channel: &str, action: ChannelAction) -> Self {
+        Self {
+            channel: String::from(channel),
+            action: Some(action),
+            event: None,
+            data: None,
+        }
+    }
+}
+
+type ChannelListener = Rc<dyn Fn(&str)>;
+
+struct ChannelState {
+    listeners: RefCell<HashMap<String, ChannelListener>>,
+    // sends made before the channel was (re)opened on the current socket
+    queue: RefCell<VecDeque<MuxFrame>>,
+    open: Cell<bool>,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            listeners: RefCell::new(HashMap::new()),
+            queue: RefCell::new(VecDeque::new()),
+            open: Cell::new(false),
+        }
+    }
+
+    // Marks the channel open and hands back what was queued until then.
+    fn reopen(&self) -> Vec<MuxFrame> {
+        self.open.set(true);
+        self.queue.borrow_mut().drain(..).collect()
+    }
+
+    // An `Open` from the server reopens the channel and hands back the queued
+    // sends, a `Close` makes later sends queue.
+    fn server_action(&self, action: ChannelAction) -> Vec<MuxFrame> {
+        match action {
+            ChannelAction::Open => self.reopen(),
+            ChannelAction::Close => {
+                self.open.set(false);
+                Vec::new()
+            }
+        }
+    }
+
+    // Queues the frame while the channel is not open, or hands it back to be
+    // sent.
+    fn outgoing(&self, frame: MuxFrame) -> Option<MuxFrame> {
+        if self.open.get() {
+            return Some(frame);
+        }
+        self.queue.borrow_mut().push_back(frame);
+        None
+    }
+}
+
+struct MuxInner {
+    websocket: Websocket,
+    channels: RefCell<HashMap<String, Rc<ChannelState>>>,
+}
+
+impl MuxInner {
+    fn send(&self, frame: &MuxFrame) -> Result<(), WsError> {
+        let frame = serde_json::to_string(frame)?;
+        self.websocket.send_event(MUX_EVENT, frame)
+    }
+
+    // Every channel is opened again on a new socket, then its queue is sent.
+    fn open_channels(&self) {
+        let channels: Vec<(String, Rc<ChannelState>)> = self
+            .channels
+            .borrow()
+            .iter()
+            .map(|(id, channel)| (id.clone(), channel.clone()))
+            .collect();
+        for (id, channel) in channels {
+            self.open_channel(&id, &channel);
+        }
+    }
+
+    fn open_channel(&self, id: &str, channel: &ChannelState) {
+        if !matches!(self.websocket.ready_state(), ReadyState::Open) {
+            return;
+        }
+        if let Err(err) = self.send(&MuxFrame::action(id, ChannelAction::Open)) {
+            console_log!("error on open channel {}: {:?}", id, err);
+            return;
+        }
+        self.flush(id, channel.reopen());
+    }
+
+    fn flush(&self, id: &str, queued: Vec<MuxFrame>) {
+        for frame in queued {
+            if let Err(err) = self.send(&frame) {
+                console_log!("error on send to channel {}: {:?}", id, err);
+            }
+        }
+    }
+
+    fn close_channels(&self) {
+        for channel in self.channels.borrow().values() {
+            channel.open.set(false);
+        }
+    }
+
+    fn dispatch(&self, data: &str) {
+        let frame: MuxFrame = match serde_json::from_str(data) {
+            Ok(frame) => frame,
+            Err(err) => {
+                console_log!("error on parse channel frame {}: {:?}", data, err);
+                return;
+            }
+        };
+        let channel = match self.channels.borrow().get(&frame.channel) {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        let (event, data) = match (frame.action, frame.event) {
+            // sends made while the server had the channel closed go out once
+            // it reopens it
+            (Some(action), _) => {
+                self.flush(&frame.channel, channel.server_action(action));
+                let event = match action {
+                    ChannelAction::Open => "open",
+                    ChannelAction::Close => "close",
+                };
+                (String::from(event), String::new())
+            }
+            (None, Some(event)) => (event, frame.data.unwrap_or(Value::Null).to_string()),
+            (None, None) => return,
+        };
+        let listener = channel.listeners.borrow().get(&event).cloned();
+        if let Some(listener) = listener {
+            listener(&data);
+        }
+    }
+}
+
+// Runs independent channels over one reconnecting socket, so separate parts
+// of an app don't each open their own connection. The multiplexer owns the
+// `open`, `close` and `mux` listeners of the socket.
+#[derive(Clone)]
+pub struct Multiplexer {
+    inner: Rc<MuxInner>,
+}
+
+impl Multiplexer {
+    pub fn build(factory: WsFactory) -> Result<Self, WsError> {
+        let websocket = factory.build()?;
+        let inner = Rc::new(MuxInner {
+            websocket,
+            channels: RefCell::new(HashMap::new()),
+        });
+        let mux: Weak<MuxInner> = Rc::downgrade(&inner);
+        inner
+            .websocket
+            .add_listener(String::from(MUX_EVENT), move |payload| {
+                if let (Some(mux), Payload::Data(data)) = (mux.upgrade(), payload) {
+                    mux.dispatch(data);
+                }
+            });
+        let mux = Rc::downgrade(&inner);
+        inner
+            .websocket
+            .add_listener(String::from("open"), move |_| {
+                if let Some(mux) = mux.upgrade() {
+                    mux.open_channels();
+                }
+            });
+        let mux = Rc::downgrade(&inner);
+        inner
+            .websocket
+            .add_listener(String::from("close"), move |_| {
+                if let Some(mux) = mux.upgrade() {
+                    mux.close_channels();
+                }
+            });
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    // Opens the channel, or returns another handle to it when it is open
+    // already.
+    pub fn channel(&self, id: &str) -> Channel {
+        let existing = self.inner.channels.borrow().get(id).cloned();
+        if existing.is_none() {
+            let channel = Rc::new(ChannelState::new());
+            self.inner
+                .channels
+                .borrow_mut()
+                .insert(String::from(id), channel.clone());
+            self.inner.open_channel(id, &channel);
+        }
+        Channel {
+            id: String::from(id),
+            mux: self.inner.clone(),
+        }
+    }
+
+    pub fn channel_ids(&self) -> Vec<String> {
+        self.inner.channels.borrow().keys().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Channel {
+    id: String,
+    mux: Rc<MuxInner>,
+}
+
+impl Channel {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn state(&self) -> Option<Rc<ChannelState>> {
+        self.mux.channels.borrow().get(&self.id).cloned()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state().is_some_and(|channel| channel.open.get())
+    }
+
+    // `open` and `close` are called when the server opens or closes the
+    // channel, every other event gets the json data of the frame.
+    pub fn add_listener(&self, event: &str, f: impl Fn(&str) + 'static) {
+        if let Some(channel) = self.state() {
+            channel
+                .listeners
+                .borrow_mut()
+                .insert(String::from(event), Rc::new(f));
+        }
+    }
+
+    pub fn remove_listener(&self, event: &str) {
+        if let Some(channel) = self.state() {
+            channel.listeners.borrow_mut().remove(event);
+        }
+    }
+
+    // Data that is valid json is embedded as it is, anything else as a string.
+    // Queued while the channel is not open on the current socket.
+    pub fn send(&self, event: &str, data: String) -> Result<(), WsError> {
+        let channel = self.state().ok_or_else(|| WsError::Closed {
+            code: CloseCode::Normal,
+            reason: format!("channel {} is closed", self.id),
+        })?;
+        let data = serde_json::from_str::<Value>(&data).unwrap_or(Value::String(data));
+        let frame = MuxFrame {
+            channel: self.id.clone(),
+            action: None,
+            event: Some(String::from(event)),
+            data: Some(data),
+        };
+        match channel.outgoing(frame) {
+            Some(frame) => self.mux.send(&frame),
+            None => Ok(()),
+        }
+    }
+
+    // Closes the channel for every handle, queued sends are dropped.
+    pub fn close(self) -> Result<(), WsError> {
+        let channel = self.mux.channels.borrow_mut().remove(&self.id);
+        match channel {
+            Some(channel) if channel.open.get() => self
+                .mux
+                .send(&MuxFrame::action(&self.id, ChannelAction::Close)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_frame(event: &str) -> MuxFrame {
+        MuxFrame {
+            channel: String::from("chat"),
+            action: None,
+            event: Some(String::from(event)),
+            data: None,
+        }
+    }
+
+    fn events(frames: Vec<MuxFrame>) -> Vec<String> {
+        frames.into_iter().filter_map(|frame| frame.event).collect()
+    }
+
+    #[test]
+    fn sends_queue_while_closed_and_go_out_once_reopened() {
+        let channel = ChannelState::new();
+        assert!(channel.outgoing(event_frame("before")).is_none());
+        assert_eq!(events(channel.reopen()), ["before"]);
+        assert!(channel.outgoing(event_frame("open")).is_some());
+    }
+
+    #[test]
+    fn server_close_then_open_flushes_the_queue() {
+        let channel = ChannelState::new();
+        channel.reopen();
+        assert!(channel.server_action(ChannelAction::Close).is_empty());
+        assert!(channel.outgoing(event_frame("first")).is_none());
+        assert!(channel.outgoing(event_frame("second")).is_none());
+        assert_eq!(
+            events(channel.server_action(ChannelAction::Open)),
+            ["first", "second"]
+        );
+        assert!(channel.open.get());
+        let sent = channel
+            .outgoing(event_frame("after"))
+            .map(|frame| frame.event);
+        assert_eq!(sent, Some(Some(String::from("after"))));
+        assert!(channel.queue.borrow().is_empty());
+    }
+}
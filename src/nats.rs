@@ -0,0 +1,563 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::set_timeout_once;
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+// `-ERR` lines of the server are emitted under this event with their message.
+pub const NATS_ERROR_EVENT: &str = "nats_error";
+
+const CRLF: &[u8] = b"\r\n";
+
+// the nats-server default, used until the INFO of the connection comes
+const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+pub struct NatsConfig {
+    name: Option<String>,
+    credentials: Option<(String, String)>,
+    auth_token: Option<String>,
+    // ms, 0 turns the client PINGs off
+    ping_interval: u32,
+    // unanswered PINGs before the connection counts as stale
+    max_pings_out: u32,
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            credentials: None,
+            auth_token: None,
+            ping_interval: 120_000,
+            max_pings_out: 2,
+        }
+    }
+}
+
+impl NatsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    pub fn credentials(mut self, user: &str, pass: &str) -> Self {
+        self.credentials = Some((String::from(user), String::from(pass)));
+        self
+    }
+
+    // Without one the factory token is sent, so a token provider keeps it
+    // fresh across reconnects.
+    pub fn auth_token(mut self, token: &str) -> Self {
+        self.auth_token = Some(String::from(token));
+        self
+    }
+
+    pub fn ping_interval(mut self, ms: u32, max_pings_out: u32) -> Self {
+        self.ping_interval = ms;
+        self.max_pings_out = max_pings_out;
+        self
+    }
+}
+
+// `*` matches one token, a trailing `>` one or more.
+pub fn subject_matches(subscription: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for pattern in subscription.split('.') {
+        match (pattern, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => (),
+            (pattern, Some(token)) if pattern == token => (),
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+struct Subscription {
+    subject: String,
+    queue_group: Option<String>,
+}
+
+impl Subscription {
+    fn sub_line(&self, sid: u64) -> Vec<u8> {
+        let line = match self.queue_group.as_ref() {
+            Some(queue_group) => format!("SUB {} {} {}\r\n", self.subject, queue_group, sid),
+            None => format!("SUB {} {}\r\n", self.subject, sid),
+        };
+        line.into_bytes()
+    }
+}
+
+enum Op {
+    Info {
+        max_payload: Option<usize>,
+    },
+    Msg {
+        subject: String,
+        sid: u64,
+        payload: Vec<u8>,
+    },
+    Ping,
+    Pong,
+    Ok,
+    Err(String),
+}
+
+// Splits off the first complete operation and its length, `None` while it is
+// still partial. MSG payloads above `max_payload` are rejected, the stream
+// can't be trusted after that.
+fn next_op(buffer: &[u8], max_payload: usize) -> Result<Option<(Op, usize)>, String> {
+    let end = match buffer.windows(2).position(|window| window == CRLF) {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let line = std::str::from_utf8(&buffer[..end]).map_err(|err| err.to_string())?;
+    let (name, args) = line.split_at(line.find(' ').unwrap_or(line.len()));
+    let args = args.trim();
+    let op = match name.to_ascii_uppercase().as_str() {
+        "INFO" => Op::Info {
+            max_payload: serde_json::from_str::<Value>(args)
+                .ok()
+                .and_then(|info| info.get("max_payload")?.as_u64())
+                .map(|max_payload| max_payload as usize),
+        },
+        "PING" => Op::Ping,
+        "PONG" => Op::Pong,
+        "+OK" => Op::Ok,
+        "-ERR" => Op::Err(String::from(args.trim_matches('\''))),
+        "MSG" => {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            if parts.len() < 3 || parts.len() > 4 {
+                return Err(format!("malformed MSG line {}", line));
+            }
+            let sid = parts[1]
+                .parse()
+                .map_err(|_| format!("bad sid in {}", line))?;
+            let size: usize = parts[parts.len() - 1]
+                .parse()
+                .map_err(|_| format!("bad size in {}", line))?;
+            if size > max_payload {
+                return Err(format!(
+                    "payload over max_payload {} in {}",
+                    max_payload, line
+                ));
+            }
+            let start = end + CRLF.len();
+            let op_end = start
+                .checked_add(size)
+                .and_then(|op_end| op_end.checked_add(CRLF.len()))
+                .ok_or_else(|| format!("bad size in {}", line))?;
+            if buffer.len() < op_end {
+                return Ok(None);
+            }
+            let op = Op::Msg {
+                subject: String::from(parts[0]),
+                sid,
+                payload: buffer[start..start + size].to_vec(),
+            };
+            return Ok(Some((op, op_end)));
+        }
+        _ => return Err(format!("unknown operation {}", line)),
+    };
+    Ok(Some((op, end + CRLF.len())))
+}
+
+struct NatsInner {
+    websocket: Websocket,
+    config: NatsConfig,
+    subscriptions: RefCell<BTreeMap<u64, Subscription>>,
+    next_sid: Cell<u64>,
+    // publishes made before the connection of the current socket started
+    queue: RefCell<VecDeque<Vec<u8>>>,
+    // a websocket message can end in the middle of an operation
+    incoming: RefCell<Vec<u8>>,
+    // from the INFO of the current connection
+    max_payload: Cell<usize>,
+    connected: Cell<bool>,
+    pings_out: Cell<u32>,
+    // bumped on every close, stops the PINGs of the previous connection
+    session: Cell<u64>,
+}
+
+impl NatsInner {
+    fn send(&self, data: Vec<u8>) -> Result<(), WsError> {
+        self.websocket.send(WsMessage::Binary(data))
+    }
+
+    fn is_open(&self) -> bool {
+        self.connected.get() && matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+
+    fn connect(inner: &Rc<Self>) {
+        let config = &inner.config;
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+            "headers": false,
+        });
+        if let Some(name) = config.name.as_ref() {
+            options["name"] = json!(name);
+        }
+        if let Some((user, pass)) = config.credentials.as_ref() {
+            options["user"] = json!(user);
+            options["pass"] = json!(pass);
+        }
+        let token = config
+            .auth_token
+            .clone()
+            .or_else(|| inner.websocket.core.factory.token.borrow().clone());
+        if let Some(token) = token {
+            options["auth_token"] = json!(token);
+        }
+        // the server takes operations right after CONNECT, so the
+        // subscriptions and the queue go out without waiting for a reply
+        let mut data = format!("CONNECT {}\r\n", options).into_bytes();
+        for (sid, subscription) in inner.subscriptions.borrow().iter() {
+            data.extend(subscription.sub_line(*sid));
+        }
+        for publish in inner.queue.borrow_mut().drain(..) {
+            data.extend(publish);
+        }
+        if let Err(err) = inner.send(data) {
+            console_log!("error on nats connect: {:?}", err);
+            return;
+        }
+        inner.connected.set(true);
+        inner.pings_out.set(0);
+        if config.ping_interval > 0 {
+            Self::ping(Rc::downgrade(inner));
+        }
+    }
+
+    fn disconnected(&self) {
+        self.connected.set(false);
+        self.session.set(self.session.get() + 1);
+        self.incoming.borrow_mut().clear();
+        self.max_payload.set(DEFAULT_MAX_PAYLOAD);
+    }
+
+    // Every PING is answered, too many unanswered ones mean the socket is
+    // dead.
+    fn ping(inner: Weak<Self>) {
+        let (session, interval) = match inner.upgrade() {
+            Some(inner) => (inner.session.get(), inner.config.ping_interval),
+            None => return,
+        };
+        set_timeout_once(
+            move || {
+                let nats = match inner.upgrade() {
+                    Some(nats) if nats.session.get() == session => nats,
+                    _ => return,
+                };
+                if nats.pings_out.get() >= nats.config.max_pings_out {
+                    console_log!("no nats PONG in time, reconnecting");
+                    nats.reconnect();
+                    return;
+                }
+                nats.pings_out.set(nats.pings_out.get() + 1);
+                if let Err(err) = nats.send(b"PING\r\n".to_vec()) {
+                    console_log!("error on nats ping: {:?}", err);
+                }
+                Self::ping(inner);
+            },
+            interval,
+        );
+    }
+
+    fn reconnect(&self) {
+        force_reconnect(&self.websocket.core);
+    }
+
+    fn receive(inner: &Rc<Self>, message: WsMessage) {
+        let bytes = match message {
+            WsMessage::Binary(bytes) => bytes,
+            WsMessage::Text(text) => text.into_bytes(),
+        };
+        let mut buffer = std::mem::take(&mut *inner.incoming.borrow_mut());
+        buffer.extend(bytes);
+        let mut offset = 0;
+        loop {
+            match next_op(&buffer[offset..], inner.max_payload.get()) {
+                Ok(Some((op, len))) => {
+                    Self::dispatch(inner, op);
+                    offset += len;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    console_log!("error on parse nats operation: {}, reconnecting", err);
+                    inner.reconnect();
+                    return;
+                }
+            }
+        }
+        buffer.drain(..offset);
+        *inner.incoming.borrow_mut() = buffer;
+    }
+
+    fn dispatch(inner: &Rc<Self>, op: Op) {
+        match op {
+            // the server sends INFO first on every connection, and again when
+            // the cluster changes
+            Op::Info { max_payload } => {
+                if let Some(max_payload) = max_payload {
+                    inner.max_payload.set(max_payload);
+                }
+                if !inner.connected.get() {
+                    Self::connect(inner);
+                }
+            }
+            Op::Msg {
+                subject,
+                sid,
+                payload,
+            } => {
+                let subscription = inner
+                    .subscriptions
+                    .borrow()
+                    .get(&sid)
+                    .map(|subscription| subscription.subject.clone());
+                let subscription = match subscription {
+                    Some(subscription) => subscription,
+                    None => return,
+                };
+                if subscription != subject {
+                    inner.emit(&subscription, Payload::Bytes(payload.clone()));
+                }
+                inner.emit(&subject, Payload::Bytes(payload));
+            }
+            Op::Ping => {
+                if let Err(err) = inner.send(b"PONG\r\n".to_vec()) {
+                    console_log!("error on nats pong: {:?}", err);
+                }
+            }
+            Op::Pong => inner.pings_out.set(0),
+            Op::Ok => (),
+            Op::Err(message) => {
+                console_log!("nats error: {}", message);
+                inner.emit(NATS_ERROR_EVENT, Payload::Data(message));
+            }
+        }
+    }
+
+    fn emit(&self, event: &str, payload: Payload) {
+        if let Some(emitter) = self.websocket.core.factory.emitter.clone() {
+            emitter.borrow().emit(String::from(event), &payload);
+        }
+    }
+}
+
+// A NATS client over the websocket port of a nats-server. Messages are
+// emitted as bytes under their subject, and under the subject of the
+// subscription when it has wildcards. Subscriptions are sent again on every
+// new connection, right behind the CONNECT that answers the INFO of the
+// server. PINGs at the configured interval find stale connections, and so
+// does a byte stream that can't be parsed, both get the socket reconnected.
+// Headers are not supported.
+#[derive(Clone)]
+pub struct NatsClient {
+    inner: Rc<NatsInner>,
+}
+
+impl NatsClient {
+    pub fn build(factory: WsFactory, config: NatsConfig) -> Result<Self, WsError> {
+        let inner = ProtocolClient::new(factory, NatsInner::receive)
+            .on_close(|nats: &Rc<NatsInner>| nats.disconnected())
+            .build(|websocket| NatsInner {
+                websocket,
+                config,
+                subscriptions: RefCell::new(BTreeMap::new()),
+                next_sid: Cell::new(1),
+                queue: RefCell::new(VecDeque::new()),
+                incoming: RefCell::new(Vec::new()),
+                max_payload: Cell::new(DEFAULT_MAX_PAYLOAD),
+                connected: Cell::new(false),
+                pings_out: Cell::new(0),
+                session: Cell::new(0),
+            })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.is_open()
+    }
+
+    // Returns the sid of the subscription, it is restored on every new
+    // connection until it is unsubscribed.
+    pub fn subscribe(&self, subject: &str, queue_group: Option<&str>) -> Result<u64, WsError> {
+        let sid = self.inner.next_sid.get();
+        self.inner.next_sid.set(sid + 1);
+        let subscription = Subscription {
+            subject: String::from(subject),
+            queue_group: queue_group.map(String::from),
+        };
+        let line = subscription.sub_line(sid);
+        self.inner
+            .subscriptions
+            .borrow_mut()
+            .insert(sid, subscription);
+        if self.inner.is_open() {
+            self.inner.send(line)?;
+        }
+        Ok(sid)
+    }
+
+    pub fn unsubscribe(&self, sid: u64) -> Result<(), WsError> {
+        let subscription = self.inner.subscriptions.borrow_mut().remove(&sid);
+        if subscription.is_none() || !self.inner.is_open() {
+            return Ok(());
+        }
+        self.inner.send(format!("UNSUB {}\r\n", sid).into_bytes())
+    }
+
+    // Queued until the connection of the current socket started.
+    pub fn publish(
+        &self,
+        subject: &str,
+        reply_to: Option<&str>,
+        payload: &[u8],
+    ) -> Result<(), WsError> {
+        let line = match reply_to {
+            Some(reply_to) => format!("PUB {} {} {}\r\n", subject, reply_to, payload.len()),
+            None => format!("PUB {} {}\r\n", subject, payload.len()),
+        };
+        let mut data = line.into_bytes();
+        data.extend_from_slice(payload);
+        data.extend_from_slice(CRLF);
+        if !self.inner.is_open() {
+            self.inner.queue.borrow_mut().push_back(data);
+            return Ok(());
+        }
+        self.inner.send(data)
+    }
+
+    pub fn subjects(&self) -> Vec<String> {
+        self.inner
+            .subscriptions
+            .borrow()
+            .values()
+            .map(|subscription| subscription.subject.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_control_lines() {
+        let (op, len) = next_op(b"PING\r\nPONG\r\n", DEFAULT_MAX_PAYLOAD)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(op, Op::Ping));
+        assert_eq!(len, 6);
+        let (op, _) = next_op(b"-ERR 'Stale Connection'\r\n", DEFAULT_MAX_PAYLOAD)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(op, Op::Err(message) if message == "Stale Connection"));
+        let (op, _) = next_op(b"+ok\r\n", DEFAULT_MAX_PAYLOAD).unwrap().unwrap();
+        assert!(matches!(op, Op::Ok));
+        assert!(next_op(b"BOGUS\r\n", DEFAULT_MAX_PAYLOAD).is_err());
+    }
+
+    #[test]
+    fn reads_max_payload_from_info() {
+        let info = b"INFO {\"server_id\":\"a\",\"max_payload\":4096}\r\n";
+        let (op, _) = next_op(info, DEFAULT_MAX_PAYLOAD).unwrap().unwrap();
+        assert!(matches!(
+            op,
+            Op::Info {
+                max_payload: Some(4096)
+            }
+        ));
+        let (op, _) = next_op(b"INFO {}\r\n", DEFAULT_MAX_PAYLOAD)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(op, Op::Info { max_payload: None }));
+    }
+
+    #[test]
+    fn parses_messages() {
+        let buffer = b"MSG a.b 7 reply 5\r\nhello\r\nPING\r\n";
+        let (op, len) = next_op(buffer, DEFAULT_MAX_PAYLOAD).unwrap().unwrap();
+        match op {
+            Op::Msg {
+                subject,
+                sid,
+                payload,
+            } => {
+                assert_eq!(subject, "a.b");
+                assert_eq!(sid, 7);
+                assert_eq!(payload, b"hello");
+            }
+            _ => panic!("expected MSG"),
+        }
+        assert_eq!(len, 26);
+        assert!(matches!(
+            next_op(&buffer[len..], DEFAULT_MAX_PAYLOAD),
+            Ok(Some((Op::Ping, 6)))
+        ));
+    }
+
+    #[test]
+    fn waits_for_partial_operations() {
+        assert!(matches!(next_op(b"PIN", DEFAULT_MAX_PAYLOAD), Ok(None)));
+        assert!(matches!(
+            next_op(b"MSG a 1 5\r\nhel", DEFAULT_MAX_PAYLOAD),
+            Ok(None)
+        ));
+        assert!(matches!(
+            next_op(b"MSG a 1 5\r\nhello", DEFAULT_MAX_PAYLOAD),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_and_oversized_messages() {
+        assert!(next_op(b"MSG a\r\n", DEFAULT_MAX_PAYLOAD).is_err());
+        assert!(next_op(b"MSG a x 1\r\nx\r\n", DEFAULT_MAX_PAYLOAD).is_err());
+        assert!(next_op(b"MSG a 1 -1\r\n", DEFAULT_MAX_PAYLOAD).is_err());
+        assert!(next_op(b"MSG a 1 5\r\nhello\r\n", 4).is_err());
+        let huge = format!("MSG a 1 {}\r\n", usize::MAX);
+        assert!(next_op(huge.as_bytes(), usize::MAX).is_err());
+    }
+
+    #[test]
+    fn matches_subjects() {
+        assert!(subject_matches("a.*.c", "a.b.c"));
+        assert!(subject_matches("a.>", "a.b.c"));
+        assert!(!subject_matches("a.>", "a"));
+        assert!(!subject_matches("a.*", "a.b.c"));
+        assert!(!subject_matches("a.b", "a.c"));
+    }
+}
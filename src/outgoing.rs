@@ -0,0 +1,642 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::sink::Sink;
+use serde::Serialize;
+use web_sys::WebSocket;
+
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+pub type CoalesceKey = Rc<dyn Fn(&WsMessage) -> Option<String>>;
+pub type OutboundInterceptor = Box<dyn Fn(WsMessage) -> Option<WsMessage>>;
+pub type BatchCombiner = Box<dyn Fn(Vec<WsMessage>) -> WsMessage>;
+
+#[derive(Clone, Default)]
+pub enum OverflowPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Reject,
+    // A queued message with the same key is replaced by the newer one, which is
+    // queued by its own priority. When nothing can be coalesced on a full queue
+    // the oldest message is dropped.
+    CoalesceByKey(CoalesceKey),
+}
+
+// Frame type for the messages the crate builds itself (json helpers,
+// heartbeats, subscribe frames). `Auto` follows the type of the last frame
+// received from the server and starts with text.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum WireMode {
+    #[default]
+    Text,
+    Binary,
+    Auto,
+}
+
+impl WireMode {
+    pub fn frame(&self, message: WsMessage, received_binary: bool) -> WsMessage {
+        let binary = match self {
+            WireMode::Text => false,
+            WireMode::Binary => true,
+            WireMode::Auto => received_binary,
+        };
+        match message {
+            WsMessage::Text(payload) if binary => WsMessage::Binary(payload.into_bytes()),
+            message => message,
+        }
+    }
+}
+
+// Ordered from most to least urgent, queued messages are flushed by class
+// and in order of arrival within a class.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+// Messages sent while the socket is not open wait here until the next
+// `onopen` flushes them in order.
+#[derive(Default)]
+pub struct OutgoingQueue {
+    messages: VecDeque<(Priority, WsMessage)>,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    dropped: u64,
+}
+
+impl OutgoingQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bounded(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity: Some(capacity),
+            policy,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, message: WsMessage) -> Result<(), WsError> {
+        self.push_with_priority(message, Priority::Normal)
+    }
+
+    pub fn push_with_priority(
+        &mut self,
+        message: WsMessage,
+        priority: Priority,
+    ) -> Result<(), WsError> {
+        if let OverflowPolicy::CoalesceByKey(key) = &self.policy {
+            if let Some(message_key) = key(&message) {
+                let queued = self
+                    .messages
+                    .iter()
+                    .position(|(_, queued)| key(queued).as_ref() == Some(&message_key));
+                if let Some(queued) = queued {
+                    self.messages.remove(queued);
+                    self.insert(priority, message);
+                    self.dropped += 1;
+                    return Ok(());
+                }
+            }
+        }
+        if !self.is_full() {
+            self.insert(priority, message);
+            return Ok(());
+        }
+        match self.policy {
+            OverflowPolicy::DropNewest => {
+                self.dropped += 1;
+                Ok(())
+            }
+            OverflowPolicy::Reject => Err(WsError::QueueFull),
+            OverflowPolicy::DropOldest | OverflowPolicy::CoalesceByKey(_) => {
+                // the oldest message of the least urgent class makes room
+                let lowest = self.messages.iter().map(|(queued, _)| *queued).max();
+                if lowest.is_some_and(|lowest| lowest < priority) {
+                    self.dropped += 1;
+                    return Ok(());
+                }
+                let oldest = self
+                    .messages
+                    .iter()
+                    .position(|(queued, _)| Some(*queued) == lowest);
+                if let Some(oldest) = oldest {
+                    self.messages.remove(oldest);
+                }
+                self.insert(priority, message);
+                self.dropped += 1;
+                Ok(())
+            }
+        }
+    }
+
+    // Puts back a message that failed to flush, it was already accepted once so
+    // the capacity is not checked again.
+    pub fn push_front(&mut self, priority: Priority, message: WsMessage) {
+        self.messages.push_front((priority, message));
+    }
+
+    pub fn pop(&mut self) -> Option<(Priority, WsMessage)> {
+        self.messages.pop_front()
+    }
+
+    fn insert(&mut self, priority: Priority, message: WsMessage) {
+        let position = self
+            .messages
+            .iter()
+            .position(|(queued, _)| *queued > priority)
+            .unwrap_or(self.messages.len());
+        self.messages.insert(position, (priority, message));
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        match self.capacity {
+            Some(capacity) => self.messages.len() >= capacity,
+            None => false,
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+}
+
+// `queued` and `dropped` come from the outgoing queue when the stats are read,
+// the rest is counted on the wire.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TxStats {
+    pub text_messages: u64,
+    pub text_bytes: u64,
+    pub binary_messages: u64,
+    pub binary_bytes: u64,
+    pub intercepted: u64,
+    pub failed: u64,
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+impl TxStats {
+    pub(crate) fn record(&mut self, binary: bool, bytes: usize, sent: bool) {
+        if !sent {
+            self.failed += 1;
+        } else if binary {
+            self.binary_messages += 1;
+            self.binary_bytes += bytes as u64;
+        } else {
+            self.text_messages += 1;
+            self.text_bytes += bytes as u64;
+        }
+    }
+}
+
+pub struct BackpressureConfig {
+    pub high_water_mark: u32,
+    pub low_water_mark: u32,
+    pub poll_interval: u32,
+    is_backpressured: Cell<bool>,
+}
+
+impl BackpressureConfig {
+    pub fn new(high_water_mark: u32) -> Self {
+        Self {
+            high_water_mark,
+            low_water_mark: 0,
+            poll_interval: 100,
+            is_backpressured: Cell::new(false),
+        }
+    }
+
+    pub fn low_water_mark(mut self, low_water_mark: u32) -> Self {
+        self.low_water_mark = low_water_mark;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: u32) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn is_backpressured(&self) -> bool {
+        self.is_backpressured.get()
+    }
+
+    // Returns the new state when the buffered amount crossed one of the marks.
+    pub(crate) fn update(&self, buffered_amount: u32) -> Option<bool> {
+        let was_backpressured = self.is_backpressured.get();
+        if !was_backpressured && buffered_amount >= self.high_water_mark {
+            self.is_backpressured.set(true);
+            return Some(true);
+        }
+        if was_backpressured && buffered_amount <= self.low_water_mark {
+            self.is_backpressured.set(false);
+            return Some(false);
+        }
+        None
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RateLimitExceeded {
+    Queue,
+    Reject,
+}
+
+// Token buckets for messages and bytes, both refilled continuously and able
+// to burst up to one second worth of traffic.
+pub struct RateLimiter {
+    messages_per_second: Option<f64>,
+    bytes_per_second: Option<f64>,
+    message_tokens: f64,
+    byte_tokens: f64,
+    last_refill: f64,
+    on_exceeded: RateLimitExceeded,
+    flush_scheduled: bool,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            messages_per_second: None,
+            bytes_per_second: None,
+            message_tokens: 0.0,
+            byte_tokens: 0.0,
+            last_refill: js_sys::Date::now(),
+            on_exceeded: RateLimitExceeded::Queue,
+            flush_scheduled: false,
+        }
+    }
+
+    pub fn messages_per_second(mut self, limit: u32) -> Self {
+        self.messages_per_second = Some(f64::from(limit));
+        self.message_tokens = f64::from(limit);
+        self
+    }
+
+    pub fn bytes_per_second(mut self, limit: u32) -> Self {
+        self.bytes_per_second = Some(f64::from(limit));
+        self.byte_tokens = f64::from(limit);
+        self
+    }
+
+    pub fn reject_excess(mut self) -> Self {
+        self.on_exceeded = RateLimitExceeded::Reject;
+        self
+    }
+
+    pub fn on_exceeded(&self) -> RateLimitExceeded {
+        self.on_exceeded
+    }
+
+    // Takes the tokens for a message of `bytes` size, or returns how many
+    // milliseconds to wait until it fits.
+    pub fn acquire(&mut self, bytes: usize) -> Result<(), u32> {
+        self.refill();
+        let bytes = bytes as f64;
+        let mut wait_ms: f64 = 0.0;
+        if let Some(rate) = self.messages_per_second {
+            if self.message_tokens < 1.0 {
+                wait_ms = wait_ms.max((1.0 - self.message_tokens) / rate * 1000.0);
+            }
+        }
+        if let Some(rate) = self.bytes_per_second {
+            // a message bigger than the whole bucket goes out once the bucket is full
+            let needed = bytes.min(rate);
+            if self.byte_tokens < needed {
+                wait_ms = wait_ms.max((needed - self.byte_tokens) / rate * 1000.0);
+            }
+        }
+        if wait_ms > 0.0 {
+            return Err(wait_ms.ceil() as u32);
+        }
+        if self.messages_per_second.is_some() {
+            self.message_tokens -= 1.0;
+        }
+        if self.bytes_per_second.is_some() {
+            self.byte_tokens -= bytes;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_flush_scheduled(&mut self, flush_scheduled: bool) -> bool {
+        let was_scheduled = self.flush_scheduled;
+        self.flush_scheduled = flush_scheduled;
+        was_scheduled
+    }
+
+    fn refill(&mut self) {
+        let now = js_sys::Date::now();
+        let elapsed_seconds = (now - self.last_refill).max(0.0) / 1000.0;
+        self.last_refill = now;
+        if let Some(rate) = self.messages_per_second {
+            self.message_tokens = (self.message_tokens + elapsed_seconds * rate).min(rate);
+        }
+        if let Some(rate) = self.bytes_per_second {
+            self.byte_tokens = (self.byte_tokens + elapsed_seconds * rate).min(rate);
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// One pending timeout at a time, later polls only swap the waker it wakes.
+#[derive(Default)]
+pub(crate) struct PollTimer {
+    waker: Rc<RefCell<Option<Waker>>>,
+    timer: Rc<Cell<Option<i32>>>,
+}
+
+impl PollTimer {
+    pub(crate) fn wake_later(&self, cx: &mut Context<'_>, interval: u32) {
+        *self.waker.borrow_mut() = Some(cx.waker().clone());
+        if self.timer.get().is_some() {
+            return;
+        }
+        let waker = self.waker.clone();
+        let timer = self.timer.clone();
+        let id = set_timeout_once(
+            move || {
+                timer.set(None);
+                let waker = waker.borrow_mut().take();
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            },
+            interval,
+        );
+        self.timer.set(Some(id));
+    }
+}
+
+impl Drop for PollTimer {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer.take() {
+            clear_timeout(id);
+        }
+    }
+}
+
+// Resolves once the frame left the offline queue and the browser send buffer
+// drained below the threshold, polling `bufferedAmount` on a timer. Fails
+// when the connection ends for good before that.
+pub struct SendFuture {
+    factory: Rc<WsFactory>,
+    websocket: Rc<RefCell<WebSocket>>,
+    threshold: u32,
+    poll_interval: u32,
+    error: Option<WsError>,
+    timer: PollTimer,
+}
+
+impl SendFuture {
+    pub(crate) fn new(
+        factory: Rc<WsFactory>,
+        websocket: Rc<RefCell<WebSocket>>,
+        threshold: u32,
+        poll_interval: u32,
+        error: Option<WsError>,
+    ) -> Self {
+        Self {
+            factory,
+            websocket,
+            threshold,
+            poll_interval,
+            error,
+            timer: PollTimer::default(),
+        }
+    }
+}
+
+impl Future for SendFuture {
+    type Output = Result<(), WsError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(err) = self.error.take() {
+            return Poll::Ready(Err(err));
+        }
+        let is_drained = self.factory.outgoing.borrow().is_empty()
+            && self.websocket.borrow().buffered_amount() <= self.threshold;
+        if is_drained {
+            return Poll::Ready(Ok(()));
+        }
+        if self.factory.is_terminated(&self.websocket.borrow()) {
+            return Poll::Ready(Err(WsError::NotConnected {
+                state: ReadyState::Closed,
+            }));
+        }
+        self.timer.wake_later(cx, self.poll_interval);
+        Poll::Pending
+    }
+}
+
+// Sends are accepted while `bufferedAmount` is below the backpressure high
+// water mark, always without a backpressure config. A flush waits for the
+// offline queue and the browser buffer to drain, and fails once the
+// connection ended for good. Closing the sink only flushes it, the connection
+// stays open for the other handles.
+pub struct WsSink {
+    websocket: Websocket,
+    timer: PollTimer,
+}
+
+impl WsSink {
+    pub(crate) fn new(websocket: Websocket) -> Self {
+        Self {
+            websocket,
+            timer: PollTimer::default(),
+        }
+    }
+
+    fn wake_later(&self, cx: &mut Context<'_>) {
+        let poll_interval = match self.websocket.core.factory.backpressure.as_ref() {
+            Some(backpressure) => backpressure.poll_interval,
+            None => 50,
+        };
+        self.timer.wake_later(cx, poll_interval);
+    }
+
+    fn check_terminated(&self) -> Result<(), WsError> {
+        let core = &self.websocket.core;
+        if core.factory.is_terminated(&core.websocket.borrow()) {
+            return Err(WsError::NotConnected {
+                state: ReadyState::Closed,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Sink<WsMessage> for WsSink {
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let high_water_mark = match self.websocket.core.factory.backpressure.as_ref() {
+            Some(backpressure) => backpressure.high_water_mark,
+            None => return Poll::Ready(Ok(())),
+        };
+        if self.websocket.buffered_amount() < high_water_mark {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(err) = self.check_terminated() {
+            return Poll::Ready(Err(err));
+        }
+        self.wake_later(cx);
+        Poll::Pending
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: WsMessage) -> Result<(), Self::Error> {
+        self.websocket.send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let factory = &self.websocket.core.factory;
+        let threshold = match factory.backpressure.as_ref() {
+            Some(backpressure) => backpressure.low_water_mark,
+            None => 0,
+        };
+        let is_drained =
+            factory.outgoing.borrow().is_empty() && self.websocket.buffered_amount() <= threshold;
+        if is_drained {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(err) = self.check_terminated() {
+            return Poll::Ready(Err(err));
+        }
+        self.wake_later(cx);
+        Poll::Pending
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(payload: &str) -> WsMessage {
+        WsMessage::Text(String::from(payload))
+    }
+
+    fn push(queue: &mut OutgoingQueue, payload: &str, priority: Priority) {
+        queue.push_with_priority(text(payload), priority).unwrap();
+    }
+
+    fn drain(queue: &mut OutgoingQueue) -> Vec<String> {
+        let mut payloads = Vec::new();
+        while let Some((_, message)) = queue.pop() {
+            match message {
+                WsMessage::Text(payload) => payloads.push(payload),
+                WsMessage::Binary(_) => panic!("expected a text frame"),
+            }
+        }
+        payloads
+    }
+
+    fn first_char_key() -> OverflowPolicy {
+        OverflowPolicy::CoalesceByKey(Rc::new(|message: &WsMessage| match message {
+            WsMessage::Text(payload) => payload.get(..1).map(String::from),
+            WsMessage::Binary(_) => None,
+        }))
+    }
+
+    #[test]
+    fn flushes_by_class_and_in_order_within_a_class() {
+        let mut queue = OutgoingQueue::new();
+        push(&mut queue, "low 1", Priority::Low);
+        push(&mut queue, "normal 1", Priority::Normal);
+        push(&mut queue, "high 1", Priority::High);
+        push(&mut queue, "normal 2", Priority::Normal);
+        push(&mut queue, "high 2", Priority::High);
+        push(&mut queue, "low 2", Priority::Low);
+        assert_eq!(
+            drain(&mut queue),
+            ["high 1", "high 2", "normal 1", "normal 2", "low 1", "low 2"]
+        );
+    }
+
+    #[test]
+    fn drop_oldest_drops_the_oldest_of_the_least_urgent_class() {
+        let mut queue = OutgoingQueue::bounded(2, OverflowPolicy::DropOldest);
+        push(&mut queue, "low", Priority::Low);
+        push(&mut queue, "normal 1", Priority::Normal);
+        push(&mut queue, "normal 2", Priority::Normal);
+        assert_eq!(queue.dropped(), 1);
+        push(&mut queue, "low 2", Priority::Low);
+        assert_eq!(queue.dropped(), 2);
+        push(&mut queue, "normal 3", Priority::Normal);
+        assert_eq!(queue.dropped(), 3);
+        assert_eq!(drain(&mut queue), ["normal 2", "normal 3"]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_messages() {
+        let mut queue = OutgoingQueue::bounded(2, OverflowPolicy::DropNewest);
+        push(&mut queue, "1", Priority::Normal);
+        push(&mut queue, "2", Priority::Normal);
+        push(&mut queue, "3", Priority::High);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&mut queue), ["1", "2"]);
+    }
+
+    #[test]
+    fn reject_fails_on_a_full_queue() {
+        let mut queue = OutgoingQueue::bounded(1, OverflowPolicy::Reject);
+        push(&mut queue, "1", Priority::Normal);
+        assert!(matches!(queue.push(text("2")), Err(WsError::QueueFull)));
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(drain(&mut queue), ["1"]);
+    }
+
+    #[test]
+    fn coalesces_by_key_and_drops_the_oldest_when_nothing_matches() {
+        let mut queue = OutgoingQueue::bounded(2, first_char_key());
+        push(&mut queue, "a1", Priority::Normal);
+        push(&mut queue, "b1", Priority::Normal);
+        push(&mut queue, "a2", Priority::Normal);
+        assert_eq!(queue.len(), 2);
+        push(&mut queue, "c1", Priority::Normal);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), ["a2", "c1"]);
+    }
+
+    #[test]
+    fn coalesced_messages_move_to_the_class_of_their_priority() {
+        let mut queue = OutgoingQueue::bounded(8, first_char_key());
+        push(&mut queue, "a low", Priority::Low);
+        push(&mut queue, "b normal", Priority::Normal);
+        push(&mut queue, "c normal", Priority::Normal);
+        push(&mut queue, "a high", Priority::High);
+        push(&mut queue, "c low", Priority::Low);
+        assert_eq!(drain(&mut queue), ["a high", "b normal", "c low"]);
+    }
+}
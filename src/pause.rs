@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+
+use crate::WsMessage;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PausePolicy {
+    // keeps at most this many frames, the oldest ones are dropped first
+    Buffer(usize),
+    Drop,
+}
+
+// Inbound frames held back while processing is paused. Heartbeats and
+// resubscribes are skipped as well, resubscribing happens on resume.
+pub struct PausedInbox {
+    policy: PausePolicy,
+    frames: VecDeque<(WsMessage, f64)>,
+    dropped: u64,
+    resubscribe: bool,
+}
+
+impl PausedInbox {
+    pub fn new(policy: PausePolicy) -> Self {
+        Self {
+            policy,
+            frames: VecDeque::new(),
+            dropped: 0,
+            resubscribe: false,
+        }
+    }
+
+    // `received_at` is kept with the frame for the receive stats.
+    pub fn push(&mut self, message: WsMessage, received_at: f64) {
+        match self.policy {
+            PausePolicy::Buffer(max) => {
+                if max == 0 {
+                    self.dropped += 1;
+                    return;
+                }
+                if self.frames.len() >= max {
+                    self.frames.pop_front();
+                    self.dropped += 1;
+                }
+                self.frames.push_back((message, received_at));
+            }
+            PausePolicy::Drop => self.dropped += 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub(crate) fn skip_resubscribe(&mut self) {
+        self.resubscribe = true;
+    }
+
+    // Returns the held frames with their receive times in arrival order and
+    // whether the subscriptions have to be sent again.
+    pub(crate) fn into_parts(self) -> (VecDeque<(WsMessage, f64)>, bool) {
+        (self.frames, self.resubscribe)
+    }
+}
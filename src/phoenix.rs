@@ -0,0 +1,526 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::set_timeout_once;
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+const PHOENIX_TOPIC: &str = "phoenix";
+const HEARTBEAT_INTERVAL: u32 = 30_000;
+// a crashed channel is joined again after this many ms
+const REJOIN_DELAY: u32 = 1_000;
+const DEFAULT_TIMEOUT: u32 = 10_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PushReply {
+    Ok(Value),
+    Error(Value),
+    // no reply within the timeout, or the socket closed before it came
+    Timeout,
+}
+
+impl PushReply {
+    fn from_payload(payload: Value) -> Self {
+        let response = payload.get("response").cloned().unwrap_or(Value::Null);
+        match payload.get("status").and_then(Value::as_str) {
+            Some("ok") => PushReply::Ok(response),
+            _ => PushReply::Error(response),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChannelState {
+    Closed,
+    Joining,
+    Joined,
+    // the join was refused or the channel crashed on the server
+    Errored,
+}
+
+// `[join_ref, ref, topic, event, payload]`, the 2.0.0 serializer.
+struct Message {
+    join_ref: Option<String>,
+    msg_ref: Option<String>,
+    topic: String,
+    event: String,
+    payload: Value,
+}
+
+impl Message {
+    fn encode(&self) -> String {
+        json!([
+            self.join_ref,
+            self.msg_ref,
+            self.topic,
+            self.event,
+            self.payload
+        ])
+        .to_string()
+    }
+
+    fn decode(data: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(data).map_err(|err| err.to_string())?;
+        let mut parts = match value {
+            Value::Array(parts) if parts.len() == 5 => parts.into_iter(),
+            _ => return Err(String::from("expected a 5 element array")),
+        };
+        let mut string = || match parts.next() {
+            Some(Value::String(part)) => Some(part),
+            _ => None,
+        };
+        let join_ref = string();
+        let msg_ref = string();
+        let topic = string().ok_or_else(|| String::from("missing topic"))?;
+        let event = string().ok_or_else(|| String::from("missing event"))?;
+        Ok(Self {
+            join_ref,
+            msg_ref,
+            topic,
+            event,
+            payload: parts.next().unwrap_or(Value::Null),
+        })
+    }
+}
+
+type EventListener = Rc<dyn Fn(Value)>;
+type JoinCallback = Rc<dyn Fn(PushReply)>;
+type ReplyCallback = Box<dyn FnOnce(PushReply)>;
+// a push with its reply timeout and callback, when it wants one
+type QueuedPush = (Message, Option<(u32, ReplyCallback)>);
+
+struct ChannelInner {
+    topic: String,
+    params: Value,
+    state: Cell<ChannelState>,
+    // the ref of the current join, messages of older joins are dropped
+    join_ref: RefCell<Option<String>>,
+    on_join: RefCell<Option<JoinCallback>>,
+    listeners: RefCell<HashMap<String, EventListener>>,
+    // pushes made before the channel was (re)joined on the current socket
+    queue: RefCell<VecDeque<QueuedPush>>,
+}
+
+struct PhoenixInner {
+    websocket: Websocket,
+    channels: RefCell<HashMap<String, Rc<ChannelInner>>>,
+    replies: RefCell<HashMap<String, ReplyCallback>>,
+    next_ref: Cell<u64>,
+    // the ref of the heartbeat still waiting for its reply
+    pending_heartbeat: RefCell<Option<String>>,
+    // bumped on every close, stops the heartbeats of the previous socket
+    session: Cell<u64>,
+}
+
+impl PhoenixInner {
+    fn make_ref(&self) -> String {
+        let msg_ref = self.next_ref.get();
+        self.next_ref.set(msg_ref + 1);
+        msg_ref.to_string()
+    }
+
+    fn is_open(&self) -> bool {
+        matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+
+    fn send(&self, message: &Message) -> Result<(), WsError> {
+        self.websocket.send(WsMessage::Text(message.encode()))
+    }
+
+    // The callback gets `Timeout` when the reply did not come in time.
+    fn send_with_reply(
+        inner: &Rc<Self>,
+        mut message: Message,
+        timeout: u32,
+        callback: ReplyCallback,
+    ) -> Result<(), WsError> {
+        let msg_ref = inner.make_ref();
+        message.msg_ref = Some(msg_ref.clone());
+        inner.replies.borrow_mut().insert(msg_ref.clone(), callback);
+        if let Err(err) = inner.send(&message) {
+            inner.replies.borrow_mut().remove(&msg_ref);
+            return Err(err);
+        }
+        let phoenix = Rc::downgrade(inner);
+        set_timeout_once(
+            move || {
+                let callback = phoenix
+                    .upgrade()
+                    .and_then(|phoenix| phoenix.replies.borrow_mut().remove(&msg_ref));
+                if let Some(callback) = callback {
+                    callback(PushReply::Timeout);
+                }
+            },
+            timeout,
+        );
+        Ok(())
+    }
+
+    fn opened(inner: &Rc<Self>) {
+        Self::heartbeat(Rc::downgrade(inner));
+        let channels: Vec<Rc<ChannelInner>> = inner.channels.borrow().values().cloned().collect();
+        for channel in channels {
+            if channel.state.get() != ChannelState::Closed {
+                Self::join(inner, &channel);
+            }
+        }
+    }
+
+    fn closed(&self) {
+        self.session.set(self.session.get() + 1);
+        self.pending_heartbeat.borrow_mut().take();
+        for channel in self.channels.borrow().values() {
+            if channel.state.get() == ChannelState::Joined {
+                channel.state.set(ChannelState::Joining);
+            }
+        }
+        let replies: Vec<ReplyCallback> = self
+            .replies
+            .borrow_mut()
+            .drain()
+            .map(|(_, callback)| callback)
+            .collect();
+        for callback in replies {
+            callback(PushReply::Timeout);
+        }
+    }
+
+    // A heartbeat without a reply by the next one means the socket is dead.
+    fn heartbeat(inner: Weak<Self>) {
+        let session = match inner.upgrade() {
+            Some(inner) => inner.session.get(),
+            None => return,
+        };
+        set_timeout_once(
+            move || {
+                let phoenix = match inner.upgrade() {
+                    Some(phoenix) if phoenix.session.get() == session => phoenix,
+                    _ => return,
+                };
+                if phoenix.pending_heartbeat.borrow().is_some() {
+                    console_log!("no phoenix heartbeat reply in time, reconnecting");
+                    force_reconnect(&phoenix.websocket.core);
+                    return;
+                }
+                let msg_ref = phoenix.make_ref();
+                let message = Message {
+                    join_ref: None,
+                    msg_ref: Some(msg_ref.clone()),
+                    topic: String::from(PHOENIX_TOPIC),
+                    event: String::from("heartbeat"),
+                    payload: json!({}),
+                };
+                match phoenix.send(&message) {
+                    Ok(()) => *phoenix.pending_heartbeat.borrow_mut() = Some(msg_ref),
+                    Err(err) => console_log!("error on phoenix heartbeat: {:?}", err),
+                }
+                Self::heartbeat(inner);
+            },
+            HEARTBEAT_INTERVAL,
+        );
+    }
+
+    fn join(inner: &Rc<Self>, channel: &Rc<ChannelInner>) {
+        if !inner.is_open() {
+            channel.state.set(ChannelState::Joining);
+            return;
+        }
+        let join_ref = inner.make_ref();
+        *channel.join_ref.borrow_mut() = Some(join_ref.clone());
+        channel.state.set(ChannelState::Joining);
+        let message = Message {
+            join_ref: Some(join_ref.clone()),
+            msg_ref: None,
+            topic: channel.topic.clone(),
+            event: String::from("phx_join"),
+            payload: channel.params.clone(),
+        };
+        let phoenix = Rc::downgrade(inner);
+        let joined = Rc::downgrade(channel);
+        let callback = Box::new(move |reply: PushReply| {
+            let (phoenix, channel) = match (phoenix.upgrade(), joined.upgrade()) {
+                (Some(phoenix), Some(channel)) => (phoenix, channel),
+                _ => return,
+            };
+            // a reply to an older join
+            if channel.join_ref.borrow().as_deref() != Some(join_ref.as_str()) {
+                return;
+            }
+            match reply {
+                PushReply::Ok(_) => {
+                    channel.state.set(ChannelState::Joined);
+                    phoenix.flush(&channel);
+                }
+                PushReply::Error(_) => channel.state.set(ChannelState::Errored),
+                // the next open joins again
+                PushReply::Timeout => (),
+            }
+            let on_join = channel.on_join.borrow().clone();
+            if let Some(on_join) = on_join {
+                on_join(reply);
+            }
+        });
+        if let Err(err) = Self::send_with_reply(inner, message, DEFAULT_TIMEOUT, callback) {
+            console_log!("error on join {}: {:?}", channel.topic, err);
+        }
+    }
+
+    fn flush(self: &Rc<Self>, channel: &ChannelInner) {
+        let join_ref = channel.join_ref.borrow().clone();
+        let queued: Vec<QueuedPush> = channel.queue.borrow_mut().drain(..).collect();
+        for (mut message, reply) in queued {
+            message.join_ref = join_ref.clone();
+            let result = match reply {
+                Some((timeout, callback)) => {
+                    Self::send_with_reply(self, message, timeout, callback)
+                }
+                None => self.send(&message),
+            };
+            if let Err(err) = result {
+                console_log!("error on push to {}: {:?}", channel.topic, err);
+            }
+        }
+    }
+
+    fn dispatch(inner: &Rc<Self>, data: &str) {
+        let message = match Message::decode(data) {
+            Ok(message) => message,
+            Err(err) => {
+                console_log!("error on parse phoenix message {}: {}", data, err);
+                return;
+            }
+        };
+        if message.event == "phx_reply" {
+            if let Some(msg_ref) = message.msg_ref.as_ref() {
+                let mut pending_heartbeat = inner.pending_heartbeat.borrow_mut();
+                if pending_heartbeat.as_ref() == Some(msg_ref) {
+                    pending_heartbeat.take();
+                    return;
+                }
+                drop(pending_heartbeat);
+                let callback = inner.replies.borrow_mut().remove(msg_ref);
+                if let Some(callback) = callback {
+                    callback(PushReply::from_payload(message.payload));
+                }
+            }
+            return;
+        }
+        let channel = match inner.channels.borrow().get(&message.topic) {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        if message.join_ref.is_some() && message.join_ref != *channel.join_ref.borrow() {
+            return;
+        }
+        match message.event.as_str() {
+            "phx_error" => {
+                channel.state.set(ChannelState::Errored);
+                let phoenix = Rc::downgrade(inner);
+                let errored = Rc::downgrade(&channel);
+                set_timeout_once(
+                    move || {
+                        if let (Some(phoenix), Some(channel)) =
+                            (phoenix.upgrade(), errored.upgrade())
+                        {
+                            if channel.state.get() == ChannelState::Errored {
+                                Self::join(&phoenix, &channel);
+                            }
+                        }
+                    },
+                    REJOIN_DELAY,
+                );
+            }
+            "phx_close" => {
+                channel.state.set(ChannelState::Closed);
+                inner.channels.borrow_mut().remove(&message.topic);
+            }
+            _ => (),
+        }
+        let listener = channel.listeners.borrow().get(&message.event).cloned();
+        if let Some(listener) = listener {
+            listener(message.payload);
+        }
+    }
+}
+
+// A Phoenix Channels client (serializer 2.0.0) over the reconnecting socket.
+// The factory url is the socket endpoint, e.g. `wss://host/socket/websocket`.
+// Joined channels are joined again after every reconnect, and a crashed one
+// after a second. A `phoenix` heartbeat goes out every 30s, the socket is
+// reconnected when the previous one got no reply. Pushes waiting for a reply
+// get `PushReply::Timeout` when the socket closes.
+#[derive(Clone)]
+pub struct PhoenixSocket {
+    inner: Rc<PhoenixInner>,
+}
+
+impl PhoenixSocket {
+    pub fn build(factory: WsFactory) -> Result<Self, WsError> {
+        let factory = factory.query_param("vsn", "2.0.0");
+        let inner = ProtocolClient::new(factory, |inner: &Rc<PhoenixInner>, message| {
+            if let WsMessage::Text(data) = message {
+                PhoenixInner::dispatch(inner, &data);
+            }
+        })
+        .on_open(PhoenixInner::opened)
+        .on_close(|phoenix: &Rc<PhoenixInner>| phoenix.closed())
+        .build(|websocket| PhoenixInner {
+            websocket,
+            channels: RefCell::new(HashMap::new()),
+            replies: RefCell::new(HashMap::new()),
+            next_ref: Cell::new(1),
+            pending_heartbeat: RefCell::new(None),
+            session: Cell::new(0),
+        })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    // Another handle to the channel of the topic when there is one already,
+    // `params` are only used for a new one.
+    pub fn channel(&self, topic: &str, params: Value) -> PhoenixChannel {
+        let existing = self.inner.channels.borrow().get(topic).cloned();
+        let channel = existing.unwrap_or_else(|| {
+            let channel = Rc::new(ChannelInner {
+                topic: String::from(topic),
+                params,
+                state: Cell::new(ChannelState::Closed),
+                join_ref: RefCell::new(None),
+                on_join: RefCell::new(None),
+                listeners: RefCell::new(HashMap::new()),
+                queue: RefCell::new(VecDeque::new()),
+            });
+            self.inner
+                .channels
+                .borrow_mut()
+                .insert(String::from(topic), channel.clone());
+            channel
+        });
+        PhoenixChannel {
+            channel,
+            phoenix: self.inner.clone(),
+        }
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.inner.channels.borrow().keys().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct PhoenixChannel {
+    channel: Rc<ChannelInner>,
+    phoenix: Rc<PhoenixInner>,
+}
+
+impl PhoenixChannel {
+    pub fn topic(&self) -> &str {
+        &self.channel.topic
+    }
+
+    pub fn state(&self) -> ChannelState {
+        self.channel.state.get()
+    }
+
+    // `on_join` gets the reply to every join, rejoins included.
+    pub fn join(&self, on_join: impl Fn(PushReply) + 'static) {
+        *self.channel.on_join.borrow_mut() = Some(Rc::new(on_join));
+        self.phoenix
+            .channels
+            .borrow_mut()
+            .entry(self.channel.topic.clone())
+            .or_insert_with(|| self.channel.clone());
+        PhoenixInner::join(&self.phoenix, &self.channel);
+    }
+
+    pub fn on(&self, event: &str, f: impl Fn(Value) + 'static) {
+        self.channel
+            .listeners
+            .borrow_mut()
+            .insert(String::from(event), Rc::new(f));
+    }
+
+    pub fn off(&self, event: &str) {
+        self.channel.listeners.borrow_mut().remove(event);
+    }
+
+    // Queued while the channel is not joined on the current socket.
+    pub fn push(&self, event: &str, payload: Value) -> Result<(), WsError> {
+        self.push_message(event, payload, None)
+    }
+
+    pub fn push_with_reply(
+        &self,
+        event: &str,
+        payload: Value,
+        timeout: u32,
+        callback: impl FnOnce(PushReply) + 'static,
+    ) -> Result<(), WsError> {
+        self.push_message(event, payload, Some((timeout, Box::new(callback))))
+    }
+
+    fn push_message(
+        &self,
+        event: &str,
+        payload: Value,
+        reply: Option<(u32, ReplyCallback)>,
+    ) -> Result<(), WsError> {
+        let message = Message {
+            join_ref: self.channel.join_ref.borrow().clone(),
+            msg_ref: None,
+            topic: self.channel.topic.clone(),
+            event: String::from(event),
+            payload,
+        };
+        if self.channel.state.get() != ChannelState::Joined || !self.phoenix.is_open() {
+            self.channel.queue.borrow_mut().push_back((message, reply));
+            return Ok(());
+        }
+        match reply {
+            Some((timeout, callback)) => {
+                PhoenixInner::send_with_reply(&self.phoenix, message, timeout, callback)
+            }
+            None => self.phoenix.send(&message),
+        }
+    }
+
+    // Leaves the channel for every handle, queued pushes are dropped.
+    pub fn leave(self) -> Result<(), WsError> {
+        self.phoenix
+            .channels
+            .borrow_mut()
+            .remove(&self.channel.topic);
+        let joined = self.channel.state.get() == ChannelState::Joined;
+        self.channel.state.set(ChannelState::Closed);
+        self.channel.queue.borrow_mut().clear();
+        if !joined || !self.phoenix.is_open() {
+            return Ok(());
+        }
+        self.phoenix.send(&Message {
+            join_ref: self.channel.join_ref.borrow().clone(),
+            msg_ref: Some(self.phoenix.make_ref()),
+            topic: self.channel.topic.clone(),
+            event: String::from("phx_leave"),
+            payload: json!({}),
+        })
+    }
+}
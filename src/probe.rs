@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use js_sys::{Function, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::WebSocket;
+
+use crate::factory::WsFactory;
+use crate::timers;
+
+pub enum ProbeMethod {
+    // a test socket has to open within the timeout, it is closed right after
+    Socket,
+    // `fetch` of the health url of an endpoint has to answer with a 2xx status
+    Health(Box<dyn Fn(&str) -> String>),
+}
+
+// Candidate endpoints, probed all at once together with the url the factory
+// was created with. The first healthy one in that order is connected to.
+pub struct EndpointProbe {
+    endpoints: Vec<String>,
+    method: ProbeMethod,
+    timeout: u32,
+}
+
+impl EndpointProbe {
+    pub fn socket(endpoints: Vec<String>) -> Self {
+        Self {
+            endpoints,
+            method: ProbeMethod::Socket,
+            timeout: 3_000,
+        }
+    }
+
+    pub fn health(endpoints: Vec<String>, health_url: impl Fn(&str) -> String + 'static) -> Self {
+        Self {
+            endpoints,
+            method: ProbeMethod::Health(Box::new(health_url)),
+            timeout: 3_000,
+        }
+    }
+
+    // Per endpoint, in ms.
+    pub fn timeout(mut self, timeout: u32) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn endpoints(&self) -> &[String] {
+        &self.endpoints
+    }
+
+    // `connect_url` is the endpoint with the query and path of the factory.
+    pub(crate) async fn is_healthy(
+        &self,
+        factory: &WsFactory,
+        endpoint: &str,
+        connect_url: &str,
+    ) -> bool {
+        match &self.method {
+            ProbeMethod::Socket => probe_socket(factory, connect_url, self.timeout).await,
+            ProbeMethod::Health(health_url) => {
+                probe_health(&health_url(endpoint), self.timeout).await
+            }
+        }
+    }
+}
+
+// `None` when the promise didn't settle within `timeout`, the timer is
+// cleared either way.
+async fn within(promise: Promise, timeout: u32) -> Option<Result<JsValue, JsValue>> {
+    timers::timeout(JsFuture::from(promise), timeout).await.ok()
+}
+
+// Owns the test socket and its handlers. Dropped when the probe is done, timed
+// out or was abandoned, which closes the socket.
+struct ProbeSocket {
+    socket: WebSocket,
+    _on_open: Closure<dyn FnMut()>,
+    _on_error: Closure<dyn FnMut()>,
+}
+
+impl Drop for ProbeSocket {
+    fn drop(&mut self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onerror(None);
+        let _ = self.socket.close();
+    }
+}
+
+async fn probe_socket(factory: &WsFactory, url: &str, timeout: u32) -> bool {
+    let socket = match factory.new_socket(url) {
+        Ok(socket) => socket,
+        Err(_) => return false,
+    };
+    let settle: Rc<RefCell<Option<Function>>> = Rc::new(RefCell::new(None));
+    let opened = {
+        let settle = settle.clone();
+        Promise::new(&mut |resolve, _reject| *settle.borrow_mut() = Some(resolve))
+    };
+    let settle_with = |healthy: bool| {
+        let settle = settle.clone();
+        Closure::wrap(Box::new(move || {
+            if let Some(resolve) = settle.borrow_mut().take() {
+                let _ = resolve.call1(&JsValue::NULL, &JsValue::from_bool(healthy));
+            }
+        }) as Box<dyn FnMut()>)
+    };
+    let probe = ProbeSocket {
+        socket,
+        _on_open: settle_with(true),
+        _on_error: settle_with(false),
+    };
+    probe
+        .socket
+        .set_onopen(Some(probe._on_open.as_ref().unchecked_ref()));
+    probe
+        .socket
+        .set_onerror(Some(probe._on_error.as_ref().unchecked_ref()));
+    let healthy = within(opened, timeout)
+        .await
+        .and_then(|healthy| healthy.ok())
+        .and_then(|healthy| healthy.as_bool())
+        .unwrap_or(false);
+    drop(probe);
+    healthy
+}
+
+async fn probe_health(url: &str, timeout: u32) -> bool {
+    let global = js_sys::global();
+    let fetch = match Reflect::get(&global, &JsValue::from_str("fetch")) {
+        Ok(fetch) => match fetch.dyn_into::<Function>() {
+            Ok(fetch) => fetch,
+            Err(_) => return false,
+        },
+        Err(_) => return false,
+    };
+    let response = match fetch.call1(&global, &JsValue::from_str(url)) {
+        Ok(response) => Promise::resolve(&response),
+        Err(_) => return false,
+    };
+    within(response, timeout)
+        .await
+        .and_then(|response| response.ok())
+        .and_then(|response| Reflect::get(&response, &JsValue::from_str("ok")).ok())
+        .and_then(|ok| ok.as_bool())
+        .unwrap_or(false)
+}
@@ -0,0 +1,27 @@
+use prost::Message;
+
+use crate::emitter::Payload;
+
+// Decodes the envelope and returns the event name with the encoded inner
+// message, `Ok(None)` for envelopes that should not be routed.
+pub type ProtobufRouter = Box<dyn Fn(&[u8]) -> Result<Option<(String, Vec<u8>)>, String>>;
+
+pub fn envelope_router<E, F>(route: F) -> ProtobufRouter
+where
+    E: Message + Default,
+    F: Fn(E) -> Option<(String, Vec<u8>)> + 'static,
+{
+    Box::new(move |payload| {
+        let envelope = E::decode(payload).map_err(|err| err.to_string())?;
+        Ok(route(envelope))
+    })
+}
+
+// Listener payloads that are not protobuf bytes, or don't decode as `M`, are
+// handed back as the error.
+pub fn decode_payload<M: Message + Default>(payload: &Payload) -> Result<M, String> {
+    match payload {
+        Payload::Bytes(bytes) => M::decode(bytes.as_slice()).map_err(|err| err.to_string()),
+        payload => Err(format!("not a protobuf payload: {}", payload)),
+    }
+}
@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use crate::core::WsCore;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::{Websocket, WsMessage};
+
+type Callback<T> = Box<dyn Fn(&Rc<T>)>;
+type MessageCallback<T> = Box<dyn Fn(&Rc<T>, WsMessage)>;
+
+// Closes the current socket without going through `close`, so the socket is
+// not released and the core reconnects as after a dropped connection. The
+// clients call it when the server went quiet.
+pub(crate) fn force_reconnect(core: &WsCore) {
+    let _ = core.websocket.borrow().close_with_code(4000);
+}
+
+// The wiring the protocol clients share. The client gets every frame through
+// the `on_message` callback, json routing and the crate heartbeat are off,
+// and listeners are never subscribed on the server. The callbacks hold the
+// state of the client weakly, the state holds the socket.
+pub(crate) struct ProtocolClient<T> {
+    factory: WsFactory,
+    on_message: MessageCallback<T>,
+    on_open: Option<Callback<T>>,
+    on_close: Option<Callback<T>>,
+}
+
+impl<T: 'static> ProtocolClient<T> {
+    pub fn new(factory: WsFactory, on_message: impl Fn(&Rc<T>, WsMessage) + 'static) -> Self {
+        Self {
+            factory,
+            on_message: Box::new(on_message),
+            on_open: None,
+            on_close: None,
+        }
+    }
+
+    // Takes the `open` listener of the socket.
+    pub fn on_open(mut self, f: impl Fn(&Rc<T>) + 'static) -> Self {
+        self.on_open = Some(Box::new(f));
+        self
+    }
+
+    // Takes the `close` listener of the socket.
+    pub fn on_close(mut self, f: impl Fn(&Rc<T>) + 'static) -> Self {
+        self.on_close = Some(Box::new(f));
+        self
+    }
+
+    // `state` builds the client state around the socket.
+    pub fn build(self, state: impl FnOnce(Websocket) -> T) -> Result<Rc<T>, WsError> {
+        let slot: Rc<RefCell<Weak<T>>> = Rc::new(RefCell::new(Weak::new()));
+        let receiver = slot.clone();
+        let on_message = self.on_message;
+        let websocket = self
+            .factory
+            .no_heartbeat()
+            .no_auto_subscribe()
+            .raw_passthrough()
+            .on_message(move |message| {
+                let inner = receiver.borrow().upgrade();
+                if let Some(inner) = inner {
+                    on_message(&inner, message);
+                }
+            })
+            .build()?;
+        let inner = Rc::new(state(websocket.clone()));
+        *slot.borrow_mut() = Rc::downgrade(&inner);
+        if let Some(on_open) = self.on_open {
+            let client = Rc::downgrade(&inner);
+            websocket.add_listener(String::from("open"), move |_| {
+                if let Some(client) = client.upgrade() {
+                    on_open(&client);
+                }
+            });
+        }
+        if let Some(on_close) = self.on_close {
+            let client = Rc::downgrade(&inner);
+            websocket.add_listener(String::from("close"), move |_| {
+                if let Some(client) = client.upgrade() {
+                    on_close(&client);
+                }
+            });
+        }
+        Ok(inner)
+    }
+}
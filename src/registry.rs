@@ -0,0 +1,61 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::core::WsCore;
+use crate::Websocket;
+
+// Holds no handle itself, so the connection still closes once the last
+// handle of it is dropped.
+struct Entry {
+    core: Weak<WsCore>,
+    handles: Weak<()>,
+}
+
+impl Entry {
+    fn upgrade(&self) -> Option<Websocket> {
+        let websocket = Websocket {
+            core: self.core.upgrade()?,
+            handles: self.handles.upgrade()?,
+        };
+        if *websocket.core.factory.is_closing.borrow() {
+            return None;
+        }
+        Some(websocket)
+    }
+}
+
+thread_local! {
+    static CONNECTIONS: RefCell<HashMap<String, Entry>> = RefCell::new(HashMap::new());
+}
+
+// Another handle to the live shared connection for `url`, if there is one.
+pub(crate) fn lookup(url: &str) -> Option<Websocket> {
+    CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        let websocket = connections.get(url).and_then(Entry::upgrade);
+        if websocket.is_none() {
+            connections.remove(url);
+        }
+        websocket
+    })
+}
+
+pub(crate) fn register(url: &str, websocket: &Websocket) {
+    let entry = Entry {
+        core: Rc::downgrade(&websocket.core),
+        handles: Rc::downgrade(&websocket.handles),
+    };
+    CONNECTIONS.with(|connections| {
+        connections.borrow_mut().insert(String::from(url), entry);
+    });
+}
+
+// Urls with a live shared connection.
+pub fn shared_urls() -> Vec<String> {
+    CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        connections.retain(|_, entry| entry.upgrade().is_some());
+        connections.keys().cloned().collect()
+    })
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::WsMessage;
+
+pub type SequenceExtractor = Box<dyn Fn(&WsMessage) -> Option<u64>>;
+
+// A frame on its way to the router with the time it was received. The json
+// is parsed once and shared by the reorder window and the resume tracker, it
+// stays `None` for frames that are no json or were not parsed yet.
+pub(crate) struct ParsedFrame {
+    pub message: WsMessage,
+    pub json: Option<Value>,
+    pub received_at: f64,
+}
+
+impl ParsedFrame {
+    pub fn new(message: WsMessage, received_at: f64) -> Self {
+        Self {
+            message,
+            json: None,
+            received_at,
+        }
+    }
+}
+
+pub(crate) fn parse_json(message: &WsMessage) -> Option<Value> {
+    match message {
+        WsMessage::Text(payload) => serde_json::from_str(payload).ok(),
+        WsMessage::Binary(payload) => serde_json::from_slice(payload).ok(),
+    }
+}
+
+// Reads the sequence number from a top level `seq` field of json frames.
+pub fn default_sequence(message: &WsMessage) -> Option<u64> {
+    parse_json(message)?.get("seq")?.as_u64()
+}
+
+enum Sequence {
+    Field(String),
+    By(SequenceExtractor),
+}
+
+// Holds frames that arrived ahead of a gap and releases them in sequence
+// order once the gap fills. A gap is skipped when the window is full or the
+// timeout fired, frames that arrive for a skipped gap are dropped. Frames
+// without a sequence number are never held.
+pub struct ReorderBuffer {
+    window: usize,
+    timeout: u32,
+    sequence: Sequence,
+    expected: Option<u64>,
+    held: BTreeMap<u64, ParsedFrame>,
+    flush_scheduled: bool,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: usize, timeout: u32) -> Self {
+        Self {
+            window: window.max(1),
+            timeout,
+            sequence: Sequence::Field(String::from("seq")),
+            expected: None,
+            held: BTreeMap::new(),
+            flush_scheduled: false,
+        }
+    }
+
+    pub fn sequence_field(mut self, field: &str) -> Self {
+        self.sequence = Sequence::Field(String::from(field));
+        self
+    }
+
+    pub fn sequence_by(mut self, f: impl Fn(&WsMessage) -> Option<u64> + 'static) -> Self {
+        self.sequence = Sequence::By(Box::new(f));
+        self
+    }
+
+    pub fn timeout(&self) -> u32 {
+        self.timeout
+    }
+
+    pub fn has_gap(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    // Returns the frames that can be delivered now, in order.
+    pub fn push(&mut self, message: WsMessage) -> Vec<WsMessage> {
+        self.push_parsed(ParsedFrame::new(message, 0.0))
+            .into_iter()
+            .map(|frame| frame.message)
+            .collect()
+    }
+
+    // Like `push`, the json of the frame is parsed here unless it already
+    // holds it, and handed on with the frames.
+    pub(crate) fn push_parsed(&mut self, mut frame: ParsedFrame) -> Vec<ParsedFrame> {
+        let seq = match &self.sequence {
+            Sequence::Field(field) => {
+                if frame.json.is_none() {
+                    frame.json = parse_json(&frame.message);
+                }
+                frame
+                    .json
+                    .as_ref()
+                    .and_then(|json| json.get(field.as_str()))
+                    .and_then(Value::as_u64)
+            }
+            Sequence::By(sequence) => sequence(&frame.message),
+        };
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return vec![frame],
+        };
+        let expected = self.expected.unwrap_or(seq);
+        if seq < expected {
+            // late frame from a gap that was already skipped, or a duplicate
+            return Vec::new();
+        }
+        if seq > expected {
+            self.held.insert(seq, frame);
+            if self.held.len() > self.window {
+                return self.flush_parsed();
+            }
+            return Vec::new();
+        }
+        let mut ready = vec![frame];
+        let mut next = seq + 1;
+        while let Some(frame) = self.held.remove(&next) {
+            ready.push(frame);
+            next += 1;
+        }
+        self.expected = Some(next);
+        ready
+    }
+
+    // Gives up on the missing frames and releases everything held.
+    pub fn flush(&mut self) -> Vec<WsMessage> {
+        self.flush_parsed()
+            .into_iter()
+            .map(|frame| frame.message)
+            .collect()
+    }
+
+    pub(crate) fn flush_parsed(&mut self) -> Vec<ParsedFrame> {
+        let held = std::mem::take(&mut self.held);
+        if let Some(last) = held.keys().next_back() {
+            self.expected = Some(last + 1);
+        }
+        held.into_values().collect()
+    }
+
+    pub(crate) fn reset(&mut self) -> Vec<ParsedFrame> {
+        let held = self.flush_parsed();
+        self.expected = None;
+        held
+    }
+
+    pub(crate) fn set_flush_scheduled(&mut self, flush_scheduled: bool) -> bool {
+        let was_scheduled = self.flush_scheduled;
+        self.flush_scheduled = flush_scheduled;
+        was_scheduled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq: u64) -> WsMessage {
+        WsMessage::Text(format!("{{\"seq\": {}}}", seq))
+    }
+
+    fn sequences(messages: Vec<WsMessage>) -> Vec<u64> {
+        messages.iter().filter_map(default_sequence).collect()
+    }
+
+    #[test]
+    fn releases_frames_in_order() {
+        let mut reorder = ReorderBuffer::new(4, 100);
+        assert_eq!(sequences(reorder.push(frame(1))), vec![1]);
+        assert!(reorder.push(frame(3)).is_empty());
+        assert!(reorder.push(frame(4)).is_empty());
+        assert!(reorder.has_gap());
+        assert_eq!(sequences(reorder.push(frame(2))), vec![2, 3, 4]);
+        assert!(!reorder.has_gap());
+    }
+
+    #[test]
+    fn passes_frames_without_sequence() {
+        let mut reorder = ReorderBuffer::new(4, 100);
+        reorder.push(frame(1));
+        reorder.push(frame(3));
+        let passed = reorder.push(WsMessage::Text(String::from("plain")));
+        assert_eq!(passed.len(), 1);
+    }
+
+    #[test]
+    fn skips_the_gap_when_the_window_is_full() {
+        let mut reorder = ReorderBuffer::new(2, 100);
+        reorder.push(frame(1));
+        assert!(reorder.push(frame(3)).is_empty());
+        assert!(reorder.push(frame(4)).is_empty());
+        assert_eq!(sequences(reorder.push(frame(5))), vec![3, 4, 5]);
+        assert_eq!(sequences(reorder.push(frame(6))), vec![6]);
+    }
+
+    #[test]
+    fn drops_frames_below_the_last_flushed_one() {
+        let mut reorder = ReorderBuffer::new(4, 100);
+        reorder.push(frame(1));
+        reorder.push(frame(3));
+        assert_eq!(sequences(reorder.flush()), vec![3]);
+        assert!(reorder.push(frame(2)).is_empty());
+        assert!(reorder.push(frame(3)).is_empty());
+        assert_eq!(sequences(reorder.push(frame(4))), vec![4]);
+    }
+
+    #[test]
+    fn reads_the_sequence_from_a_custom_field_once() {
+        let mut reorder = ReorderBuffer::new(4, 100).sequence_field("n");
+        let frame = ParsedFrame::new(WsMessage::Text(String::from("{\"n\": 7}")), 5.0);
+        let ready = reorder.push_parsed(frame);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].received_at, 5.0);
+        let seq = ready[0].json.as_ref().and_then(|json| json.get("n"));
+        assert_eq!(seq, Some(&Value::from(7)));
+    }
+}
@@ -0,0 +1,83 @@
+use serde_json::{json, Value};
+
+use crate::reorder::parse_json;
+use crate::WsMessage;
+
+pub type ResumeIdExtractor = Box<dyn Fn(&WsMessage) -> Option<Value>>;
+pub type ResumeFrameBuilder = Box<dyn Fn(&Value) -> WsMessage>;
+
+// Remembers the id of the last routed message, and after a reconnect sends it
+// back so the server can replay what was missed in between. By default the id
+// is read from a top level `id` field and goes out as `{"resume_from": <id>}`.
+pub struct ResumeTracker {
+    id: ResumeId,
+    frame: ResumeFrameBuilder,
+    last_id: Option<Value>,
+}
+
+enum ResumeId {
+    Field(String),
+    By(ResumeIdExtractor),
+}
+
+impl ResumeTracker {
+    pub fn new() -> Self {
+        Self {
+            id: ResumeId::Field(String::from("id")),
+            frame: Box::new(|id| WsMessage::Text(json!({ "resume_from": id }).to_string())),
+            last_id: None,
+        }
+    }
+
+    pub fn id_field(mut self, field: &str) -> Self {
+        self.id = ResumeId::Field(String::from(field));
+        self
+    }
+
+    pub fn id_by(mut self, f: impl Fn(&WsMessage) -> Option<Value> + 'static) -> Self {
+        self.id = ResumeId::By(Box::new(f));
+        self
+    }
+
+    pub fn frame(mut self, f: impl Fn(&Value) -> WsMessage + 'static) -> Self {
+        self.frame = Box::new(f);
+        self
+    }
+
+    pub fn last_id(&self) -> Option<&Value> {
+        self.last_id.as_ref()
+    }
+
+    // `json` is the frame parsed by an earlier stage, if any.
+    pub(crate) fn extract(&self, message: &WsMessage, json: Option<&Value>) -> Option<Value> {
+        match &self.id {
+            ResumeId::Field(field) => match json {
+                Some(json) => id_field(json, field),
+                None => id_field(&parse_json(message)?, field),
+            },
+            ResumeId::By(id) => id(message),
+        }
+    }
+
+    pub(crate) fn record(&mut self, id: Value) {
+        self.last_id = Some(id);
+    }
+
+    // `None` until a message with an id was routed.
+    pub(crate) fn resume_frame(&self) -> Option<WsMessage> {
+        self.last_id.as_ref().map(|id| (self.frame)(id))
+    }
+}
+
+impl Default for ResumeTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn id_field(value: &Value, field: &str) -> Option<Value> {
+    match value.get(field)? {
+        Value::Null => None,
+        id => Some(id.clone()),
+    }
+}
@@ -0,0 +1,98 @@
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+
+use crate::timers::{clear_interval, clear_timeout, set_interval, set_timeout_once};
+
+struct ScheduledTimer {
+    id: Cell<i32>,
+    interval: bool,
+    active: Cell<bool>,
+}
+
+impl ScheduledTimer {
+    fn cancel(&self) {
+        if !self.active.replace(false) {
+            return;
+        }
+        if self.interval {
+            clear_interval(self.id.get());
+        } else {
+            clear_timeout(self.id.get());
+        }
+    }
+}
+
+// The scheduled send stops when the handle is cancelled or dropped, or when
+// the websocket is closed.
+pub struct ScheduleHandle {
+    timer: Rc<ScheduledTimer>,
+}
+
+impl ScheduleHandle {
+    pub fn cancel(&self) {
+        self.timer.cancel();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.timer.active.get()
+    }
+}
+
+impl Drop for ScheduleHandle {
+    fn drop(&mut self) {
+        self.timer.cancel();
+    }
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    timers: RefCell<Vec<Weak<ScheduledTimer>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn schedule(
+        &self,
+        mut f: impl FnMut() + 'static,
+        time: u32,
+        interval: bool,
+    ) -> ScheduleHandle {
+        let timer = Rc::new(ScheduledTimer {
+            id: Cell::new(0),
+            interval,
+            active: Cell::new(true),
+        });
+        let weak_timer = Rc::downgrade(&timer);
+        let tick = move || {
+            let timer = match weak_timer.upgrade() {
+                Some(timer) if timer.active.get() => timer,
+                _ => return,
+            };
+            if !timer.interval {
+                timer.active.set(false);
+            }
+            f();
+        };
+        let id = if interval {
+            set_interval(tick, time)
+        } else {
+            set_timeout_once(tick, time)
+        };
+        timer.id.set(id);
+        let mut timers = self.timers.borrow_mut();
+        timers.retain(|timer| timer.strong_count() > 0);
+        timers.push(Rc::downgrade(&timer));
+        ScheduleHandle { timer }
+    }
+
+    pub(crate) fn cancel_all(&self) {
+        for timer in self.timers.borrow_mut().drain(..) {
+            if let Some(timer) = timer.upgrade() {
+                timer.cancel();
+            }
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use js_sys::Reflect;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{MessageEvent, MessagePort, SharedWorker, SharedWorkerGlobalScope, WebSocket};
+
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::timers::{clear_interval, set_interval};
+use crate::{ReadyState, Websocket, WsMessage};
+
+// The worker can't see a tab go away without a `leave`, a tab that misses a
+// few pings in a row is dropped.
+const TAB_PING_INTERVAL: u32 = 5_000;
+const TAB_TIMEOUT: f64 = 15_000.0;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+// Exchanged as json strings between the tabs and the worker.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PortMessage {
+    Subscribe { event: String },
+    Unsubscribe { event: String },
+    Send { data: String },
+    Leave,
+    Ping,
+    Pong,
+    Event { event: String, data: String },
+    State { ready_state: u16 },
+}
+
+fn post(port: &MessagePort, message: &PortMessage) {
+    let message = match serde_json::to_string(message) {
+        Ok(message) => message,
+        Err(err) => {
+            console_log!("error on serialize port message: {:?}", err);
+            return;
+        }
+    };
+    if let Err(err) = port.post_message(&JsValue::from_str(&message)) {
+        console_log!("error on post port message: {:?}", err);
+    }
+}
+
+fn parse(event: &MessageEvent) -> Option<PortMessage> {
+    let data = event.data().as_string()?;
+    match serde_json::from_str(&data) {
+        Ok(message) => Some(message),
+        Err(err) => {
+            console_log!("unknown port message {}: {:?}", data, err);
+            None
+        }
+    }
+}
+
+struct Tab {
+    id: u32,
+    port: MessagePort,
+    events: HashSet<String>,
+    // when the tab last posted anything, pongs included
+    last_seen: f64,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl Drop for Tab {
+    fn drop(&mut self) {
+        self.port.set_onmessage(None);
+        self.port.close();
+    }
+}
+
+#[derive(Default)]
+struct Tabs {
+    next_id: u32,
+    tabs: Vec<Tab>,
+    // events the host listens to on the socket
+    listening: HashSet<String>,
+}
+
+impl Tabs {
+    fn broadcast(&self, message: &PortMessage) {
+        for tab in self.tabs.iter() {
+            post(&tab.port, message);
+        }
+    }
+
+    fn forward(&self, event: &str, data: &str) {
+        let message = PortMessage::Event {
+            event: String::from(event),
+            data: String::from(data),
+        };
+        for tab in self.tabs.iter().filter(|tab| tab.events.contains(event)) {
+            post(&tab.port, &message);
+        }
+    }
+
+    fn seen(&mut self, id: u32, now: f64) {
+        if let Some(tab) = self.tabs.iter_mut().find(|tab| tab.id == id) {
+            tab.last_seen = now;
+        }
+    }
+
+    // Drops the tabs that stopped answering and pings the others.
+    fn check_alive(&mut self, now: f64) {
+        self.tabs.retain(|tab| now - tab.last_seen <= TAB_TIMEOUT);
+        self.broadcast(&PortMessage::Ping);
+    }
+}
+
+// Runs inside the SharedWorker script and owns the one upstream socket. Every
+// tab that connects to the worker gets the events it subscribed to, only
+// `Payload::Data` payloads cross the port.
+pub struct SharedSocketHost {
+    scope: SharedWorkerGlobalScope,
+    websocket: Websocket,
+    tabs: Rc<RefCell<Tabs>>,
+    ping_interval: i32,
+    _onconnect: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl SharedSocketHost {
+    pub fn start(factory: WsFactory) -> Result<Self, WsError> {
+        let global = js_sys::global();
+        if !Reflect::has(&global, &JsValue::from_str("onconnect")).unwrap_or(false) {
+            return Err(WsError::ConnectionFailed(String::from(
+                "not running in a SharedWorker",
+            )));
+        }
+        let scope: SharedWorkerGlobalScope = global.unchecked_into();
+        let websocket = factory.build()?;
+        let tabs = Rc::new(RefCell::new(Tabs::default()));
+        for event in ["open", "close"].iter() {
+            tabs.borrow_mut().listening.insert(String::from(*event));
+            let tabs = tabs.clone();
+            let name = String::from(*event);
+            websocket.add_listener(String::from(*event), move |payload| {
+                let ready_state = if name == "open" {
+                    WebSocket::OPEN
+                } else {
+                    WebSocket::CLOSED
+                };
+                let tabs = tabs.borrow();
+                tabs.broadcast(&PortMessage::State { ready_state });
+                if let Payload::Data(data) = payload {
+                    tabs.forward(&name, data);
+                }
+            });
+        }
+        let onconnect = {
+            let websocket = websocket.clone();
+            let tabs = tabs.clone();
+            Closure::wrap(Box::new(move |event: MessageEvent| {
+                if let Ok(port) = event.ports().get(0).dyn_into::<MessagePort>() {
+                    Self::attach(&websocket, &tabs, port);
+                }
+            }) as Box<dyn FnMut(MessageEvent)>)
+        };
+        scope.set_onconnect(Some(onconnect.as_ref().unchecked_ref()));
+        let ping_interval = {
+            let tabs = tabs.clone();
+            set_interval(
+                move || tabs.borrow_mut().check_alive(js_sys::Date::now()),
+                TAB_PING_INTERVAL,
+            )
+        };
+        Ok(Self {
+            scope,
+            websocket,
+            tabs,
+            ping_interval,
+            _onconnect: onconnect,
+        })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.websocket
+    }
+
+    pub fn tab_count(&self) -> usize {
+        self.tabs.borrow().tabs.len()
+    }
+
+    // The tab keeps the closure of its port, both go once it left or stopped
+    // answering the pings.
+    fn attach(websocket: &Websocket, tabs: &Rc<RefCell<Tabs>>, port: MessagePort) {
+        let id = {
+            let mut tabs = tabs.borrow_mut();
+            let id = tabs.next_id;
+            tabs.next_id += 1;
+            id
+        };
+        let ready_state = websocket_ready_state(websocket);
+        let websocket = websocket.clone();
+        let tabs_ref = tabs.clone();
+        let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let message = match parse(&event) {
+                Some(message) => message,
+                None => return,
+            };
+            tabs_ref.borrow_mut().seen(id, js_sys::Date::now());
+            match message {
+                PortMessage::Subscribe { event } => {
+                    Self::subscribe(&websocket, &tabs_ref, id, event);
+                }
+                PortMessage::Unsubscribe { event } => {
+                    let mut tabs = tabs_ref.borrow_mut();
+                    if let Some(tab) = tabs.tabs.iter_mut().find(|tab| tab.id == id) {
+                        tab.events.remove(&event);
+                    }
+                }
+                PortMessage::Send { data } => {
+                    if let Err(err) = websocket.send(WsMessage::Text(data)) {
+                        console_log!("error on send from tab {}: {:?}", id, err);
+                    }
+                }
+                PortMessage::Leave => {
+                    // dropped once the borrow ended, it closes the port
+                    let left = {
+                        let mut tabs = tabs_ref.borrow_mut();
+                        let index = tabs.tabs.iter().position(|tab| tab.id == id);
+                        index.map(|index| tabs.tabs.remove(index))
+                    };
+                    drop(left);
+                }
+                _ => (),
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        port.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        tabs.borrow_mut().tabs.push(Tab {
+            id,
+            port: port.clone(),
+            events: HashSet::new(),
+            last_seen: js_sys::Date::now(),
+            _onmessage: onmessage,
+        });
+        port.start();
+        post(&port, &PortMessage::State { ready_state });
+    }
+
+    fn subscribe(websocket: &Websocket, tabs: &Rc<RefCell<Tabs>>, id: u32, event: String) {
+        let listen = {
+            let mut tabs = tabs.borrow_mut();
+            if let Some(tab) = tabs.tabs.iter_mut().find(|tab| tab.id == id) {
+                tab.events.insert(event.clone());
+            }
+            tabs.listening.insert(event.clone())
+        };
+        if listen {
+            let tabs = tabs.clone();
+            let name = event.clone();
+            websocket.add_listener(event, move |payload| {
+                if let Payload::Data(data) = payload {
+                    tabs.borrow().forward(&name, data);
+                }
+            });
+        }
+    }
+}
+
+impl Drop for SharedSocketHost {
+    fn drop(&mut self) {
+        clear_interval(self.ping_interval);
+        self.scope.set_onconnect(None);
+        // the port closures of the tabs hold socket handles and the socket
+        // listeners hold the tabs, dropping the tabs lets the socket close
+        let tabs = std::mem::take(&mut self.tabs.borrow_mut().tabs);
+        drop(tabs);
+    }
+}
+
+fn websocket_ready_state(websocket: &Websocket) -> u16 {
+    match websocket.ready_state() {
+        ReadyState::Connecting => WebSocket::CONNECTING,
+        ReadyState::Open => WebSocket::OPEN,
+        ReadyState::Closing => WebSocket::CLOSING,
+        ReadyState::Closed => WebSocket::CLOSED,
+        ReadyState::Other(state) => state,
+    }
+}
+
+type TabListener = Rc<dyn Fn(&str)>;
+
+// The per tab side, talks to the `SharedSocketHost` running in the worker
+// script at `script_url`. Tabs that use the same script url and name share
+// one connection.
+pub struct SharedSocketClient {
+    port: MessagePort,
+    listeners: Rc<RefCell<HashMap<String, TabListener>>>,
+    ready_state: Rc<Cell<u16>>,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl SharedSocketClient {
+    pub fn connect(script_url: &str, name: &str) -> Result<Self, WsError> {
+        let worker = SharedWorker::new_with_str(script_url, name).map_err(WsError::Js)?;
+        let port = worker.port();
+        let listeners: Rc<RefCell<HashMap<String, TabListener>>> =
+            Rc::new(RefCell::new(HashMap::new()));
+        let ready_state = Rc::new(Cell::new(WebSocket::CONNECTING));
+        let onmessage = {
+            let listeners = listeners.clone();
+            let ready_state = ready_state.clone();
+            let pong_port = port.clone();
+            Closure::wrap(Box::new(move |event: MessageEvent| match parse(&event) {
+                Some(PortMessage::Ping) => post(&pong_port, &PortMessage::Pong),
+                Some(PortMessage::Event { event, data }) => {
+                    let listener = listeners.borrow().get(&event).cloned();
+                    if let Some(listener) = listener {
+                        listener(&data);
+                    }
+                }
+                Some(PortMessage::State { ready_state: state }) => ready_state.set(state),
+                _ => (),
+            }) as Box<dyn FnMut(MessageEvent)>)
+        };
+        port.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        port.start();
+        Ok(Self {
+            port,
+            listeners,
+            ready_state,
+            _onmessage: onmessage,
+        })
+    }
+
+    pub fn add_listener(&self, event: &str, f: impl Fn(&str) + 'static) {
+        self.listeners
+            .borrow_mut()
+            .insert(String::from(event), Rc::new(f));
+        post(
+            &self.port,
+            &PortMessage::Subscribe {
+                event: String::from(event),
+            },
+        );
+    }
+
+    pub fn remove_listener(&self, event: &str) {
+        self.listeners.borrow_mut().remove(event);
+        post(
+            &self.port,
+            &PortMessage::Unsubscribe {
+                event: String::from(event),
+            },
+        );
+    }
+
+    // Sent by the worker as a text frame, or queued there while it is
+    // reconnecting.
+    pub fn send(&self, data: &str) {
+        post(
+            &self.port,
+            &PortMessage::Send {
+                data: String::from(data),
+            },
+        );
+    }
+
+    // As last reported by the worker.
+    pub fn ready_state(&self) -> ReadyState {
+        ReadyState::from(self.ready_state.get())
+    }
+}
+
+impl Drop for SharedSocketClient {
+    fn drop(&mut self) {
+        post(&self.port, &PortMessage::Leave);
+        self.port.set_onmessage(None);
+        self.port.close();
+    }
+}
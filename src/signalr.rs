@@ -0,0 +1,481 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use jsonrpc_core::Id;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::close::CloseCode;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::simple_rpc::{IdGenerator, NumericIdGenerator, CONNECTION_CLOSED};
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+// Every record of the json hub protocol ends with this character.
+const RECORD_SEPARATOR: char = '\u{1e}';
+const KEEP_ALIVE_INTERVAL: u32 = 15_000;
+const SERVER_TIMEOUT: u32 = 30_000;
+
+#[derive(Serialize, Deserialize)]
+struct HandshakeRequest<'a> {
+    protocol: &'a str,
+    version: u8,
+}
+
+#[derive(Deserialize)]
+struct HandshakeResponse {
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HubMessage {
+    #[serde(rename = "type")]
+    kind: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invocation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arguments: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    item: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allow_reconnect: Option<bool>,
+}
+
+impl HubMessage {
+    fn new(kind: u8) -> Self {
+        Self {
+            kind,
+            invocation_id: None,
+            target: None,
+            arguments: None,
+            item: None,
+            result: None,
+            error: None,
+            allow_reconnect: None,
+        }
+    }
+
+    fn invocation(kind: u8, target: &str, arguments: Vec<Value>) -> Self {
+        let mut message = Self::new(kind);
+        message.target = Some(String::from(target));
+        message.arguments = Some(arguments);
+        message
+    }
+}
+
+const INVOCATION: u8 = 1;
+const STREAM_ITEM: u8 = 2;
+const COMPLETION: u8 = 3;
+const STREAM_INVOCATION: u8 = 4;
+const CANCEL_INVOCATION: u8 = 5;
+const PING: u8 = 6;
+const CLOSE: u8 = 7;
+
+type HubMethod = Rc<dyn Fn(Vec<Value>)>;
+type CompletionCallback = Box<dyn FnOnce(Result<Value, String>)>;
+type StreamItemCallback = Rc<dyn Fn(Value)>;
+
+struct Pending {
+    on_item: Option<StreamItemCallback>,
+    on_complete: CompletionCallback,
+}
+
+struct SignalRInner {
+    websocket: Websocket,
+    id_generator: Box<dyn IdGenerator>,
+    methods: RefCell<HashMap<String, HubMethod>>,
+    // invocations and streams waiting for their completion
+    pending: RefCell<HashMap<String, Pending>>,
+    // messages made before the handshake of the current socket completed
+    queue: RefCell<VecDeque<HubMessage>>,
+    handshake_done: Cell<bool>,
+    // bumped on every close, stops the keep-alive of the previous socket
+    session: Cell<u64>,
+    server_timeout: Cell<Option<i32>>,
+}
+
+impl SignalRInner {
+    fn send_record(&self, record: String) -> Result<(), WsError> {
+        self.websocket
+            .send(WsMessage::Text(format!("{}{}", record, RECORD_SEPARATOR)))
+    }
+
+    fn send(&self, message: &HubMessage) -> Result<(), WsError> {
+        self.send_record(serde_json::to_string(message)?)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.handshake_done.get() && matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+
+    fn send_or_queue(&self, message: HubMessage) -> Result<(), WsError> {
+        if !self.is_ready() {
+            self.queue.borrow_mut().push_back(message);
+            return Ok(());
+        }
+        self.send(&message)
+    }
+
+    fn invocation_id(&self) -> String {
+        match self.id_generator.next_id() {
+            Id::Num(id) => id.to_string(),
+            Id::Str(id) => id,
+            Id::Null => String::new(),
+        }
+    }
+
+    fn opened(&self) {
+        self.handshake_done.set(false);
+        let handshake = HandshakeRequest {
+            protocol: "json",
+            version: 1,
+        };
+        let sent = serde_json::to_string(&handshake)
+            .map_err(WsError::from)
+            .and_then(|handshake| self.send_record(handshake));
+        if let Err(err) = sent {
+            console_log!("error on signalr handshake: {:?}", err);
+        }
+    }
+
+    fn closed(&self) {
+        self.handshake_done.set(false);
+        self.session.set(self.session.get() + 1);
+        if let Some(id) = self.server_timeout.take() {
+            clear_timeout(id);
+        }
+        // the server forgets invocations with the connection
+        let pending: Vec<Pending> = self
+            .pending
+            .borrow_mut()
+            .drain()
+            .map(|(_, pending)| pending)
+            .collect();
+        for pending in pending {
+            (pending.on_complete)(Err(String::from(CONNECTION_CLOSED)));
+        }
+    }
+
+    fn keep_alive(inner: Weak<Self>) {
+        let session = match inner.upgrade() {
+            Some(inner) => inner.session.get(),
+            None => return,
+        };
+        set_timeout_once(
+            move || {
+                let signalr = match inner.upgrade() {
+                    Some(signalr) if signalr.session.get() == session => signalr,
+                    _ => return,
+                };
+                if let Err(err) = signalr.send(&HubMessage::new(PING)) {
+                    console_log!("error on signalr ping: {:?}", err);
+                }
+                Self::keep_alive(inner);
+            },
+            KEEP_ALIVE_INTERVAL,
+        );
+    }
+
+    fn reset_server_timeout(&self) {
+        if let Some(id) = self.server_timeout.take() {
+            clear_timeout(id);
+        }
+        if !self.handshake_done.get() {
+            return;
+        }
+        let core = Rc::downgrade(&self.websocket.core);
+        let id = set_timeout_once(
+            move || {
+                if let Some(core) = core.upgrade() {
+                    console_log!("no signalr message in time, reconnecting");
+                    force_reconnect(&core);
+                }
+            },
+            SERVER_TIMEOUT,
+        );
+        self.server_timeout.set(Some(id));
+    }
+
+    fn receive(inner: &Rc<Self>, data: &str) {
+        for record in data
+            .split(RECORD_SEPARATOR)
+            .filter(|record| !record.is_empty())
+        {
+            if !inner.handshake_done.get() {
+                Self::handshake_answered(inner, record);
+                continue;
+            }
+            match serde_json::from_str::<HubMessage>(record) {
+                Ok(message) => Self::dispatch(inner, message),
+                Err(err) => console_log!("error on parse signalr message {}: {:?}", record, err),
+            }
+        }
+        inner.reset_server_timeout();
+    }
+
+    fn handshake_answered(inner: &Rc<Self>, record: &str) {
+        match serde_json::from_str::<HandshakeResponse>(record) {
+            Ok(HandshakeResponse { error: None }) => (),
+            Ok(HandshakeResponse { error: Some(err) }) => {
+                console_log!("signalr handshake failed: {}", err);
+                return;
+            }
+            Err(err) => {
+                console_log!("error on parse signalr handshake {}: {:?}", record, err);
+                return;
+            }
+        }
+        inner.handshake_done.set(true);
+        Self::keep_alive(Rc::downgrade(inner));
+        let queued: Vec<HubMessage> = inner.queue.borrow_mut().drain(..).collect();
+        for message in queued {
+            if let Err(err) = inner.send(&message) {
+                console_log!("error on signalr send: {:?}", err);
+            }
+        }
+    }
+
+    fn dispatch(inner: &Rc<Self>, message: HubMessage) {
+        match message.kind {
+            INVOCATION => {
+                let target = message.target.unwrap_or_default();
+                let method = inner.methods.borrow().get(&target).cloned();
+                match method {
+                    Some(method) => method(message.arguments.unwrap_or_default()),
+                    None => console_log!("no signalr hub method {}", target),
+                }
+                // the server waits for a result, hub methods don't return one
+                if let Some(invocation_id) = message.invocation_id {
+                    let mut completion = HubMessage::new(COMPLETION);
+                    completion.invocation_id = Some(invocation_id);
+                    completion.error = Some(String::from("Client didn't provide a result."));
+                    if let Err(err) = inner.send(&completion) {
+                        console_log!("error on signalr completion: {:?}", err);
+                    }
+                }
+            }
+            STREAM_ITEM => {
+                let on_item = message.invocation_id.as_ref().and_then(|id| {
+                    inner
+                        .pending
+                        .borrow()
+                        .get(id)
+                        .and_then(|pending| pending.on_item.clone())
+                });
+                if let Some(on_item) = on_item {
+                    on_item(message.item.unwrap_or(Value::Null));
+                }
+            }
+            COMPLETION => {
+                let pending = message
+                    .invocation_id
+                    .as_ref()
+                    .and_then(|id| inner.pending.borrow_mut().remove(id));
+                if let Some(pending) = pending {
+                    let result = match message.error {
+                        Some(err) => Err(err),
+                        None => Ok(message.result.unwrap_or(Value::Null)),
+                    };
+                    (pending.on_complete)(result);
+                }
+            }
+            PING => (),
+            CLOSE => {
+                if let Some(err) = message.error.as_ref() {
+                    console_log!("signalr server closed the connection: {}", err);
+                }
+                if message.allow_reconnect.unwrap_or(false) {
+                    force_reconnect(&inner.websocket.core);
+                } else if let Err(err) = inner
+                    .websocket
+                    .clone()
+                    .close(Some(CloseCode::Normal), message.error)
+                {
+                    console_log!("error on close: {:?}", err);
+                }
+            }
+            kind => console_log!("unknown signalr message type {}", kind),
+        }
+    }
+}
+
+// A SignalR client speaking the json hub protocol over the reconnecting
+// socket. The factory url is the hub url with negotiation skipped on the
+// server, `?id=` tokens of a negotiate call are single use and don't survive
+// a reconnect. Every open starts with the json handshake, invocations made
+// before it completes are queued, and those still waiting for a completion
+// fail when the socket closes. The client pings the hub and reconnects when
+// the server stays silent past its timeout. Invocation ids come from the rpc
+// id generator, numeric by default.
+#[derive(Clone)]
+pub struct SignalRClient {
+    inner: Rc<SignalRInner>,
+}
+
+impl SignalRClient {
+    pub fn build(factory: WsFactory) -> Result<Self, WsError> {
+        Self::build_with_ids(factory, NumericIdGenerator::new())
+    }
+
+    pub fn build_with_ids(
+        factory: WsFactory,
+        id_generator: impl IdGenerator + 'static,
+    ) -> Result<Self, WsError> {
+        let inner = ProtocolClient::new(factory, |inner: &Rc<SignalRInner>, message| {
+            if let WsMessage::Text(data) = message {
+                SignalRInner::receive(inner, &data);
+            }
+        })
+        .on_open(|signalr: &Rc<SignalRInner>| signalr.opened())
+        .on_close(|signalr: &Rc<SignalRInner>| signalr.closed())
+        .build(|websocket| SignalRInner {
+            websocket,
+            id_generator: Box::new(id_generator),
+            methods: RefCell::new(HashMap::new()),
+            pending: RefCell::new(HashMap::new()),
+            queue: RefCell::new(VecDeque::new()),
+            handshake_done: Cell::new(false),
+            session: Cell::new(0),
+            server_timeout: Cell::new(None),
+        })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.is_ready()
+    }
+
+    // Called with the arguments of every invocation of `target` by the hub.
+    pub fn on(&self, target: &str, f: impl Fn(Vec<Value>) + 'static) {
+        self.inner
+            .methods
+            .borrow_mut()
+            .insert(String::from(target), Rc::new(f));
+    }
+
+    pub fn off(&self, target: &str) {
+        self.inner.methods.borrow_mut().remove(target);
+    }
+
+    // Invokes the hub method without waiting for a result. Queued until the
+    // handshake of the current socket completed, as are `invoke` and
+    // `stream`.
+    pub fn send(&self, target: &str, arguments: Vec<Value>) -> Result<(), WsError> {
+        self.inner
+            .send_or_queue(HubMessage::invocation(INVOCATION, target, arguments))
+    }
+
+    // The callback gets the result or the error of the hub method, or
+    // `connection closed` when the socket closed before it completed.
+    pub fn invoke(
+        &self,
+        target: &str,
+        arguments: Vec<Value>,
+        callback: impl FnOnce(Result<Value, String>) + 'static,
+    ) -> Result<(), WsError> {
+        let invocation_id = self.inner.invocation_id();
+        let mut message = HubMessage::invocation(INVOCATION, target, arguments);
+        message.invocation_id = Some(invocation_id.clone());
+        self.inner.pending.borrow_mut().insert(
+            invocation_id.clone(),
+            Pending {
+                on_item: None,
+                on_complete: Box::new(callback),
+            },
+        );
+        if let Err(err) = self.inner.send_or_queue(message) {
+            self.inner.pending.borrow_mut().remove(&invocation_id);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Every item of the server stream goes to `on_item`, `on_complete` is
+    // called once it ended, failed or the socket closed.
+    pub fn stream(
+        &self,
+        target: &str,
+        arguments: Vec<Value>,
+        on_item: impl Fn(Value) + 'static,
+        on_complete: impl FnOnce(Result<(), String>) + 'static,
+    ) -> Result<HubStream, WsError> {
+        let invocation_id = self.inner.invocation_id();
+        let mut message = HubMessage::invocation(STREAM_INVOCATION, target, arguments);
+        message.invocation_id = Some(invocation_id.clone());
+        self.inner.pending.borrow_mut().insert(
+            invocation_id.clone(),
+            Pending {
+                on_item: Some(Rc::new(on_item)),
+                on_complete: Box::new(move |result| on_complete(result.map(|_| ()))),
+            },
+        );
+        if let Err(err) = self.inner.send_or_queue(message) {
+            self.inner.pending.borrow_mut().remove(&invocation_id);
+            return Err(err);
+        }
+        Ok(HubStream {
+            invocation_id,
+            signalr: Rc::downgrade(&self.inner),
+        })
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.inner.pending.borrow().len()
+    }
+}
+
+pub struct HubStream {
+    invocation_id: String,
+    signalr: Weak<SignalRInner>,
+}
+
+impl HubStream {
+    pub fn invocation_id(&self) -> &str {
+        &self.invocation_id
+    }
+
+    // Asks the server to stop the stream, no callback is called after it.
+    pub fn cancel(self) -> Result<(), WsError> {
+        let signalr = match self.signalr.upgrade() {
+            Some(signalr) => signalr,
+            None => return Ok(()),
+        };
+        if signalr
+            .pending
+            .borrow_mut()
+            .remove(&self.invocation_id)
+            .is_none()
+        {
+            return Ok(());
+        }
+        let mut message = HubMessage::new(CANCEL_INVOCATION);
+        message.invocation_id = Some(self.invocation_id);
+        signalr.send_or_queue(message)
+    }
+}
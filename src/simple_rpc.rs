@@ -1,14 +1,179 @@
 use core::sync::atomic;
 use std::collections::HashMap;
 use std::fmt;
-use std::sync::atomic::AtomicUsize;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::Arc;
 
-use jsonrpc_core::{Call, Id, MethodCall, Output, Params, Response, Value, Version};
+use jsonrpc_core::{Call, Id, MethodCall, Notification, Output, Params, Response, Value, Version};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Map;
 
+use crate::error::WsError;
+use crate::{Websocket, WsMessage};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RpcCodec {
+    #[default]
+    Json,
+    #[cfg(feature = "rmp-serde")]
+    MessagePack,
+}
+
+pub trait IdGenerator {
+    fn next_id(&self) -> Id;
+}
+
+#[derive(Default)]
+pub struct NumericIdGenerator {
+    id: Arc<AtomicUsize>,
+}
+
+impl NumericIdGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdGenerator for NumericIdGenerator {
+    fn next_id(&self) -> Id {
+        let id = self.id.fetch_add(1, atomic::Ordering::AcqRel);
+        Id::Num(id as u64)
+    }
+}
+
+pub struct StringIdGenerator {
+    prefix: String,
+    id: Arc<AtomicUsize>,
+}
+
+impl StringIdGenerator {
+    pub fn new<P: Into<String>>(prefix: P) -> Self {
+        Self {
+            prefix: prefix.into(),
+            id: Arc::new(Default::default()),
+        }
+    }
+}
+
+impl IdGenerator for StringIdGenerator {
+    fn next_id(&self) -> Id {
+        let id = self.id.fetch_add(1, atomic::Ordering::AcqRel);
+        Id::Str(format!("{}{}", self.prefix, id))
+    }
+}
+
+// Numeric ids from 1 up to `max`, then from 1 again. With `MAX_SAFE_ID` they
+// stay exact as JSON numbers in the browser, the range WAMP asks for.
+pub struct BoundedIdGenerator {
+    max: u64,
+    id: Arc<AtomicU64>,
+}
+
+pub const MAX_SAFE_ID: u64 = 1 << 53;
+
+impl Default for BoundedIdGenerator {
+    fn default() -> Self {
+        Self::new(MAX_SAFE_ID)
+    }
+}
+
+impl BoundedIdGenerator {
+    pub fn new(max: u64) -> Self {
+        Self {
+            max: max.max(1),
+            id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // The id `next_id` returned last, 0 before the first one.
+    pub fn current(&self) -> u64 {
+        self.id.load(atomic::Ordering::Acquire)
+    }
+}
+
+impl IdGenerator for BoundedIdGenerator {
+    fn next_id(&self) -> Id {
+        let max = self.max;
+        let previous = self
+            .id
+            .fetch_update(atomic::Ordering::AcqRel, atomic::Ordering::Acquire, |id| {
+                Some(if id >= max { 1 } else { id + 1 })
+            })
+            .unwrap_or(0);
+        Id::Num(if previous >= max { 1 } else { previous + 1 })
+    }
+}
+
+#[derive(Default)]
+pub struct UuidIdGenerator;
+
+impl UuidIdGenerator {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn random_bytes() -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        for byte in bytes.iter_mut() {
+            *byte = (js_sys::Math::random() * 256.0) as u8;
+        }
+        // version 4, variant RFC 4122
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        bytes
+    }
+}
+
+impl IdGenerator for UuidIdGenerator {
+    fn next_id(&self) -> Id {
+        let mut uuid = String::with_capacity(36);
+        for (index, byte) in Self::random_bytes().iter().enumerate() {
+            if index == 4 || index == 6 || index == 8 || index == 10 {
+                uuid.push('-');
+            }
+            uuid.push_str(&format!("{:02x}", byte));
+        }
+        Id::Str(uuid)
+    }
+}
+
+// A json-rpc frame with `params`, `result` and `error` left as raw json, so
+// it is parsed once and a successful result is never built into a `Value`
+// tree. A `null` result is kept as the raw `null`, not taken for a missing one.
+#[derive(Deserialize)]
+struct RawRpcMessage<'a> {
+    #[serde(default)]
+    jsonrpc: Option<Version>,
+    #[serde(default)]
+    id: Option<Id>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(borrow, default, deserialize_with = "present")]
+    params: Option<&'a RawValue>,
+    #[serde(borrow, default, deserialize_with = "present")]
+    result: Option<&'a RawValue>,
+    #[serde(borrow, default, deserialize_with = "present")]
+    error: Option<&'a RawValue>,
+}
+
+fn present<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'de RawValue>, D::Error> {
+    <&RawValue>::deserialize(deserializer).map(Some)
+}
+
+// An incoming json-rpc or msgpack-rpc frame. `Result` is the fast path for
+// successful json responses, the result is borrowed straight from the payload.
+pub enum RpcMessage<'a> {
+    Notification(Notification),
+    Result(Id, &'a RawValue),
+    Response(Result<RPCResponse, RpcError>),
+}
+
 pub struct RPCResponse {
-    pub(crate) id: Option<u64>,
+    pub(crate) id: Option<Id>,
     pub(crate) result: Value,
 }
 
@@ -25,135 +190,1237 @@ impl fmt::Display for RPCResponse {
 
 #[derive(Debug)]
 pub struct RpcError {
-    pub(crate) id: Option<u64>,
+    pub(crate) id: Option<Id>,
+    pub(crate) code: Option<i64>,
     pub(crate) msg: String,
 }
 
+impl RpcError {
+    pub fn id(&self) -> Option<&Id> {
+        self.id.as_ref()
+    }
+
+    pub fn code(&self) -> Option<i64> {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        self.msg.as_str()
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        match self.code {
+            Some(code) => (-32099..=-32000).contains(&code),
+            None => false,
+        }
+    }
+}
+
 impl fmt::Display for RpcError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.msg)
     }
 }
 
-pub type RPCHandler = Box<dyn Fn(String) + 'static>;
+#[derive(Clone, Debug)]
+pub struct RpcRequestHandle {
+    id: Id,
+    method: String,
+    created_at: f64,
+}
+
+impl RpcRequestHandle {
+    pub(crate) fn new(id: Id, method: String) -> Self {
+        Self {
+            id,
+            method,
+            created_at: js_sys::Date::now(),
+        }
+    }
+
+    // For responses without a pending request, there is no method and no
+    // creation time.
+    fn unknown(id: Id) -> Self {
+        Self {
+            id,
+            method: String::new(),
+            created_at: 0.0,
+        }
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn method(&self) -> &str {
+        self.method.as_str()
+    }
+
+    pub fn created_at(&self) -> f64 {
+        self.created_at
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PendingRpc {
+    pub id: Id,
+    pub method: String,
+    pub elapsed_ms: f64,
+}
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: u32,
+    pub retry_on: Rc<dyn Fn(&RpcError) -> bool>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, backoff: u32) -> Self {
+        Self {
+            max_retries,
+            backoff,
+            retry_on: Rc::new(|err: &RpcError| err.is_server_error()),
+        }
+    }
+
+    pub fn retry_on(mut self, f: impl Fn(&RpcError) -> bool + 'static) -> Self {
+        self.retry_on = Rc::new(f);
+        self
+    }
+
+    fn delay(&self, attempt: u32) -> u32 {
+        // exponential backoff: backoff, 2 * backoff, 4 * backoff, ...
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+// upper bounds of the latency histogram buckets, the last bucket is unbounded
+pub const LATENCY_BUCKETS_MS: [f64; 8] = [10.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RpcMethodStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub total_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub buckets: Vec<u64>,
+}
+
+impl RpcMethodStats {
+    fn new() -> Self {
+        Self {
+            calls: 0,
+            errors: 0,
+            total_ms: 0.0,
+            min_ms: f64::MAX,
+            max_ms: 0.0,
+            buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+        }
+    }
+
+    pub fn average_ms(&self) -> f64 {
+        if self.calls == 0 {
+            return 0.0;
+        }
+        self.total_ms / self.calls as f64
+    }
+
+    fn record(&mut self, latency_ms: f64, failed: bool) {
+        self.calls += 1;
+        if failed {
+            self.errors += 1;
+        }
+        self.total_ms += latency_ms;
+        self.min_ms = self.min_ms.min(latency_ms);
+        self.max_ms = self.max_ms.max(latency_ms);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+struct RetryState {
+    policy: RetryPolicy,
+    request: WsMessage,
+    attempt: u32,
+}
+
+// Anything serializable can be used as rpc params: structs and maps become
+// named params, tuples and sequences positional ones, `()` means no params and
+// a single scalar is wrapped into a one element array.
+pub trait IntoParams {
+    fn into_params(self) -> Result<Params, WsError>;
+}
+
+impl<T: Serialize> IntoParams for T {
+    fn into_params(self) -> Result<Params, WsError> {
+        let value = serde_json::to_value(self).map_err(|err| WsError::Params(err.to_string()))?;
+        let params = match value {
+            Value::Null => Params::None,
+            Value::Array(values) => Params::Array(values),
+            Value::Object(map) => Params::Map(map),
+            scalar => Params::Array(vec![scalar]),
+        };
+        Ok(params)
+    }
+}
+
+pub const CONNECTION_CLOSED: &str = "connection closed";
+
+pub type RPCHandler = Box<dyn Fn(&RpcRequestHandle, String) + 'static>;
+pub type RawRPCHandler = Box<dyn Fn(&RpcRequestHandle, &RawValue) + 'static>;
+pub type SharedRPCHandler = Rc<dyn Fn(&RpcRequestHandle, String) + 'static>;
+// Gets `None` once the subscription ended with the connection.
+pub type SubscriptionHandler = Rc<dyn Fn(Option<Value>) + 'static>;
+pub type RPCValidator = Box<dyn Fn(&RpcRequestHandle, &Output) -> Result<(), String> + 'static>;
+pub type OutgoingInterceptor = Box<dyn Fn(&mut MethodCall) + 'static>;
+pub type IncomingInterceptor = Box<dyn Fn(&mut Output) + 'static>;
+
+// Subscription ids are strings or numbers, both are looked up as strings.
+pub(crate) fn subscription_key(subscription: &Value) -> Option<String> {
+    match subscription {
+        Value::String(subscription) => Some(subscription.clone()),
+        Value::Number(subscription) => Some(subscription.to_string()),
+        _ => None,
+    }
+}
 
 pub struct RPCSubscriber {
-    id: Arc<AtomicUsize>,
-    subscriber: HashMap<u64, RPCHandler>,
-    error_subscriber: HashMap<u64, RPCHandler>,
+    codec: RpcCodec,
+    method_prefix: Option<String>,
+    id_generator: Box<dyn IdGenerator>,
+    requests: HashMap<Id, RpcRequestHandle>,
+    subscriber: HashMap<Id, RPCHandler>,
+    error_subscriber: HashMap<Id, RPCHandler>,
+    progress_subscriber: HashMap<Id, SharedRPCHandler>,
+    raw_subscriber: HashMap<Id, RawRPCHandler>,
+    progress_key: String,
+    subscription_key: String,
+    subscriptions: HashMap<String, SubscriptionHandler>,
+    method_subscriber: HashMap<String, SharedRPCHandler>,
+    method_error_subscriber: HashMap<String, SharedRPCHandler>,
+    fallback_handler: Option<SharedRPCHandler>,
+    fallback_error_handler: Option<SharedRPCHandler>,
+    retries: HashMap<Id, RetryState>,
+    stats: HashMap<String, RpcMethodStats>,
+    outgoing_interceptors: Vec<OutgoingInterceptor>,
+    incoming_interceptors: Vec<IncomingInterceptor>,
+    validator: Option<RPCValidator>,
 }
 
 impl RPCSubscriber {
     pub fn new() -> Self {
+        Self::with_id_generator(Box::new(NumericIdGenerator::new()))
+    }
+
+    pub fn with_id_generator(id_generator: Box<dyn IdGenerator>) -> Self {
         Self {
-            id: Arc::new(Default::default()),
+            codec: RpcCodec::default(),
+            method_prefix: None,
+            id_generator,
+            requests: HashMap::new(),
             subscriber: HashMap::new(),
             error_subscriber: HashMap::new(),
+            progress_subscriber: HashMap::new(),
+            raw_subscriber: HashMap::new(),
+            progress_key: String::from("id"),
+            subscription_key: String::from("subscription"),
+            subscriptions: HashMap::new(),
+            method_subscriber: HashMap::new(),
+            method_error_subscriber: HashMap::new(),
+            fallback_handler: None,
+            fallback_error_handler: None,
+            retries: HashMap::new(),
+            stats: HashMap::new(),
+            outgoing_interceptors: Vec::new(),
+            incoming_interceptors: Vec::new(),
+            validator: None,
         }
     }
 
-    pub fn prepare_request(&self, method: &str, params: Params) -> (u64, Call) {
-        let id = self.id.fetch_add(1, atomic::Ordering::AcqRel);
-        let request = match params {
+    pub fn codec(&self) -> RpcCodec {
+        self.codec
+    }
+
+    pub fn set_codec(&mut self, codec: RpcCodec) {
+        self.codec = codec;
+    }
+
+    pub fn set_method_prefix(&mut self, prefix: String) {
+        self.method_prefix = Some(prefix);
+    }
+
+    pub fn set_id_generator(&mut self, id_generator: Box<dyn IdGenerator>) {
+        self.id_generator = id_generator;
+    }
+
+    pub fn add_outgoing_interceptor(&mut self, interceptor: OutgoingInterceptor) {
+        self.outgoing_interceptors.push(interceptor);
+    }
+
+    pub fn add_incoming_interceptor(&mut self, interceptor: IncomingInterceptor) {
+        self.incoming_interceptors.push(interceptor);
+    }
+
+    pub fn set_validator(&mut self, validator: RPCValidator) {
+        self.validator = Some(validator);
+    }
+
+    pub fn prepare_request(&mut self, method: &str, params: Params) -> (RpcRequestHandle, Call) {
+        let id = self.id_generator.next_id();
+        let method = match self.method_prefix.as_ref() {
+            Some(prefix) => format!("{}{}", prefix, method),
+            None => String::from(method),
+        };
+        let method = method.as_str();
+        let mut request = match params {
             Params::Map(val) => Self::build_map_request(id, method, val),
             Params::Array(val) => Self::build_vec_request(id, method, val),
             Params::None => Self::build_none_request(id, method),
         };
-        (id as u64, request)
+        let mut handle = RpcRequestHandle::new(Id::Null, String::from(method));
+        if let Call::MethodCall(method_call) = &mut request {
+            for interceptor in self.outgoing_interceptors.iter() {
+                interceptor(method_call);
+            }
+            // interceptors are allowed to rewrite the id and method, handlers must follow them
+            handle = RpcRequestHandle::new(method_call.id.clone(), method_call.method.clone());
+        }
+        self.requests.insert(handle.id.clone(), handle.clone());
+        (handle, request)
+    }
+
+    pub fn get_request(&self, request_id: &Id) -> Option<&RpcRequestHandle> {
+        self.requests.get(request_id)
+    }
+
+    pub fn stats(&self) -> HashMap<String, RpcMethodStats> {
+        self.stats.clone()
+    }
+
+    pub fn pending_requests(&self) -> Vec<PendingRpc> {
+        let now = js_sys::Date::now();
+        let mut pending: Vec<PendingRpc> = self
+            .requests
+            .values()
+            .map(|request| PendingRpc {
+                id: request.id.clone(),
+                method: request.method.clone(),
+                elapsed_ms: now - request.created_at,
+            })
+            .collect();
+        // oldest first, stuck calls are the interesting ones
+        pending.sort_by(|a, b| b.elapsed_ms.total_cmp(&a.elapsed_ms));
+        pending
+    }
+
+    pub fn complete_request(
+        &mut self,
+        request_id: &Id,
+        failed: bool,
+    ) -> (RpcRequestHandle, Option<SharedRPCHandler>) {
+        self.finish_request(request_id, failed, true)
+    }
+
+    // Requests failed by a disconnect never got an answer, their time until
+    // the close is no latency sample.
+    fn finish_request(
+        &mut self,
+        request_id: &Id,
+        failed: bool,
+        record: bool,
+    ) -> (RpcRequestHandle, Option<SharedRPCHandler>) {
+        let handler = if failed {
+            self.error_subscriber.remove(request_id)
+        } else {
+            self.subscriber.remove(request_id)
+        };
+        let request = match self.cancel_request(request_id) {
+            Some(request) => {
+                if record {
+                    let latency_ms = js_sys::Date::now() - request.created_at;
+                    self.stats
+                        .entry(request.method.clone())
+                        .or_insert_with(RpcMethodStats::new)
+                        .record(latency_ms, failed);
+                }
+                request
+            }
+            // cancelled, answered already or never sent: the defaults are
+            // only for requests that are still pending
+            None => {
+                let request = RpcRequestHandle::unknown(request_id.clone());
+                return (request, handler.map(SharedRPCHandler::from));
+            }
+        };
+        let handler = match handler {
+            Some(handler) => Some(SharedRPCHandler::from(handler)),
+            None => self.get_default_handler(request.method(), failed),
+        };
+        (request, handler)
+    }
+
+    // Completes every pending request as failed and returns the error handlers
+    // that still have to be told the response will never come.
+    pub fn fail_pending(&mut self) -> Vec<(RpcRequestHandle, SharedRPCHandler)> {
+        let mut request_ids: Vec<Id> = self.requests.keys().cloned().collect();
+        request_ids.extend(
+            self.error_subscriber
+                .keys()
+                .filter(|request_id| !self.requests.contains_key(request_id))
+                .cloned(),
+        );
+        let failed = request_ids
+            .iter()
+            .filter_map(|request_id| {
+                let (request, handler) = self.finish_request(request_id, true, false);
+                handler.map(|handler| (request, handler))
+            })
+            .collect();
+        self.subscriber.clear();
+        self.error_subscriber.clear();
+        self.progress_subscriber.clear();
+        self.raw_subscriber.clear();
+        self.retries.clear();
+        failed
+    }
+
+    pub fn set_method_handler(&mut self, method: String, handler: RPCHandler) {
+        self.method_subscriber.insert(method, Rc::from(handler));
+    }
+
+    pub fn set_method_error_handler(&mut self, method: String, error_handler: RPCHandler) {
+        self.method_error_subscriber
+            .insert(method, Rc::from(error_handler));
+    }
+
+    pub fn set_fallback_handler(&mut self, handler: RPCHandler) {
+        self.fallback_handler = Some(Rc::from(handler));
+    }
+
+    pub fn set_fallback_error_handler(&mut self, error_handler: RPCHandler) {
+        self.fallback_error_handler = Some(Rc::from(error_handler));
+    }
+
+    fn get_default_handler(&self, method: &str, failed: bool) -> Option<SharedRPCHandler> {
+        let (method_subscriber, fallback) = if failed {
+            (&self.method_error_subscriber, &self.fallback_error_handler)
+        } else {
+            (&self.method_subscriber, &self.fallback_handler)
+        };
+        method_subscriber.get(method).or(fallback.as_ref()).cloned()
     }
 
-    pub fn set_handler(&mut self, request_id: u64, handler: RPCHandler) {
+    pub fn set_handler(&mut self, request_id: Id, handler: RPCHandler) {
         self.subscriber.insert(request_id, Box::new(handler));
     }
 
-    pub fn set_error_handler(&mut self, request_id: u64, error_handler: RPCHandler) {
+    pub fn set_error_handler(&mut self, request_id: Id, error_handler: RPCHandler) {
         self.error_subscriber.insert(request_id, error_handler);
     }
 
-    pub fn get_handler(&mut self, request_id: u64) -> Option<&RPCHandler> {
-        self.subscriber.get(&request_id)
+    pub fn set_retry(&mut self, request_id: Id, policy: RetryPolicy, request: WsMessage) {
+        let state = RetryState {
+            policy,
+            request,
+            attempt: 0,
+        };
+        self.retries.insert(request_id, state);
+    }
+
+    // Returns the request to resend and the delay before resending it, when the
+    // retry policy of the failed request allows another attempt.
+    pub fn next_retry(&mut self, err: &RpcError) -> Option<(WsMessage, u32)> {
+        let request_id = err.id.as_ref()?;
+        let state = self.retries.get_mut(request_id)?;
+        if state.attempt >= state.policy.max_retries || !(state.policy.retry_on)(err) {
+            self.retries.remove(request_id);
+            return None;
+        }
+        let delay = state.policy.delay(state.attempt);
+        state.attempt += 1;
+        Some((state.request.clone(), delay))
+    }
+
+    pub fn set_progress_handler(&mut self, request_id: Id, progress_handler: RPCHandler) {
+        self.progress_subscriber
+            .insert(request_id, Rc::from(progress_handler));
+    }
+
+    pub fn set_raw_handler(&mut self, request_id: Id, raw_handler: RawRPCHandler) {
+        self.raw_subscriber.insert(request_id, raw_handler);
+    }
+
+    pub fn take_raw_handler(&mut self, request_id: &Id) -> Option<RawRPCHandler> {
+        self.raw_subscriber.remove(request_id)
     }
 
-    pub fn get_error_handler(&mut self, request_id: u64) -> Option<&RPCHandler> {
-        self.error_subscriber.get(&request_id)
+    // Successful responses borrow their result straight from the payload
+    // instead of parsing it into a `Value` tree and printing it back.
+    // Interceptors and the validator work on `Output`, so they opt out.
+    pub fn get_message<'a>(&self, json: &'a str) -> RpcMessage<'a> {
+        let message = match serde_json::from_str::<RawRpcMessage>(json) {
+            Ok(message) => message,
+            Err(err) => return RpcMessage::Response(self.resolve_response(Err(err.to_string()))),
+        };
+        if let (Some(method), None) = (message.method, message.id.as_ref()) {
+            let params = match message.params {
+                Some(params) => serde_json::from_str::<Params>(params.get()),
+                None => Ok(Params::None),
+            };
+            return match params {
+                Ok(params) => RpcMessage::Notification(Notification {
+                    jsonrpc: message.jsonrpc,
+                    method,
+                    params,
+                }),
+                Err(err) => RpcMessage::Response(self.resolve_response(Err(err.to_string()))),
+            };
+        }
+        let id = message.id.unwrap_or(Id::Null);
+        let raw_result = self.incoming_interceptors.is_empty() && self.validator.is_none();
+        let result = match (message.result, message.error) {
+            (Some(result), None) if raw_result && id != Id::Null => {
+                return RpcMessage::Result(self.normalize_id(id), result)
+            }
+            (Some(result), None) => serde_json::from_str::<Value>(result.get()).map(Ok),
+            (_, Some(error)) => serde_json::from_str::<jsonrpc_core::Error>(error.get()).map(Err),
+            (None, None) => {
+                let msg = String::from("response has neither result nor error");
+                return RpcMessage::Response(self.resolve_response(Err(msg)));
+            }
+        };
+        let response = match result {
+            Ok(result) => Ok(Response::Single(Output::from(result, id, message.jsonrpc))),
+            Err(err) => Err(err.to_string()),
+        };
+        RpcMessage::Response(self.resolve_response(response))
+    }
+
+    pub fn set_progress_key(&mut self, progress_key: String) {
+        self.progress_key = progress_key;
+    }
+
+    // Partial results arrive as notifications whose params reference the id of
+    // the pending request under the progress key.
+    pub fn get_progress_handler(
+        &self,
+        notification: &Notification,
+    ) -> Option<(RpcRequestHandle, SharedRPCHandler)> {
+        let request_id = match &notification.params {
+            Params::Map(params) => params.get(self.progress_key.as_str())?,
+            _ => return None,
+        };
+        let request_id = serde_json::from_value::<Id>(request_id.clone()).ok()?;
+        let request_id = self.normalize_id(request_id);
+        let handler = self.progress_subscriber.get(&request_id)?.clone();
+        let request = self
+            .requests
+            .get(&request_id)
+            .cloned()
+            .unwrap_or_else(|| RpcRequestHandle::new(request_id, String::new()));
+        Some((request, handler))
+    }
+
+    pub fn set_subscription_key(&mut self, subscription_key: String) {
+        self.subscription_key = subscription_key;
+    }
+
+    pub(crate) fn set_subscription_handler(
+        &mut self,
+        subscription: String,
+        handler: SubscriptionHandler,
+    ) {
+        self.subscriptions.insert(subscription, handler);
+    }
+
+    pub(crate) fn remove_subscription(&mut self, subscription: &str) {
+        self.subscriptions.remove(subscription);
+    }
+
+    // The server forgets subscriptions with the connection.
+    pub(crate) fn take_subscriptions(&mut self) -> Vec<SubscriptionHandler> {
+        self.subscriptions
+            .drain()
+            .map(|(_, handler)| handler)
+            .collect()
+    }
+
+    // Subscription notifications carry the id the subscribe call returned
+    // under the subscription key, and the item under `result`.
+    pub fn get_subscription_handler(
+        &self,
+        notification: &Notification,
+    ) -> Option<(SubscriptionHandler, Value)> {
+        let params = match &notification.params {
+            Params::Map(params) => params,
+            _ => return None,
+        };
+        let subscription = subscription_key(params.get(self.subscription_key.as_str())?)?;
+        let handler = self.subscriptions.get(&subscription)?.clone();
+        let result = params.get("result").cloned().unwrap_or(Value::Null);
+        Some((handler, result))
     }
 
-    pub fn get_response(json: String) -> Result<RPCResponse, RpcError> {
-        let response = Response::from_json(json.as_str());
+    pub fn cancel_request(&mut self, request_id: &Id) -> Option<RpcRequestHandle> {
+        self.retries.remove(request_id);
+        self.progress_subscriber.remove(request_id);
+        self.raw_subscriber.remove(request_id);
+        self.subscriber.remove(request_id);
+        self.error_subscriber.remove(request_id);
+        self.requests.remove(request_id)
+    }
+
+    pub fn get_handler(&mut self, request_id: &Id) -> Option<&RPCHandler> {
+        self.subscriber.get(request_id)
+    }
+
+    pub fn get_error_handler(&mut self, request_id: &Id) -> Option<&RPCHandler> {
+        self.error_subscriber.get(request_id)
+    }
+
+    pub fn encode_request(&self, request: &Call) -> Result<WsMessage, RpcError> {
+        match self.codec {
+            RpcCodec::Json => match serde_json::to_string(request) {
+                Ok(payload) => Ok(WsMessage::Text(payload)),
+                Err(err) => Err(RpcError {
+                    id: None,
+                    code: None,
+                    msg: err.to_string(),
+                }),
+            },
+            #[cfg(feature = "rmp-serde")]
+            RpcCodec::MessagePack => match encode_msgpack_call(request) {
+                Ok(payload) => Ok(WsMessage::Binary(payload)),
+                Err(msg) => Err(RpcError {
+                    id: None,
+                    code: None,
+                    msg,
+                }),
+            },
+        }
+    }
+
+    // `None` when the frame is no msgpack-rpc response or notification, it
+    // is then handled like any other binary frame.
+    #[cfg(feature = "rmp-serde")]
+    pub fn get_msgpack_message(&self, payload: &[u8]) -> Option<RpcMessage<'static>> {
+        let message = match rmp_serde::from_slice::<Value>(payload).ok()? {
+            Value::Array(message) => message,
+            _ => return None,
+        };
+        match message.as_slice() {
+            [kind, id, error, result] if kind.as_u64() == Some(MSGPACK_RESPONSE) => {
+                let id = serde_json::from_value::<Id>(id.clone()).ok()?;
+                let result = match error {
+                    Value::Null => Ok(result.clone()),
+                    error => Err(msgpack_error(error)),
+                };
+                let output = Output::from(result, id, Some(Version::V2));
+                let response = self.resolve_response(Ok(Response::Single(output)));
+                Some(RpcMessage::Response(response))
+            }
+            [kind, Value::String(method), params]
+                if kind.as_u64() == Some(MSGPACK_NOTIFICATION) =>
+            {
+                Some(RpcMessage::Notification(Notification {
+                    jsonrpc: Some(Version::V2),
+                    method: method.clone(),
+                    params: msgpack_params(params.clone())?,
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    fn resolve_response(
+        &self,
+        response: Result<Response, String>,
+    ) -> Result<RPCResponse, RpcError> {
         match response {
             Ok(response) => match response {
-                Response::Single(val) => match val {
-                    Output::Failure(fail) => {
-                        let id = match fail.id {
-                            Id::Num(id) => Some(id),
-                            Id::Str(str_id) => {
-                                let id = str_id.parse::<u64>().unwrap();
-                                Some(id)
-                            }
+                Response::Single(mut val) => {
+                    self.intercept_output(&mut val);
+                    self.normalize_output_id(&mut val);
+                    if let Err(msg) = self.validate_output(&val) {
+                        let id = match val.id() {
                             Id::Null => None,
+                            id => Some(id.clone()),
                         };
-                        Err(RpcError {
+                        return Err(RpcError {
                             id,
-                            msg: fail.error.message,
-                        })
+                            code: None,
+                            msg,
+                        });
                     }
-                    Output::Success(success) => {
-                        let id = match success.id {
-                            Id::Num(id) => Some(id),
-                            Id::Str(str_id) => {
-                                let id = str_id.parse::<u64>().unwrap();
-                                Some(id)
-                            }
-                            Id::Null => None,
-                        };
-                        Ok(RPCResponse {
-                            id,
-                            result: success.result,
-                        })
+                    match val {
+                        Output::Failure(fail) => {
+                            let id = match fail.id {
+                                Id::Null => None,
+                                id => Some(id),
+                            };
+                            Err(RpcError {
+                                id,
+                                code: Some(fail.error.code.code()),
+                                msg: fail.error.message,
+                            })
+                        }
+                        Output::Success(success) => {
+                            let id = match success.id {
+                                Id::Null => None,
+                                id => Some(id),
+                            };
+                            Ok(RPCResponse {
+                                id,
+                                result: success.result,
+                            })
+                        }
                     }
-                },
+                }
                 _ => Err(RpcError {
                     id: None,
+                    code: None,
                     msg: String::from("this is batch response"),
                 }),
             },
-            Err(err) => Err(RpcError {
+            Err(msg) => Err(RpcError {
                 id: None,
-                msg: err.to_string(),
+                code: None,
+                msg,
             }),
         }
     }
 
-    fn build_map_request(id: usize, method: &str, params: Map<String, Value>) -> Call {
+    fn intercept_output(&self, output: &mut Output) {
+        for interceptor in self.incoming_interceptors.iter() {
+            interceptor(output);
+        }
+    }
+
+    fn normalize_output_id(&self, output: &mut Output) {
+        let id = match output {
+            Output::Success(success) => &mut success.id,
+            Output::Failure(fail) => &mut fail.id,
+        };
+        *id = self.normalize_id(id.clone());
+    }
+
+    // Servers are free to echo an id in another representation than it was
+    // sent with, e.g. `"5"` for `5`, so match it against the known requests
+    // before routing. Unknown ids are left as is, their responses are logged
+    // instead of going to the fallback handlers.
+    fn normalize_id(&self, id: Id) -> Id {
+        if self.is_known_id(&id) {
+            return id;
+        }
+        let alternative = match &id {
+            Id::Str(str_id) => match str_id.parse::<u64>() {
+                Ok(num_id) => Id::Num(num_id),
+                Err(_) => return id,
+            },
+            Id::Num(num_id) => Id::Str(num_id.to_string()),
+            Id::Null => return id,
+        };
+        if self.is_known_id(&alternative) {
+            alternative
+        } else {
+            id
+        }
+    }
+
+    fn is_known_id(&self, id: &Id) -> bool {
+        self.requests.contains_key(id)
+            || self.subscriber.contains_key(id)
+            || self.error_subscriber.contains_key(id)
+    }
+
+    fn validate_output(&self, output: &Output) -> Result<(), String> {
+        match (self.validator.as_ref(), self.requests.get(output.id())) {
+            (Some(validator), Some(request)) => validator(request, output),
+            _ => Ok(()),
+        }
+    }
+
+    fn build_map_request(id: Id, method: &str, params: Map<String, Value>) -> Call {
         Call::MethodCall(MethodCall {
             jsonrpc: Some(Version::V2),
             method: method.into(),
             params: Params::Map(params),
-            id: Id::Num(id as u64),
+            id,
         })
     }
 
-    fn build_vec_request(id: usize, method: &str, params: Vec<Value>) -> Call {
+    fn build_vec_request(id: Id, method: &str, params: Vec<Value>) -> Call {
         Call::MethodCall(MethodCall {
             jsonrpc: Some(Version::V2),
             method: method.into(),
             params: Params::Array(params),
-            id: Id::Num(id as u64),
+            id,
         })
     }
 
-    fn build_none_request(id: usize, method: &str) -> Call {
+    fn build_none_request(id: Id, method: &str) -> Call {
         Call::MethodCall(MethodCall {
             jsonrpc: Some(Version::V2),
             method: method.into(),
             params: Params::None,
-            id: Id::Num(id as u64),
+            id,
         })
     }
 }
 
+// msgpack-rpc frames are arrays: `[0, msgid, method, params]` requests,
+// `[1, msgid, error, result]` responses and `[2, method, params]`
+// notifications. Params are always an array, named params travel as its only
+// element.
+#[cfg(feature = "rmp-serde")]
+const MSGPACK_REQUEST: u64 = 0;
+#[cfg(feature = "rmp-serde")]
+const MSGPACK_RESPONSE: u64 = 1;
+#[cfg(feature = "rmp-serde")]
+const MSGPACK_NOTIFICATION: u64 = 2;
+
+#[cfg(feature = "rmp-serde")]
+fn encode_msgpack_call(request: &Call) -> Result<Vec<u8>, String> {
+    let to_array = |params: &Params| match params {
+        Params::None => Vec::new(),
+        Params::Array(values) => values.clone(),
+        Params::Map(map) => vec![Value::Object(map.clone())],
+    };
+    let payload = match request {
+        Call::MethodCall(call) => rmp_serde::to_vec(&(
+            MSGPACK_REQUEST,
+            &call.id,
+            &call.method,
+            to_array(&call.params),
+        )),
+        Call::Notification(notification) => rmp_serde::to_vec(&(
+            MSGPACK_NOTIFICATION,
+            &notification.method,
+            to_array(&notification.params),
+        )),
+        Call::Invalid { .. } => return Err(String::from("invalid request")),
+    };
+    payload.map_err(|err| err.to_string())
+}
+
+#[cfg(feature = "rmp-serde")]
+fn msgpack_params(params: Value) -> Option<Params> {
+    match params {
+        Value::Null => Some(Params::None),
+        Value::Array(mut values) => match values.len() {
+            0 => Some(Params::None),
+            1 if values[0].is_object() => match values.remove(0) {
+                Value::Object(map) => Some(Params::Map(map)),
+                _ => None,
+            },
+            _ => Some(Params::Array(values)),
+        },
+        Value::Object(map) => Some(Params::Map(map)),
+        _ => None,
+    }
+}
+
+// The error of a response is whatever the server sends, json-rpc like maps
+// keep their code and message.
+#[cfg(feature = "rmp-serde")]
+fn msgpack_error(error: &Value) -> jsonrpc_core::Error {
+    let code = error
+        .get("code")
+        .and_then(Value::as_i64)
+        .map(jsonrpc_core::ErrorCode::from)
+        .unwrap_or(jsonrpc_core::ErrorCode::InternalError);
+    let message = match error.get("message").or(Some(error)) {
+        Some(Value::String(message)) => message.clone(),
+        _ => error.to_string(),
+    };
+    jsonrpc_core::Error {
+        code,
+        message,
+        data: None,
+    }
+}
+
+pub struct RpcNamespace<'a> {
+    websocket: &'a Websocket,
+    namespace: String,
+}
+
+impl<'a> RpcNamespace<'a> {
+    pub(crate) fn new(websocket: &'a Websocket, namespace: String) -> Self {
+        Self {
+            websocket,
+            namespace,
+        }
+    }
+
+    pub fn namespace(&self, namespace: &str) -> RpcNamespace<'a> {
+        RpcNamespace::new(self.websocket, self.method(namespace))
+    }
+
+    pub fn send_text_rpc(
+        &self,
+        method: &str,
+        rpc_params: impl IntoParams,
+        callback: RPCHandler,
+        error_callback: RPCHandler,
+    ) -> Result<RpcRequestHandle, WsError> {
+        self.websocket
+            .send_text_rpc(self.method(method), rpc_params, callback, error_callback)
+    }
+
+    pub fn send_binary_rpc(
+        &self,
+        method: &str,
+        rpc_params: impl IntoParams,
+        callback: RPCHandler,
+        error_callback: RPCHandler,
+    ) -> Result<RpcRequestHandle, WsError> {
+        self.websocket
+            .send_binary_rpc(self.method(method), rpc_params, callback, error_callback)
+    }
+
+    pub fn send_rpc(
+        &self,
+        method: &str,
+        rpc_params: impl IntoParams,
+    ) -> Result<RpcRequestHandle, WsError> {
+        self.websocket.send_rpc(self.method(method), rpc_params)
+    }
+
+    fn method(&self, method: &str) -> String {
+        format!("{}.{}", self.namespace, method)
+    }
+}
+
 impl Drop for RPCSubscriber {
     fn drop(&mut self) {
-        self.subscriber.clear();
+        for (request, handler) in self.fail_pending() {
+            handler(&request, String::from(CONNECTION_CLOSED));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    fn handle(id: Id, method: &str) -> RpcRequestHandle {
+        RpcRequestHandle {
+            id,
+            method: String::from(method),
+            created_at: 0.0,
+        }
+    }
+
+    fn rpc_error(id: Id, code: i64) -> RpcError {
+        RpcError {
+            id: Some(id),
+            code: Some(code),
+            msg: String::from("failed"),
+        }
+    }
+
+    #[test]
+    fn numeric_and_string_ids_count_up() {
+        let numeric = NumericIdGenerator::new();
+        assert_eq!(numeric.next_id(), Id::Num(0));
+        assert_eq!(numeric.next_id(), Id::Num(1));
+        let string = StringIdGenerator::new("req-");
+        assert_eq!(string.next_id(), Id::Str(String::from("req-0")));
+        assert_eq!(string.next_id(), Id::Str(String::from("req-1")));
+    }
+
+    #[test]
+    fn bounded_ids_wrap_around_to_one() {
+        let bounded = BoundedIdGenerator::new(3);
+        assert_eq!(bounded.current(), 0);
+        let ids: Vec<Id> = (0..5).map(|_| bounded.next_id()).collect();
+        assert_eq!(
+            ids,
+            [Id::Num(1), Id::Num(2), Id::Num(3), Id::Num(1), Id::Num(2)]
+        );
+        assert_eq!(bounded.current(), 2);
+        assert_eq!(BoundedIdGenerator::new(0).next_id(), Id::Num(1));
+    }
+
+    #[test]
+    fn normalizes_ids_to_the_known_representation() {
+        let mut subscriber = RPCSubscriber::new();
+        subscriber.set_handler(Id::Num(5), Box::new(|_, _| ()));
+        let pending = Id::Str(String::from("7"));
+        subscriber
+            .requests
+            .insert(pending.clone(), handle(pending, "pending"));
+        assert_eq!(
+            subscriber.normalize_id(Id::Str(String::from("5"))),
+            Id::Num(5)
+        );
+        assert_eq!(
+            subscriber.normalize_id(Id::Num(7)),
+            Id::Str(String::from("7"))
+        );
+        assert_eq!(subscriber.normalize_id(Id::Num(9)), Id::Num(9));
+        assert_eq!(
+            subscriber.normalize_id(Id::Str(String::from("abc"))),
+            Id::Str(String::from("abc"))
+        );
+    }
+
+    #[test]
+    fn retries_with_exponential_backoff() {
+        let policy = RetryPolicy::new(3, 100);
+        assert_eq!(policy.delay(0), 100);
+        assert_eq!(policy.delay(2), 400);
+        assert_eq!(RetryPolicy::new(1, u32::MAX).delay(5), u32::MAX);
+
+        let mut subscriber = RPCSubscriber::new();
+        let request = WsMessage::Text(String::from("request"));
+        subscriber.set_retry(Id::Num(1), RetryPolicy::new(2, 100), request);
+        let err = rpc_error(Id::Num(1), -32000);
+        let delays: Vec<Option<u32>> = (0..3)
+            .map(|_| subscriber.next_retry(&err).map(|(_, delay)| delay))
+            .collect();
+        assert_eq!(delays, [Some(100), Some(200), None]);
+        assert!(subscriber.next_retry(&err).is_none());
+    }
+
+    #[test]
+    fn retries_only_the_errors_the_policy_takes() {
+        let mut subscriber = RPCSubscriber::new();
+        let request = WsMessage::Text(String::from("request"));
+        let policy = RetryPolicy::new(2, 100).retry_on(|err| err.code() == Some(-32601));
+        subscriber.set_retry(Id::Num(1), policy, request.clone());
+        assert!(subscriber
+            .next_retry(&rpc_error(Id::Num(1), -32601))
+            .is_some());
+        // an error the policy doesn't take ends the retries for good
+        assert!(subscriber
+            .next_retry(&rpc_error(Id::Num(1), -32000))
+            .is_none());
+        assert!(subscriber
+            .next_retry(&rpc_error(Id::Num(1), -32601))
+            .is_none());
+        subscriber.set_retry(Id::Num(2), RetryPolicy::new(2, 100), request);
+        assert!(subscriber
+            .next_retry(&rpc_error(Id::Num(2), -32601))
+            .is_none());
+    }
+
+    #[test]
+    fn records_latencies_into_buckets() {
+        let mut stats = RpcMethodStats::new();
+        assert_eq!(stats.average_ms(), 0.0);
+        stats.record(5.0, false);
+        stats.record(10.0, false);
+        stats.record(300.0, true);
+        stats.record(10_000.0, false);
+        assert_eq!(stats.calls, 4);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.min_ms, 5.0);
+        assert_eq!(stats.max_ms, 10_000.0);
+        assert_eq!(stats.average_ms(), 2578.75);
+        assert_eq!(stats.buckets, [2, 0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn fail_pending_hands_every_request_to_its_error_handler() {
+        let failed = Rc::new(RefCell::new(Vec::new()));
+        let mut subscriber = RPCSubscriber::new();
+        for (id, method) in [(1, "own"), (2, "by_method"), (3, "unhandled")].iter() {
+            subscriber
+                .requests
+                .insert(Id::Num(*id), handle(Id::Num(*id), method));
+            subscriber.set_handler(Id::Num(*id), Box::new(|_, _| ()));
+        }
+        let own = failed.clone();
+        subscriber.set_error_handler(
+            Id::Num(1),
+            Box::new(move |request, msg| own.borrow_mut().push((request.id().clone(), msg))),
+        );
+        let by_method = failed.clone();
+        subscriber.set_method_error_handler(
+            String::from("by_method"),
+            Box::new(move |request, msg| by_method.borrow_mut().push((request.id().clone(), msg))),
+        );
+        for (request, handler) in subscriber.fail_pending() {
+            handler(&request, String::from(CONNECTION_CLOSED));
+        }
+        let mut failed = failed.borrow().clone();
+        failed.sort_by_key(|(id, _)| format!("{:?}", id));
+        assert_eq!(
+            failed,
+            [
+                (Id::Num(1), String::from(CONNECTION_CLOSED)),
+                (Id::Num(2), String::from(CONNECTION_CLOSED))
+            ]
+        );
+        assert!(subscriber.requests.is_empty());
+        assert!(subscriber.subscriber.is_empty());
+        assert!(subscriber.error_subscriber.is_empty());
+        assert!(subscriber.stats.is_empty());
+    }
+
+    #[test]
+    fn late_replies_to_cancelled_requests_skip_the_fallbacks() {
+        let mut subscriber = RPCSubscriber::new();
+        subscriber.set_fallback_handler(Box::new(|_, _| panic!("fallback called")));
+        subscriber.set_fallback_error_handler(Box::new(|_, _| panic!("fallback called")));
+        subscriber
+            .requests
+            .insert(Id::Num(1), handle(Id::Num(1), "slow"));
+        assert!(subscriber.cancel_request(&Id::Num(1)).is_some());
+        let (request, handler) = subscriber.complete_request(&Id::Num(1), false);
+        assert_eq!(request.id(), &Id::Num(1));
+        assert!(handler.is_none());
+        assert!(subscriber.complete_request(&Id::Num(1), true).1.is_none());
+        assert!(subscriber.complete_request(&Id::Num(2), false).1.is_none());
+    }
+
+    #[test]
+    fn serializes_anything_into_params() {
+        #[derive(Serialize)]
+        struct Named {
+            a: u32,
+        }
+        let mut map = Map::new();
+        map.insert(String::from("a"), Value::from(1));
+        assert_eq!(Named { a: 1 }.into_params().unwrap(), Params::Map(map));
+        assert_eq!(
+            (1, "two").into_params().unwrap(),
+            Params::Array(vec![Value::from(1), Value::from("two")])
+        );
+        assert_eq!(
+            vec![1, 2].into_params().unwrap(),
+            Params::Array(vec![Value::from(1), Value::from(2)])
+        );
+        assert_eq!(().into_params().unwrap(), Params::None);
+        assert_eq!(
+            "scalar".into_params().unwrap(),
+            Params::Array(vec![Value::from("scalar")])
+        );
+    }
+
+    #[test]
+    fn parses_json_responses_once_into_a_message() {
+        let subscriber = RPCSubscriber::new();
+        match subscriber.get_message(r#"{"jsonrpc": "2.0", "id": 1, "result": {"a": [1]}}"#) {
+            RpcMessage::Result(id, result) => {
+                assert_eq!(id, Id::Num(1));
+                assert_eq!(result.get(), r#"{"a": [1]}"#);
+            }
+            _ => panic!("expected a raw result"),
+        }
+        match subscriber.get_message(r#"{"jsonrpc": "2.0", "id": 2, "result": null}"#) {
+            RpcMessage::Result(_, result) => assert_eq!(result.get(), "null"),
+            _ => panic!("expected a raw null result"),
+        }
+        let failure = r#"{"jsonrpc": "2.0", "id": 3, "error": {"code": -32601, "message": "no"}}"#;
+        match subscriber.get_message(failure) {
+            RpcMessage::Response(Err(err)) => {
+                assert_eq!(err.id(), Some(&Id::Num(3)));
+                assert_eq!(err.code(), Some(-32601));
+                assert_eq!(err.message(), "no");
+            }
+            _ => panic!("expected a failed response"),
+        }
+        match subscriber.get_message(r#"{"jsonrpc": "2.0", "method": "tick", "params": [1]}"#) {
+            RpcMessage::Notification(notification) => {
+                assert_eq!(notification.method, "tick");
+                assert_eq!(notification.params, Params::Array(vec![Value::from(1)]));
+            }
+            _ => panic!("expected a notification"),
+        }
+        match subscriber.get_message(r#"{"jsonrpc": "2.0", "id": 4}"#) {
+            RpcMessage::Response(Err(err)) => assert_eq!(err.id(), None),
+            _ => panic!("expected an invalid response"),
+        }
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    fn decode(payload: &[u8]) -> Value {
+        rmp_serde::from_slice(payload).unwrap()
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    #[test]
+    fn encodes_msgpack_requests_as_arrays() {
+        let request = Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: String::from("add"),
+            params: Params::Array(vec![Value::from(1), Value::from(2)]),
+            id: Id::Num(7),
+        });
+        let payload = encode_msgpack_call(&request).unwrap();
+        assert_eq!(decode(&payload), serde_json::json!([0, 7, "add", [1, 2]]));
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    #[test]
+    fn encodes_named_params_as_the_only_element() {
+        let mut params = Map::new();
+        params.insert(String::from("a"), Value::from(1));
+        let request = Call::Notification(Notification {
+            jsonrpc: Some(Version::V2),
+            method: String::from("ping"),
+            params: Params::Map(params),
+        });
+        let payload = encode_msgpack_call(&request).unwrap();
+        assert_eq!(decode(&payload), serde_json::json!([2, "ping", [{"a": 1}]]));
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    #[test]
+    fn decodes_msgpack_responses() {
+        let subscriber = RPCSubscriber::new();
+        let payload = rmp_serde::to_vec(&(1, 7, (), "ok")).unwrap();
+        match subscriber.get_msgpack_message(&payload) {
+            Some(RpcMessage::Response(Ok(response))) => {
+                assert_eq!(response.id, Some(Id::Num(7)));
+                assert_eq!(response.result, Value::from("ok"));
+            }
+            _ => panic!("expected a successful response"),
+        }
+        let error = serde_json::json!({"code": -32601, "message": "no such method"});
+        let payload = rmp_serde::to_vec(&(1, 8, error, ())).unwrap();
+        match subscriber.get_msgpack_message(&payload) {
+            Some(RpcMessage::Response(Err(err))) => {
+                assert_eq!(err.id(), Some(&Id::Num(8)));
+                assert_eq!(err.code(), Some(-32601));
+                assert_eq!(err.message(), "no such method");
+            }
+            _ => panic!("expected a failed response"),
+        }
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    #[test]
+    fn decodes_msgpack_notifications() {
+        let subscriber = RPCSubscriber::new();
+        let payload = rmp_serde::to_vec(&(2, "tick", vec![1])).unwrap();
+        match subscriber.get_msgpack_message(&payload) {
+            Some(RpcMessage::Notification(notification)) => {
+                assert_eq!(notification.method, "tick");
+                assert_eq!(notification.params, Params::Array(vec![Value::from(1)]));
+            }
+            _ => panic!("expected a notification"),
+        }
+    }
+
+    #[cfg(feature = "rmp-serde")]
+    #[test]
+    fn leaves_other_binary_frames_alone() {
+        let subscriber = RPCSubscriber::new();
+        let request = rmp_serde::to_vec(&(0, 1, "add", vec![1])).unwrap();
+        assert!(subscriber.get_msgpack_message(&request).is_none());
+        let map = rmp_serde::to_vec_named(&serde_json::json!({"event": "x"})).unwrap();
+        assert!(subscriber.get_msgpack_message(&map).is_none());
+        assert!(subscriber.get_msgpack_message(&[0xc1]).is_none());
     }
 }
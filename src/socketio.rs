@@ -0,0 +1,528 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use serde::Deserialize;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+pub const DEFAULT_NAMESPACE: &str = "/";
+
+// Engine.IO packet types, the first character of every frame.
+const ENGINE_OPEN: char = '0';
+const ENGINE_CLOSE: char = '1';
+const ENGINE_PING: char = '2';
+const ENGINE_PONG: char = '3';
+const ENGINE_MESSAGE: char = '4';
+
+// Socket.IO packet types, the character after the Engine.IO message type.
+const CONNECT: u8 = 0;
+const DISCONNECT: u8 = 1;
+const EVENT: u8 = 2;
+const ACK: u8 = 3;
+const CONNECT_ERROR: u8 = 4;
+const BINARY_EVENT: u8 = 5;
+const BINARY_ACK: u8 = 6;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Handshake {
+    ping_interval: u32,
+    ping_timeout: u32,
+}
+
+struct Packet {
+    kind: u8,
+    namespace: String,
+    id: Option<u64>,
+    data: Option<Value>,
+}
+
+impl Packet {
+    fn new(kind: u8, namespace: &str, data: Option<Value>) -> Self {
+        Self {
+            kind,
+            namespace: String::from(namespace),
+            id: None,
+            data,
+        }
+    }
+
+    // `<type>[<namespace>,][<ack id>][<json>]`, the default namespace is left
+    // out.
+    fn encode(&self) -> String {
+        let mut frame = format!("{}{}", ENGINE_MESSAGE, self.kind);
+        if self.namespace != DEFAULT_NAMESPACE {
+            frame.push_str(&self.namespace);
+            frame.push(',');
+        }
+        if let Some(id) = self.id {
+            frame.push_str(&id.to_string());
+        }
+        if let Some(data) = self.data.as_ref() {
+            frame.push_str(&data.to_string());
+        }
+        frame
+    }
+
+    // `frame` is without the Engine.IO message type.
+    fn decode(frame: &str) -> Result<Self, String> {
+        let kind = frame
+            .chars()
+            .next()
+            .and_then(|kind| kind.to_digit(10))
+            .ok_or_else(|| format!("invalid packet type: {}", frame))? as u8;
+        let mut rest = &frame[1..];
+        if kind == BINARY_EVENT || kind == BINARY_ACK {
+            return Err(String::from("binary packets are not supported"));
+        }
+        let mut namespace = DEFAULT_NAMESPACE;
+        if rest.starts_with('/') {
+            let end = rest.find(',').unwrap_or(rest.len());
+            namespace = &rest[..end];
+            rest = rest.get(end + 1..).unwrap_or("");
+        }
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let id = rest[..digits].parse::<u64>().ok();
+        rest = &rest[digits..];
+        let data = if rest.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_str(rest).map_err(|err| err.to_string())?)
+        };
+        Ok(Self {
+            kind,
+            namespace: String::from(namespace),
+            id,
+            data,
+        })
+    }
+}
+
+type EventListener = Rc<dyn Fn(Vec<Value>, Option<Ack>)>;
+type AckCallback = Box<dyn FnOnce(Vec<Value>)>;
+
+struct NamespaceState {
+    auth: Option<Value>,
+    listeners: RefCell<HashMap<String, EventListener>>,
+    // emits made before the namespace was (re)connected on the current socket
+    queue: RefCell<VecDeque<Packet>>,
+    connected: Cell<bool>,
+}
+
+struct SocketIoInner {
+    websocket: Websocket,
+    namespaces: RefCell<HashMap<String, Rc<NamespaceState>>>,
+    acks: RefCell<HashMap<u64, AckCallback>>,
+    next_ack_id: Cell<u64>,
+    // set once the Engine.IO open packet of the current socket arrived
+    engine_open: Cell<bool>,
+    // ms the server may stay silent before the socket counts as dead
+    heartbeat_timeout: Cell<u32>,
+    watchdog: Cell<Option<i32>>,
+}
+
+impl SocketIoInner {
+    fn send_frame(&self, frame: String) -> Result<(), WsError> {
+        self.websocket.send(WsMessage::Text(frame))
+    }
+
+    fn send(&self, packet: &Packet) -> Result<(), WsError> {
+        self.send_frame(packet.encode())
+    }
+
+    fn receive(inner: &Rc<Self>, message: WsMessage) {
+        let frame = match message {
+            WsMessage::Text(frame) => frame,
+            WsMessage::Binary(_) => {
+                console_log!("binary socket.io frames are not supported");
+                return;
+            }
+        };
+        inner.reset_watchdog();
+        let mut chars = frame.chars();
+        match chars.next() {
+            Some(ENGINE_OPEN) => inner.engine_opened(chars.as_str()),
+            Some(ENGINE_PING) => {
+                if let Err(err) = inner.send_frame(String::from(ENGINE_PONG)) {
+                    console_log!("error on engine.io pong: {:?}", err);
+                }
+            }
+            Some(ENGINE_MESSAGE) => match Packet::decode(chars.as_str()) {
+                Ok(packet) => inner.dispatch(packet),
+                Err(err) => console_log!("error on parse socket.io packet {}: {}", frame, err),
+            },
+            Some(ENGINE_CLOSE) => inner.engine_closed(),
+            _ => (),
+        }
+    }
+
+    fn engine_opened(&self, handshake: &str) {
+        match serde_json::from_str::<Handshake>(handshake) {
+            Ok(handshake) => self
+                .heartbeat_timeout
+                .set(handshake.ping_interval + handshake.ping_timeout),
+            Err(err) => console_log!("error on parse engine.io handshake: {:?}", err),
+        }
+        self.engine_open.set(true);
+        self.reset_watchdog();
+        let namespaces: Vec<(String, Rc<NamespaceState>)> = self
+            .namespaces
+            .borrow()
+            .iter()
+            .map(|(name, namespace)| (name.clone(), namespace.clone()))
+            .collect();
+        for (name, namespace) in namespaces {
+            self.connect_namespace(&name, &namespace);
+        }
+    }
+
+    fn engine_closed(&self) {
+        self.engine_open.set(false);
+        if let Some(id) = self.watchdog.take() {
+            clear_timeout(id);
+        }
+        for namespace in self.namespaces.borrow().values() {
+            namespace.connected.set(false);
+        }
+        // the server forgot the ack ids with the socket
+        self.acks.borrow_mut().clear();
+    }
+
+    fn reset_watchdog(&self) {
+        if let Some(id) = self.watchdog.take() {
+            clear_timeout(id);
+        }
+        if !self.engine_open.get() {
+            return;
+        }
+        let core = Rc::downgrade(&self.websocket.core);
+        let id = set_timeout_once(
+            move || {
+                if let Some(core) = core.upgrade() {
+                    console_log!("no engine.io ping in time, reconnecting");
+                    force_reconnect(&core);
+                }
+            },
+            self.heartbeat_timeout.get(),
+        );
+        self.watchdog.set(Some(id));
+    }
+
+    fn connect_namespace(&self, name: &str, namespace: &NamespaceState) {
+        if !self.engine_open.get() {
+            return;
+        }
+        if let Err(err) = self.send(&Packet::new(CONNECT, name, namespace.auth.clone())) {
+            console_log!("error on connect namespace {}: {:?}", name, err);
+        }
+    }
+
+    fn dispatch(&self, packet: Packet) {
+        let namespace = match self.namespaces.borrow().get(&packet.namespace) {
+            Some(namespace) => namespace.clone(),
+            None => return,
+        };
+        match packet.kind {
+            CONNECT => {
+                namespace.connected.set(true);
+                let queued: Vec<Packet> = namespace.queue.borrow_mut().drain(..).collect();
+                for packet in queued {
+                    if let Err(err) = self.send(&packet) {
+                        console_log!("error on emit to {}: {:?}", packet.namespace, err);
+                    }
+                }
+                self.call(
+                    &namespace,
+                    "connect",
+                    packet.data.into_iter().collect(),
+                    None,
+                );
+            }
+            // the server closed the namespace, it is not connected again
+            DISCONNECT => {
+                self.namespaces.borrow_mut().remove(&packet.namespace);
+                self.call(&namespace, "disconnect", Vec::new(), None);
+            }
+            CONNECT_ERROR => {
+                self.call(
+                    &namespace,
+                    "connect_error",
+                    packet.data.into_iter().collect(),
+                    None,
+                );
+            }
+            EVENT => {
+                let ack = packet.id.map(|id| Ack {
+                    id,
+                    namespace: packet.namespace.clone(),
+                    socket: self.websocket.clone(),
+                });
+                let mut args = match packet.data {
+                    Some(Value::Array(args)) if !args.is_empty() => args,
+                    _ => return,
+                };
+                let event = match args.remove(0) {
+                    Value::String(event) => event,
+                    _ => return,
+                };
+                self.call(&namespace, &event, args, ack);
+            }
+            ACK => {
+                let callback = packet.id.and_then(|id| self.acks.borrow_mut().remove(&id));
+                if let Some(callback) = callback {
+                    let args = match packet.data {
+                        Some(Value::Array(args)) => args,
+                        _ => Vec::new(),
+                    };
+                    callback(args);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn call(&self, namespace: &NamespaceState, event: &str, args: Vec<Value>, ack: Option<Ack>) {
+        let listener = namespace.listeners.borrow().get(event).cloned();
+        if let Some(listener) = listener {
+            listener(args, ack);
+        }
+    }
+}
+
+// A Socket.IO client (Engine.IO v4) over the websocket transport of the
+// reconnecting socket. The factory url has to point at the Socket.IO path of
+// the server, e.g. `wss://host/socket.io/`. Namespaces connect again on every
+// Engine.IO open, and a server that misses its pings for longer than the
+// handshake allows gets the socket closed and reconnected. Binary events are
+// not supported.
+#[derive(Clone)]
+pub struct SocketIo {
+    inner: Rc<SocketIoInner>,
+}
+
+impl SocketIo {
+    pub fn build(factory: WsFactory) -> Result<Self, WsError> {
+        let factory = factory
+            .query_param("EIO", "4")
+            .query_param("transport", "websocket");
+        let inner = ProtocolClient::new(factory, SocketIoInner::receive)
+            .on_close(|socket: &Rc<SocketIoInner>| socket.engine_closed())
+            .build(|websocket| SocketIoInner {
+                websocket,
+                namespaces: RefCell::new(HashMap::new()),
+                acks: RefCell::new(HashMap::new()),
+                next_ack_id: Cell::new(0),
+                engine_open: Cell::new(false),
+                heartbeat_timeout: Cell::new(45_000),
+                watchdog: Cell::new(None),
+            })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    // The default namespace, connected without auth.
+    pub fn socket(&self) -> Namespace {
+        self.of(DEFAULT_NAMESPACE, None)
+    }
+
+    // Connects the namespace, or returns another handle to it when it is
+    // connected already. `auth` is sent with every connect of it.
+    pub fn of(&self, name: &str, auth: Option<Value>) -> Namespace {
+        let existing = self.inner.namespaces.borrow().get(name).cloned();
+        if existing.is_none() {
+            let namespace = Rc::new(NamespaceState {
+                auth,
+                listeners: RefCell::new(HashMap::new()),
+                queue: RefCell::new(VecDeque::new()),
+                connected: Cell::new(false),
+            });
+            self.inner
+                .namespaces
+                .borrow_mut()
+                .insert(String::from(name), namespace.clone());
+            self.inner.connect_namespace(name, &namespace);
+        }
+        Namespace {
+            name: String::from(name),
+            socket: self.inner.clone(),
+        }
+    }
+
+    pub fn namespaces(&self) -> Vec<String> {
+        self.inner.namespaces.borrow().keys().cloned().collect()
+    }
+}
+
+// Answers an event the server emitted with an ack callback.
+pub struct Ack {
+    id: u64,
+    namespace: String,
+    socket: Websocket,
+}
+
+impl Ack {
+    pub fn send(self, args: Vec<Value>) -> Result<(), WsError> {
+        let mut packet = Packet::new(ACK, &self.namespace, Some(Value::Array(args)));
+        packet.id = Some(self.id);
+        self.socket.send(WsMessage::Text(packet.encode()))
+    }
+}
+
+#[derive(Clone)]
+pub struct Namespace {
+    name: String,
+    socket: Rc<SocketIoInner>,
+}
+
+impl Namespace {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn state(&self) -> Option<Rc<NamespaceState>> {
+        self.socket.namespaces.borrow().get(&self.name).cloned()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state()
+            .is_some_and(|namespace| namespace.connected.get())
+    }
+
+    // Besides the events of the server there are `connect`, `connect_error`
+    // and `disconnect`. The ack is set when the server waits for an answer.
+    pub fn on(&self, event: &str, f: impl Fn(Vec<Value>, Option<Ack>) + 'static) {
+        if let Some(namespace) = self.state() {
+            namespace
+                .listeners
+                .borrow_mut()
+                .insert(String::from(event), Rc::new(f));
+        }
+    }
+
+    pub fn off(&self, event: &str) {
+        if let Some(namespace) = self.state() {
+            namespace.listeners.borrow_mut().remove(event);
+        }
+    }
+
+    // Queued while the namespace is not connected on the current socket.
+    pub fn emit(&self, event: &str, args: Vec<Value>) -> Result<(), WsError> {
+        self.emit_packet(event, args, None)
+    }
+
+    // The callback gets the arguments of the server ack. It is dropped
+    // without a call when the socket closes before the ack arrived.
+    pub fn emit_with_ack(
+        &self,
+        event: &str,
+        args: Vec<Value>,
+        callback: impl FnOnce(Vec<Value>) + 'static,
+    ) -> Result<(), WsError> {
+        self.emit_packet(event, args, Some(Box::new(callback)))
+    }
+
+    fn emit_packet(
+        &self,
+        event: &str,
+        mut args: Vec<Value>,
+        callback: Option<AckCallback>,
+    ) -> Result<(), WsError> {
+        let namespace = self.state().ok_or_else(|| WsError::NotConnected {
+            state: self.socket.websocket.ready_state(),
+        })?;
+        args.insert(0, Value::String(String::from(event)));
+        let mut packet = Packet::new(EVENT, &self.name, Some(Value::Array(args)));
+        if let Some(callback) = callback {
+            let id = self.socket.next_ack_id.get();
+            self.socket.next_ack_id.set(id + 1);
+            self.socket.acks.borrow_mut().insert(id, callback);
+            packet.id = Some(id);
+        }
+        if !namespace.connected.get()
+            || !matches!(self.socket.websocket.ready_state(), ReadyState::Open)
+        {
+            namespace.queue.borrow_mut().push_back(packet);
+            return Ok(());
+        }
+        self.socket.send(&packet)
+    }
+
+    // Leaves the namespace for every handle, queued emits are dropped.
+    pub fn disconnect(self) -> Result<(), WsError> {
+        let namespace = self.socket.namespaces.borrow_mut().remove(&self.name);
+        match namespace {
+            Some(namespace) if namespace.connected.get() => {
+                self.socket.send(&Packet::new(DISCONNECT, &self.name, None))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_packets() {
+        let packet = Packet::decode("2[\"chat\",1]").unwrap();
+        assert_eq!(packet.kind, EVENT);
+        assert_eq!(packet.namespace, DEFAULT_NAMESPACE);
+        assert_eq!(packet.id, None);
+        assert_eq!(packet.data, Some(serde_json::json!(["chat", 1])));
+        let packet = Packet::decode("3/admin,12[\"ok\"]").unwrap();
+        assert_eq!(packet.kind, ACK);
+        assert_eq!(packet.namespace, "/admin");
+        assert_eq!(packet.id, Some(12));
+        assert_eq!(packet.data, Some(serde_json::json!(["ok"])));
+        let packet = Packet::decode("1/admin,").unwrap();
+        assert_eq!(packet.kind, DISCONNECT);
+        assert_eq!(packet.namespace, "/admin");
+        assert_eq!(packet.data, None);
+        let packet = Packet::decode("0/admin").unwrap();
+        assert_eq!(packet.kind, CONNECT);
+        assert_eq!(packet.namespace, "/admin");
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        assert!(Packet::decode("").is_err());
+        assert!(Packet::decode("x").is_err());
+        assert!(Packet::decode("2[\"chat\"").is_err());
+        assert!(Packet::decode("51-[\"a\",{\"_placeholder\":true,\"num\":0}]").is_err());
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let mut packet = Packet::new(EVENT, "/admin", Some(serde_json::json!(["a"])));
+        packet.id = Some(3);
+        let frame = packet.encode();
+        assert_eq!(frame, "42/admin,3[\"a\"]");
+        let decoded = Packet::decode(&frame[1..]).unwrap();
+        assert_eq!(decoded.namespace, "/admin");
+        assert_eq!(decoded.id, Some(3));
+        assert_eq!(decoded.data, packet.data);
+    }
+}
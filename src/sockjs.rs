@@ -0,0 +1,143 @@
+use std::cell::RefCell;
+
+use serde_json::Value;
+use wasm_bindgen::JsValue;
+
+use crate::error::WsError;
+use crate::WsMessage;
+
+const SESSION_ID_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+const SESSION_ID_LEN: usize = 8;
+
+pub enum SockJsFrame {
+    Open,
+    Heartbeat,
+    Messages(Vec<String>),
+    // sent by the server right before it closes the socket
+    Close { code: u16, reason: String },
+}
+
+// The SockJS websocket transport: the session opens with an `o` frame, the
+// server sends `h` heartbeats, `a[...]` arrays of messages and a `c[code,
+// reason]` frame before closing. Messages from the client go out as a json
+// array of strings. Only text messages can be carried.
+#[derive(Default)]
+pub struct SockJs {
+    // the rest of the open sequence, run once the `o` frame came
+    pending: RefCell<Option<Box<dyn FnOnce()>>>,
+    // code and reason of the `c` frame, they replace the ones of the close
+    // event that follows
+    close: RefCell<Option<(u16, String)>>,
+}
+
+impl SockJs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Three digits, lets a load balancer route the session to one server.
+    pub fn server_id() -> String {
+        format!("{:03}", (js_sys::Math::random() * 1000.0) as u32 % 1000)
+    }
+
+    pub fn session_id() -> String {
+        (0..SESSION_ID_LEN)
+            .map(|_| {
+                let index = (js_sys::Math::random() * SESSION_ID_CHARS.len() as f64) as usize;
+                SESSION_ID_CHARS[index % SESSION_ID_CHARS.len()] as char
+            })
+            .collect()
+    }
+
+    pub fn decode(frame: &str) -> Result<SockJsFrame, String> {
+        let (kind, body) = match frame.char_indices().nth(1) {
+            Some((index, _)) => frame.split_at(index),
+            None => (frame, ""),
+        };
+        match kind {
+            "o" => Ok(SockJsFrame::Open),
+            "h" => Ok(SockJsFrame::Heartbeat),
+            "a" => serde_json::from_str(body)
+                .map(SockJsFrame::Messages)
+                .map_err(|err| err.to_string()),
+            "c" => {
+                let close: Vec<Value> =
+                    serde_json::from_str(body).map_err(|err| err.to_string())?;
+                Ok(SockJsFrame::Close {
+                    code: close.first().and_then(Value::as_u64).unwrap_or(0) as u16,
+                    reason: close
+                        .get(1)
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                })
+            }
+            _ => Err(format!("unknown sockjs frame {}", frame)),
+        }
+    }
+
+    pub fn encode(message: &WsMessage) -> Result<String, WsError> {
+        match message {
+            WsMessage::Text(payload) => Ok(serde_json::to_string(&[payload])?),
+            WsMessage::Binary(_) => Err(WsError::SendFailed(JsValue::from_str(
+                "sockjs only carries text messages",
+            ))),
+        }
+    }
+
+    pub(crate) fn begin(&self, ready: Box<dyn FnOnce()>) {
+        *self.pending.borrow_mut() = Some(ready);
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.borrow().is_some()
+    }
+
+    pub(crate) fn take_pending(&self) -> Option<Box<dyn FnOnce()>> {
+        self.pending.borrow_mut().take()
+    }
+
+    pub(crate) fn set_close(&self, code: u16, reason: String) {
+        *self.close.borrow_mut() = Some((code, reason));
+    }
+
+    pub(crate) fn take_close(&self) -> Option<(u16, String)> {
+        self.close.borrow_mut().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_frames() {
+        assert!(matches!(SockJs::decode("o"), Ok(SockJsFrame::Open)));
+        assert!(matches!(SockJs::decode("h"), Ok(SockJsFrame::Heartbeat)));
+        match SockJs::decode("a[\"one\",\"two\"]") {
+            Ok(SockJsFrame::Messages(messages)) => assert_eq!(messages, vec!["one", "two"]),
+            _ => panic!("expected messages"),
+        }
+        match SockJs::decode("c[3000,\"Go away!\"]") {
+            Ok(SockJsFrame::Close { code, reason }) => {
+                assert_eq!(code, 3000);
+                assert_eq!(reason, "Go away!");
+            }
+            _ => panic!("expected close"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        assert!(SockJs::decode("").is_err());
+        assert!(SockJs::decode("x").is_err());
+        assert!(SockJs::decode("a[1]").is_err());
+        assert!(SockJs::decode("c").is_err());
+    }
+
+    #[test]
+    fn encodes_text_messages() {
+        let message = WsMessage::Text(String::from("say \"hi\""));
+        assert_eq!(SockJs::encode(&message).unwrap(), "[\"say \\\"hi\\\"\"]");
+    }
+}
@@ -0,0 +1,50 @@
+use serde::Serialize;
+
+// Aggregated over every socket the client opened, reconnects included.
+// Timestamps are `Date.now()` milliseconds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConnectionStats {
+    pub first_connected_at: Option<f64>,
+    // open time of the current socket, `None` while disconnected
+    pub connected_at: Option<f64>,
+    pub uptime_ms: f64,
+    pub connections: u64,
+    pub reconnects: u64,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<f64>,
+}
+
+impl ConnectionStats {
+    pub(crate) fn record_open(&mut self, now: f64) {
+        if self.connections > 0 {
+            self.reconnects += 1;
+        }
+        self.connections += 1;
+        self.first_connected_at.get_or_insert(now);
+        self.connected_at = Some(now);
+    }
+
+    pub(crate) fn record_close(&mut self, now: f64) {
+        if let Some(connected_at) = self.connected_at.take() {
+            self.uptime_ms += now - connected_at;
+        }
+    }
+
+    pub(crate) fn record_error(&mut self, error: String, now: f64) {
+        self.last_error = Some(error);
+        self.last_error_at = Some(now);
+    }
+
+    // Uptime includes the current connection up to `now`.
+    pub(crate) fn snapshot(&self, now: f64) -> Self {
+        let mut stats = self.clone();
+        if let Some(connected_at) = self.connected_at {
+            stats.uptime_ms += now - connected_at;
+        }
+        stats
+    }
+}
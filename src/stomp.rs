@@ -0,0 +1,620 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::{force_reconnect, ProtocolClient};
+use crate::timers::{clear_timeout, set_timeout_once};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+pub const STOMP_SUBPROTOCOL: &str = "v12.stomp";
+
+// ERROR frames of the server are emitted under this event.
+pub const STOMP_ERROR_EVENT: &str = "stomp_error";
+
+pub struct StompConfig {
+    host: Option<String>,
+    login: Option<(String, String)>,
+    // (ms between our heart-beats, ms between the ones we want), 0 is none
+    heart_beat: (u32, u32),
+}
+
+impl StompConfig {
+    pub fn new() -> Self {
+        Self {
+            host: None,
+            login: None,
+            heart_beat: (10_000, 10_000),
+        }
+    }
+
+    // The virtual host, the host of the url by default.
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(String::from(host));
+        self
+    }
+
+    pub fn login(mut self, login: &str, passcode: &str) -> Self {
+        self.login = Some((String::from(login), String::from(passcode)));
+        self
+    }
+
+    pub fn heart_beat(mut self, outgoing: u32, incoming: u32) -> Self {
+        self.heart_beat = (outgoing, incoming);
+        self
+    }
+}
+
+impl Default for StompConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AckMode {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+impl AckMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            AckMode::Auto => "auto",
+            AckMode::Client => "client",
+            AckMode::ClientIndividual => "client-individual",
+        }
+    }
+}
+
+// A MESSAGE or ERROR frame as it is emitted, json encoded, under the
+// destination of its subscription or `stomp_error`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StompMessage {
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl StompMessage {
+    pub fn from_payload(payload: &Payload) -> Option<Self> {
+        match payload {
+            Payload::Data(data) => serde_json::from_str(data).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn destination(&self) -> Option<&str> {
+        self.header("destination")
+    }
+
+    // The id to pass to `ack` and `nack`, set for client ack modes.
+    pub fn ack_id(&self) -> Option<&str> {
+        self.header("ack")
+    }
+}
+
+struct Frame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Frame {
+    fn new(command: &str) -> Self {
+        Self {
+            command: String::from(command),
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        // the first occurrence of a repeated header wins
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn encode(&self) -> String {
+        // CONNECT headers are not escaped, for 1.0 servers
+        let escaped = self.command != "CONNECT";
+        let mut frame = format!("{}\n", self.command);
+        for (name, value) in self.headers.iter() {
+            if escaped {
+                frame.push_str(&format!("{}:{}\n", escape(name), escape(value)));
+            } else {
+                frame.push_str(&format!("{}:{}\n", name, value));
+            }
+        }
+        if !self.body.is_empty() {
+            frame.push_str(&format!("content-length:{}\n", self.body.len()));
+        }
+        frame.push('\n');
+        frame.push_str(&self.body);
+        frame.push('\0');
+        frame
+    }
+
+    // A message can carry several frames, heart-beat EOLs are skipped.
+    fn decode_all(mut data: &str) -> Result<Vec<Frame>, String> {
+        let mut frames = Vec::new();
+        loop {
+            data = data.trim_start_matches(['\n', '\r']);
+            if data.is_empty() {
+                return Ok(frames);
+            }
+            let (frame, rest) = Self::decode(data)?;
+            frames.push(frame);
+            data = rest;
+        }
+    }
+
+    fn decode(data: &str) -> Result<(Frame, &str), String> {
+        let mut lines = data;
+        let mut next_line = || -> Result<&str, String> {
+            let end = lines
+                .find('\n')
+                .ok_or_else(|| String::from("incomplete frame"))?;
+            let line = lines[..end].trim_end_matches('\r');
+            lines = &lines[end + 1..];
+            Ok(line)
+        };
+        let command = String::from(next_line()?);
+        let unescaped = command != "CONNECTED";
+        let mut headers = Vec::new();
+        loop {
+            let line = next_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("invalid header: {}", line))?;
+            if unescaped {
+                headers.push((unescape(name), unescape(value)));
+            } else {
+                headers.push((String::from(name), String::from(value)));
+            }
+        }
+        let mut frame = Frame {
+            command,
+            headers,
+            body: String::new(),
+        };
+        let end = match frame.get("content-length").and_then(|len| len.parse().ok()) {
+            Some(len) if lines.get(..len).is_some() => len,
+            Some(_) => return Err(String::from("body shorter than content-length")),
+            None => lines
+                .find('\0')
+                .ok_or_else(|| String::from("frame without NULL"))?,
+        };
+        frame.body = String::from(&lines[..end]);
+        let rest = lines[end..]
+            .strip_prefix('\0')
+            .ok_or_else(|| String::from("frame without NULL"))?;
+        Ok((frame, rest))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace(':', "\\c")
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some('c') => unescaped.push(':'),
+            Some(c) => unescaped.push(c),
+            None => (),
+        }
+    }
+    unescaped
+}
+
+struct Subscription {
+    destination: String,
+    ack: AckMode,
+}
+
+struct StompInner {
+    websocket: Websocket,
+    config: StompConfig,
+    subscriptions: RefCell<HashMap<String, Subscription>>,
+    next_id: Cell<u64>,
+    // SEND frames made before the session of the current socket started
+    queue: RefCell<VecDeque<Frame>>,
+    connected: Cell<bool>,
+    // bumped on every close, stops the heart-beats of the previous session
+    session: Cell<u64>,
+    // the negotiated ms between heart-beats of the server
+    expected_heart_beat: Cell<Option<u32>>,
+    watchdog: Cell<Option<i32>>,
+}
+
+impl StompInner {
+    fn send(&self, frame: &Frame) -> Result<(), WsError> {
+        self.websocket.send(WsMessage::Text(frame.encode()))
+    }
+
+    fn connect(&self) {
+        let host = match self.config.host.clone() {
+            Some(host) => host,
+            None => url_host(&self.websocket.url()),
+        };
+        let (outgoing, incoming) = self.config.heart_beat;
+        let mut frame = Frame::new("CONNECT")
+            .header("accept-version", "1.2")
+            .header("host", &host)
+            .header("heart-beat", &format!("{},{}", outgoing, incoming));
+        if let Some((login, passcode)) = self.config.login.as_ref() {
+            frame = frame.header("login", login).header("passcode", passcode);
+        }
+        if let Err(err) = self.send(&frame) {
+            console_log!("error on stomp connect: {:?}", err);
+        }
+    }
+
+    fn disconnected(&self) {
+        self.connected.set(false);
+        self.session.set(self.session.get() + 1);
+        if let Some(id) = self.watchdog.take() {
+            clear_timeout(id);
+        }
+    }
+
+    fn receive(inner: &Rc<Self>, message: WsMessage) {
+        let data = match message {
+            WsMessage::Text(data) => data,
+            WsMessage::Binary(data) => String::from_utf8_lossy(&data).into_owned(),
+        };
+        inner.reset_watchdog();
+        let frames = match Frame::decode_all(&data) {
+            Ok(frames) => frames,
+            Err(err) => {
+                console_log!("error on parse stomp frame {}: {}", data, err);
+                return;
+            }
+        };
+        for frame in frames {
+            Self::dispatch(inner, frame);
+        }
+    }
+
+    fn dispatch(inner: &Rc<Self>, frame: Frame) {
+        match frame.command.as_str() {
+            "CONNECTED" => Self::session_started(inner, &frame),
+            "MESSAGE" => {
+                let destination = frame
+                    .get("subscription")
+                    .and_then(|id| {
+                        inner
+                            .subscriptions
+                            .borrow()
+                            .get(id)
+                            .map(|subscription| subscription.destination.clone())
+                    })
+                    .or_else(|| frame.get("destination").map(String::from));
+                if let Some(destination) = destination {
+                    inner.emit(destination, frame);
+                }
+            }
+            "ERROR" => inner.emit(String::from(STOMP_ERROR_EVENT), frame),
+            // RECEIPT frames are not asked for
+            _ => (),
+        }
+    }
+
+    fn emit(&self, event: String, frame: Frame) {
+        let message = StompMessage {
+            headers: frame.headers.into_iter().rev().collect(),
+            body: frame.body,
+        };
+        let emitter = self.websocket.core.factory.emitter.clone();
+        if let (Some(emitter), Ok(message)) = (emitter, serde_json::to_string(&message)) {
+            emitter.borrow().emit(event, &Payload::Data(message));
+        }
+    }
+
+    // Destinations are subscribed again on every new session, then the
+    // queued sends go out.
+    fn session_started(inner: &Rc<Self>, frame: &Frame) {
+        inner.connected.set(true);
+        let (outgoing, incoming) = inner.config.heart_beat;
+        let (server_outgoing, server_incoming) = frame
+            .get("heart-beat")
+            .and_then(|heart_beat| heart_beat.split_once(','))
+            .map(|(sx, sy)| {
+                (
+                    sx.trim().parse().unwrap_or(0),
+                    sy.trim().parse().unwrap_or(0),
+                )
+            })
+            .unwrap_or((0, 0));
+        if outgoing != 0 && server_incoming != 0 {
+            Self::heart_beat(Rc::downgrade(inner), outgoing.max(server_incoming));
+        }
+        let expected = if incoming != 0 && server_outgoing != 0 {
+            Some(incoming.max(server_outgoing))
+        } else {
+            None
+        };
+        inner.expected_heart_beat.set(expected);
+        inner.reset_watchdog();
+        let frames: Vec<Frame> = inner
+            .subscriptions
+            .borrow()
+            .iter()
+            .map(|(id, subscription)| subscribe_frame(id, subscription))
+            .collect();
+        let queued: Vec<Frame> = inner.queue.borrow_mut().drain(..).collect();
+        for frame in frames.iter().chain(queued.iter()) {
+            if let Err(err) = inner.send(frame) {
+                console_log!("error on stomp {}: {:?}", frame.command, err);
+            }
+        }
+    }
+
+    fn heart_beat(inner: Weak<Self>, interval: u32) {
+        let session = match inner.upgrade() {
+            Some(inner) => inner.session.get(),
+            None => return,
+        };
+        set_timeout_once(
+            move || {
+                let stomp = match inner.upgrade() {
+                    Some(stomp) if stomp.session.get() == session => stomp,
+                    _ => return,
+                };
+                if let Err(err) = stomp.websocket.send(WsMessage::Text(String::from("\n"))) {
+                    console_log!("error on stomp heart-beat: {:?}", err);
+                }
+                Self::heart_beat(inner, interval);
+            },
+            interval,
+        );
+    }
+
+    // Without anything from the server for twice the negotiated interval the
+    // socket counts as dead.
+    fn reset_watchdog(&self) {
+        if let Some(id) = self.watchdog.take() {
+            clear_timeout(id);
+        }
+        let interval = match self.expected_heart_beat.get() {
+            Some(interval) if self.connected.get() => interval,
+            _ => return,
+        };
+        let core = Rc::downgrade(&self.websocket.core);
+        let id = set_timeout_once(
+            move || {
+                if let Some(core) = core.upgrade() {
+                    console_log!("no stomp heart-beat in time, reconnecting");
+                    force_reconnect(&core);
+                }
+            },
+            interval * 2,
+        );
+        self.watchdog.set(Some(id));
+    }
+
+    fn is_open(&self) -> bool {
+        self.connected.get() && matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+}
+
+fn subscribe_frame(id: &str, subscription: &Subscription) -> Frame {
+    Frame::new("SUBSCRIBE")
+        .header("id", id)
+        .header("destination", &subscription.destination)
+        .header("ack", subscription.ack.as_str())
+}
+
+fn url_host(url: &str) -> String {
+    let rest = url.split("://").nth(1).unwrap_or(url);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    match host.rfind(':') {
+        Some(port) if !host.ends_with(']') => String::from(&host[..port]),
+        _ => String::from(host),
+    }
+}
+
+// A STOMP 1.2 client over the reconnecting socket, for Spring and RabbitMQ
+// Web-STOMP backends. MESSAGE frames are emitted under the destination of
+// their subscription as json encoded `StompMessage`s. Every open sends a
+// CONNECT and the subscriptions go out again once the server answers, sends
+// made before that wait in a queue. Liveness is checked with the heart-beats
+// negotiated in CONNECTED.
+#[derive(Clone)]
+pub struct StompClient {
+    inner: Rc<StompInner>,
+}
+
+impl StompClient {
+    pub fn build(mut factory: WsFactory, config: StompConfig) -> Result<Self, WsError> {
+        if factory.socket_factory.is_none() {
+            factory = factory.socket_factory(|url| WebSocket::new_with_str(url, STOMP_SUBPROTOCOL));
+        }
+        let inner = ProtocolClient::new(factory, StompInner::receive)
+            .on_open(|stomp: &Rc<StompInner>| stomp.connect())
+            .on_close(|stomp: &Rc<StompInner>| stomp.disconnected())
+            .build(|websocket| StompInner {
+                websocket,
+                config,
+                subscriptions: RefCell::new(HashMap::new()),
+                next_id: Cell::new(0),
+                queue: RefCell::new(VecDeque::new()),
+                connected: Cell::new(false),
+                session: Cell::new(0),
+                expected_heart_beat: Cell::new(None),
+                watchdog: Cell::new(None),
+            })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected.get()
+    }
+
+    // Messages of the destination are emitted under its name, listen to
+    // them with `add_listener`. The subscription is made again on every new
+    // session until it is unsubscribed.
+    pub fn subscribe(&self, destination: &str, ack: AckMode) -> Result<String, WsError> {
+        let id = self.inner.next_id.get();
+        self.inner.next_id.set(id + 1);
+        let id = format!("sub-{}", id);
+        let subscription = Subscription {
+            destination: String::from(destination),
+            ack,
+        };
+        let frame = subscribe_frame(&id, &subscription);
+        self.inner
+            .subscriptions
+            .borrow_mut()
+            .insert(id.clone(), subscription);
+        if self.inner.is_open() {
+            self.inner.send(&frame)?;
+        }
+        Ok(id)
+    }
+
+    pub fn unsubscribe(&self, id: &str) -> Result<(), WsError> {
+        let subscription = self.inner.subscriptions.borrow_mut().remove(id);
+        if subscription.is_some() && self.inner.is_open() {
+            return self.inner.send(&Frame::new("UNSUBSCRIBE").header("id", id));
+        }
+        Ok(())
+    }
+
+    // Queued until the session of the current socket started.
+    pub fn send(
+        &self,
+        destination: &str,
+        body: String,
+        content_type: Option<&str>,
+    ) -> Result<(), WsError> {
+        let mut frame = Frame::new("SEND").header("destination", destination);
+        if let Some(content_type) = content_type {
+            frame = frame.header("content-type", content_type);
+        }
+        frame.body = body;
+        if !self.inner.is_open() {
+            self.inner.queue.borrow_mut().push_back(frame);
+            return Ok(());
+        }
+        self.inner.send(&frame)
+    }
+
+    // `ack_id` is the `ack` header of the message. Acks are only valid on the
+    // session the message arrived on, so they are never queued.
+    pub fn ack(&self, ack_id: &str) -> Result<(), WsError> {
+        self.inner.send(&Frame::new("ACK").header("id", ack_id))
+    }
+
+    pub fn nack(&self, ack_id: &str) -> Result<(), WsError> {
+        self.inner.send(&Frame::new("NACK").header("id", ack_id))
+    }
+
+    pub fn destinations(&self) -> Vec<String> {
+        self.inner
+            .subscriptions
+            .borrow()
+            .values()
+            .map(|subscription| subscription.destination.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_frames_between_heart_beats() {
+        let data = "\nMESSAGE\ndestination:/topic/a\nsubscription:sub-0\n\nhello\0\r\n\nRECEIPT\nreceipt-id:1\n\n\0\n";
+        let frames = Frame::decode_all(data).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].command, "MESSAGE");
+        assert_eq!(frames[0].get("destination"), Some("/topic/a"));
+        assert_eq!(frames[0].body, "hello");
+        assert_eq!(frames[1].command, "RECEIPT");
+        assert_eq!(frames[1].get("receipt-id"), Some("1"));
+        assert_eq!(frames[1].body, "");
+    }
+
+    #[test]
+    fn reads_the_body_by_content_length() {
+        let (frame, rest) = Frame::decode("MESSAGE\ncontent-length:3\n\na\0b\0tail").unwrap();
+        assert_eq!(frame.body, "a\0b");
+        assert_eq!(rest, "tail");
+        assert!(Frame::decode("MESSAGE\ncontent-length:9\n\nab\0").is_err());
+    }
+
+    #[test]
+    fn unescapes_headers_except_in_connected() {
+        let (frame, _) = Frame::decode("MESSAGE\na\\cb:c\\nd\\\\\nx:1\nx:2\n\n\0").unwrap();
+        assert_eq!(frame.get("a:b"), Some("c\nd\\"));
+        assert_eq!(frame.get("x"), Some("1"));
+        let (frame, _) = Frame::decode("CONNECTED\nserver:a\\cb\n\n\0").unwrap();
+        assert_eq!(frame.get("server"), Some("a\\cb"));
+    }
+
+    #[test]
+    fn rejects_incomplete_frames() {
+        assert!(Frame::decode("MESSAGE\ndestination:/a").is_err());
+        assert!(Frame::decode("MESSAGE\ninvalid\n\n\0").is_err());
+        assert!(Frame::decode("MESSAGE\n\nbody").is_err());
+    }
+
+    #[test]
+    fn round_trips_escaped_headers() {
+        let frame = Frame::new("SEND").header("a:b", "c\r\nd");
+        let encoded = frame.encode();
+        let (decoded, rest) = Frame::decode(&encoded).unwrap();
+        assert_eq!(decoded.get("a:b"), Some("c\r\nd"));
+        assert_eq!(rest, "");
+    }
+}
@@ -0,0 +1,421 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use futures::sink::Sink;
+use futures::stream::Stream;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::connect::CloseInfo;
+use crate::emitter::Payload;
+use crate::error::WsError;
+use crate::outgoing::{SendFuture, WsSink};
+use crate::simple_rpc::{subscription_key, IntoParams, RpcError, CONNECTION_CLOSED};
+use crate::timers::set_timeout_once;
+use crate::{Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    // a reconnect opened a new socket
+    Connecting,
+    // the socket opened, after the auth handshake when there is one
+    Open,
+    Error(String),
+    Closed(CloseInfo),
+}
+
+pub const DEFAULT_RECEIVER_CAPACITY: usize = 1024;
+
+pub(crate) struct Inbox {
+    messages: VecDeque<WsMessage>,
+    capacity: usize,
+    dropped: u64,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+impl Inbox {
+    fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            capacity: DEFAULT_RECEIVER_CAPACITY,
+            dropped: 0,
+            waker: None,
+            closed: false,
+        }
+    }
+
+    // A receiver that isn't polled keeps the newest `capacity` messages.
+    pub(crate) fn push(&mut self, message: WsMessage) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+            self.dropped += 1;
+        }
+        self.messages.push_back(message);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Every incoming message once chunks, fragments and compression were undone,
+// across reconnects. Ends once the connection closed for good, i.e. on
+// `close` or without a reconnect to follow.
+pub struct WsReceiver {
+    inbox: Rc<RefCell<Inbox>>,
+    _websocket: Websocket,
+}
+
+impl WsReceiver {
+    pub(crate) fn new(websocket: Websocket) -> Self {
+        let inbox = Rc::new(RefCell::new(Inbox::new()));
+        websocket
+            .core
+            .factory
+            .receivers
+            .borrow_mut()
+            .push(Rc::downgrade(&inbox));
+        Self {
+            inbox,
+            _websocket: websocket,
+        }
+    }
+
+    // At most `capacity` messages wait to be polled, the oldest ones are
+    // dropped first. `DEFAULT_RECEIVER_CAPACITY` by default.
+    pub fn capacity(self, capacity: usize) -> Self {
+        {
+            let mut inbox = self.inbox.borrow_mut();
+            inbox.capacity = capacity;
+            while inbox.messages.len() > capacity {
+                inbox.messages.pop_front();
+                inbox.dropped += 1;
+            }
+        }
+        self
+    }
+
+    // Messages dropped because the receiver was full.
+    pub fn dropped(&self) -> u64 {
+        self.inbox.borrow().dropped
+    }
+}
+
+impl Stream for WsReceiver {
+    type Item = WsMessage;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut inbox = self.inbox.borrow_mut();
+        if let Some(message) = inbox.messages.pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        if inbox.closed {
+            return Poll::Ready(None);
+        }
+        inbox.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// The payloads of one emitter event. Registering replaces the listener of the
+// event, dropping the stream removes it unless it was replaced since.
+pub struct ListenStream {
+    receiver: UnboundedReceiver<Payload>,
+    name: String,
+    owner: Option<u64>,
+    websocket: Websocket,
+}
+
+impl ListenStream {
+    pub(crate) fn new(websocket: Websocket, name: String) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        let owner = websocket.core.factory.emitter.as_ref().map(|emitter| {
+            emitter.borrow_mut().on_owned(
+                name.clone(),
+                Box::new(move |payload: &Payload| {
+                    let _ = sender.unbounded_send(payload.clone());
+                }),
+            )
+        });
+        Self {
+            receiver,
+            name,
+            owner,
+            websocket,
+        }
+    }
+}
+
+impl Stream for ListenStream {
+    type Item = Payload;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+impl Drop for ListenStream {
+    fn drop(&mut self) {
+        let (emitter, owner) = match (self.websocket.core.factory.emitter.clone(), self.owner) {
+            (Some(emitter), Some(owner)) => (emitter, owner),
+            _ => return,
+        };
+        let name = self.name.clone();
+        if let Ok(mut emitter) = emitter.try_borrow_mut() {
+            emitter.off_owned(&name, owner);
+            return;
+        }
+        // dropped from inside a handler, while the emitter is in use
+        set_timeout_once(move || emitter.borrow_mut().off_owned(&name, owner), 0);
+    }
+}
+
+// The sending half of `Websocket::split`, sends made while reconnecting are
+// queued like on the websocket itself.
+pub struct WsSender {
+    websocket: Websocket,
+    sink: WsSink,
+}
+
+impl WsSender {
+    pub(crate) fn new(websocket: Websocket) -> Self {
+        Self {
+            sink: websocket.sink(),
+            websocket,
+        }
+    }
+
+    pub fn send(&self, message: WsMessage) -> Result<(), WsError> {
+        self.websocket.send(message)
+    }
+
+    pub fn send_event(&self, event: &str, data: String) -> Result<(), WsError> {
+        self.websocket.send_event(event, data)
+    }
+
+    pub fn send_async(&self, message: WsMessage) -> SendFuture {
+        self.websocket.send_async(message)
+    }
+}
+
+impl Sink<WsMessage> for WsSender {
+    type Error = WsError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: WsMessage) -> Result<(), Self::Error> {
+        Pin::new(&mut self.sink).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+fn rpc_error(msg: String) -> RpcError {
+    RpcError {
+        id: None,
+        code: None,
+        msg,
+    }
+}
+
+struct RpcStreamState<T> {
+    items: VecDeque<Result<T, RpcError>>,
+    waker: Option<Waker>,
+    // the id returned by the subscribe call, as the server sent it
+    subscription: Option<Value>,
+    closed: bool,
+}
+
+impl<T> RpcStreamState<T> {
+    fn push(&mut self, item: Result<T, RpcError>) {
+        self.items.push_back(item);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Notifications of a json-rpc subscription, deserialized into `T`. The
+// subscription ends with an error item when the connection closes, since the
+// server forgets it, and is unsubscribed when the stream is dropped.
+pub struct RpcSubscriptionStream<T> {
+    state: Rc<RefCell<RpcStreamState<T>>>,
+    websocket: Websocket,
+    unsubscribe_method: String,
+}
+
+impl<T: DeserializeOwned + 'static> RpcSubscriptionStream<T> {
+    pub(crate) fn subscribe(
+        websocket: Websocket,
+        method: String,
+        rpc_params: impl IntoParams,
+        unsubscribe_method: String,
+    ) -> Result<Self, WsError> {
+        let rpc_subscriber = websocket
+            .core
+            .factory
+            .rpc_subscriber
+            .clone()
+            .ok_or(WsError::RpcDisabled)?;
+        let state = Rc::new(RefCell::new(RpcStreamState {
+            items: VecDeque::new(),
+            waker: None,
+            subscription: None,
+            closed: false,
+        }));
+        let on_result = {
+            let state = Rc::downgrade(&state);
+            // the subscriber lives in the factory, a strong socket would keep
+            // the connection alive when the reply never comes
+            let core = Rc::downgrade(&websocket.core);
+            let handles = Rc::downgrade(&websocket.handles);
+            let unsubscribe_method = unsubscribe_method.clone();
+            move |_: &_, result: String| {
+                let subscription: Value = serde_json::from_str(&result).unwrap_or(Value::Null);
+                let key = subscription_key(&subscription);
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    // dropped while the subscribe call was pending
+                    None => {
+                        let websocket = core.upgrade().zip(handles.upgrade());
+                        if let (Some((core, handles)), Some(_)) = (websocket, key) {
+                            let websocket = Websocket { core, handles };
+                            unsubscribe(&websocket, &unsubscribe_method, subscription);
+                        }
+                        return;
+                    }
+                };
+                let key = match key {
+                    Some(key) => key,
+                    None => {
+                        let mut state = state.borrow_mut();
+                        state.push(Err(rpc_error(format!(
+                            "invalid subscription id: {}",
+                            result
+                        ))));
+                        state.close();
+                        return;
+                    }
+                };
+                state.borrow_mut().subscription = Some(subscription);
+                let state = Rc::downgrade(&state);
+                let handler = move |result: Option<Value>| {
+                    let state = match state.upgrade() {
+                        Some(state) => state,
+                        None => return,
+                    };
+                    let mut state = state.borrow_mut();
+                    match result {
+                        Some(result) => {
+                            let item = serde_json::from_value::<T>(result)
+                                .map_err(|err| rpc_error(err.to_string()));
+                            state.push(item);
+                        }
+                        None => {
+                            state.subscription = None;
+                            state.push(Err(rpc_error(String::from(CONNECTION_CLOSED))));
+                            state.close();
+                        }
+                    }
+                };
+                rpc_subscriber
+                    .borrow_mut()
+                    .set_subscription_handler(key, Rc::new(handler));
+            }
+        };
+        let on_error = {
+            let state = Rc::downgrade(&state);
+            move |_: &_, error: String| {
+                if let Some(state) = state.upgrade() {
+                    let mut state = state.borrow_mut();
+                    state.push(Err(rpc_error(error)));
+                    state.close();
+                }
+            }
+        };
+        websocket.send_text_rpc(method, rpc_params, Box::new(on_result), Box::new(on_error))?;
+        Ok(Self {
+            state,
+            websocket,
+            unsubscribe_method,
+        })
+    }
+}
+
+impl<T> Stream for RpcSubscriptionStream<T> {
+    type Item = Result<T, RpcError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.borrow_mut();
+        if let Some(item) = state.items.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for RpcSubscriptionStream<T> {
+    fn drop(&mut self) {
+        let subscription = self.state.borrow_mut().subscription.take();
+        if let Some(subscription) = subscription {
+            unsubscribe(&self.websocket, &self.unsubscribe_method, subscription);
+        }
+    }
+}
+
+fn unsubscribe(websocket: &Websocket, method: &str, subscription: Value) {
+    if let (Some(rpc_subscriber), Some(key)) = (
+        websocket.core.factory.rpc_subscriber.as_ref(),
+        subscription_key(&subscription),
+    ) {
+        rpc_subscriber.borrow_mut().remove_subscription(&key);
+    }
+    if let Err(err) = websocket.send_rpc(String::from(method), vec![subscription]) {
+        console_log!("error on unsubscribe {}: {:?}", method, err);
+    }
+}
@@ -0,0 +1,308 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+// Bound on the global scope instead of `window`, so they resolve to the
+// `Window` on the main thread and to the `WorkerGlobalScope` inside workers.
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout_js(handler: &JsValue, timeout: u32) -> i32;
+    #[wasm_bindgen(js_name = setInterval)]
+    fn set_interval_js(handler: &JsValue, timeout: u32) -> i32;
+    #[wasm_bindgen(js_name = clearTimeout)]
+    fn clear_timeout_js(id: i32);
+    #[wasm_bindgen(js_name = clearInterval)]
+    fn clear_interval_js(id: i32);
+}
+
+// Every timeout and interval of the crate goes through the timer of the
+// thread, so tests can swap in one that fires them by hand.
+pub trait Timer {
+    fn set_timeout(&self, f: Box<dyn FnOnce()>, timeout: u32) -> i32;
+    fn clear_timeout(&self, id: i32);
+    fn set_interval(&self, f: Box<dyn FnMut()>, interval: u32) -> i32;
+    fn clear_interval(&self, id: i32);
+}
+
+thread_local! {
+    // the closures of the pending global timers, dropped once they ran or
+    // were cleared
+    static TIMEOUTS: RefCell<HashMap<i32, Closure<dyn FnMut()>>> = RefCell::new(HashMap::new());
+    static INTERVALS: RefCell<HashMap<i32, Closure<dyn FnMut()>>> = RefCell::new(HashMap::new());
+}
+
+pub struct GlobalTimer;
+
+impl Timer for GlobalTimer {
+    fn set_timeout(&self, f: Box<dyn FnOnce()>, timeout: u32) -> i32 {
+        let id = Rc::new(Cell::new(0));
+        let fired = id.clone();
+        let closure = Closure::once(move || {
+            // js frees the closure once it returned
+            let closure = TIMEOUTS.with(|timeouts| timeouts.borrow_mut().remove(&fired.get()));
+            f();
+            drop(closure);
+        });
+        id.set(set_timeout_js(closure.as_ref().unchecked_ref(), timeout));
+        TIMEOUTS.with(|timeouts| timeouts.borrow_mut().insert(id.get(), closure));
+        id.get()
+    }
+
+    fn clear_timeout(&self, id: i32) {
+        clear_timeout_js(id);
+        let closure = TIMEOUTS.with(|timeouts| timeouts.borrow_mut().remove(&id));
+        drop(closure);
+    }
+
+    fn set_interval(&self, f: Box<dyn FnMut()>, interval: u32) -> i32 {
+        let closure = Closure::wrap(f);
+        let id = set_interval_js(closure.as_ref().unchecked_ref(), interval);
+        INTERVALS.with(|intervals| intervals.borrow_mut().insert(id, closure));
+        id
+    }
+
+    fn clear_interval(&self, id: i32) {
+        clear_interval_js(id);
+        let closure = INTERVALS.with(|intervals| intervals.borrow_mut().remove(&id));
+        drop(closure);
+    }
+}
+
+thread_local! {
+    static TIMER: RefCell<Rc<dyn Timer>> = RefCell::new(Rc::new(GlobalTimer));
+}
+
+pub fn set_timer(timer: impl Timer + 'static) {
+    TIMER.with(|current| *current.borrow_mut() = Rc::new(timer));
+}
+
+fn timer() -> Rc<dyn Timer> {
+    TIMER.with(|current| current.borrow().clone())
+}
+
+pub fn set_timeout_once(f: impl FnOnce() + 'static, timeout: u32) -> i32 {
+    timer().set_timeout(Box::new(f), timeout)
+}
+
+pub fn set_interval(f: impl FnMut() + 'static, interval: u32) -> i32 {
+    timer().set_interval(Box::new(f), interval)
+}
+
+pub fn clear_timeout(id: i32) {
+    timer().clear_timeout(id);
+}
+
+pub fn clear_interval(id: i32) {
+    timer().clear_interval(id);
+}
+
+#[derive(Default)]
+struct SleepState {
+    elapsed: bool,
+    waker: Option<Waker>,
+}
+
+// Resolves once `timeout` ms passed, dropping it clears the timeout.
+pub struct Sleep {
+    state: Rc<RefCell<SleepState>>,
+    id: i32,
+}
+
+pub fn sleep(timeout: u32) -> Sleep {
+    let state = Rc::new(RefCell::new(SleepState::default()));
+    let id = {
+        let state = state.clone();
+        set_timeout_once(
+            move || {
+                let mut state = state.borrow_mut();
+                state.elapsed = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            },
+            timeout,
+        )
+    };
+    Sleep { state, id }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        if state.elapsed {
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if !self.state.borrow().elapsed {
+            clear_timeout(self.id);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Elapsed(pub u32);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not done after {} ms", self.0)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+// `Err(Elapsed)` when `future` is not done within `timeout` ms, the future is
+// dropped then.
+pub struct Timeout<F> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+    timeout: u32,
+}
+
+pub fn timeout<F: Future>(future: F, timeout: u32) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(timeout),
+        timeout,
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        let timeout = self.timeout;
+        Pin::new(&mut self.sleep)
+            .poll(cx)
+            .map(|_| Err(Elapsed(timeout)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+    use futures::future::{pending, FutureExt};
+    use futures::task::noop_waker_ref;
+
+    #[derive(Default)]
+    struct ManualState {
+        next_id: i32,
+        timeouts: HashMap<i32, Box<dyn FnOnce()>>,
+    }
+
+    // Holds the timeouts until the test fires them.
+    #[derive(Clone, Default)]
+    struct ManualTimer {
+        state: Rc<RefCell<ManualState>>,
+    }
+
+    impl ManualTimer {
+        fn install() -> Self {
+            let timer = Self::default();
+            set_timer(timer.clone());
+            timer
+        }
+
+        fn pending(&self) -> usize {
+            self.state.borrow().timeouts.len()
+        }
+
+        fn fire_all(&self) {
+            let timeouts: Vec<Box<dyn FnOnce()>> = self
+                .state
+                .borrow_mut()
+                .timeouts
+                .drain()
+                .map(|(_, f)| f)
+                .collect();
+            for f in timeouts {
+                f();
+            }
+        }
+    }
+
+    impl Timer for ManualTimer {
+        fn set_timeout(&self, f: Box<dyn FnOnce()>, _timeout: u32) -> i32 {
+            let mut state = self.state.borrow_mut();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.timeouts.insert(id, f);
+            id
+        }
+
+        fn clear_timeout(&self, id: i32) {
+            self.state.borrow_mut().timeouts.remove(&id);
+        }
+
+        fn set_interval(&self, _f: Box<dyn FnMut()>, _interval: u32) -> i32 {
+            unimplemented!("no intervals in these tests")
+        }
+
+        fn clear_interval(&self, _id: i32) {}
+    }
+
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn sleep_resolves_once_the_timer_fired() {
+        let timer = ManualTimer::install();
+        let mut sleeping = sleep(100);
+        assert!(poll_once(&mut sleeping).is_pending());
+        assert_eq!(timer.pending(), 1);
+        timer.fire_all();
+        block_on(sleeping);
+        assert_eq!(timer.pending(), 0);
+    }
+
+    #[test]
+    fn timeout_fails_with_elapsed() {
+        let timer = ManualTimer::install();
+        let mut waiting = timeout(pending::<()>(), 250);
+        assert!(poll_once(&mut waiting).is_pending());
+        timer.fire_all();
+        assert_eq!(block_on(waiting), Err(Elapsed(250)));
+    }
+
+    #[test]
+    fn timeout_passes_on_a_ready_future() {
+        let timer = ManualTimer::install();
+        assert_eq!(block_on(timeout(async { 7 }, 250)), Ok(7));
+        assert_eq!(timer.pending(), 0);
+    }
+
+    #[test]
+    fn dropping_a_pending_future_clears_its_timer() {
+        let timer = ManualTimer::install();
+        let mut sleeping = sleep(100);
+        assert!(poll_once(&mut sleeping).is_pending());
+        let mut waiting = timeout(pending::<()>(), 250);
+        assert!(poll_once(&mut waiting).is_pending());
+        assert_eq!(timer.pending(), 2);
+        drop(sleeping);
+        assert_eq!(timer.pending(), 1);
+        drop(waiting);
+        assert_eq!(timer.pending(), 0);
+    }
+}
@@ -0,0 +1,198 @@
+use std::rc::Rc;
+
+use js_sys::Reflect;
+use wasm_bindgen::JsValue;
+
+enum UrlPart {
+    Static(String),
+    // read again for every connect attempt, e.g. a token that expires
+    // skipped when it returns `None`
+    Dynamic(Rc<dyn Fn() -> Option<String>>),
+}
+
+impl UrlPart {
+    fn value(&self) -> Option<String> {
+        match self {
+            UrlPart::Static(value) => Some(value.clone()),
+            UrlPart::Dynamic(f) => f(),
+        }
+    }
+}
+
+// Path segments and query parameters appended to the connect url, each one
+// percent-encoded.
+#[derive(Default)]
+pub struct UrlBuilder {
+    path_segments: Vec<UrlPart>,
+    // always after `path_segments`, whatever the order they were added in
+    suffix: Vec<UrlPart>,
+    query: Vec<(String, UrlPart)>,
+}
+
+impl UrlBuilder {
+    pub fn path_segment(&mut self, segment: &str) {
+        self.path_segments
+            .push(UrlPart::Static(String::from(segment)));
+    }
+
+    pub fn path_segment_fn(&mut self, f: impl Fn() -> String + 'static) {
+        self.path_segments
+            .push(UrlPart::Dynamic(Rc::new(move || Some(f()))));
+    }
+
+    pub fn suffix_segment(&mut self, segment: &str) {
+        self.suffix.push(UrlPart::Static(String::from(segment)));
+    }
+
+    pub fn suffix_segment_fn(&mut self, f: impl Fn() -> String + 'static) {
+        self.suffix
+            .push(UrlPart::Dynamic(Rc::new(move || Some(f()))));
+    }
+
+    pub fn query_param(&mut self, key: &str, value: &str) {
+        self.query
+            .push((String::from(key), UrlPart::Static(String::from(value))));
+    }
+
+    pub fn query_param_fn(&mut self, key: &str, f: impl Fn() -> String + 'static) {
+        self.query_param_opt(key, move || Some(f()));
+    }
+
+    pub fn query_param_opt(&mut self, key: &str, f: impl Fn() -> Option<String> + 'static) {
+        self.query
+            .push((String::from(key), UrlPart::Dynamic(Rc::new(f))));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.path_segments.is_empty() && self.suffix.is_empty() && self.query.is_empty()
+    }
+
+    pub fn build(&self, base: &str) -> String {
+        if self.is_empty() {
+            return String::from(base);
+        }
+        let (base, query) = match base.split_once('?') {
+            Some((base, query)) => (base, Some(query)),
+            None => (base, None),
+        };
+        let mut url = String::from(base);
+        let segments = self.path_segments.iter().chain(self.suffix.iter());
+        for segment in segments.filter_map(UrlPart::value) {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str(&encode_component(&segment));
+        }
+        let mut params: Vec<String> = query
+            .filter(|query| !query.is_empty())
+            .map(String::from)
+            .into_iter()
+            .collect();
+        for (key, value) in self.query.iter() {
+            let value = match value.value() {
+                Some(value) => value,
+                None => continue,
+            };
+            params.push(format!(
+                "{}={}",
+                encode_component(key),
+                encode_component(&value)
+            ));
+        }
+        if !params.is_empty() {
+            url.push('?');
+            url.push_str(&params.join("&"));
+        }
+        url
+    }
+}
+
+// Same as `encodeURIComponent`, done in rust since a `&str` never holds the
+// lone surrogates it throws on.
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'!'
+            | b'~'
+            | b'*'
+            | b'\''
+            | b'('
+            | b')' => encoded.push(char::from(byte)),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Read from `location` of the global scope, so it works in workers too.
+pub fn is_secure_page() -> bool {
+    Reflect::get(&js_sys::global(), &JsValue::from_str("location"))
+        .and_then(|location| Reflect::get(&location, &JsValue::from_str("protocol")))
+        .ok()
+        .and_then(|protocol| protocol.as_string())
+        .is_some_and(|protocol| protocol == "https:")
+}
+
+pub fn upgrade_to_tls(url: String) -> String {
+    match url.strip_prefix("ws://") {
+        Some(rest) => format!("wss://{}", rest),
+        None => url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_the_base_alone_when_empty() {
+        let builder = UrlBuilder::default();
+        assert_eq!(builder.build("wss://host/ws?a=1"), "wss://host/ws?a=1");
+    }
+
+    #[test]
+    fn appends_segments_and_params() {
+        let mut builder = UrlBuilder::default();
+        builder.path_segment("room 1");
+        builder.path_segment_fn(|| String::from("a/b"));
+        builder.query_param("token", "x&y=z");
+        builder.query_param_opt("skipped", || None);
+        builder.query_param_fn("v", || String::from("2"));
+        assert_eq!(
+            builder.build("wss://host/ws/?a=1"),
+            "wss://host/ws/room%201/a%2Fb?a=1&token=x%26y%3Dz&v=2"
+        );
+        assert_eq!(
+            builder.build("wss://host/ws?"),
+            "wss://host/ws/room%201/a%2Fb?token=x%26y%3Dz&v=2"
+        );
+    }
+
+    #[test]
+    fn keeps_the_suffix_last() {
+        let mut builder = UrlBuilder::default();
+        builder.path_segment("a");
+        builder.suffix_segment_fn(|| String::from("123"));
+        builder.suffix_segment("websocket");
+        builder.path_segment("b");
+        builder.query_param("v", "1");
+        assert_eq!(
+            builder.build("wss://host/echo"),
+            "wss://host/echo/a/b/123/websocket?v=1"
+        );
+    }
+
+    #[test]
+    fn encodes_like_encode_uri_component() {
+        assert_eq!(encode_component("azAZ09-_.!~*'()"), "azAZ09-_.!~*'()");
+        assert_eq!(encode_component("é €"), "%C3%A9%20%E2%82%AC");
+        assert_eq!(encode_component("a+b#c"), "a%2Bb%23c");
+    }
+}
@@ -0,0 +1,420 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use jsonrpc_core::Id;
+use serde_json::{json, Map, Value};
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+use crate::close::CloseCode;
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::protocol::ProtocolClient;
+use crate::simple_rpc::{BoundedIdGenerator, IdGenerator, CONNECTION_CLOSED};
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+const WAMP_SUBPROTOCOL: &str = "wamp.2.json";
+
+const HELLO: u64 = 1;
+const WELCOME: u64 = 2;
+const ABORT: u64 = 3;
+const GOODBYE: u64 = 6;
+const ERROR: u64 = 8;
+const PUBLISH: u64 = 16;
+const PUBLISHED: u64 = 17;
+const SUBSCRIBE: u64 = 32;
+const SUBSCRIBED: u64 = 33;
+const UNSUBSCRIBE: u64 = 34;
+const UNSUBSCRIBED: u64 = 35;
+const EVENT: u64 = 36;
+const CALL: u64 = 48;
+const RESULT: u64 = 50;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WampArgs {
+    pub args: Vec<Value>,
+    pub kwargs: Map<String, Value>,
+}
+
+impl WampArgs {
+    pub fn new(args: Vec<Value>) -> Self {
+        Self {
+            args,
+            kwargs: Map::new(),
+        }
+    }
+
+    pub fn kwarg(mut self, key: &str, value: Value) -> Self {
+        self.kwargs.insert(String::from(key), value);
+        self
+    }
+
+    // Empty args and kwargs are left off the end of the message.
+    fn append_to(self, message: &mut Vec<Value>) {
+        if self.args.is_empty() && self.kwargs.is_empty() {
+            return;
+        }
+        message.push(Value::Array(self.args));
+        if !self.kwargs.is_empty() {
+            message.push(Value::Object(self.kwargs));
+        }
+    }
+
+    fn from_parts(parts: &[Value]) -> Self {
+        Self {
+            args: parts
+                .first()
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default(),
+            kwargs: parts
+                .get(1)
+                .and_then(Value::as_object)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WampError {
+    // the error uri, `connection closed` when the session ended first
+    pub error: String,
+    pub arguments: WampArgs,
+}
+
+impl WampError {
+    fn closed() -> Self {
+        Self {
+            error: String::from(CONNECTION_CLOSED),
+            arguments: WampArgs::default(),
+        }
+    }
+}
+
+type EventHandler = Rc<dyn Fn(WampArgs)>;
+type CallCallback = Box<dyn FnOnce(Result<WampArgs, WampError>)>;
+
+enum Request {
+    Call(CallCallback),
+    Subscribe(String),
+    Unsubscribe,
+    Publish,
+}
+
+struct WampInner {
+    websocket: Websocket,
+    realm: String,
+    // WAMP wants ids from 1 to 2^53, the rpc generators start at 0
+    ids: BoundedIdGenerator,
+    session: Cell<Option<u64>>,
+    // requests sent in the current session, by request id
+    requests: RefCell<HashMap<u64, Request>>,
+    // topics subscribed to, with the subscription id of the current session
+    topics: RefCell<HashMap<String, (EventHandler, Option<u64>)>>,
+    // calls and publishes made before the session of the current socket
+    // started
+    queue: RefCell<VecDeque<(Vec<Value>, Request)>>,
+}
+
+impl WampInner {
+    fn request_id(&self) -> u64 {
+        match self.ids.next_id() {
+            Id::Num(id) => id,
+            _ => unreachable!(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.session.get().is_some() && matches!(self.websocket.ready_state(), ReadyState::Open)
+    }
+
+    fn send(&self, message: Vec<Value>) -> Result<(), WsError> {
+        self.websocket
+            .send(WsMessage::Text(Value::Array(message).to_string()))
+    }
+
+    // `message` goes out with a fresh request id after its type.
+    fn request(&self, mut message: Vec<Value>, request: Request) -> Result<(), WsError> {
+        if !self.is_open() {
+            self.queue.borrow_mut().push_back((message, request));
+            return Ok(());
+        }
+        let request_id = self.request_id();
+        message.insert(1, json!(request_id));
+        self.requests.borrow_mut().insert(request_id, request);
+        if let Err(err) = self.send(message) {
+            self.requests.borrow_mut().remove(&request_id);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn hello(&self) {
+        let details = json!({
+            "roles": {
+                "caller": {},
+                "publisher": {},
+                "subscriber": {},
+            }
+        });
+        if let Err(err) = self.send(vec![json!(HELLO), json!(self.realm), details]) {
+            console_log!("error on wamp hello: {:?}", err);
+        }
+    }
+
+    fn closed(&self) {
+        self.session.set(None);
+        for (_, subscription) in self.topics.borrow_mut().values_mut() {
+            subscription.take();
+        }
+        // the router forgets requests with the session
+        let requests: Vec<Request> = self
+            .requests
+            .borrow_mut()
+            .drain()
+            .map(|(_, request)| request)
+            .collect();
+        for request in requests {
+            if let Request::Call(callback) = request {
+                callback(Err(WampError::closed()));
+            }
+        }
+    }
+
+    // Topics are subscribed again, then the queue goes out.
+    fn welcomed(&self, session: u64) {
+        self.session.set(Some(session));
+        let topics: Vec<String> = self.topics.borrow().keys().cloned().collect();
+        for topic in topics {
+            let message = vec![json!(SUBSCRIBE), json!({}), json!(topic)];
+            if let Err(err) = self.request(message, Request::Subscribe(topic)) {
+                console_log!("error on wamp subscribe: {:?}", err);
+            }
+        }
+        let queued: Vec<(Vec<Value>, Request)> = self.queue.borrow_mut().drain(..).collect();
+        for (message, request) in queued {
+            if let Err(err) = self.request(message, request) {
+                console_log!("error on wamp request: {:?}", err);
+            }
+        }
+    }
+
+    fn dispatch(&self, data: &str) {
+        let message = match serde_json::from_str::<Value>(data) {
+            Ok(Value::Array(message)) if !message.is_empty() => message,
+            _ => {
+                console_log!("error on parse wamp message {}", data);
+                return;
+            }
+        };
+        let id = |index: usize| message.get(index).and_then(Value::as_u64).unwrap_or(0);
+        match id(0) {
+            WELCOME => self.welcomed(id(1)),
+            ABORT | GOODBYE => {
+                let reason = message
+                    .get(2)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                // a GOODBYE after `leave` is the reply of the router
+                if id(0) == GOODBYE && self.session.get().is_some() {
+                    let goodbye = vec![
+                        json!(GOODBYE),
+                        json!({}),
+                        json!("wamp.close.goodbye_and_out"),
+                    ];
+                    let _ = self.send(goodbye);
+                }
+                console_log!("wamp session ended: {}", reason);
+                if let Err(err) = self
+                    .websocket
+                    .clone()
+                    .close(Some(CloseCode::Normal), Some(reason))
+                {
+                    console_log!("error on close: {:?}", err);
+                }
+            }
+            EVENT => {
+                let handler = self
+                    .topics
+                    .borrow()
+                    .values()
+                    .find(|(_, subscription)| *subscription == Some(id(1)))
+                    .map(|(handler, _)| handler.clone());
+                if let Some(handler) = handler {
+                    handler(WampArgs::from_parts(message.get(4..).unwrap_or_default()));
+                }
+            }
+            RESULT | ERROR => {
+                let (request_id, rest) = if id(0) == RESULT {
+                    (id(1), 3)
+                } else {
+                    (id(2), 5)
+                };
+                let request = self.requests.borrow_mut().remove(&request_id);
+                let arguments = WampArgs::from_parts(message.get(rest..).unwrap_or_default());
+                match (request, id(0)) {
+                    (Some(Request::Call(callback)), RESULT) => callback(Ok(arguments)),
+                    (Some(Request::Call(callback)), _) => callback(Err(WampError {
+                        error: message
+                            .get(4)
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string(),
+                        arguments,
+                    })),
+                    (Some(Request::Subscribe(topic)), _) => {
+                        console_log!("wamp subscribe to {} failed: {:?}", topic, message.get(4));
+                    }
+                    _ => (),
+                }
+            }
+            SUBSCRIBED => {
+                let request = self.requests.borrow_mut().remove(&id(1));
+                if let Some(Request::Subscribe(topic)) = request {
+                    if let Some((_, subscription)) = self.topics.borrow_mut().get_mut(&topic) {
+                        *subscription = Some(id(2));
+                    } else {
+                        // unsubscribed while waiting for the reply
+                        let message = vec![json!(UNSUBSCRIBE), json!(id(2))];
+                        let _ = self.request(message, Request::Unsubscribe);
+                    }
+                }
+            }
+            UNSUBSCRIBED | PUBLISHED => {
+                self.requests.borrow_mut().remove(&id(1));
+            }
+            kind => console_log!("unknown wamp message type {}", kind),
+        }
+    }
+}
+
+// A WAMP v2 basic profile client (caller, publisher and subscriber roles)
+// with the JSON serializer. The factory url is the router endpoint, the
+// session joins `realm` after every reconnect and subscribes to its topics
+// again. Request ids go through the rpc id bookkeeping, bounded to the range
+// WAMP allows. Requests made outside a session wait for the next WELCOME,
+// calls still waiting for a result fail with a `connection closed` error
+// when the socket closes.
+#[derive(Clone)]
+pub struct WampClient {
+    inner: Rc<WampInner>,
+}
+
+impl WampClient {
+    pub fn build(mut factory: WsFactory, realm: &str) -> Result<Self, WsError> {
+        if factory.socket_factory.is_none() {
+            factory = factory.socket_factory(|url| WebSocket::new_with_str(url, WAMP_SUBPROTOCOL));
+        }
+        let inner = ProtocolClient::new(factory, |inner: &Rc<WampInner>, message| {
+            if let WsMessage::Text(data) = message {
+                inner.dispatch(&data);
+            }
+        })
+        .on_open(|wamp: &Rc<WampInner>| wamp.hello())
+        .on_close(|wamp: &Rc<WampInner>| wamp.closed())
+        .build(|websocket| WampInner {
+            websocket,
+            realm: String::from(realm),
+            ids: BoundedIdGenerator::default(),
+            session: Cell::new(None),
+            requests: RefCell::new(HashMap::new()),
+            topics: RefCell::new(HashMap::new()),
+            queue: RefCell::new(VecDeque::new()),
+        })?;
+        Ok(Self { inner })
+    }
+
+    pub fn websocket(&self) -> &Websocket {
+        &self.inner.websocket
+    }
+
+    // The id the router gave the current session.
+    pub fn session_id(&self) -> Option<u64> {
+        self.inner.session.get()
+    }
+
+    // The callback gets the result, the error of the callee, or `connection
+    // closed` when the session ended first. Queued until the session started.
+    pub fn call(
+        &self,
+        procedure: &str,
+        arguments: WampArgs,
+        callback: impl FnOnce(Result<WampArgs, WampError>) + 'static,
+    ) -> Result<(), WsError> {
+        let mut message = vec![json!(CALL), json!({}), json!(procedure)];
+        arguments.append_to(&mut message);
+        self.inner
+            .request(message, Request::Call(Box::new(callback)))
+    }
+
+    pub fn publish(&self, topic: &str, arguments: WampArgs) -> Result<(), WsError> {
+        let mut message = vec![json!(PUBLISH), json!({}), json!(topic)];
+        arguments.append_to(&mut message);
+        self.inner.request(message, Request::Publish)
+    }
+
+    // Replaces the handler of the topic when it is subscribed already.
+    pub fn subscribe(
+        &self,
+        topic: &str,
+        handler: impl Fn(WampArgs) + 'static,
+    ) -> Result<(), WsError> {
+        let handler: EventHandler = Rc::new(handler);
+        if let Some((previous, _)) = self.inner.topics.borrow_mut().get_mut(topic) {
+            *previous = handler;
+            return Ok(());
+        }
+        self.inner
+            .topics
+            .borrow_mut()
+            .insert(String::from(topic), (handler, None));
+        if !self.inner.is_open() {
+            return Ok(());
+        }
+        let message = vec![json!(SUBSCRIBE), json!({}), json!(topic)];
+        self.inner
+            .request(message, Request::Subscribe(String::from(topic)))
+    }
+
+    pub fn unsubscribe(&self, topic: &str) -> Result<(), WsError> {
+        let subscription = self.inner.topics.borrow_mut().remove(topic);
+        match subscription {
+            Some((_, Some(subscription))) if self.inner.is_open() => {
+                let message = vec![json!(UNSUBSCRIBE), json!(subscription)];
+                self.inner.request(message, Request::Unsubscribe)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.inner.topics.borrow().keys().cloned().collect()
+    }
+
+    // Ends the session, the router answers with GOODBYE and the socket
+    // closes.
+    pub fn leave(&self) -> Result<(), WsError> {
+        if !self.inner.is_open() {
+            return Ok(());
+        }
+        self.inner.session.set(None);
+        self.inner.send(vec![
+            json!(GOODBYE),
+            json!({}),
+            json!("wamp.close.close_realm"),
+        ])
+    }
+}
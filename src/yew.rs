@@ -0,0 +1,334 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ::yew::prelude::*;
+use futures::future::abortable;
+use futures::stream::StreamExt;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::close::CloseCode;
+use crate::emitter::{Emitter, Payload};
+use crate::error::WsError;
+use crate::factory::WsFactory;
+use crate::stream::ConnectionState;
+use crate::timers::set_timeout_once;
+use crate::{ReadyState, Websocket, WsMessage};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+macro_rules! console_log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+type Configure = Rc<dyn Fn(WsFactory) -> WsFactory>;
+type EventHandler = Rc<dyn Fn(&Payload)>;
+// the handler of the latest render of a component
+type LatestHandler = Rc<RefCell<Option<EventHandler>>>;
+
+// The components listening to one event of one emitter, the emitter holds a
+// single handler for the event that calls all of them in order.
+#[derive(Default)]
+struct EventListeners {
+    // of the handler in the emitter
+    owner: u64,
+    next_id: u64,
+    handlers: Vec<(u64, LatestHandler)>,
+}
+
+// keyed by the emitter and the event
+type ListenerMap = HashMap<(usize, String), Rc<RefCell<EventListeners>>>;
+
+thread_local! {
+    static EVENT_LISTENERS: RefCell<ListenerMap> = RefCell::new(HashMap::new());
+}
+
+fn listeners_key(emitter: &Rc<RefCell<Emitter>>, event: &str) -> (usize, String) {
+    (
+        Rc::as_ptr(emitter) as *const () as usize,
+        String::from(event),
+    )
+}
+
+// Returns the id to pass to `unlisten`.
+fn listen(emitter: &Rc<RefCell<Emitter>>, event: &str, handler: LatestHandler) -> u64 {
+    let key = listeners_key(emitter, event);
+    let listeners = EVENT_LISTENERS.with(|all| all.borrow_mut().entry(key).or_default().clone());
+    let id = {
+        let mut listeners = listeners.borrow_mut();
+        let id = listeners.next_id;
+        listeners.next_id += 1;
+        listeners.handlers.push((id, handler));
+        id
+    };
+    // installed again for every component, so the event is taken back from a
+    // `Websocket::listen` that came in between
+    let fan_out = Rc::downgrade(&listeners);
+    let owner = emitter.borrow_mut().on_owned(
+        String::from(event),
+        Box::new(move |payload: &Payload| {
+            let handlers: Vec<EventHandler> = match fan_out.upgrade() {
+                Some(listeners) => listeners
+                    .borrow()
+                    .handlers
+                    .iter()
+                    .filter_map(|(_, latest)| latest.borrow().clone())
+                    .collect(),
+                None => return,
+            };
+            for handler in handlers {
+                handler(payload);
+            }
+        }),
+    );
+    listeners.borrow_mut().owner = owner;
+    id
+}
+
+// The emitter handler goes with the last component.
+fn unlisten(emitter: Rc<RefCell<Emitter>>, event: String, id: u64) {
+    let key = listeners_key(&emitter, &event);
+    let owner = EVENT_LISTENERS.with(|all| {
+        let mut all = all.borrow_mut();
+        let listeners = all.get(&key)?.clone();
+        let mut listeners = listeners.borrow_mut();
+        listeners
+            .handlers
+            .retain(|(handler_id, _)| *handler_id != id);
+        if !listeners.handlers.is_empty() {
+            return None;
+        }
+        all.remove(&key);
+        Some(listeners.owner)
+    });
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return,
+    };
+    // the component can unmount while the emitter runs a handler
+    if let Ok(mut emitter) = emitter.try_borrow_mut() {
+        emitter.off_owned(&event, owner);
+        return;
+    }
+    set_timeout_once(move || emitter.borrow_mut().off_owned(&event, owner), 0);
+}
+
+// Builder options of the socket, read when it is built. Changing them does
+// not reconnect, a new url does.
+#[derive(Clone, Default)]
+pub struct WsOptions {
+    configure: Option<Configure>,
+}
+
+impl WsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called with the factory of the url before the socket is built.
+    pub fn configure(mut self, f: impl Fn(WsFactory) -> WsFactory + 'static) -> Self {
+        self.configure = Some(Rc::new(f));
+        self
+    }
+
+    fn build(&self, url: &str) -> Result<Websocket, WsError> {
+        let factory = Websocket::connect(String::from(url));
+        match self.configure.as_ref() {
+            Some(configure) => configure(factory).build(),
+            None => factory.build(),
+        }
+    }
+}
+
+impl PartialEq for WsOptions {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.configure.as_ref(), other.configure.as_ref()) {
+            (Some(configure), Some(other)) => Rc::ptr_eq(configure, other),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+// What `use_websocket` and the context of `WebsocketProvider` hand out. The
+// socket is `None` until the first render committed and when it failed to
+// build.
+#[derive(Clone)]
+pub struct UseWebsocket {
+    websocket: Option<Websocket>,
+    state: ConnectionState,
+}
+
+impl PartialEq for UseWebsocket {
+    fn eq(&self, other: &Self) -> bool {
+        let same_socket = match (self.websocket.as_ref(), other.websocket.as_ref()) {
+            (Some(websocket), Some(other)) => Rc::ptr_eq(&websocket.core, &other.core),
+            (None, None) => true,
+            _ => false,
+        };
+        same_socket && self.state == other.state
+    }
+}
+
+impl UseWebsocket {
+    pub fn websocket(&self) -> Option<&Websocket> {
+        self.websocket.as_ref()
+    }
+
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state == ConnectionState::Open
+    }
+
+    pub fn send(&self, message: WsMessage) -> Result<(), WsError> {
+        self.connected()?.send(message)
+    }
+
+    pub fn send_json<T: Serialize>(&self, payload: &T) -> Result<(), WsError> {
+        self.connected()?.send_json(payload)
+    }
+
+    // A callback for event handlers of the component, every value is sent
+    // as json. Errors are only logged.
+    pub fn json_sender<T: Serialize + 'static>(&self) -> Callback<T> {
+        let websocket = self.websocket.clone();
+        Callback::from(move |payload: T| {
+            let sent = match websocket.as_ref() {
+                Some(websocket) => websocket.send_json(&payload),
+                None => Err(WsError::NotConnected {
+                    state: ReadyState::Closed,
+                }),
+            };
+            if let Err(err) = sent {
+                console_log!("error on send: {:?}", err);
+            }
+        })
+    }
+
+    fn connected(&self) -> Result<&Websocket, WsError> {
+        self.websocket.as_ref().ok_or(WsError::NotConnected {
+            state: ReadyState::Closed,
+        })
+    }
+}
+
+// Connects to `url` once the component is mounted and closes the socket when
+// it unmounts or the url changes. The component re-renders on every
+// connection state change.
+#[hook]
+pub fn use_websocket(url: &str, options: WsOptions) -> UseWebsocket {
+    let websocket = use_state(|| None::<Websocket>);
+    let state = use_state(|| ConnectionState::Connecting);
+    {
+        let websocket = websocket.clone();
+        let state = state.clone();
+        use_effect_with(String::from(url), move |url| {
+            let built = match options.build(url) {
+                Ok(built) => Some(built),
+                Err(err) => {
+                    console_log!("error on build websocket {}: {:?}", url, err);
+                    state.set(ConnectionState::Error(err.to_string()));
+                    None
+                }
+            };
+            // aborted on cleanup, the stream is dropped with the task
+            let updates = built.as_ref().map(|built| {
+                let mut states = Box::pin(built.state_stream());
+                let state = state.clone();
+                let (task, updates) = abortable(async move {
+                    while let Some(next) = states.next().await {
+                        state.set(next);
+                    }
+                });
+                spawn_local(async move {
+                    let _ = task.await;
+                });
+                websocket.set(Some(built.clone()));
+                updates
+            });
+            move || {
+                if let Some(updates) = updates {
+                    updates.abort();
+                }
+                // other handles may still be held by the children, closing
+                // ends the connection for all of them
+                if let Some(built) = built {
+                    if let Err(err) = built.close(Some(CloseCode::Normal), None) {
+                        console_log!("error on close: {:?}", err);
+                    }
+                }
+            }
+        });
+    }
+    UseWebsocket {
+        websocket: (*websocket).clone(),
+        state: (*state).clone(),
+    }
+}
+
+// Calls `handler` with every payload emitted under `event` while the
+// component is mounted. The handler of the latest render is used, the
+// listener itself is only replaced when the event or the socket changes.
+// Every component using the hook for the event gets the payload, a listener
+// added with `Websocket::listen` is replaced.
+#[hook]
+pub fn use_ws_event<F>(websocket: &UseWebsocket, event: &str, handler: F)
+where
+    F: Fn(&Payload) + 'static,
+{
+    let latest = use_mut_ref(|| -> Option<EventHandler> { None });
+    *latest.borrow_mut() = Some(Rc::new(handler));
+    // keyed on the socket alone, state changes don't replace the listener
+    let socket = websocket.websocket.clone();
+    let core = socket.as_ref().map(|websocket| Rc::as_ptr(&websocket.core));
+    use_effect_with((String::from(event), core), move |(event, _)| {
+        let emitter = socket
+            .as_ref()
+            .and_then(|websocket| websocket.core.factory.emitter.clone());
+        let id = emitter
+            .as_ref()
+            .map(|emitter| listen(emitter, event, latest));
+        let event = event.clone();
+        move || {
+            if let (Some(emitter), Some(id)) = (emitter, id) {
+                unlisten(emitter, event, id);
+            }
+        }
+    });
+}
+
+// The socket of the closest `WebsocketProvider` above the component.
+#[hook]
+pub fn use_websocket_context() -> Option<UseWebsocket> {
+    use_context::<UseWebsocket>()
+}
+
+#[derive(Properties, PartialEq)]
+pub struct WebsocketProviderProps {
+    pub url: AttrValue,
+    #[prop_or_default]
+    pub options: WsOptions,
+    #[prop_or_default]
+    pub children: Html,
+}
+
+// Owns one socket for the components below it, they get it with
+// `use_websocket_context`.
+#[function_component]
+pub fn WebsocketProvider(props: &WebsocketProviderProps) -> Html {
+    let websocket = use_websocket(&props.url, props.options.clone());
+    html! {
+        <ContextProvider<UseWebsocket> context={websocket}>
+            { props.children.clone() }
+        </ContextProvider<UseWebsocket>>
+    }
+}
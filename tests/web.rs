@@ -11,3 +11,24 @@ wasm_bindgen_test_configure!(run_in_browser);
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+// Nothing listens on the discard port, so the socket fails right away. Without
+// reconnect it stays closed and a send is refused instead of being queued.
+#[wasm_bindgen_test]
+async fn js_connect_without_reconnect() {
+    use wasm_bindgen::JsValue;
+    use websocket::js::{JsReadyState, JsWebsocket};
+    use websocket::timers::sleep;
+
+    let socket = JsWebsocket::connect("ws://127.0.0.1:9".to_string(), Some(false)).unwrap();
+    for _ in 0..50 {
+        if socket.ready_state() == JsReadyState::Closed {
+            break;
+        }
+        sleep(100).await;
+    }
+    assert_eq!(socket.ready_state(), JsReadyState::Closed);
+    sleep(1500).await;
+    assert_eq!(socket.ready_state(), JsReadyState::Closed);
+    assert!(socket.send(JsValue::from_str("ping")).is_err());
+}